@@ -0,0 +1,164 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+// Benchmarks of frame encoding and decoding, masking, an echo round trip
+// over an in-memory connection, sending small client messages and, if
+// enabled, the deflate extension.
+//
+// Requires feature `testing`: cargo bench --features testing
+// (add `deflate` or `deflate-rust` to include the deflate benchmarks).
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use futures::{executor::block_on, io::AsyncReadExt};
+use soketto::{Parsing, base::{Codec, Header, OpCode}, connection::{Builder, Mode}, mock::duplex};
+
+/// Payload sizes from 16 B to 1 MiB.
+const SIZES: &[usize] = &[16, 128, 1024, 16 * 1024, 128 * 1024, 1024 * 1024];
+
+fn header(c: &mut Criterion) {
+    let mut group = c.benchmark_group("header");
+    for &len in &[16, 1024, 128 * 1024] {
+        let mut header = Header::new(OpCode::Binary);
+        header.set_masked(true);
+        header.set_mask(0x1234_5678);
+        header.set_payload_len(len);
+        let mut codec = Codec::new();
+        let bytes = codec.encode_header(&header).to_vec();
+
+        group.bench_with_input(BenchmarkId::new("encode", len), &header, |b, header| {
+            b.iter(|| codec.encode_header(header).len())
+        });
+        group.bench_with_input(BenchmarkId::new("decode", len), &bytes, |b, bytes| {
+            b.iter(|| match codec.decode_header(bytes) {
+                Ok(Parsing::Done { offset, .. }) => offset,
+                _ => unreachable!("valid header")
+            })
+        });
+    }
+    group.finish()
+}
+
+fn mask(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mask");
+    let mut header = Header::new(OpCode::Binary);
+    header.set_masked(true);
+    header.set_mask(0x1234_5678);
+    for &len in SIZES {
+        group.throughput(Throughput::Bytes(len as u64));
+        // Offsets into the buffer to cover unaligned payload data.
+        for &offset in &[0, 1, 3] {
+            let mut buffer = vec![0xAB; len + offset];
+            let id = BenchmarkId::new(format!("offset-{}", offset), len);
+            group.bench_function(id, |b| {
+                b.iter(|| Codec::apply_mask(&header, &mut buffer[offset ..]))
+            });
+        }
+    }
+    group.finish()
+}
+
+fn echo(c: &mut Criterion) {
+    let mut group = c.benchmark_group("echo");
+    for &len in SIZES {
+        group.throughput(Throughput::Bytes(len as u64));
+        let (a, b) = duplex(64 * 1024);
+        let (mut client_tx, mut client_rx) = Builder::new(a, Mode::Client).finish();
+        let (mut server_tx, mut server_rx) = Builder::new(b, Mode::Server).finish();
+        let payload = vec![0xAB; len];
+        let mut client_buf = Vec::new();
+        let mut server_buf = Vec::new();
+        group.bench_function(BenchmarkId::from_parameter(len), |b| {
+            b.iter(|| block_on(async {
+                client_tx.send_binary(&payload).await.unwrap();
+                client_tx.flush().await.unwrap();
+                server_buf.clear();
+                server_rx.receive_data(&mut server_buf).await.unwrap();
+                server_tx.send_binary(&server_buf).await.unwrap();
+                server_tx.flush().await.unwrap();
+                client_buf.clear();
+                client_rx.receive_data(&mut client_buf).await.unwrap();
+            }))
+        });
+    }
+    group.finish()
+}
+
+fn client_send(c: &mut Criterion) {
+    let mut group = c.benchmark_group("client-send");
+    group.throughput(Throughput::Elements(1000));
+    let (a, mut b) = duplex(64 * 1024);
+    let (mut sender, _receiver) = Builder::new(a, Mode::Client).finish();
+    let mut sink = vec![0; 64 * 1024];
+    group.bench_function("1000x16", |bench| {
+        bench.iter(|| block_on(async {
+            for _ in 0 .. 1000 {
+                sender.send_binary(&[0xAB; 16]).await.unwrap()
+            }
+            sender.flush().await.unwrap();
+            while b.read(&mut sink).await.unwrap() == sink.len() {}
+        }))
+    });
+    group.finish()
+}
+
+#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+fn deflate(c: &mut Criterion) {
+    use soketto::{Storage, extension::{Extension, deflate::Deflate}};
+
+    let mut group = c.benchmark_group("deflate");
+    let mut text = String::new();
+    for i in 0 .. {
+        if text.len() >= 1024 * 1024 {
+            break
+        }
+        text.push_str(&format!("{{\"id\":{},\"result\":\"hello world\"}}", i))
+    }
+    for &len in &SIZES[2 ..] {
+        group.throughput(Throughput::Bytes(len as u64));
+        let data = &text.as_bytes()[.. len];
+
+        let mut client = Deflate::new(Mode::Client);
+        client.configure(&[]).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("encode", len), data, |b, data| {
+            b.iter(|| {
+                let mut header = Header::new(OpCode::Text);
+                let mut storage = Storage::Shared(data);
+                client.encode(&mut header, &mut storage).unwrap();
+                storage.as_ref().len()
+            })
+        });
+
+        // Without context takeover every message can be decoded independently.
+        let mut encoder = Deflate::new(Mode::Client);
+        encoder.configure(&[]).unwrap();
+        let mut header = Header::new(OpCode::Text);
+        let mut storage = Storage::Shared(data);
+        encoder.encode(&mut header, &mut storage).unwrap();
+        let compressed = storage.as_ref().to_vec();
+        let mut decoder = Deflate::new(Mode::Server);
+        decoder.set_client_no_context_takeover(true);
+        decoder.configure(&[]).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("decode", len), &compressed, |b, compressed| {
+            b.iter(|| {
+                let mut h = header.clone();
+                let mut buffer = compressed.clone();
+                decoder.decode(&mut h, &mut buffer).unwrap();
+                buffer.len()
+            })
+        });
+    }
+    group.finish()
+}
+
+#[cfg(not(any(feature = "deflate", feature = "deflate-rust")))]
+fn deflate(_: &mut Criterion) {}
+
+criterion_group!(benches, header, mask, echo, client_send, deflate);
+criterion_main!(benches);
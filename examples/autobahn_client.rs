@@ -14,11 +14,15 @@
 //
 // See https://github.com/crossbario/autobahn-testsuite for details.
 
-use futures::io::{BufReader, BufWriter};
-use soketto::{BoxedError, connection, handshake};
-use std::str::FromStr;
+use soketto::{BoxedError, connection, extension::Extension, handshake};
+use std::{convert::TryFrom, str::FromStr};
 use tokio::net::TcpStream;
-use tokio_util::compat::{Compat, Tokio02AsyncReadCompatExt};
+use tokio_util::compat::Compat;
+
+#[cfg(not(feature = "tokio"))]
+use futures::io::{BufReader, BufWriter};
+#[cfg(not(feature = "tokio"))]
+use tokio_util::compat::Tokio02AsyncReadCompatExt;
 
 const SOKETTO_VERSION: &str = env!("CARGO_PKG_VERSION");
 
@@ -39,10 +43,8 @@ async fn num_of_cases() -> Result<usize, BoxedError> {
     let mut client = new_client(socket, "/getCaseCount");
     assert!(matches!(client.handshake().await?, handshake::ServerResponse::Accepted {..}));
     let (_, mut receiver) = client.into_builder().finish();
-    let mut data = Vec::new();
-    let kind = receiver.receive_data(&mut data).await?;
-    assert!(kind.is_text());
-    let num = usize::from_str(std::str::from_utf8(&data)?)?;
+    let text = String::try_from(receiver.receive().await?).map_err(|m| format!("unexpected message: {:?}", m))?;
+    let num = usize::from_str(&text)?;
     log::info!("{} cases to run", num);
     Ok(num)
 }
@@ -52,7 +54,11 @@ async fn run_case(n: usize) -> Result<(), BoxedError> {
     let resource = format!("/runCase?case={}&agent=soketto-{}", n, SOKETTO_VERSION);
     let socket = TcpStream::connect("127.0.0.1:9001").await?;
     let mut client = new_client(socket, &resource);
-    assert!(matches!(client.handshake().await?, handshake::ServerResponse::Accepted {..}));
+    match client.handshake().await? {
+        handshake::ServerResponse::Accepted { extensions, .. } =>
+            log::info!("case {}: compression negotiated: {}", n, extensions.iter().any(|e| e == "permessage-deflate")),
+        other => return Err(format!("unexpected response: {:?}", other).into())
+    }
     let (mut sender, mut receiver) = client.into_builder().finish();
     let mut message = Vec::new();
     loop {
@@ -84,17 +90,30 @@ async fn update_report() -> Result<(), BoxedError> {
     Ok(())
 }
 
-#[cfg(not(feature = "deflate"))]
-fn new_client(socket: TcpStream, path: &str) -> handshake::Client<'_, BufReader<BufWriter<Compat<TcpStream>>>> {
-    handshake::Client::new(BufReader::new(BufWriter::new(socket.compat())), "127.0.0.1:9001", path)
-}
-
-#[cfg(feature = "deflate")]
+#[cfg(not(feature = "tokio"))]
 fn new_client(socket: TcpStream, path: &str) -> handshake::Client<'_, BufReader<BufWriter<Compat<TcpStream>>>> {
     let socket = BufReader::with_capacity(8 * 1024, BufWriter::with_capacity(64 * 1024, socket.compat()));
     let mut client = handshake::Client::new(socket, "127.0.0.1:9001", path);
-    let deflate = soketto::extension::deflate::Deflate::new(soketto::Mode::Client);
-    client.add_extension(Box::new(deflate));
+    for e in extensions() {
+        client.add_extension(e);
+    }
     client
 }
 
+// With feature `tokio`, the socket is used directly, without buffering.
+#[cfg(feature = "tokio")]
+fn new_client(socket: TcpStream, path: &str) -> handshake::Client<'_, Compat<TcpStream>> {
+    let mut client = handshake::Client::from_tokio(socket, "127.0.0.1:9001", path);
+    for e in extensions() {
+        client.add_extension(e);
+    }
+    client
+}
+
+fn extensions() -> Vec<Box<dyn Extension + Send + Sync>> {
+    #[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+    return vec![Box::new(soketto::extension::deflate::Deflate::new(soketto::Mode::Client))];
+    #[cfg(not(any(feature = "deflate", feature = "deflate-rust")))]
+    Vec::new()
+}
+
@@ -14,10 +14,14 @@
 //
 // See https://github.com/crossbario/autobahn-testsuite for details.
 
-use futures::io::{BufReader, BufWriter};
-use soketto::{BoxedError, connection, handshake};
+use soketto::{BoxedError, connection, extension::Extension, handshake};
 use tokio::{net::{TcpListener, TcpStream}, stream::StreamExt};
-use tokio_util::compat::{Compat, Tokio02AsyncReadCompatExt};
+use tokio_util::compat::Compat;
+
+#[cfg(not(feature = "tokio"))]
+use futures::io::{BufReader, BufWriter};
+#[cfg(not(feature = "tokio"))]
+use tokio_util::compat::Tokio02AsyncReadCompatExt;
 
 #[tokio::main]
 async fn main() -> Result<(), BoxedError> {
@@ -25,12 +29,32 @@ async fn main() -> Result<(), BoxedError> {
     let mut incoming = listener.incoming();
     while let Some(socket) = incoming.next().await {
         let mut server = new_server(socket?);
-        let key = {
-            let req = server.receive_request().await?;
-            req.into_key()
+        let (key, path) = match server.receive_request().await {
+            Ok(req) => {
+                let path = req.path().to_string();
+                (req.into_key(), path)
+            }
+            Err(handshake::Error::InvalidRequestMethod { .. }) => {
+                let reject = handshake::server::Response::method_not_allowed();
+                server.send_response(&reject).await?;
+                continue
+            }
+            Err(e) => return Err(e.into())
         };
-        let accept = handshake::server::Response::Accept { key: &key, protocol: None };
+        if path != "/" {
+            log::debug!("unknown path: {}", path);
+            let reject = handshake::server::Response::Reject {
+                status_code: 404,
+                extra_headers: &[],
+                body: &[],
+                content_type: None
+            };
+            server.send_response(&reject).await?;
+            continue
+        }
+        let accept = handshake::server::Response::Accept { key: key.as_ref(), protocol: None, extra_headers: &[] };
         server.send_response(&accept).await?;
+        log::info!("compression negotiated: {}", server.extensions().any(|e| e.is_enabled()));
         let (mut sender, mut receiver) = server.into_builder().finish();
         let mut message = Vec::new();
         loop {
@@ -61,17 +85,30 @@ async fn main() -> Result<(), BoxedError> {
     Ok(())
 }
 
-#[cfg(not(feature = "deflate"))]
-fn new_server<'a>(socket: TcpStream) -> handshake::Server<'a, BufReader<BufWriter<Compat<TcpStream>>>> {
-    handshake::Server::new(BufReader::new(BufWriter::new(socket.compat())))
-}
-
-#[cfg(feature = "deflate")]
+#[cfg(not(feature = "tokio"))]
 fn new_server<'a>(socket: TcpStream) -> handshake::Server<'a, BufReader<BufWriter<Compat<TcpStream>>>> {
     let socket = BufReader::with_capacity(8 * 1024, BufWriter::with_capacity(16 * 1024, socket.compat()));
     let mut server = handshake::Server::new(socket);
-    let deflate = soketto::extension::deflate::Deflate::new(soketto::Mode::Server);
-    server.add_extension(Box::new(deflate));
+    for e in extensions() {
+        server.add_extension(e);
+    }
+    server
+}
+
+// With feature `tokio`, the socket is used directly, without buffering.
+#[cfg(feature = "tokio")]
+fn new_server<'a>(socket: TcpStream) -> handshake::Server<'a, Compat<TcpStream>> {
+    let mut server = handshake::Server::from_tokio(socket);
+    for e in extensions() {
+        server.add_extension(e);
+    }
     server
 }
 
+fn extensions() -> Vec<Box<dyn Extension + Send + Sync>> {
+    #[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+    return vec![Box::new(soketto::extension::deflate::Deflate::new(soketto::Mode::Server))];
+    #[cfg(not(any(feature = "deflate", feature = "deflate-rust")))]
+    Vec::new()
+}
+
@@ -0,0 +1,65 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+// An echo server where hyper handles HTTP and soketto the websocket
+// connection after the upgrade.
+//
+// Requires feature `http`: cargo run --example hyper_server --features http
+
+use hyper::{Body, Request, Response, service::{make_service_fn, service_fn}};
+use soketto::{BoxedError, Data, connection, handshake};
+use tokio_util::compat::Tokio02AsyncReadCompatExt;
+
+#[tokio::main]
+async fn main() -> Result<(), BoxedError> {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, BoxedError>(service_fn(handle))
+    });
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 9001).into()).serve(make_service);
+    log::info!("listening on {}", server.local_addr());
+    server.await?;
+    Ok(())
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, BoxedError> {
+    if !handshake::http::is_upgrade_request(&request) {
+        return Ok(Response::new(Body::from("Hello HTTP!")))
+    }
+    let response = match handshake::http::upgrade_response(&request, None) {
+        Ok(response) => response,
+        Err(e) => {
+            log::debug!("invalid upgrade request: {}", e);
+            let mut response = Response::new(Body::empty());
+            *response.status_mut() = hyper::StatusCode::BAD_REQUEST;
+            return Ok(response)
+        }
+    };
+    tokio::spawn(async move {
+        if let Err(e) = echo(request).await {
+            log::error!("connection error: {}", e)
+        }
+    });
+    Ok(response.map(|()| Body::empty()))
+}
+
+async fn echo(request: Request<Body>) -> Result<(), BoxedError> {
+    let upgraded = request.into_body().on_upgrade().await?;
+    let builder = connection::Builder::new(upgraded.compat(), connection::Mode::Server);
+    let (mut sender, mut receiver) = builder.finish();
+    let mut message = Vec::new();
+    loop {
+        message.clear();
+        match receiver.receive_data(&mut message).await {
+            Ok(Data::Binary(_)) => sender.send_binary_mut(&mut message).await?,
+            Ok(Data::Text(_)) => sender.send_text(std::str::from_utf8(&message)?).await?,
+            Err(connection::Error::Closed) => return Ok(()),
+            Err(e) => return Err(e.into())
+        }
+        sender.flush().await?
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+// A client which sends a message to a `wss://` echo server and prints
+// the answer.
+//
+// Requires feature `tls`: cargo run --example wss_client --features tls [domain] [resource]
+//
+// Without arguments, the public echo server at ws.postman-echo.com is used.
+
+use soketto::{BoxedError, Message, handshake::{ServerResponse, client::connect_tls}};
+
+#[tokio::main]
+async fn main() -> Result<(), BoxedError> {
+    let mut args = std::env::args().skip(1);
+    let domain = args.next().unwrap_or_else(|| "ws.postman-echo.com".into());
+    let resource = args.next().unwrap_or_else(|| "/raw".into());
+
+    // The default connector trusts the Mozilla root certificates.
+    let connector = async_tls::TlsConnector::default();
+    let mut client = connect_tls(&connector, &domain, 443, resource.as_str()).await?;
+
+    let (mut sender, mut receiver) = match client.handshake().await? {
+        ServerResponse::Accepted { .. } => client.into_builder().finish(),
+        other => return Err(format!("handshake failed: {:?}", other).into())
+    };
+
+    sender.send_text("hello over TLS").await?;
+    sender.flush().await?;
+
+    match receiver.receive().await? {
+        Message::Text(text) => println!("received: {}", String::from_utf8_lossy(&text)),
+        other => println!("received: {:?}", other)
+    }
+
+    sender.close().await?;
+    Ok(())
+}
@@ -20,11 +20,11 @@
 use crate::{as_u64, Parsing};
 use std::{convert::TryFrom, fmt, io};
 
-/// Max. size of a frame header.
-pub(crate) const MAX_HEADER_SIZE: usize = 14;
+/// Max. size of an encoded frame header, including the mask.
+pub const MAX_HEADER_SIZE: usize = 14;
 
 /// Max. size of a control frame payload.
-pub(crate) const MAX_CTRL_BODY_SIZE: u64 = 125;
+pub const MAX_CTRL_PAYLOAD: usize = 125;
 
 // OpCode /////////////////////////////////////////////////////////////////////////////////////////
 
@@ -68,16 +68,12 @@ pub enum OpCode {
 impl OpCode {
     /// Is this a control opcode?
     pub fn is_control(self) -> bool {
-        if let OpCode::Close | OpCode::Ping | OpCode::Pong = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, OpCode::Close | OpCode::Ping | OpCode::Pong)
     }
 
     /// Is this opcode reserved?
     pub fn is_reserved(self) -> bool {
-        match self {
+        matches! { self,
             OpCode::Reserved3
             | OpCode::Reserved4
             | OpCode::Reserved5
@@ -87,8 +83,7 @@ impl OpCode {
             | OpCode::Reserved12
             | OpCode::Reserved13
             | OpCode::Reserved14
-            | OpCode::Reserved15 => true,
-            _ => false
+            | OpCode::Reserved15
         }
     }
 }
@@ -181,7 +176,7 @@ impl From<OpCode> for u8 {
 // Frame header ///////////////////////////////////////////////////////////////////////////////////
 
 /// A websocket base frame header, i.e. everything but the payload.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Header {
     fin: bool,
     rsv1: bool,
@@ -309,6 +304,32 @@ impl Header {
         self.payload_len = len;
         self
     }
+
+    /// Return this header with the given payload length.
+    pub fn with_payload_len(mut self, len: usize) -> Self {
+        self.payload_len = len;
+        self
+    }
+
+    /// The number of bytes this header occupies when encoded.
+    ///
+    /// Depending on the payload length, the header has 2, 4 or 10 bytes,
+    /// plus 4 bytes if it is masked (cf. [`MAX_HEADER_SIZE`]).
+    pub fn encoded_len(&self) -> usize {
+        let len =
+            if self.payload_len < usize::from(TWO_EXT) {
+                2
+            } else if self.payload_len <= usize::from(u16::MAX) {
+                4
+            } else {
+                10
+            };
+        if self.masked {
+            len + 4
+        } else {
+            len
+        }
+    }
 }
 
 // Base codec ////////////////////////////////////////////////////////////////////////////////////.
@@ -446,7 +467,7 @@ impl Codec {
             n => u64::from(n)
         };
 
-        if len > MAX_CTRL_BODY_SIZE && header.opcode().is_control() {
+        if len > as_u64(MAX_CTRL_PAYLOAD) && header.opcode().is_control() {
             return Err(Error::InvalidControlFrameLen)
         }
 
@@ -510,7 +531,7 @@ impl Codec {
             second_byte |= len as u8;
             self.header_buffer[offset] = second_byte;
             offset += 1;
-        } else if len <= usize::from(u16::max_value()) {
+        } else if len <= usize::from(u16::MAX) {
             second_byte |= TWO_EXT;
             self.header_buffer[offset] = second_byte;
             offset += 1;
@@ -529,6 +550,7 @@ impl Codec {
             offset += 4;
         }
 
+        debug_assert_eq!(offset, header.encoded_len());
         &self.header_buffer[.. offset]
     }
 
@@ -617,8 +639,77 @@ impl From<UnknownOpCode> for Error {
 #[cfg(test)]
 mod test {
     use crate::Parsing;
-    use quickcheck::QuickCheck;
-    use super::{OpCode, Codec, Error};
+    use quickcheck::{Arbitrary, Gen, QuickCheck};
+    use std::convert::TryFrom;
+    use super::{OpCode, Codec, Error, Header, MAX_CTRL_PAYLOAD, MAX_HEADER_SIZE};
+
+    /// Number of cases per property, to keep test times reasonable.
+    const CASES: u64 = 500;
+
+    /// A valid frame header with some payload data.
+    #[derive(Debug, Clone)]
+    struct Frame {
+        header: Header,
+        payload: Vec<u8>
+    }
+
+    impl Arbitrary for Frame {
+        fn arbitrary<G: Gen>(g: &mut G) -> Self {
+            let opcodes = [0, 1, 2, 8, 9, 10];
+            let opcode = OpCode::try_from(opcodes[usize::arbitrary(g) % opcodes.len()]).unwrap();
+            let n = usize::arbitrary(g);
+            // Payload lengths of all three encoding widths.
+            let len =
+                if opcode.is_control() {
+                    n % (MAX_CTRL_PAYLOAD + 1)
+                } else {
+                    match u8::arbitrary(g) % 3 {
+                        0 => n % 126,
+                        1 => 126 + n % (usize::from(u16::MAX) - 125),
+                        _ => usize::from(u16::MAX) + 1 + n % 4096
+                    }
+                };
+            let mut header = Header::new(opcode).with_payload_len(len);
+            header.set_fin(opcode.is_control() || bool::arbitrary(g))
+                .set_rsv1(bool::arbitrary(g))
+                .set_rsv2(bool::arbitrary(g))
+                .set_rsv3(bool::arbitrary(g))
+                .set_masked(bool::arbitrary(g));
+            if header.is_masked() {
+                header.set_mask(u32::arbitrary(g));
+            }
+            let seed = u8::arbitrary(g);
+            let payload = (0 .. len).map(|i| (i as u8).wrapping_mul(seed)).collect();
+            Frame { header, payload }
+        }
+    }
+
+    /// A codec which accepts all reserved bits.
+    fn codec() -> Codec {
+        let mut codec = Codec::new();
+        codec.add_reserved_bits((true, true, true));
+        codec
+    }
+
+    #[test]
+    fn encoded_len() {
+        let cases = [(0, 2), (125, 2), (126, 4), (65535, 4), (65536, 10)];
+        let mut codec = Codec::new();
+        for (payload_len, len) in &cases {
+            for masked in &[false, true] {
+                let mut header = Header::new(OpCode::Binary).with_payload_len(*payload_len);
+                header.set_masked(*masked).set_mask(0x01020304);
+                let expected = if *masked { len + 4 } else { *len };
+                assert_eq!(expected, header.encoded_len(), "{}", header);
+                assert_eq!(expected, codec.encode_header(&header).len(), "{}", header);
+                assert!(header.encoded_len() <= MAX_HEADER_SIZE);
+                let encoded = codec.encode_header(&header).to_vec();
+                let decoded = codec.decode_header(&encoded).unwrap();
+                assert!(matches!(decoded, Parsing::Done { value, offset }
+                    if offset == expected && value.payload_len() == *payload_len))
+            }
+        }
+    }
 
     #[test]
     fn decode_partial_header() {
@@ -658,7 +749,7 @@ mod test {
         if let Ok(Parsing::Done { value, offset }) = Codec::new().decode_header(partial_payload) {
             assert_eq!(3, value.payload_len() - (partial_payload.len() - offset))
         } else {
-            assert!(false)
+            panic!("unexpected decoding result")
         }
     }
 
@@ -727,8 +818,43 @@ mod test {
             assert!(header.opcode() == OpCode::Ping);
             assert!(header.payload_len() == 0)
         } else {
-            assert!(false)
+            panic!("unexpected decoding result")
+        }
+    }
+
+    #[test]
+    fn encode_decode_round_trip() {
+        fn property(frame: Frame) -> bool {
+            let mut codec = codec();
+            let mut bytes = codec.encode_header(&frame.header).to_vec();
+            let offset = bytes.len();
+            bytes.extend_from_slice(&frame.payload);
+            Codec::apply_mask(&frame.header, &mut bytes[offset ..]);
+            match codec.decode_header(&bytes) {
+                Ok(Parsing::Done { value, offset: o }) => {
+                    let mut payload = bytes[o ..].to_vec();
+                    Codec::apply_mask(&value, &mut payload);
+                    o == offset && value == frame.header && payload == frame.payload
+                }
+                _ => false
+            }
+        }
+        QuickCheck::new().tests(CASES).quickcheck(property as fn(Frame) -> bool)
+    }
+
+    #[test]
+    fn header_bit_flips_are_detected() {
+        fn property(frame: Frame, position: usize) -> bool {
+            let mut codec = codec();
+            let mut bytes = codec.encode_header(&frame.header).to_vec();
+            let position = position % (bytes.len() * 8);
+            bytes[position / 8] ^= 1 << (position % 8);
+            match codec.decode_header(&bytes) {
+                Ok(Parsing::Done { value, .. }) => value != frame.header,
+                Ok(Parsing::NeedMore(_)) | Err(_) => true
+            }
         }
+        QuickCheck::new().tests(CASES).quickcheck(property as fn(Frame, usize) -> bool)
     }
 
     #[test]
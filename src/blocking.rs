@@ -0,0 +1,371 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A blocking API over `std::io::{Read, Write}` sockets (requires feature `blocking`).
+//!
+//! The handshakes run the [`handshake`] types to completion on the current
+//! thread and [`Connection`] drives a [`ConnectionState`] with blocking I/O,
+//! so framing, masking, closing and extensions work exactly as with async
+//! sockets. Timeouts can be set on the socket, e.g. with
+//! [`std::net::TcpStream::set_read_timeout`], and are returned as
+//! [`connection::Error::Io`] errors after which the connection can still be
+//! used. Errors while writing leave the connection closed, as the remote may
+//! have received an incomplete frame.
+//!
+//! # Example
+//!
+//! ```no_run
+//! # fn doc() -> Result<(), soketto::BoxedError> {
+//! use soketto::{Message, blocking::Client, handshake::ServerResponse};
+//!
+//! let socket = std::net::TcpStream::connect("...")?;
+//! let mut client = Client::new(socket, "...", "/");
+//! let mut connection = match client.handshake()? {
+//!     ServerResponse::Accepted { .. } => client.into_connection(),
+//!     other => return Err(format!("handshake failed: {:?}", other).into())
+//! };
+//! connection.send_text("hello")?;
+//! if let Message::Text(text) = connection.receive()? {
+//!     println!("text: {:?}", text)
+//! }
+//! connection.close()?;
+//! # Ok(())
+//! # }
+//! ```
+
+use bytes::BytesMut;
+use crate::connection::{self, ConnectionState, Error, Event, Mode};
+use crate::data::{ByteBuf125, CloseReason, Message, OutgoingMessage};
+use crate::handshake::{self, ClientRequest, ServerResponse, server::Response};
+use futures::{executor::block_on, io::AllowStdIo};
+use std::{borrow::Cow, collections::VecDeque, fmt, io::{self, Read, Write}};
+
+/// Size of the buffer to read into.
+const READ_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Blocking websocket client handshake.
+///
+/// The underlying [`handshake::Client`] can be configured with
+/// [`Client::handshake_mut`].
+#[derive(Debug)]
+pub struct Client<'a, T> {
+    inner: handshake::Client<'a, AllowStdIo<T>>
+}
+
+impl<'a, T: Read + Write> Client<'a, T> {
+    /// Create a new client handshake for some host and resource.
+    pub fn new(socket: T, host: impl Into<Cow<'a, str>>, resource: impl Into<Cow<'a, str>>) -> Self {
+        Client { inner: handshake::Client::new(AllowStdIo::new(socket), host, resource) }
+    }
+
+    /// Access the async handshake to configure it, e.g. to add extensions.
+    pub fn handshake_mut(&mut self) -> &mut handshake::Client<'a, AllowStdIo<T>> {
+        &mut self.inner
+    }
+
+    /// Perform the handshake, cf. [`handshake::Client::handshake`].
+    pub fn handshake(&mut self) -> Result<ServerResponse, handshake::Error> {
+        block_on(self.inner.handshake())
+    }
+
+    /// Turn this handshake into a [`Connection`].
+    pub fn into_connection(self) -> Connection<T> {
+        Connection::from_builder(self.inner.into_builder())
+    }
+
+    /// Get out the inner socket.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().into_inner()
+    }
+}
+
+/// Blocking websocket server handshake.
+///
+/// The underlying [`handshake::Server`] can be configured with
+/// [`Server::handshake_mut`].
+#[derive(Debug)]
+pub struct Server<'a, T> {
+    inner: handshake::Server<'a, AllowStdIo<T>>
+}
+
+impl<'a, T: Read + Write> Server<'a, T> {
+    /// Create a new server handshake.
+    pub fn new(socket: T) -> Self {
+        Server { inner: handshake::Server::new(AllowStdIo::new(socket)) }
+    }
+
+    /// Access the async handshake to configure it, e.g. to add extensions.
+    pub fn handshake_mut(&mut self) -> &mut handshake::Server<'a, AllowStdIo<T>> {
+        &mut self.inner
+    }
+
+    /// Receive the client handshake request, cf. [`handshake::Server::receive_request`].
+    pub fn receive_request(&mut self) -> Result<ClientRequest<'a>, handshake::Error> {
+        block_on(self.inner.receive_request())
+    }
+
+    /// Respond to the client, cf. [`handshake::Server::send_response`].
+    pub fn send_response(&mut self, r: &Response<'_>) -> Result<(), handshake::Error> {
+        block_on(self.inner.send_response(r))
+    }
+
+    /// Turn this handshake into a [`Connection`].
+    pub fn into_connection(self) -> Connection<T> {
+        Connection::from_builder(self.inner.into_builder())
+    }
+
+    /// Get out the inner socket.
+    pub fn into_inner(self) -> T {
+        self.inner.into_inner().into_inner()
+    }
+}
+
+/// A blocking websocket connection.
+pub struct Connection<T> {
+    socket: T,
+    state: ConnectionState,
+    events: VecDeque<Event>,
+    read_buffer: Vec<u8>,
+    output: BytesMut
+}
+
+impl<T> fmt::Debug for Connection<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("state", &self.state)
+            .field("events", &self.events.len())
+            .finish()
+    }
+}
+
+impl<T: Read + Write> Connection<T> {
+    /// Create a new connection from the given socket and mode.
+    ///
+    /// **Note**: Use this type only after a successful handshake.
+    pub fn new(socket: T, mode: Mode) -> Self {
+        Connection::with_state(socket, ConnectionState::new(mode))
+    }
+
+    /// Create a connection from a socket and a configured connection state.
+    pub fn with_state(socket: T, state: ConnectionState) -> Self {
+        Connection {
+            socket,
+            state,
+            events: VecDeque::new(),
+            read_buffer: vec![0; READ_BUFFER_SIZE],
+            output: BytesMut::new()
+        }
+    }
+
+    /// Create a connection with the settings of a [`connection::Builder`].
+    ///
+    /// Cf. [`ConnectionState::from_builder`].
+    fn from_builder(builder: connection::Builder<AllowStdIo<T>>) -> Self {
+        let (socket, mut state) = ConnectionState::from_builder(builder);
+        // Handle frames which have been read together with the handshake.
+        let events = state.handle_input(&[]);
+        let mut c = Connection::with_state(socket.into_inner(), state);
+        c.events.extend(events);
+        c
+    }
+
+    /// Access the connection state to configure it.
+    pub fn state_mut(&mut self) -> &mut ConnectionState {
+        &mut self.state
+    }
+
+    /// Send a text message.
+    pub fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
+        self.send(OutgoingMessage::Text(data.as_ref().into()))
+    }
+
+    /// Send a binary message.
+    pub fn send_binary(&mut self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+        self.send(OutgoingMessage::Binary(data.as_ref().into()))
+    }
+
+    /// Ping the remote end.
+    pub fn send_ping(&mut self, data: impl Into<ByteBuf125>) -> Result<(), Error> {
+        self.send(OutgoingMessage::Ping(data.into()))
+    }
+
+    /// Send a message and flush the socket.
+    pub fn send(&mut self, message: OutgoingMessage) -> Result<(), Error> {
+        self.state.queue_send(message)?;
+        self.write_output()
+    }
+
+    /// Send a close message and flush the socket.
+    ///
+    /// Afterwards [`Connection::receive`] returns [`Message::Closed`] once
+    /// the remote has answered.
+    pub fn close(&mut self) -> Result<(), Error> {
+        self.send(OutgoingMessage::Close(CloseReason::with_code(1000))) // 1000 = normal closure
+    }
+
+    /// Receive the next websocket message, cf. [`connection::Receiver::receive`].
+    ///
+    /// PINGs are answered before this method returns.
+    pub fn receive(&mut self) -> Result<Message, Error> {
+        loop {
+            while let Some(event) = self.events.pop_front() {
+                self.write_output()?;
+                match event {
+                    Event::Message(m) => return Ok(m),
+                    Event::Ping(_) => continue,
+                    Event::Error(e) => return Err(e)
+                }
+            }
+            if self.state.is_closed() {
+                return Err(Error::Closed)
+            }
+            let n = match self.socket.read(&mut self.read_buffer) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into())
+            };
+            if n == 0 {
+                return Err(Error::UnexpectedEof)
+            }
+            log::trace!("read {} bytes", n);
+            let events = self.state.handle_input(&self.read_buffer[.. n]);
+            self.events.extend(events)
+        }
+    }
+
+    /// Get a reference to the socket.
+    pub fn get_ref(&self) -> &T {
+        &self.socket
+    }
+
+    /// Get a mutable reference to the socket.
+    ///
+    /// Reading from or writing to the socket directly corrupts the connection.
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.socket
+    }
+
+    /// Get out the inner socket.
+    pub fn into_inner(self) -> T {
+        self.socket
+    }
+
+    /// Write all queued output to the socket and flush it.
+    ///
+    /// If writing fails, the connection is closed and the output discarded,
+    /// as part of it may have been written already.
+    fn write_output(&mut self) -> Result<(), Error> {
+        self.state.take_output(&mut self.output);
+        if self.output.is_empty() {
+            return Ok(())
+        }
+        let result = self.socket.write_all(&self.output);
+        self.output.clear();
+        if let Err(e) = result {
+            self.state.abort();
+            return Err(e.into())
+        }
+        self.socket.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use crate::{Message, connection::{self, ConnectionState, Mode}};
+    use crate::data::{ByteSlice125, OutgoingMessage};
+    use crate::handshake::{self, ServerResponse, server::Response};
+    use std::{collections::VecDeque, convert::TryFrom, io};
+    use super::{Client, Connection};
+    use tokio_util::compat::Tokio02AsyncReadCompatExt;
+
+    #[tokio::test(threaded_scheduler)]
+    async fn blocking_client_async_server() {
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = std::thread::spawn(move || {
+            let socket = std::net::TcpStream::connect(addr).unwrap();
+            let mut client = Client::new(socket, "localhost", "/");
+            assert!(matches!(client.handshake().unwrap(), ServerResponse::Accepted { .. }));
+            let mut connection = client.into_connection();
+            connection.send_text("hello").unwrap();
+            assert_eq!(Message::Text("hello".into()), connection.receive().unwrap());
+            connection.send_ping(ByteSlice125::try_from(&b"ping"[..]).unwrap()).unwrap();
+            connection.send_binary([1, 2, 3]).unwrap();
+            assert_eq!(Message::Pong(b"ping".to_vec()), connection.receive().unwrap());
+            assert_eq!(Message::Binary([1, 2, 3][..].into()), connection.receive().unwrap());
+            connection.close().unwrap();
+            assert!(matches!(connection.receive().unwrap(), Message::Closed(r) if r.code() == Some(1000)))
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut server = handshake::Server::new(socket.compat());
+        let key = server.receive_request().await.unwrap().into_key();
+        let accept = Response::Accept { key: key.as_ref(), protocol: None, extra_headers: &[] };
+        server.send_response(&accept).await.unwrap();
+        let (mut sender, mut receiver) = server.into_builder().finish();
+        loop {
+            match receiver.receive().await {
+                Ok(Message::Text(t)) => sender.send_text(std::str::from_utf8(&t).unwrap()).await.unwrap(),
+                Ok(Message::Binary(b)) => sender.send_binary(&b).await.unwrap(),
+                Ok(_) => continue,
+                Err(connection::Error::Closed) => break,
+                Err(e) => panic!("unexpected error: {}", e)
+            }
+            sender.flush().await.unwrap()
+        }
+
+        client.join().unwrap()
+    }
+
+    /// A socket returning the given read results and failing all writes.
+    struct Socket(VecDeque<io::Result<Vec<u8>>>);
+
+    impl io::Read for Socket {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Ok(data)) => {
+                    buf[.. data.len()].copy_from_slice(&data);
+                    Ok(data.len())
+                }
+                Some(Err(e)) => Err(e),
+                None => Ok(0)
+            }
+        }
+    }
+
+    impl io::Write for Socket {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn interrupted_reads_and_write_errors() {
+        let mut client = ConnectionState::new(Mode::Client);
+        client.queue_send(OutgoingMessage::Text("hello".into())).unwrap();
+        let mut frame = BytesMut::new();
+        client.take_output(&mut frame);
+
+        let reads = vec![Err(io::ErrorKind::Interrupted.into()), Ok(frame.to_vec())];
+        let mut connection = Connection::new(Socket(reads.into()), Mode::Server);
+        assert_eq!(Message::Text("hello".into()), connection.receive().unwrap());
+        match connection.send_text("hello") {
+            Err(connection::Error::Io(e)) => assert_eq!(io::ErrorKind::BrokenPipe, e.kind()),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert!(connection.output.is_empty());
+        assert!(matches!(connection.send_text("hello"), Err(connection::Error::Closed)))
+    }
+}
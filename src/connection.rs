@@ -9,11 +9,21 @@
 //! A persistent websocket connection after the handshake phase, represented
 //! as a [`Sender`] and [`Receiver`] pair.
 
-use bytes::{Buf, BytesMut};
-use crate::{Storage, Parsing, base::{self, Header, MAX_HEADER_SIZE, OpCode}, extension::Extension};
-use crate::data::{ByteSlice125, Data, Incoming};
-use futures::{io::{ReadHalf, WriteHalf}, lock::BiLock, prelude::*};
-use std::{fmt, io, str};
+use bytes::{Bytes, BytesMut};
+use crate::{Storage, Parsing, base::{self, Header, OpCode}, extension::{self, Extension}};
+use crate::data::{ByteBuf125, CloseReason, Data, Message, MessageMeta, OutgoingMessage, PayloadBuf};
+use crate::observer::Observer;
+use futures::{io::{ReadHalf, WriteHalf}, lock::{BiLock, BiLockGuard, Mutex, ReuniteError as BiLockReuniteError}, prelude::*};
+use std::{fmt, io, pin::Pin, str, sync::Arc, task::{Context, Poll}, time::{Duration, Instant}};
+
+mod ping;
+mod state;
+
+use ping::Clock;
+use state::Control;
+
+pub use ping::{PingToken, PongReceipt};
+pub use state::{ConnectionState, Event};
 
 /// Accumulated max. size of a complete message.
 const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
@@ -21,6 +31,9 @@ const MAX_MESSAGE_SIZE: usize = 256 * 1024 * 1024;
 /// Max. size of a single message frame.
 const MAX_FRAME_SIZE: usize = MAX_MESSAGE_SIZE;
 
+/// Max. number of control frames received per message.
+const MAX_CONTROL_FRAMES: usize = 1000;
+
 /// Is the connection used by a client or server?
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Mode {
@@ -32,11 +45,7 @@ pub enum Mode {
 
 impl Mode {
     pub fn is_client(self) -> bool {
-        if let Mode::Client = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Mode::Client)
     }
 
     pub fn is_server(self) -> bool {
@@ -45,41 +54,213 @@ impl Mode {
 }
 
 /// Connection ID.
-#[derive(Clone, Copy, Debug)]
-struct Id(u32);
+#[derive(Clone, Debug)]
+enum Id {
+    /// A generated ID.
+    Generated(u32),
+    /// An ID set with [`Builder::set_trace_id`].
+    Custom(Arc<str>)
+}
 
 impl fmt::Display for Id {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:08x}", self.0)
+        match self {
+            Id::Generated(n) => write!(f, "{:08x}", n),
+            Id::Custom(s) => f.write_str(s)
+        }
+    }
+}
+
+/// A source of frame masks, overriding the default random masks.
+struct MaskSource(Box<dyn FnMut() -> [u8; 4] + Send + Sync>);
+
+impl fmt::Debug for MaskSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MaskSource")
     }
 }
 
-/// The sending half of a connection.
+/// A factory of timers limiting the time to receive a message.
+struct MessageTimeout(Box<dyn Fn() -> future::BoxFuture<'static, ()> + Send + Sync>);
+
+impl fmt::Debug for MessageTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("MessageTimeout")
+    }
+}
+
+/// A running timer created by a [`MessageTimeout`].
+///
+/// The mutex is never contended but makes the timer `Sync`.
+struct Deadline(std::sync::Mutex<future::BoxFuture<'static, ()>>);
+
+impl fmt::Debug for Deadline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Deadline")
+    }
+}
+
+/// The direction of a frame, cf. [`Builder::set_frame_observer`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// The frame has been received from the remote.
+    Incoming,
+    /// The frame is sent to the remote.
+    Outgoing
+}
+
+type ObserverFn = dyn FnMut(Direction, &Header, &[u8]) + Send;
+
+/// A callback observing all frames, shared by [`Sender`] and [`Receiver`].
+#[derive(Clone)]
+struct FrameObserver(Arc<std::sync::Mutex<Box<ObserverFn>>>);
+
+impl FrameObserver {
+    fn observe(&self, direction: Direction, header: &Header, data: &[u8]) {
+        let mut f = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        (f)(direction, header, data)
+    }
+}
+
+impl fmt::Debug for FrameObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("FrameObserver")
+    }
+}
+
+/// What [`Receiver::receive_next`] has received.
 #[derive(Debug)]
+enum Next {
+    /// Text or binary data.
+    Data(Data),
+    /// A PONG (its payload data is in the control buffer).
+    Pong,
+    /// The remote has closed the connection.
+    Closed(CloseReason)
+}
+
+/// The write half of a connection and its protocol state, shared by
+/// [`Sender`] and [`Receiver`].
+struct Writer<T> {
+    socket: WriteHalf<T>,
+    core: ConnectionState
+}
+
+/// The state of a connection with regard to the closing handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Messages can be sent and received.
+    Open,
+    /// We have sent a CLOSE frame and wait for the remote to answer it.
+    ///
+    /// Nothing else can be sent and received messages are discarded.
+    CloseSent,
+    /// The closing handshake is complete or the connection has failed.
+    Closed
+}
+
+impl<T: AsyncWrite + Unpin> Writer<T> {
+    /// Write and flush the output queued by the connection state, e.g.
+    /// answers to control frames.
+    ///
+    /// The socket is closed after the last frame of a closed connection.
+    async fn write_output(&mut self) -> Result<(), Error> {
+        if self.core.output().is_empty() {
+            return Ok(())
+        }
+        let result = self.socket.write_all(self.core.output()).await;
+        self.core.output().clear();
+        result.or(Err(Error::Closed))?;
+        self.socket.flush().await.or(Err(Error::Closed))?;
+        if self.core.is_closed() {
+            self.socket.close().await.or(Err(Error::Closed))?
+        }
+        Ok(())
+    }
+}
+
+/// Get the next mask from the given source.
+///
+/// The source must have been set with [`ConnectionState::check_mask_source`].
+fn next_mask(source: &mut Option<MaskSource>) -> u32 {
+    let m = source.as_mut().expect("mask source is checked before frames are queued");
+    u32::from_be_bytes((m.0)())
+}
+
+/// The default source of frame masks, a per-connection PRNG.
+///
+/// RFC 6455 requires masks to be unpredictable to intermediaries which can
+/// not observe previous frames of the connection (cf. section 10.3) but not
+/// of cryptographic strength. We therefore seed a xorshift64* generator
+/// once from the thread RNG instead of querying the thread RNG for every
+/// frame. [`Builder::set_mask_rng`] replaces the generator.
+#[cfg(feature = "rand")]
+fn default_mask_source() -> Option<MaskSource> {
+    let mut state = rand::random::<u64>() | 1; // must not be 0
+    Some(MaskSource(Box::new(move || {
+        state ^= state >> 12;
+        state ^= state << 25;
+        state ^= state >> 27;
+        let x = state.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        ((x >> 32) as u32).to_be_bytes()
+    })))
+}
+
+/// A source of frame masks using the given random number generator.
+#[cfg(feature = "rand")]
+fn mask_rng(mut rng: impl rand::RngCore + Send + Sync + 'static) -> MaskSource {
+    MaskSource(Box::new(move || {
+        let mut mask = [0; 4];
+        rng.fill_bytes(&mut mask);
+        mask
+    }))
+}
+
+#[cfg(not(feature = "rand"))]
+fn default_mask_source() -> Option<MaskSource> {
+    None
+}
+
+#[cfg(feature = "rand")]
+fn new_id() -> Id {
+    Id::Generated(rand::random())
+}
+
+#[cfg(not(feature = "rand"))]
+fn new_id() -> Id {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+    Id::Generated(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// The sending half of a connection.
+///
+/// Both halves share a [`ConnectionState`] which implements the protocol.
 pub struct Sender<T> {
     id: Id,
     mode: Mode,
-    codec: base::Codec,
-    writer: BiLock<WriteHalf<T>>,
+    writer: BiLock<Writer<T>>,
     mask_buffer: Vec<u8>,
-    extensions: BiLock<Vec<Box<dyn Extension + Send>>>,
     has_extensions: bool
 }
 
 /// The receiving half of a connection.
-#[derive(Debug)]
+///
+/// Both halves share a [`ConnectionState`] which implements the protocol.
 pub struct Receiver<T> {
     id: Id,
     mode: Mode,
-    codec: base::Codec,
     reader: ReadHalf<T>,
-    writer: BiLock<WriteHalf<T>>,
-    extensions: BiLock<Vec<Box<dyn Extension + Send>>>,
+    writer: BiLock<Writer<T>>,
     has_extensions: bool,
+    streaming: bool,
     buffer: BytesMut,
     ctrl_buffer: BytesMut,
-    max_message_size: usize,
-    is_closed: bool
+    meta: MessageMeta,
+    message_timeout: Option<MessageTimeout>,
+    deadline: Option<Deadline>,
+    partial: Vec<u8>,
+    last_rtt: Option<Duration>
 }
 
 /// A connection builder.
@@ -87,15 +268,103 @@ pub struct Receiver<T> {
 /// Allows configuring certain parameters and extensions before
 /// creating the [`Sender`]/[`Receiver`] pair that represents the
 /// connection.
-#[derive(Debug)]
+///
+/// A builder is returned by the handshakes (cf.
+/// [`handshake::Server::into_builder`](crate::handshake::Server::into_builder)
+/// and [`handshake::Client::into_builder`](crate::handshake::Client::into_builder)),
+/// with the negotiated extensions and any data read after the handshake,
+/// or created with [`Builder::new`] if the handshake has been performed by
+/// other means. Settings which are not changed keep their defaults.
+///
+/// # Example
+///
+/// ```no_run
+/// # async fn doc() -> Result<(), soketto::BoxedError> {
+/// use soketto::{connection::Builder, handshake::{Server, server::Response}};
+///
+/// # let socket = futures::io::Cursor::new(Vec::new());
+/// let mut server = Server::new(socket);
+/// let key = server.receive_request().await?.into_key();
+/// server.send_response(&Response::Accept { key: key.as_ref(), protocol: None, extra_headers: &[] }).await?;
+///
+/// let mut builder = server.into_builder();
+/// builder.set_max_message_size(1024 * 1024);
+/// builder.set_close_on_error(false);
+/// let (sender, receiver) = builder.finish();
+/// # Ok(())
+/// # }
+/// ```
 pub struct Builder<T> {
     id: Id,
     mode: Mode,
     socket: T,
     codec: base::Codec,
-    extensions: Vec<Box<dyn Extension + Send>>,
+    extensions: Vec<Box<dyn Extension + Send + Sync>>,
     buffer: BytesMut,
-    max_message_size: usize
+    max_message_size: usize,
+    tolerate_continuation_rsv_bits: bool,
+    close_on_error: bool,
+    mask_source: Option<MaskSource>,
+    frame_observer: Option<FrameObserver>,
+    message_timeout: Option<MessageTimeout>,
+    max_control_frames: usize,
+    metrics: Option<Arc<dyn Observer>>,
+    clock: Option<Clock>
+}
+
+// The socket is not included, so it does not need to implement `Debug`.
+// The connection state of a `Sender` or `Receiver` is shared by both
+// halves and can not be accessed here.
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Sender")
+            .field("id", &format_args!("{}", self.id))
+            .field("mode", &self.mode)
+            .field("has_extensions", &self.has_extensions)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Receiver")
+            .field("id", &format_args!("{}", self.id))
+            .field("mode", &self.mode)
+            .field("has_extensions", &self.has_extensions)
+            .field("buffered", &self.buffer.len())
+            .field("message_meta", &self.meta)
+            .finish()
+    }
+}
+
+impl<T> fmt::Debug for Builder<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Builder")
+            .field("id", &format_args!("{}", self.id))
+            .field("mode", &self.mode)
+            .field("extensions", &self.extensions.iter().map(|e| e.name()).collect::<Vec<_>>())
+            .field("buffered", &self.buffer.len())
+            .field("max_message_size", &self.max_message_size)
+            .field("max_frame_size", &self.codec.max_data_size())
+            .field("close_on_error", &self.close_on_error)
+            .finish()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<T> Builder<tokio_util::compat::Compat<T>>
+where
+    T: tokio_util::compat::Tokio02AsyncReadCompatExt,
+    tokio_util::compat::Compat<T>: AsyncRead + AsyncWrite + Unpin
+{
+    /// Create a new `Builder` from a tokio socket (requires feature `tokio`).
+    ///
+    /// The socket is wrapped in a [`Compat`](tokio_util::compat::Compat)
+    /// adapter, cf. [`Builder::new`].
+    pub fn from_tokio(socket: T, mode: Mode) -> Self {
+        Builder::new(socket.compat(), mode)
+    }
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
@@ -111,29 +380,67 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
         let mut codec = base::Codec::default();
         codec.set_max_data_size(MAX_FRAME_SIZE);
         Builder {
-            id: Id(rand::random()),
+            id: new_id(),
             mode,
             socket,
             codec,
             extensions: Vec::new(),
             buffer: BytesMut::new(),
-            max_message_size: MAX_MESSAGE_SIZE
+            max_message_size: MAX_MESSAGE_SIZE,
+            tolerate_continuation_rsv_bits: false,
+            close_on_error: true,
+            mask_source: None,
+            frame_observer: None,
+            message_timeout: None,
+            max_control_frames: MAX_CONTROL_FRAMES,
+            metrics: None,
+            clock: None
         }
     }
 
     /// Set a custom buffer to use.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// builder.set_buffer(bytes::BytesMut::with_capacity(64 * 1024));
+    /// ```
     pub fn set_buffer(&mut self, b: BytesMut) {
         self.buffer = b
     }
 
     /// Add extensions to use with this connection.
     ///
-    /// Only enabled extensions will be considered.
+    /// Only enabled extensions will be considered. Extensions are applied in
+    /// the given order when sending and in reverse order when receiving.
+    ///
+    /// # Panics
+    ///
+    /// If an extension uses a reserved bit which is already used by another
+    /// extension of this connection.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// # #[cfg(any(feature = "deflate", feature = "deflate-rust"))] {
+    /// use soketto::extension::{Extension, deflate::Deflate};
+    /// let mut deflate = Deflate::new(Mode::Server);
+    /// deflate.configure(&[]).unwrap(); // enables the extension
+    /// builder.add_extensions(vec![Box::new(deflate) as Box<dyn Extension + Send + Sync>]);
+    /// # }
+    /// ```
     pub fn add_extensions<I>(&mut self, extensions: I)
     where
-        I: IntoIterator<Item = Box<dyn Extension + Send>>
+        I: IntoIterator<Item = Box<dyn Extension + Send + Sync>>
     {
         for e in extensions.into_iter().filter(|e| e.is_enabled()) {
+            extension::assert_no_conflict(&self.extensions, &*e, false);
             log::debug!("{}: using extension: {}", self.id, e.name());
             self.codec.add_reserved_bits(e.reserved_bits());
             self.extensions.push(e)
@@ -144,45 +451,288 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
     ///
     /// Message fragments will be buffered and concatenated up to this value,
     /// i.e. the sum of all message frames payload lengths will not be greater
-    /// than this maximum. However, extensions may increase the total message
-    /// size further, e.g. by decompressing the payload data.
+    /// than this maximum. The maximum is also given to all extensions (cf.
+    /// [`Extension::set_max_message_size`]) which may increase the message
+    /// size, e.g. by decompressing the payload data.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// builder.set_max_message_size(16 * 1024 * 1024);
+    /// ```
     pub fn set_max_message_size(&mut self, max: usize) {
         self.max_message_size = max
     }
 
     /// Set the maximum size of a single websocket frame payload.
+    ///
+    /// This applies to received and sent frames. Larger messages are sent as
+    /// multiple fragments, each masked separately, and the sending task
+    /// yields to the executor between fragments, so that a large message
+    /// does not delay other tasks on the same thread until it is written.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// builder.set_max_frame_size(64 * 1024);
+    /// ```
     pub fn set_max_frame_size(&mut self, max: usize) {
         self.codec.set_max_data_size(max);
     }
 
+    /// Tolerate reserved bits in continuation frames (default: false).
+    ///
+    /// Extensions like permessage-deflate set reserved bits only in the first
+    /// frame of a message, hence continuation frames with reserved bits are
+    /// rejected with [`base::Error::InvalidReservedBit`]. Some peers set them
+    /// in every frame nevertheless. If tolerated, the reserved bits of
+    /// continuation frames are ignored.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// builder.set_tolerate_continuation_rsv_bits(true);
+    /// ```
+    pub fn set_tolerate_continuation_rsv_bits(&mut self, tolerate: bool) {
+        self.tolerate_continuation_rsv_bits = tolerate
+    }
+
+    /// Close the connection if the remote violates the protocol (default: true).
+    ///
+    /// If receiving fails because of the remote, e.g. due to an unexpected
+    /// opcode, invalid UTF-8 text or a message which is too large, a close
+    /// frame with the corresponding status code (1002, 1007 or 1009) is sent
+    /// before the error is returned and the connection is closed. If disabled,
+    /// only the error is returned and closing the connection is up to the user.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// builder.set_close_on_error(false);
+    /// ```
+    pub fn set_close_on_error(&mut self, close: bool) {
+        self.close_on_error = close
+    }
+
+    /// Set an identifier of this connection (default: a random number).
+    ///
+    /// It prefixes log messages and is the `id` field of tracing events
+    /// (feature `tracing`), to attribute them to a connection.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// builder.set_trace_id("client-42");
+    /// ```
+    pub fn set_trace_id(&mut self, id: impl fmt::Display) {
+        self.id = Id::Custom(id.to_string().into())
+    }
+
+    /// Set a custom source of frame masks.
+    ///
+    /// By default, masks of frames sent in [`Mode::Client`] are generated
+    /// randomly. A custom source makes the output deterministic which can be
+    /// useful for testing. The masks should be unpredictable otherwise.
+    ///
+    /// If feature `rand` is disabled, clients must set a mask source, e.g.
+    /// one based on a platform-specific random number generator. Sending
+    /// fails with [`Error::MaskSourceRequired`] otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Client);
+    /// let mut n = 0u32;
+    /// builder.set_mask_source(move || {
+    ///     n = n.wrapping_add(1);
+    ///     n.to_be_bytes()
+    /// });
+    /// ```
+    pub fn set_mask_source(&mut self, f: impl FnMut() -> [u8; 4] + Send + Sync + 'static) {
+        self.mask_source = Some(MaskSource(Box::new(f)))
+    }
+
+    /// Generate frame masks with the given random number generator (requires feature `rand`).
+    ///
+    /// By default masks are generated by a fast, non-cryptographic PRNG
+    /// seeded from the thread RNG, which is all RFC 6455 asks for. Use this
+    /// method to generate masks with e.g. `rand::rngs::OsRng` instead, or
+    /// with an explicitly seeded generator for deterministic tests.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Client);
+    /// builder.set_mask_rng(rand::rngs::OsRng);
+    /// ```
+    #[cfg(feature = "rand")]
+    pub fn set_mask_rng(&mut self, rng: impl rand::RngCore + Send + Sync + 'static) {
+        self.mask_source = Some(mask_rng(rng))
+    }
+
+    /// Set a callback which observes every frame received or sent.
+    ///
+    /// Received frames are observed after decoding, with unmasked payload
+    /// data but before extensions are applied. Frames to send are observed
+    /// after extensions have been applied but before masking and encoding.
+    /// This includes PONG and CLOSE frames sent in response to the remote.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// builder.set_frame_observer(|direction, header, data| {
+    ///     println!("{:?}: {} ({} bytes)", direction, header, data.len())
+    /// });
+    /// ```
+    pub fn set_frame_observer(&mut self, f: impl FnMut(Direction, &Header, &[u8]) + Send + 'static) {
+        self.frame_observer = Some(FrameObserver(Arc::new(std::sync::Mutex::new(Box::new(f)))))
+    }
+
+    /// Set the maximum number of control frames received per message (default: 1000).
+    ///
+    /// Control frames (PING, PONG and CLOSE) between the fragments of a text
+    /// or binary message are counted until the message is complete. If the
+    /// remote sends more, a close frame with status code 1008 (policy
+    /// violation) is sent and receiving fails with [`Error::TooManyControlFrames`].
+    /// This prevents peers from keeping the receiver busy without ever
+    /// completing a message. Control frames between messages, e.g. PINGs
+    /// keeping an idle connection alive, are not limited.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// builder.set_max_control_frames_per_message(100);
+    /// ```
+    pub fn set_max_control_frames_per_message(&mut self, max: usize) {
+        self.max_control_frames = max
+    }
+
+    /// Set a factory of timers which limit the time to receive a message.
+    ///
+    /// A timer is created when the first frame of a text or binary message
+    /// arrives and dropped when the message is complete. If it fires before,
+    /// a close frame with status code 1008 (policy violation) is sent and
+    /// receiving fails with [`Error::MessageTimeout`]. Unlike idle timeouts,
+    /// this also applies if the remote keeps sending control frames.
+    ///
+    /// The timers can be created by any runtime, e.g. with
+    /// `move || tokio::time::delay_for(timeout)`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// # #[cfg(feature = "tokio")]
+    /// builder.set_message_timeout(|| tokio::time::delay_for(std::time::Duration::from_secs(30)));
+    /// ```
+    pub fn set_message_timeout<F>(&mut self, f: impl Fn() -> F + Send + Sync + 'static)
+    where
+        F: Future<Output = ()> + Send + 'static
+    {
+        self.message_timeout = Some(MessageTimeout(Box::new(move || f().boxed())))
+    }
+
+    /// Set an observer of frames, messages and the closing of the connection.
+    ///
+    /// Cf. [`crate::observer`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Server);
+    /// # #[derive(Debug)] struct Metrics;
+    /// # impl soketto::observer::Observer for Metrics {}
+    /// builder.set_observer(std::sync::Arc::new(Metrics));
+    /// ```
+    pub fn set_observer(&mut self, observer: Arc<dyn Observer>) {
+        self.metrics = Some(observer)
+    }
+
+    /// Set a clock to measure round-trip times of PINGs.
+    ///
+    /// PINGs sent with [`Sender::ping_with_token`] record the current time
+    /// and the matching PONGs complete their [`PongReceipt`]s with the time
+    /// elapsed in between. The latest round-trip time is also available from
+    /// [`Receiver::last_rtt`]. A clock can be as simple as `Instant::now`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use soketto::connection::{Builder, Mode};
+    /// # let socket = futures::io::Cursor::new(Vec::new());
+    /// let mut builder = Builder::new(socket, Mode::Client);
+    /// builder.set_clock(std::time::Instant::now);
+    /// ```
+    pub fn set_clock(&mut self, f: impl Fn() -> Instant + Send + Sync + 'static) {
+        self.clock = Some(Clock(Box::new(f)))
+    }
+
+    /// Get out the inner socket.
+    ///
+    /// Data which has already been read from the socket, e.g. the buffer of
+    /// a reunited connection (cf. [`Sender::reunite`]), is dropped.
+    pub fn into_inner(self) -> T {
+        self.socket
+    }
+
     /// Create a configured [`Sender`]/[`Receiver`] pair.
     pub fn finish(self) -> (Sender<T>, Receiver<T>) {
-        let (rhlf, whlf) = self.socket.split();
-        let (wrt1, wrt2) = BiLock::new(whlf);
-        let has_extensions = !self.extensions.is_empty();
-        let (ext1, ext2) = BiLock::new(self.extensions);
+        let (id, mode, has_extensions) = (self.id.clone(), self.mode, !self.extensions.is_empty());
+        let (socket, buffer, message_timeout, core) = ConnectionState::split_builder(self);
+        let streaming = core.is_streaming();
+        let (rhlf, whlf) = socket.split();
+        let (wrt1, wrt2) = BiLock::new(Writer { socket: whlf, core });
 
         let recv = Receiver {
-            id: self.id,
-            mode: self.mode,
+            id: id.clone(),
+            mode,
             reader: rhlf,
             writer: wrt1,
-            codec: self.codec.clone(),
-            extensions: ext1,
             has_extensions,
-            buffer: self.buffer,
+            streaming,
+            buffer,
             ctrl_buffer: BytesMut::new(),
-            max_message_size: self.max_message_size,
-            is_closed: false
+            meta: MessageMeta::default(),
+            message_timeout,
+            deadline: None,
+            partial: Vec::new(),
+            last_rtt: None
         };
 
         let send = Sender {
-            id: self.id,
-            mode: self.mode,
+            id,
+            mode,
             writer: wrt2,
             mask_buffer: Vec::new(),
-            codec: self.codec,
-            extensions: ext2,
             has_extensions
         };
 
@@ -193,52 +743,216 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Builder<T> {
 impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
     /// Receive the next websocket message.
     ///
-    /// The received frames forming the complete message will be appended to
-    /// the given `message` argument. The returned [`Incoming`] value describes
-    /// the type of data that was received, e.g. binary or textual data.
-    ///
-    /// Interleaved PONG frames are returned immediately as `Data::Pong`
-    /// values. If PONGs are not expected or uninteresting,
-    /// [`Receiver::receive_data`] may be used instead which skips over PONGs
-    /// and considers only application payload data.
-    pub async fn receive(&mut self, message: &mut Vec<u8>) -> Result<Incoming<'_>, Error> {
-        let mut first_fragment_opcode = None;
-        let mut length: usize = 0;
-        let message_len = message.len();
+    /// Interleaved PONG frames are returned immediately as [`Message::Pong`]
+    /// values. When the remote closes the connection, [`Message::Closed`] is
+    /// returned and subsequent calls fail with [`Error::Closed`].
+    ///
+    /// The payload data of every message is returned in a new buffer.
+    /// [`Receiver::receive_data`] may be used instead to receive data into a
+    /// buffer that can be reused. It skips over PONGs and considers only
+    /// application payload data.
+    pub async fn receive(&mut self) -> Result<Message, Error> {
+        // Data of a fragmented message interrupted by a PONG is kept for the next call.
+        let mut message = std::mem::take(&mut self.partial);
+        match self.receive_next(&mut message, None::<&mut Vec<u8>>).await {
+            // Collecting a `Vec` into `BytesMut` takes over its allocation without copying.
+            Ok(Next::Data(Data::Text(_))) => Ok(Message::Text(message.into_iter().collect())),
+            Ok(Next::Data(Data::Binary(_))) => Ok(Message::Binary(message.into_iter().collect())),
+            Ok(Next::Pong) => {
+                self.partial = message;
+                Ok(Message::Pong(self.ctrl_buffer.to_vec()))
+            }
+            Ok(Next::Closed(reason)) => Ok(Message::Closed(reason)),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Receive the next websocket message, skipping over control frames.
+    ///
+    /// The payload data of exactly one message is appended to the given
+    /// `message` argument and existing contents are left untouched, so the
+    /// buffer can be cleared, reused or grown freely between calls. The
+    /// returned [`Data`] value describes the type of data that was received,
+    /// e.g. binary or textual data, and the number of bytes appended.
+    ///
+    /// Frames which have been read but not consumed yet, e.g. pipelined
+    /// messages or the start of the next frame, are kept in the receiver's
+    /// own buffer. If an error is returned, `message` is truncated to its
+    /// original length.
+    pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<Data, Error> {
+        let len = message.len();
+        self.resume(message);
         loop {
-            if self.is_closed {
-                log::debug!("{}: can not receive, connection is closed", self.id);
-                return Err(Error::Closed)
+            match self.receive_next(message, None::<&mut Vec<u8>>).await {
+                Ok(Next::Data(d)) => return Ok(d),
+                Ok(Next::Pong) => continue,
+                Ok(Next::Closed(_)) => {
+                    message.truncate(len);
+                    return Err(Error::Closed)
+                }
+                Err(e) => {
+                    message.truncate(len);
+                    return Err(e)
+                }
+            }
+        }
+    }
+
+    /// Receive the next websocket message into any [`PayloadBuf`], skipping over control frames.
+    ///
+    /// Like [`Receiver::receive_data`], the payload data of exactly one
+    /// message is appended to `message` and existing contents are left
+    /// untouched. Each frame is appended as soon as it has been decoded, so
+    /// the receiver does not keep a copy of the message. Only extensions
+    /// which can not decode a message frame by frame require the whole
+    /// message to be assembled in a temporary buffer first, which is freed
+    /// afterwards. If an error is returned, `message` is truncated to its
+    /// original length.
+    pub async fn receive_into<B>(&mut self, message: &mut B) -> Result<Data, Error>
+    where
+        B: PayloadBuf + ?Sized
+    {
+        if !self.streaming {
+            let mut buffer = std::mem::take(&mut self.partial);
+            let result = self.receive_data(&mut buffer).await;
+            if result.is_ok() {
+                message.reserve(buffer.len());
+                message.extend_from_slice(&buffer)
+            }
+            // The buffer is dropped, so that the receiver keeps no memory of the message.
+            return result
+        }
+
+        let len = message.len();
+
+        // Data of a fragmented message interrupted by a PONG in `receive` is continued.
+        if !self.partial.is_empty() {
+            message.reserve(self.partial.len());
+            message.extend_from_slice(&self.partial);
+            self.partial = Vec::new();
+            self.writer.lock().await.core.message_drained()
+        }
+
+        let mut frame = Vec::new();
+        loop {
+            match self.receive_next(&mut frame, Some(&mut *message)).await {
+                Ok(Next::Data(d)) => return Ok(d),
+                Ok(Next::Pong) => continue,
+                Ok(Next::Closed(_)) => {
+                    message.truncate(len);
+                    return Err(Error::Closed)
+                }
+                Err(e) => {
+                    message.truncate(len);
+                    return Err(e)
+                }
+            }
+        }
+    }
+
+    /// Information about the frames of the last text or binary message
+    /// received with [`Receiver::receive`] or [`Receiver::receive_data`].
+    pub fn message_meta(&self) -> MessageMeta {
+        self.meta
+    }
+
+    /// The round-trip time of the last PING answered by the remote.
+    ///
+    /// Only PINGs sent with [`Sender::ping_with_token`] are measured and
+    /// only if a clock has been set with [`Builder::set_clock`].
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+
+    /// Turn this receiver into a [`futures::Stream`] of [`Message`]s.
+    ///
+    /// The stream ends after [`Message::Closed`] or the first error and
+    /// returns `None` when polled afterwards.
+    ///
+    /// The stream is not `Unpin`, so it may need to be pinned, e.g. with
+    /// `Box::pin`, before calling [`futures::StreamExt::next`].
+    pub fn into_stream(self) -> impl stream::FusedStream<Item = Result<Message, Error>> {
+        stream::unfold(Some(self), |receiver| async move {
+            let mut receiver = receiver?;
+            match receiver.receive().await {
+                Ok(m @ Message::Closed(_)) => Some((Ok(m), None)),
+                Ok(m) => Some((Ok(m), Some(receiver))),
+                Err(e) => Some((Err(e), None))
             }
+        })
+        .fuse()
+    }
+
+    /// Continue a message interrupted by a PONG in [`Receiver::receive`].
+    ///
+    /// The payload data received so far is moved to the end of `message`.
+    fn resume(&mut self, message: &mut Vec<u8>) {
+        if message.is_empty() {
+            std::mem::swap(message, &mut self.partial)
+        } else {
+            message.extend_from_slice(&self.partial);
+            self.partial.clear()
+        }
+    }
+
+    /// Receive the next message or control frame of interest.
+    ///
+    /// Payload data is appended to `message`, PONG data is left in `ctrl_buffer`.
+    /// If a PONG interrupts a fragmented message, the next call continues the
+    /// message, which must be given the same `message` buffer. If an error is
+    /// returned, the message is discarded.
+    ///
+    /// If messages are decoded frame by frame, a `sink` may be given, to
+    /// which the payload data of each frame is moved from `message`.
+    async fn receive_next<S>(&mut self, message: &mut Vec<u8>, sink: Option<&mut S>) -> Result<Next, Error>
+    where
+        S: PayloadBuf + ?Sized
+    {
+        let result = self.receive_frames(message, sink).await;
+        if result.is_err() {
+            self.deadline = None;
+            self.writer.lock().await.core.discard_message()
+        }
+        result
+    }
 
+    /// Read frames and hand them to the connection state, cf. [`Receiver::receive_next`].
+    async fn receive_frames<S>(&mut self, message: &mut Vec<u8>, mut sink: Option<&mut S>) -> Result<Next, Error>
+    where
+        S: PayloadBuf + ?Sized
+    {
+        loop {
             self.ctrl_buffer.clear();
-            let mut header = self.receive_header().await?;
-            log::trace!("{}: recv: {}", self.id, header);
+            let (mut header, closing) = self.receive_header().await?;
 
             // Handle control frames.
             if header.opcode().is_control() {
                 self.read_buffer(&header).await?;
                 self.ctrl_buffer = self.buffer.split_to(header.payload_len());
-                base::Codec::apply_mask(&header, &mut self.ctrl_buffer);
-                if header.opcode() == OpCode::Pong {
-                    return Ok(Incoming::Pong(&self.ctrl_buffer[..]))
+                let payload = &mut self.ctrl_buffer;
+                let control = with_core(&self.writer, |core| {
+                    let control = core.on_control(&header, payload)?;
+                    Ok((control, core.last_rtt()))
+                });
+                let (control, rtt) = control.await?;
+                self.last_rtt = rtt;
+                match control {
+                    Some(Control::Pong) => return Ok(Next::Pong),
+                    Some(Control::Closed(reason)) => return Ok(Next::Closed(reason)),
+                    Some(Control::Ping) | None => continue
                 }
-                self.on_control(&header).await?;
-                continue
             }
 
-            length = length.saturating_add(header.payload_len());
-
-            // Check if total message does not exceed maximum.
-            if length > self.max_message_size {
-                log::warn!("{}: accumulated message length exceeds maximum", self.id);
-                return Err(Error::MessageTooLarge { current: length, maximum: self.max_message_size })
+            // The first frame of a message starts its timer.
+            if !closing && self.deadline.is_none() {
+                if let Some(t) = &self.message_timeout {
+                    self.deadline = Some(Deadline(std::sync::Mutex::new((t.0)())))
+                }
             }
 
             // Get the frame's payload data bytes from buffer or socket.
+            let old_msg_len = message.len();
             {
-                let old_msg_len = message.len();
-
                 let bytes_to_read = {
                     let required = header.payload_len();
                     let buffered = self.buffer.len();
@@ -258,81 +972,57 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
                 if bytes_to_read > 0 {
                     let n = message.len();
                     message.resize(n + bytes_to_read, 0u8);
-                    self.reader.read_exact(&mut message[n ..]).await?
+                    match until_deadline(&mut self.deadline, self.reader.read_exact(&mut message[n ..])).await {
+                        Ok(r) => r?,
+                        Err(e) => return Err(self.fail(1008, e).await)
+                    }
                 }
 
                 debug_assert_eq!(header.payload_len(), message.len() - old_msg_len);
-
-                base::Codec::apply_mask(&header, &mut message[old_msg_len ..]);
             }
 
-            match (header.is_fin(), header.opcode()) {
-                (false, OpCode::Continue) => { // Intermediate message fragment.
-                    if first_fragment_opcode.is_none() {
-                        log::debug!("{}: continue frame while not processing message fragments", self.id);
-                        return Err(Error::UnexpectedOpCode(OpCode::Continue))
-                    }
-                    continue
-                }
-                (false, oc) => { // Initial message fragment.
-                    if first_fragment_opcode.is_some() {
-                        log::debug!("{}: initial fragment while processing a fragmented message", self.id);
-                        return Err(Error::UnexpectedOpCode(oc))
-                    }
-                    first_fragment_opcode = Some(oc);
-                    self.decode_with_extensions(&mut header, message).await?;
-                    continue
-                }
-                (true, OpCode::Continue) => { // Last message fragment.
-                    if let Some(oc) = first_fragment_opcode.take() {
-                        header.set_payload_len(message.len());
-                        log::trace!("{}: last fragment: total length = {} bytes", self.id, message.len());
-                        self.decode_with_extensions(&mut header, message).await?;
-                        header.set_opcode(oc);
-                    } else {
-                        log::debug!("{}: last continue frame while not processing message fragments", self.id);
-                        return Err(Error::UnexpectedOpCode(OpCode::Continue))
-                    }
-                }
-                (true, oc) => { // Regular non-fragmented message.
-                    if first_fragment_opcode.is_some() {
-                        log::debug!("{}: regular message while processing fragmented message", self.id);
-                        return Err(Error::UnexpectedOpCode(oc))
+            let sink = sink.as_deref_mut();
+            let data = with_core(&self.writer, |core| {
+                let data = core.on_data(&mut header, message, old_msg_len)?;
+                if let Some(sink) = sink {
+                    if !message.is_empty() {
+                        sink.reserve(message.len());
+                        sink.extend_from_slice(message);
+                        message.clear()
                     }
-                    self.decode_with_extensions(&mut header, message).await?
+                    core.message_drained()
                 }
-            }
-
-            let num_bytes = message.len() - message_len;
-
-            if header.opcode() == OpCode::Text {
-                return Ok(Incoming::Data(Data::Text(num_bytes)))
-            } else {
-                return Ok(Incoming::Data(Data::Binary(num_bytes)))
-            }
-        }
-    }
-
-    /// Receive the next websocket message, skipping over control frames.
-    pub async fn receive_data(&mut self, message: &mut Vec<u8>) -> Result<Data, Error> {
-        loop {
-            if let Incoming::Data(d) = self.receive(message).await? {
-                return Ok(d)
+                Ok((data, core.message_meta()))
+            });
+            if let (Some(data), meta) = data.await? {
+                self.deadline = None;
+                self.meta = meta;
+                return Ok(Next::Data(data))
             }
         }
     }
 
-    /// Read the next frame header.
-    async fn receive_header(&mut self) -> Result<Header, Error> {
+    /// Read and check the next frame header.
+    ///
+    /// Returns the header and whether we have sent a CLOSE frame, which
+    /// the remote has not answered yet.
+    async fn receive_header(&mut self) -> Result<(Header, bool), Error> {
         loop {
-            match self.codec.decode_header(&self.buffer)? {
-                Parsing::Done { value: header, offset } => {
-                    debug_assert!(offset <= MAX_HEADER_SIZE);
-                    self.buffer.advance(offset);
-                    return Ok(header)
-                }
+            let buffer = &mut self.buffer;
+            let header = with_core(&self.writer, |core| {
+                Ok(match core.next_header(buffer)? {
+                    Parsing::Done { value, offset } => Parsing::Done { value: (value, core.is_closing()), offset },
+                    Parsing::NeedMore(n) => Parsing::NeedMore(n)
+                })
+            });
+            match header.await? {
+                Parsing::Done { value, .. } => return Ok(value),
                 Parsing::NeedMore(n) => {
-                    crate::read(&mut self.reader, &mut self.buffer, n).await?
+                    let read = crate::read(&mut self.reader, &mut self.buffer, n);
+                    match until_deadline(&mut self.deadline, read).await {
+                        Ok(r) => r?,
+                        Err(e) => return Err(self.fail(1008, e).await)
+                    }
                 }
             }
         }
@@ -346,77 +1036,31 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Receiver<T> {
         let i = self.buffer.len();
         let d = header.payload_len() - i;
         self.buffer.resize(i + d, 0u8);
-        self.reader.read_exact(&mut self.buffer[i ..]).await?;
-        Ok(())
+        match until_deadline(&mut self.deadline, self.reader.read_exact(&mut self.buffer[i ..])).await {
+            Ok(r) => Ok(r?),
+            Err(e) => Err(self.fail(1008, e).await)
+        }
     }
 
-    /// Answer incoming control frames.
-    async fn on_control(&mut self, header: &Header) -> Result<(), Error> {
-        match header.opcode() {
-            OpCode::Ping => {
-                let mut answer = Header::new(OpCode::Pong);
-                let mut unused = Vec::new();
-                let mut data = Storage::Unique(&mut self.ctrl_buffer);
-                write(self.id, self.mode, &mut self.codec, &mut self.writer, &mut answer, &mut data, &mut unused).await?;
-                self.flush().await?;
-                Ok(())
-            }
-            OpCode::Pong => Ok(()),
-            OpCode::Close => {
-                self.is_closed = true;
-                let (mut header, code) = close_answer(&self.ctrl_buffer)?;
-                let mut unused = Vec::new();
-                if let Some(c) = code {
-                    let mut data = c.to_be_bytes();
-                    let mut data = Storage::Unique(&mut data);
-                    write(self.id, self.mode, &mut self.codec, &mut self.writer, &mut header, &mut data, &mut unused).await?
-                } else {
-                    let mut data = Storage::Unique(&mut []);
-                    write(self.id, self.mode, &mut self.codec, &mut self.writer, &mut header, &mut data, &mut unused).await?
-                }
-                self.flush().await?;
-                self.writer.lock().await.close().await.or(Err(Error::Closed))
-            }
-            OpCode::Binary
-            | OpCode::Text
-            | OpCode::Continue
-            | OpCode::Reserved3
-            | OpCode::Reserved4
-            | OpCode::Reserved5
-            | OpCode::Reserved6
-            | OpCode::Reserved7
-            | OpCode::Reserved11
-            | OpCode::Reserved12
-            | OpCode::Reserved13
-            | OpCode::Reserved14
-            | OpCode::Reserved15 => Err(Error::UnexpectedOpCode(header.opcode()))
-        }
-    }
-
-    /// Apply all extensions to the given header and the internal message buffer.
-    async fn decode_with_extensions(&mut self, header: &mut Header, message: &mut Vec<u8>) -> Result<(), Error> {
-        if !self.has_extensions {
-            return Ok(())
-        }
-        for e in self.extensions.lock().await.iter_mut() {
-            log::trace!("{}: decoding with extension: {}", self.id, e.name());
-            e.decode(header, message).map_err(Error::Extension)?
-        }
-        Ok(())
-    }
-
-    /// Flush the socket buffer.
-    async fn flush(&mut self) -> Result<(), Error> {
-        log::trace!("{}: flushing connection", self.id);
-        if self.is_closed {
-            return Ok(())
-        }
-        self.writer.lock().await.flush().await.or(Err(Error::Closed))
+    /// Send a close frame with the given status code and close the connection.
+    ///
+    /// Returns the given error which caused the connection to fail.
+    ///
+    /// Nothing is sent if closing on errors has been disabled.
+    async fn fail(&mut self, code: u16, e: Error) -> Error {
+        self.deadline = None;
+        let mut writer = self.writer.lock().await;
+        let e = writer.core.fail(code, e);
+        let _ = writer.write_output().await;
+        e
     }
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
     /// Send a text value over the websocket connection.
+    ///
+    /// Taking a `str` ensures only valid UTF-8 is sent. To send received
+    /// bytes as text, convert them with [`std::str::from_utf8`] first.
     pub async fn send_text(&mut self, data: impl AsRef<str>) -> Result<(), Error> {
         let mut header = Header::new(OpCode::Text);
         self.send_frame(&mut header, &mut Storage::Shared(data.as_ref().as_bytes())).await
@@ -437,81 +1081,489 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Sender<T> {
         self.send_frame(&mut header, &mut Storage::Unique(data.as_mut())).await
     }
 
+    /// Send shared binary data over the websocket connection.
+    ///
+    /// In [`Mode::Server`] frames are not masked and the data is written to
+    /// the socket without being copied, so the same buffer can be sent to
+    /// many connections, e.g. when broadcasting. In [`Mode::Client`], or if
+    /// an extension modifies the payload data (e.g. compression), the data
+    /// is copied first and the shared buffer is left unchanged.
+    pub async fn send_binary_shared(&mut self, data: Bytes) -> Result<(), Error> {
+        let mut header = Header::new(OpCode::Binary);
+        self.send_frame(&mut header, &mut Storage::Shared(&data)).await
+    }
+
+    /// Send a shared text value over the websocket connection.
+    ///
+    /// Cf. [`Sender::send_binary_shared`] for when the data is copied.
+    pub async fn send_text_shared(&mut self, data: Arc<str>) -> Result<(), Error> {
+        let mut header = Header::new(OpCode::Text);
+        self.send_frame(&mut header, &mut Storage::Shared(data.as_bytes())).await
+    }
+
     /// Ping the remote end.
-    pub async fn send_ping(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
+    ///
+    /// The payload data can be given as [`ByteBuf125`] or as borrowed
+    /// [`ByteSlice125`](crate::data::ByteSlice125).
+    pub async fn send_ping(&mut self, data: impl Into<ByteBuf125>) -> Result<(), Error> {
         let mut header = Header::new(OpCode::Ping);
-        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+        let data = data.into();
+        self.send_frame(&mut header, &mut Storage::Shared(&data)).await
+    }
+
+    /// Ping the remote end with payload data identifying this PING.
+    ///
+    /// The returned [`PingToken`] can be turned into a [`PongReceipt`] which
+    /// completes once the [`Receiver`] receives the matching PONG, with the
+    /// round-trip time if a clock has been set (cf. [`Builder::set_clock`]).
+    /// PONGs are still returned by [`Receiver::receive`] as usual.
+    pub async fn ping_with_token(&mut self) -> Result<PingToken, Error> {
+        let mut writer = self.writer.lock().await;
+        let (data, token) = writer.core.start_ping()?;
+        let header = Header::new(OpCode::Ping);
+        write(&self.writer, writer, &header, &mut Storage::Shared(&data), &mut self.mask_buffer).await?;
+        Ok(token)
     }
 
     /// Send an unsolicited Pong to the remote.
-    pub async fn send_pong(&mut self, data: ByteSlice125<'_>) -> Result<(), Error> {
+    ///
+    /// The payload data can be given as [`ByteBuf125`] or as borrowed
+    /// [`ByteSlice125`](crate::data::ByteSlice125).
+    pub async fn send_pong(&mut self, data: impl Into<ByteBuf125>) -> Result<(), Error> {
         let mut header = Header::new(OpCode::Pong);
-        self.write(&mut header, &mut Storage::Shared(data.as_ref())).await
+        let data = data.into();
+        self.send_frame(&mut header, &mut Storage::Shared(&data)).await
     }
 
     /// Flush the socket buffer.
     pub async fn flush(&mut self) -> Result<(), Error> {
         log::trace!("{}: flushing connection", self.id);
-        self.writer.lock().await.flush().await.or(Err(Error::Closed))
+        self.writer.lock().await.socket.flush().await.or(Err(Error::Closed))
     }
 
     /// Send a close message and close the connection.
     pub async fn close(&mut self) -> Result<(), Error> {
+        self.close_with(&CloseReason::with_code(1000)).await // 1000 = normal closure
+    }
+
+    /// Abort the connection without a closing handshake.
+    ///
+    /// Unlike [`Sender::close`] no CLOSE frame is sent and nothing is written
+    /// to or flushed on the socket. The remote sees the connection end without
+    /// a closing handshake once the socket is closed or dropped, i.e. an
+    /// abnormal closure (close code 1006). Afterwards sending and receiving
+    /// fail with [`Error::Closed`].
+    ///
+    /// To get the socket back, e.g. to shut it down immediately, use
+    /// [`Sender::reunite`] and [`Builder::into_inner`].
+    pub async fn abort(&mut self) {
+        self.writer.lock().await.core.abort()
+    }
+
+    /// Put this sender and the receiver of the same connection back together.
+    ///
+    /// The returned [`Builder`] has the socket, unread data, extensions and
+    /// settings of the connection. [`Builder::finish`] splits it again and
+    /// [`Builder::into_inner`] recovers the socket.
+    ///
+    /// If both halves do not belong to the same connection, they are
+    /// returned unchanged in the error.
+    #[allow(clippy::result_large_err)]
+    pub fn reunite(mut self, mut receiver: Receiver<T>) -> Result<Builder<T>, ReuniteError<T>> {
+        let writer = match self.writer.reunite(receiver.writer) {
+            Ok(w) => w,
+            Err(BiLockReuniteError(w1, w2)) => {
+                self.writer = w1;
+                receiver.writer = w2;
+                return Err(ReuniteError(self, receiver))
+            }
+        };
+        let Writer { socket, core } = writer;
+        let socket = receiver.reader.reunite(socket).expect("reader and writer of same connection");
+        Ok(core.into_builder(socket, receiver.buffer, receiver.message_timeout))
+    }
+
+    /// Turn this sender into a [`SharedSender`] which can be cloned and
+    /// used by multiple tasks concurrently.
+    pub fn into_shared(self) -> SharedSender<T> {
+        SharedSender { sender: Arc::new(Mutex::new(self)) }
+    }
+
+    /// Send a close message with the given code and reason and close the connection.
+    async fn close_with(&mut self, reason: &CloseReason) -> Result<(), Error> {
         log::trace!("{}: closing connection", self.id);
+        let payload = close_payload(reason);
         let mut header = Header::new(OpCode::Close);
-        let code = 1000_u16.to_be_bytes(); // 1000 = normal closure
-        self.write(&mut header, &mut Storage::Shared(&code[..])).await?;
+        self.send_frame(&mut header, &mut Storage::Shared(&payload)).await?;
         self.flush().await?;
-        self.writer.lock().await.close().await.or(Err(Error::Closed))
+        self.writer.lock().await.socket.close().await.or(Err(Error::Closed))
     }
 
     /// Send arbitrary websocket frames.
     ///
     /// Before sending, extensions will be applied to header and payload data.
+    /// A CLOSE frame starts the closing handshake.
     async fn send_frame(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
-        if !self.has_extensions {
-            return self.write(header, data).await
+        let mut writer = self.writer.lock().await;
+        writer.core.prepare_send(header, data)?;
+        write(&self.writer, writer, header, data, &mut self.mask_buffer).await
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Sender<T> {
+    /// Turn this sender into a [`futures::Sink`] of [`OutgoingMessage`]s.
+    ///
+    /// Text and binary messages are sent as data, PINGs as control frames
+    /// and [`OutgoingMessage::Close`] closes the connection with the given
+    /// code and reason, after which sending fails with [`Error::Closed`].
+    /// Flushing and closing the sink flush and close the connection.
+    pub fn into_sink(self) -> SenderSink<T> {
+        SenderSink { state: SinkState::Idle(self) }
+    }
+}
+
+/// A [`futures::Sink`] of [`OutgoingMessage`]s, created with [`Sender::into_sink`].
+pub struct SenderSink<T> {
+    state: SinkState<T>
+}
+
+type SinkFuture<T> = future::BoxFuture<'static, (Sender<T>, Result<(), Error>)>;
+
+/// The state of a [`SenderSink`].
+enum SinkState<T> {
+    /// Ready to start the next operation.
+    Idle(Sender<T>),
+    /// An operation is in progress.
+    Busy(SinkOp, SinkFuture<T>),
+    /// The connection has been closed.
+    Closed
+}
+
+/// The kinds of sink operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SinkOp {
+    Send,
+    Flush,
+    Close
+}
+
+impl<T> fmt::Debug for SenderSink<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = match &self.state {
+            SinkState::Idle(_) => "Idle",
+            SinkState::Busy(SinkOp::Send, _) => "Sending",
+            SinkState::Busy(SinkOp::Flush, _) => "Flushing",
+            SinkState::Busy(SinkOp::Close, _) => "Closing",
+            SinkState::Closed => "Closed"
+        };
+        f.debug_struct("SenderSink").field("state", &state).finish()
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> SenderSink<T> {
+    /// Drive the current operation to completion.
+    ///
+    /// Returns the completed operation, if any.
+    fn poll_op(&mut self, cx: &mut Context) -> Poll<Result<Option<SinkOp>, Error>> {
+        if let SinkState::Busy(op, f) = &mut self.state {
+            let op = *op;
+            let (sender, result) = futures::ready!(f.as_mut().poll(cx));
+            self.state = if op == SinkOp::Close { SinkState::Closed } else { SinkState::Idle(sender) };
+            return Poll::Ready(result.map(|()| Some(op)))
+        }
+        Poll::Ready(Ok(None))
+    }
+
+    /// Start the given operation.
+    fn start<F>(&mut self, op: SinkOp, f: impl FnOnce(Sender<T>) -> F) -> Result<(), Error>
+    where
+        F: Future<Output = (Sender<T>, Result<(), Error>)> + Send + 'static
+    {
+        match std::mem::replace(&mut self.state, SinkState::Closed) {
+            SinkState::Idle(sender) => {
+                self.state = SinkState::Busy(op, f(sender).boxed());
+                Ok(())
+            }
+            SinkState::Busy(o, fut) => {
+                self.state = SinkState::Busy(o, fut);
+                Err(Error::SinkNotReady)
+            }
+            SinkState::Closed => Err(Error::Closed)
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Sink<OutgoingMessage> for SenderSink<T> {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_op(cx))?;
+        if let SinkState::Closed = this.state {
+            return Poll::Ready(Err(Error::Closed))
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: OutgoingMessage) -> Result<(), Error> {
+        let this = self.get_mut();
+        match message {
+            OutgoingMessage::Text(text) => this.start(SinkOp::Send, |mut s| async move {
+                let r = s.send_text(&text).await;
+                (s, r)
+            }),
+            OutgoingMessage::Binary(mut data) => this.start(SinkOp::Send, |mut s| async move {
+                let r = s.send_binary_mut(&mut data[..]).await;
+                (s, r)
+            }),
+            OutgoingMessage::Ping(data) => this.start(SinkOp::Send, |mut s| async move {
+                let r = s.send_ping(data).await;
+                (s, r)
+            }),
+            OutgoingMessage::Close(reason) => this.start(SinkOp::Close, |mut s| async move {
+                let r = s.close_with(&reason).await;
+                (s, r)
+            })
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        loop {
+            match futures::ready!(this.poll_op(cx))? {
+                Some(SinkOp::Flush) | Some(SinkOp::Close) => return Poll::Ready(Ok(())),
+                Some(SinkOp::Send) | None => {}
+            }
+            if let SinkState::Closed = this.state {
+                return Poll::Ready(Ok(()))
+            }
+            this.start(SinkOp::Flush, |mut s| async move {
+                let r = s.flush().await;
+                (s, r)
+            })?
         }
+    }
 
-        for e in self.extensions.lock().await.iter_mut() {
-            log::trace!("{}: encoding with extension: {}", self.id, e.name());
-            e.encode(header, data).map_err(Error::Extension)?
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(SinkOp::Close) = futures::ready!(this.poll_op(cx))? {
+                return Poll::Ready(Ok(()))
+            }
+            if let SinkState::Closed = this.state {
+                return Poll::Ready(Ok(()))
+            }
+            this.start(SinkOp::Close, |mut s| async move {
+                let r = s.close().await;
+                (s, r)
+            })?
         }
+    }
+}
+
+/// A cloneable handle to a [`Sender`], created with [`Sender::into_shared`].
+///
+/// Every operation waits for exclusive access to the sender and completes
+/// once its frame has been written to the socket. Messages sent from
+/// different clones are therefore never interleaved and producers are
+/// slowed down to the speed of the connection.
+pub struct SharedSender<T> {
+    sender: Arc<Mutex<Sender<T>>>
+}
+
+impl<T> fmt::Debug for SharedSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct("SharedSender");
+        match self.sender.try_lock() {
+            Some(sender) => d.field("sender", &*sender),
+            None => d.field("sender", &"<locked>")
+        };
+        d.finish()
+    }
+}
 
-        self.write(header, data).await
+impl<T> Clone for SharedSender<T> {
+    fn clone(&self) -> Self {
+        SharedSender { sender: self.sender.clone() }
     }
+}
 
-    /// Write final header and payload data to socket.
+impl<T: AsyncRead + AsyncWrite + Unpin> SharedSender<T> {
+    /// Send a text value over the websocket connection.
+    pub async fn send_text(&self, data: impl AsRef<str>) -> Result<(), Error> {
+        self.sender.lock().await.send_text(data).await
+    }
+
+    /// Send some binary data over the websocket connection.
+    pub async fn send_binary(&self, data: impl AsRef<[u8]>) -> Result<(), Error> {
+        self.sender.lock().await.send_binary(data).await
+    }
+
+    /// Send some binary data over the websocket connection.
+    ///
+    /// In contrast to [`SharedSender::send_binary`] the provided data is
+    /// modified in-place, e.g. if masking is necessary.
+    pub async fn send_binary_mut(&self, data: impl AsMut<[u8]>) -> Result<(), Error> {
+        self.sender.lock().await.send_binary_mut(data).await
+    }
+
+    /// Send shared binary data, cf. [`Sender::send_binary_shared`].
+    pub async fn send_binary_shared(&self, data: Bytes) -> Result<(), Error> {
+        self.sender.lock().await.send_binary_shared(data).await
+    }
+
+    /// Send a shared text value, cf. [`Sender::send_text_shared`].
+    pub async fn send_text_shared(&self, data: Arc<str>) -> Result<(), Error> {
+        self.sender.lock().await.send_text_shared(data).await
+    }
+
+    /// Ping the remote end.
+    pub async fn send_ping(&self, data: impl Into<ByteBuf125>) -> Result<(), Error> {
+        self.sender.lock().await.send_ping(data).await
+    }
+
+    /// Ping the remote end with payload data identifying this PING (cf. [`Sender::ping_with_token`]).
+    pub async fn ping_with_token(&self) -> Result<PingToken, Error> {
+        self.sender.lock().await.ping_with_token().await
+    }
+
+    /// Send an unsolicited Pong to the remote.
+    pub async fn send_pong(&self, data: impl Into<ByteBuf125>) -> Result<(), Error> {
+        self.sender.lock().await.send_pong(data).await
+    }
+
+    /// Flush the socket buffer.
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.sender.lock().await.flush().await
+    }
+
+    /// Send a close message and close the connection.
+    ///
+    /// The connection is closed for all clones.
+    pub async fn close(&self) -> Result<(), Error> {
+        self.sender.lock().await.close().await
+    }
+
+    /// Abort the connection without a closing handshake (cf. [`Sender::abort`]).
     ///
-    /// The data will be masked if necessary.
-    /// No extensions will be applied to header and payload data.
-    async fn write(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
-        write(self.id, self.mode, &mut self.codec, &mut self.writer, header, data, &mut self.mask_buffer).await
+    /// The connection is aborted for all clones.
+    pub async fn abort(&self) {
+        self.sender.lock().await.abort().await
+    }
+}
+
+/// Await the given future unless the deadline of the current message expires first.
+///
+/// The future is dropped on expiry, which fails the connection.
+async fn until_deadline<F: Future>(deadline: &mut Option<Deadline>, f: F) -> Result<F::Output, Error> {
+    let timer = match deadline {
+        Some(t) => t.0.get_mut().unwrap_or_else(|e| e.into_inner()),
+        None => return Ok(f.await)
+    };
+    futures::pin_mut!(f);
+    match future::select(f, timer.as_mut()).await {
+        future::Either::Left((x, _)) => Ok(x),
+        future::Either::Right(_) => Err(Error::MessageTimeout)
     }
 }
 
+/// Emit a tracing event for a frame sent or received.
+#[cfg(feature = "tracing")]
+fn trace_frame(direction: &'static str, id: &Id, header: &Header) {
+    tracing::trace!(
+        id = %id,
+        opcode = %header.opcode(),
+        payload_len = header.payload_len(),
+        fin = header.is_fin(),
+        masked = header.is_masked(),
+        mask = header.mask(),
+        "{}", direction
+    )
+}
+
+/// Call `f` with the connection state and write the output it queues.
+///
+/// If `f` fails, its error is returned even if writing fails as well.
+async fn with_core<T, R, F>(writer: &BiLock<Writer<T>>, f: F) -> Result<R, Error>
+where
+    T: AsyncWrite + Unpin,
+    F: FnOnce(&mut ConnectionState) -> Result<R, Error>
+{
+    let mut writer = writer.lock().await;
+    let result = f(&mut writer.core);
+    let written = writer.write_output().await;
+    let value = result?;
+    written?;
+    Ok(value)
+}
+
 /// Write header and payload data to socket.
-async fn write<T: AsyncWrite + Unpin>
-    ( id: Id
-    , mode: Mode
-    , codec: &mut base::Codec
-    , writer: &mut BiLock<WriteHalf<T>>
-    , header: &mut Header
+///
+/// Data frames with more payload data than the maximum frame size are
+/// split into fragments. The task yields between fragments and releases
+/// the lock meanwhile, so that the receiver can answer PINGs or close the
+/// connection.
+async fn write<'a, T: AsyncWrite + Unpin>
+    ( lock: &'a BiLock<Writer<T>>
+    , mut w: BiLockGuard<'a, Writer<T>>
+    , header: &Header
     , data: &mut Storage<'_>
     , mask_buffer: &mut Vec<u8>
     ) -> Result<(), Error>
 {
-    if mode.is_client() {
-        header.set_masked(true);
-        header.set_mask(rand::random());
+    let fragments = w.core.fragments(header, data.as_ref().len());
+    for (i, (mut fragment, range)) in fragments.enumerate() {
+        if i > 0 {
+            drop(w);
+            YieldNow(false).await;
+            w = lock.lock().await;
+            if w.core.is_closed() {
+                return Err(Error::Closed)
+            }
+        }
+        let mut chunk = match data {
+            Storage::Shared(slice) => Storage::Shared(&slice[range]),
+            Storage::Unique(slice) => Storage::Unique(&mut slice[range]),
+            Storage::Owned(bytes) => Storage::Unique(&mut bytes[range])
+        };
+        write_frame(&mut w, &mut fragment, &mut chunk, mask_buffer).await?
+    }
+    Ok(())
+}
+
+/// A future which returns `Pending` once, to let other tasks run.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(())
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
     }
-    header.set_payload_len(data.as_ref().len());
+}
 
-    log::trace!("{}: send: {}", id, header);
+/// Write a single frame to the socket.
+///
+/// The payload data is written as given, without being copied to the
+/// output of the connection state, unless it needs to be masked.
+async fn write_frame<T: AsyncWrite + Unpin>
+    ( w: &mut Writer<T>
+    , header: &mut Header
+    , data: &mut Storage<'_>
+    , mask_buffer: &mut Vec<u8>
+    ) -> Result<(), Error>
+{
+    w.core.queue_header(header, data.as_ref());
+    let result = w.socket.write_all(w.core.output()).await;
+    w.core.output().clear();
+    result.or(Err(Error::Closed))?;
 
-    let header_bytes = codec.encode_header(&header);
-    let mut w = writer.lock().await;
-    w.write_all(&header_bytes).await.or(Err(Error::Closed))?;
+    let w = &mut w.socket;
 
     if !header.is_masked() {
         return w.write_all(data.as_ref()).await.or(Err(Error::Closed))
@@ -535,23 +1587,67 @@ async fn write<T: AsyncWrite + Unpin>
     }
 }
 
-/// Create a close frame based on the given data.
-fn close_answer(data: &[u8]) -> Result<(Header, Option<u16>), Error> {
-    let answer = Header::new(OpCode::Close);
-    if data.len() < 2 {
-        return Ok((answer, None))
+/// Determine the status code to answer a close frame with the given data.
+///
+/// Invalid close codes are answered with a protocol error (1002). A payload
+/// of one byte (1002) or a reason which is not valid UTF-8 (1007) are also
+/// returned as errors.
+fn close_answer(data: &[u8]) -> Result<Option<u16>, (u16, Error)> {
+    match data.len() {
+        0 => return Ok(None),
+        1 => return Err((1002, Error::InvalidClosePayload)), // a code needs two bytes
+        _ => {}
+    }
+    if let Err(e) = std::str::from_utf8(&data[2 ..]) {
+        return Err((1007, Error::Utf8(e))) // 1007 = invalid payload data
     }
-    std::str::from_utf8(&data[2 ..])?; // check reason is properly encoded
     let code = u16::from_be_bytes([data[0], data[1]]);
     match code {
         | 1000 ..= 1003
         | 1007 ..= 1011
-        | 1015
-        | 3000 ..= 4999 => Ok((answer, Some(code))), // acceptable codes
-        _               => Ok((answer, Some(1002))) // invalid code => protocol error (1002)
+        | 3000 ..= 4999 => Ok(Some(code)), // acceptable codes
+        _               => Ok(Some(1002)) // invalid code => protocol error (1002)
+    }
+}
+
+/// Encode code and reason of a close frame.
+fn close_payload(reason: &CloseReason) -> Vec<u8> {
+    let mut payload = Vec::new();
+    if let Some(code) = reason.code() {
+        payload.extend_from_slice(&code.to_be_bytes());
+        payload.extend_from_slice(reason.reason().as_bytes())
+    }
+    debug_assert!(payload.len() <= base::MAX_CTRL_PAYLOAD);
+    payload
+}
+
+/// Extract code and reason from the payload data of a (valid) close frame.
+fn close_reason(data: &[u8]) -> CloseReason {
+    if data.len() < 2 {
+        return CloseReason::default()
+    }
+    let code = u16::from_be_bytes([data[0], data[1]]);
+    CloseReason::truncated(code, &String::from_utf8_lossy(&data[2 ..]))
+}
+
+/// Error returned by [`Sender::reunite`] if the sender and receiver do not
+/// belong to the same connection.
+pub struct ReuniteError<T>(pub Sender<T>, pub Receiver<T>);
+
+impl<T> fmt::Debug for ReuniteError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ReuniteError").field(&"..").finish()
+    }
+}
+
+impl<T> fmt::Display for ReuniteError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("tried to reunite a sender and receiver of different connections")
     }
 }
 
+impl<T> std::error::Error for ReuniteError<T> {}
+
 /// Errors which may occur when sending or receiving messages.
 #[non_exhaustive]
 #[derive(Debug)]
@@ -564,10 +1660,32 @@ pub enum Error {
     Extension(crate::BoxedError),
     /// An unexpected opcode was encountered.
     UnexpectedOpCode(OpCode),
-    /// A close reason was not correctly UTF-8 encoded.
+    /// A text message or close reason was not correctly UTF-8 encoded.
     Utf8(str::Utf8Error),
+    /// A received close frame had a payload of one byte, which is too short
+    /// for a status code.
+    InvalidClosePayload,
     /// The total message payload data size exceeds the configured maximum.
     MessageTooLarge { current: usize, maximum: usize },
+    /// The opening handshake failed.
+    Handshake(crate::handshake::Error),
+    /// A message was given to a [`SenderSink`] before it was ready.
+    ///
+    /// [`Sink::poll_ready`] must return `Ready(Ok(()))` before each call to
+    /// [`Sink::start_send`].
+    SinkNotReady,
+    /// More control frames than allowed were received during a message (cf.
+    /// [`Builder::set_max_control_frames_per_message`]).
+    TooManyControlFrames { maximum: usize },
+    /// A message was not received completely before its timer fired (cf.
+    /// [`Builder::set_message_timeout`]).
+    MessageTimeout,
+    /// The connection ended without a closing handshake, i.e. the stream
+    /// ended in the middle of a frame or before a close frame was received.
+    UnexpectedEof,
+    /// A client needs to mask a frame, but no mask source has been set and
+    /// feature `rand` is disabled (cf. [`Builder::set_mask_source`]).
+    MaskSourceRequired,
     /// The connection is closed.
     Closed
 }
@@ -585,8 +1703,22 @@ impl fmt::Display for Error {
                 write!(f, "unexpected opcode: {}", c),
             Error::Utf8(e) =>
                 write!(f, "utf-8 error: {}", e),
+            Error::InvalidClosePayload =>
+                f.write_str("invalid close frame payload"),
             Error::MessageTooLarge { current, maximum } =>
                 write!(f, "message too large: len >= {}, maximum = {}", current, maximum),
+            Error::Handshake(e) =>
+                write!(f, "handshake error: {}", e),
+            Error::SinkNotReady =>
+                f.write_str("sink not ready to send"),
+            Error::TooManyControlFrames { maximum } =>
+                write!(f, "too many control frames: maximum = {}", maximum),
+            Error::MessageTimeout =>
+                f.write_str("timeout while receiving a message"),
+            Error::UnexpectedEof =>
+                f.write_str("connection closed without close frame"),
+            Error::MaskSourceRequired =>
+                f.write_str("no mask source set for client frames"),
             Error::Closed =>
                 f.write_str("connection closed")
         }
@@ -600,18 +1732,44 @@ impl std::error::Error for Error {
             Error::Codec(e) => Some(e),
             Error::Extension(e) => Some(&**e),
             Error::Utf8(e) => Some(e),
+            Error::Handshake(e) => Some(e),
             Error::UnexpectedOpCode(_)
+            | Error::InvalidClosePayload
             | Error::MessageTooLarge {..}
+            | Error::SinkNotReady
+            | Error::TooManyControlFrames {..}
+            | Error::MessageTimeout
+            | Error::UnexpectedEof
+            | Error::MaskSourceRequired
             | Error::Closed
             => None
         }
     }
 }
 
+impl Error {
+    /// Did the connection end without a closing handshake?
+    ///
+    /// This is the case if the stream ended unexpectedly or was reset or
+    /// aborted, which corresponds to close code 1006 (abnormal closure).
+    pub fn is_abnormal_closure(&self) -> bool {
+        match self {
+            Error::UnexpectedEof => true,
+            Error::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::BrokenPipe
+            ),
+            _ => false
+        }
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
         if e.kind() == io::ErrorKind::UnexpectedEof {
-            Error::Closed
+            Error::UnexpectedEof
         } else {
             Error::Io(e)
         }
@@ -629,3 +1787,1611 @@ impl From<base::Error> for Error {
         Error::Codec(e)
     }
 }
+
+impl From<crate::handshake::Error> for Error {
+    fn from(e: crate::handshake::Error) -> Self {
+        Error::Handshake(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        base::OpCode,
+        extension::{Extension, testing::{Call, CallLog, MockExtension, exchange}},
+        mock::duplex
+    };
+    use futures::{executor::block_on, future, prelude::*};
+    use bytes::BytesMut;
+    use crate::{Parsing, base, data::{ByteBuf125, ByteSlice125, CloseReason, Data, Message, OutgoingMessage, PayloadBuf}};
+    use futures::stream::FusedStream;
+    use std::{convert::TryFrom, pin::Pin, task::{Context, Poll}};
+    use super::{Builder, ConnectionState, Direction, Error, Event, Mode, Receiver, ReuniteError, Sender, SenderSink, SharedSender};
+
+    /// An extension which appends its name when encoding and removes it when decoding.
+    fn tag(name: &'static str, rsv1: bool, log: &CallLog) -> MockExtension {
+        let mut e = MockExtension::with_log(name, log.clone());
+        e.set_reserved_bits((rsv1, false, false));
+        e.on_encode(move |header, data| {
+            data.extend_from_slice(name.as_bytes());
+            if rsv1 {
+                header.set_rsv1(true);
+            }
+            Ok(())
+        });
+        e.on_decode(move |header, data| {
+            if !data.ends_with(name.as_bytes()) {
+                return Err(format!("{}: missing tag", name).into())
+            }
+            data.truncate(data.len() - name.len());
+            if rsv1 {
+                header.set_rsv1(false);
+            }
+            Ok(())
+        });
+        e
+    }
+
+    /// Box an extension which is used without handshake.
+    fn enabled(mut e: MockExtension) -> Box<dyn Extension + Send + Sync> {
+        e.set_enabled(true);
+        Box::new(e)
+    }
+
+    /// The sequence of encode and decode calls as (name, is_encode) pairs.
+    fn codec_calls(log: &CallLog) -> Vec<(String, bool)> {
+        log.calls().into_iter().filter_map(|c| match c {
+            Call::Encode { name, .. } => Some((name, true)),
+            Call::Decode { name, .. } => Some((name, false)),
+            Call::Configure { .. } => None
+        })
+        .collect()
+    }
+
+    #[test]
+    fn extension_order() {
+        let (a, b) = duplex(64);
+        let log = CallLog::new();
+
+        let mut client = Builder::new(a, Mode::Client);
+        client.add_extensions(vec![enabled(tag("a", false, &log)), enabled(tag("b", false, &log))]);
+        let (mut sender, _) = client.finish();
+
+        let mut server = Builder::new(b, Mode::Server);
+        server.add_extensions(vec![enabled(tag("a", false, &log)), enabled(tag("b", false, &log))]);
+        let (_, mut receiver) = server.finish();
+
+        block_on(future::join(
+            async {
+                sender.send_text("hello").await.unwrap();
+                sender.flush().await.unwrap()
+            },
+            async {
+                let mut message = Vec::new();
+                receiver.receive_data(&mut message).await.unwrap();
+                assert_eq!(b"hello", &message[..])
+            }
+        ));
+
+        let expected = [("a", true), ("b", true), ("b", false), ("a", false)];
+        assert_eq!(codec_calls(&log), expected.iter().map(|(n, e)| (n.to_string(), *e)).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn extension_order_and_reserved_bits_on_the_wire() {
+        let log = CallLog::new();
+        let client: Vec<Box<dyn Extension + Send + Sync>> = vec![Box::new(tag("a", true, &log)), Box::new(tag("b", false, &log))];
+        let server: Vec<Box<dyn Extension + Send + Sync>> = vec![Box::new(tag("a", true, &log)), Box::new(tag("b", false, &log))];
+        let messages: &[&[u8]] = &[b"hello"];
+        let result = block_on(exchange(client, server, messages)).unwrap();
+
+        assert_eq!(vec!["a", "b"], result.extensions);
+        assert_eq!(vec![b"hello".to_vec()], result.server_received);
+        assert_eq!(vec![b"hello".to_vec()], result.client_received);
+
+        for frames in &[&result.client_frames, &result.server_frames] {
+            let data = frames.iter().find(|f| f.header.opcode() == OpCode::Binary).unwrap();
+            assert!(data.header.is_rsv1());
+            assert!(!data.header.is_rsv2() && !data.header.is_rsv3());
+            assert_eq!(b"helloab", &data.payload[..])
+        }
+
+        // The server decodes with RSV1 set and the last extension first.
+        let decodes: Vec<_> = log.calls().into_iter().filter_map(|c| match c {
+            Call::Decode { name, header, .. } => Some((name, header.is_rsv1())),
+            _ => None
+        })
+        .collect();
+        assert_eq!(("b".to_string(), true), decodes[0]);
+        assert_eq!(("a".to_string(), true), decodes[1])
+    }
+
+    #[test]
+    fn declined_extension_is_not_used() {
+        let log = CallLog::new();
+        let mut declining = MockExtension::with_log("b", log.clone());
+        declining.set_accept(false);
+        let client: Vec<Box<dyn Extension + Send + Sync>> = vec![Box::new(tag("a", false, &log)), Box::new(tag("b", false, &log))];
+        let server: Vec<Box<dyn Extension + Send + Sync>> = vec![Box::new(tag("a", false, &log)), Box::new(declining)];
+        let messages: &[&[u8]] = &[b"hello"];
+        let result = block_on(exchange(client, server, messages)).unwrap();
+        assert_eq!(vec!["a"], result.extensions);
+        assert_eq!(vec![b"hello".to_vec()], result.server_received);
+        assert!(codec_calls(&log).iter().all(|(n, _)| n == "a"))
+    }
+
+    #[test]
+    #[should_panic(expected = "extensions a and b both use RSV1")]
+    fn reserved_bit_conflict() {
+        let (a, _b) = duplex(64);
+        let log = CallLog::new();
+        let mut builder = Builder::new(a, Mode::Client);
+        builder.add_extensions(vec![enabled(tag("a", true, &log)), enabled(tag("b", true, &log))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "extensions a and b both use RSV1")]
+    fn reserved_bit_conflict_during_handshake() {
+        let (a, _b) = duplex(64);
+        let log = CallLog::new();
+        let mut client = crate::handshake::Client::new(a, "example.com", "/");
+        client.add_extension(enabled(tag("a", true, &log)));
+        client.add_extension(enabled(tag("a", true, &log))); // alternative offers are fine
+        client.add_extension(enabled(tag("b", true, &log)));
+    }
+
+    #[test]
+    fn receive_messages() {
+        let (a, b) = duplex(64);
+        let (mut sender, _receiver) = Builder::new(a, Mode::Client).finish();
+        let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+
+        block_on(future::join(
+            async {
+                sender.send_text("hello").await.unwrap();
+                sender.send_binary([1, 2, 3]).await.unwrap();
+                sender.send_pong(ByteSlice125::try_from(&b"pong"[..]).unwrap()).await.unwrap();
+                sender.send_pong(ByteBuf125::try_from(format!("pong {}", 2).as_str()).unwrap()).await.unwrap();
+                sender.close().await.unwrap()
+            },
+            async {
+                let text = receiver.receive().await.unwrap();
+                assert!(text.is_text());
+                assert_eq!(Ok("hello".to_string()), String::try_from(text));
+                let binary = receiver.receive().await.unwrap();
+                assert!(binary.is_binary());
+                assert_eq!(Err(binary.clone()), String::try_from(binary.clone()));
+                assert_eq!(vec![1, 2, 3], Vec::from(binary));
+                assert_eq!(Message::Pong(b"pong".to_vec()), receiver.receive().await.unwrap());
+                assert_eq!(Message::Pong(b"pong 2".to_vec()), receiver.receive().await.unwrap());
+                match receiver.receive().await.unwrap() {
+                    Message::Closed(reason) => {
+                        assert_eq!(Some(1000), reason.code());
+                        assert_eq!("", reason.reason())
+                    }
+                    other => panic!("unexpected message: {:?}", other)
+                }
+                assert!(matches!(receiver.receive().await, Err(Error::Closed)))
+            }
+        ));
+    }
+
+    #[test]
+    fn sink_messages() {
+        let (a, mut b) = duplex(64);
+        let (sender, _receiver) = Builder::new(a, Mode::Client).finish();
+        let sink = sender.into_sink();
+
+        let messages = vec![
+            OutgoingMessage::from("hello"),
+            OutgoingMessage::Binary(vec![1, 2, 3]),
+            OutgoingMessage::Ping(ByteBuf125::from_static(b"ping")),
+            OutgoingMessage::Text("world".into()),
+            OutgoingMessage::Close(CloseReason::new(4000, "bye").unwrap())
+        ];
+
+        let bytes = block_on(async move {
+            stream::iter(messages).map(Ok).forward(sink).await.unwrap();
+            let mut bytes = Vec::new();
+            b.read_to_end(&mut bytes).await.unwrap();
+            bytes
+        });
+
+        let codec = base::Codec::new();
+        let mut bytes = &bytes[..];
+        let mut frames = Vec::new();
+        while !bytes.is_empty() {
+            match codec.decode_header(bytes).unwrap() {
+                Parsing::Done { value, offset } => {
+                    let mut payload = bytes[offset .. offset + value.payload_len()].to_vec();
+                    base::Codec::apply_mask(&value, &mut payload);
+                    bytes = &bytes[offset + value.payload_len() ..];
+                    frames.push((value.opcode(), payload))
+                }
+                Parsing::NeedMore(_) => panic!("incomplete frame")
+            }
+        }
+
+        assert_eq!(vec![
+            (OpCode::Text, b"hello".to_vec()),
+            (OpCode::Binary, vec![1, 2, 3]),
+            (OpCode::Ping, b"ping".to_vec()),
+            (OpCode::Text, b"world".to_vec()),
+            (OpCode::Close, b"\x0f\xa0bye".to_vec())
+        ], frames)
+    }
+
+    #[test]
+    fn relay() {
+        let (c1, r1) = duplex(64);
+        let (r2, s2) = duplex(64);
+        let (mut sender, _receiver) = Builder::new(c1, Mode::Client).finish();
+        let (_, inbound) = Builder::new(r1, Mode::Server).finish();
+        let (outbound, _) = Builder::new(r2, Mode::Client).finish();
+        let (_sender, mut receiver) = Builder::new(s2, Mode::Server).finish();
+
+        let relay = inbound.into_stream()
+            .try_filter_map(|m| future::ok(OutgoingMessage::try_from(m).ok()))
+            .forward(outbound.into_sink());
+
+        block_on(future::join3(
+            async {
+                sender.send_text("hello").await.unwrap();
+                sender.send_pong(ByteBuf125::from_static(b"skipped")).await.unwrap();
+                sender.send_binary([1, 2, 3]).await.unwrap();
+                sender.close().await.unwrap()
+            },
+            async {
+                relay.await.unwrap()
+            },
+            async {
+                assert_eq!(Message::Text("hello".into()), receiver.receive().await.unwrap());
+                assert_eq!(Message::Binary(BytesMut::from(&[1, 2, 3][..])), receiver.receive().await.unwrap());
+                let expected = CloseReason::with_code(1000);
+                assert_eq!(Message::Closed(expected), receiver.receive().await.unwrap())
+            }
+        ));
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn shared_sender() {
+        use tokio_util::compat::Tokio02AsyncReadCompatExt;
+
+        const CLONES: u8 = 32;
+        const MESSAGES: u8 = 50;
+
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (_sender, mut receiver) = Builder::new(socket.compat(), Mode::Client).finish();
+            let mut next = vec![0; usize::from(CLONES)];
+            for _ in 0 .. usize::from(CLONES) * usize::from(MESSAGES) {
+                let data = match receiver.receive().await.unwrap() {
+                    Message::Binary(data) => data,
+                    other => panic!("unexpected message: {:?}", other)
+                };
+                let (id, n) = (data[0], data[1]);
+                assert_eq!(next[usize::from(id)], n);
+                assert!(data[2 ..].iter().all(|b| *b == id));
+                next[usize::from(id)] += 1
+            }
+        });
+
+        let (socket, _) = listener.accept().await.unwrap();
+        let (sender, _receiver) = Builder::new(socket.compat(), Mode::Server).finish();
+        let sender = sender.into_shared();
+        let producers = (0 .. CLONES).map(|id| {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                for n in 0 .. MESSAGES {
+                    let mut data = vec![id; 2 + usize::from(id) * 997 % 20000 + usize::from(n)];
+                    data[1] = n;
+                    sender.send_binary(&data).await.unwrap();
+                    sender.flush().await.unwrap()
+                }
+            })
+        });
+        for p in future::join_all(producers).await {
+            p.unwrap()
+        }
+        client.await.unwrap()
+    }
+
+    /// A masked frame with the given first header byte and payload.
+    fn raw_frame(b0: u8, payload: &[u8]) -> Vec<u8> {
+        const MASK: [u8; 4] = [0x11, 0x22, 0x33, 0x44];
+        let mut frame = vec![b0];
+        if payload.len() < 126 {
+            frame.push(0x80 | payload.len() as u8)
+        } else {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&u16::try_from(payload.len()).unwrap().to_be_bytes())
+        }
+        frame.extend_from_slice(&MASK);
+        frame.extend(payload.iter().zip(MASK.iter().cycle()).map(|(b, m)| b ^ m));
+        frame
+    }
+
+    /// Let a server receive the given bytes and return the result of
+    /// `Receiver::receive` together with the close frame sent in response.
+    ///
+    /// The same bytes are given to a `ConnectionState` which must produce
+    /// the same result and output.
+    fn receive_raw(frames: &[Vec<u8>]) -> (Result<Message, Error>, Vec<u8>) {
+        let (mut a, b) = duplex(64);
+        let (sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        let (result, answer) = block_on(async {
+            for f in frames {
+                a.write_all(f).await.unwrap()
+            }
+            let result = receiver.receive().await;
+            drop((sender, receiver));
+            let mut answer = Vec::new();
+            a.read_to_end(&mut answer).await.unwrap();
+            (result, answer)
+        });
+        let (state_result, state_answer) = receive_state(frames);
+        assert_eq!(format!("{:?}", result), format!("{:?}", state_result));
+        assert_eq!(answer, state_answer);
+        (result, answer)
+    }
+
+    /// Give the frames one by one to a server `ConnectionState` until the
+    /// first message or error and return it together with the output.
+    fn receive_state(frames: &[Vec<u8>]) -> (Result<Message, Error>, Vec<u8>) {
+        let mut state = ConnectionState::new(Mode::Server);
+        let mut result = Err(Error::UnexpectedEof);
+        for f in frames {
+            let next = state.handle_input(f).into_iter().find_map(|e| match e {
+                Event::Message(m) => Some(Ok(m)),
+                Event::Error(e) => Some(Err(e)),
+                Event::Ping(_) => None
+            });
+            if let Some(r) = next {
+                result = r;
+                break
+            }
+        }
+        let mut output = BytesMut::new();
+        state.take_output(&mut output);
+        (result, output.to_vec())
+    }
+
+    #[test]
+    fn protocol_violations_fail_the_connection() {
+        const PROTOCOL_ERROR: &[u8] = &[0x88, 0x02, 0x03, 0xEA]; // 1002
+
+        // Reserved bit 2 without an extension (autobahn 3.2).
+        let (result, answer) = receive_raw(&[raw_frame(0xA1, b"hello")]);
+        assert!(matches!(result, Err(Error::Codec(base::Error::InvalidReservedBit(2)))));
+        assert_eq!(PROTOCOL_ERROR, &answer[..]);
+
+        // Fragmented PING (autobahn 5.1).
+        let (result, answer) = receive_raw(&[raw_frame(0x09, b"ping"), raw_frame(0x80, b"pong")]);
+        assert!(matches!(result, Err(Error::Codec(base::Error::FragmentedControl))));
+        assert_eq!(PROTOCOL_ERROR, &answer[..]);
+
+        // Continuation frame without a message to continue (autobahn 5.9).
+        let (result, answer) = receive_raw(&[raw_frame(0x80, b"hello")]);
+        assert!(matches!(result, Err(Error::UnexpectedOpCode(OpCode::Continue))));
+        assert_eq!(PROTOCOL_ERROR, &answer[..]);
+
+        // A new message while a fragmented one is incomplete.
+        let (result, answer) = receive_raw(&[raw_frame(0x01, b"a"), raw_frame(0x81, b"b")]);
+        assert!(matches!(result, Err(Error::UnexpectedOpCode(OpCode::Text))));
+        assert_eq!(PROTOCOL_ERROR, &answer[..]);
+
+        // Continuation frame with a reserved bit set.
+        let (result, answer) = receive_raw(&[raw_frame(0x01, b"a"), raw_frame(0xC0, b"b")]);
+        assert!(matches!(result, Err(Error::Codec(base::Error::InvalidReservedBit(1)))));
+        assert_eq!(PROTOCOL_ERROR, &answer[..]);
+
+        // Control frame with more than 125 bytes of payload data (autobahn 2.5).
+        let mut frame = vec![0x89, 0x80 | 126, 0, 126, 0, 0, 0, 0];
+        frame.extend_from_slice(&[0; 126]);
+        let (result, answer) = receive_raw(&[frame]);
+        assert!(matches!(result, Err(Error::Codec(base::Error::InvalidControlFrameLen))));
+        assert_eq!(PROTOCOL_ERROR, &answer[..])
+    }
+
+    #[test]
+    fn close_answers() {
+        let cases: &[(&[u8], &[u8])] = &[
+            (b"", &[0x88, 0x00]), // autobahn 7.3.1
+            (&[0x03], &[0x88, 0x02, 0x03, 0xEA]), // 7.3.2: 1002
+            (&[0x03, 0xE8], &[0x88, 0x02, 0x03, 0xE8]), // 7.3.3: 1000
+            (&[0x03, 0xE8, b'o', b'k'], &[0x88, 0x02, 0x03, 0xE8]), // 7.3.4: 1000
+            (&[0x03, 0xE8, 0xFF], &[0x88, 0x02, 0x03, 0xEF]), // invalid UTF-8 => 1007
+            (&[0x13, 0x87], &[0x88, 0x02, 0x13, 0x87]), // 7.7.13: 4999
+            (&[0x03, 0xE8, 0xCE, 0xBA, 0xE1, 0xBD, 0xB9, 0xCF, 0x83, 0xCE, 0xBC, 0xCE, 0xB5, 0xED, 0xA0, 0x80],
+             &[0x88, 0x02, 0x03, 0xEF]), // 7.5.1: invalid UTF-8 => 1007
+            (&[0x03, 0xED], &[0x88, 0x02, 0x03, 0xEA]), // 7.9.4: 1005 => 1002
+            (&[0x03, 0xF7], &[0x88, 0x02, 0x03, 0xEA]), // 1015 => 1002
+            (&[0x03, 0xE7], &[0x88, 0x02, 0x03, 0xEA]), // 7.9.2: 999 => 1002
+            (&[0x13, 0x88], &[0x88, 0x02, 0x03, 0xEA]) // 5000 => 1002
+        ];
+        for (payload, expected) in cases {
+            let (result, answer) = receive_raw(&[raw_frame(0x88, payload)]);
+            match payload.len() {
+                1 => assert!(matches!(result, Err(Error::InvalidClosePayload))),
+                n if n > 2 && std::str::from_utf8(&payload[2 ..]).is_err() =>
+                    assert!(matches!(result, Err(Error::Utf8(_)))),
+                _ => assert!(result.unwrap().is_closed())
+            }
+            assert_eq!(expected, &&answer[..], "close payload {:?}", payload)
+        }
+    }
+
+    #[test]
+    fn pipelined_frames() {
+        let binary: Vec<u8> = (0 .. 200).collect();
+        let frames = [
+            raw_frame(0x01, b"hel"), // first text fragment
+            raw_frame(0x89, b"p1"),  // ping
+            raw_frame(0x00, b"l"),   // continuation
+            raw_frame(0x89, b"p2"),  // ping
+            raw_frame(0x80, b"o"),   // last continuation
+            raw_frame(0x82, &binary),
+            raw_frame(0x89, b"p3"),  // ping
+            raw_frame(0x81, b"end")
+        ].concat();
+
+        // Split the bytes between the buffer carried over from the handshake
+        // and the socket at every position, with different read sizes.
+        for chunk in &[1, 3, 1024] {
+            for split in 0 ..= frames.len() {
+                let (mut a, b) = duplex(*chunk);
+                let mut builder = Builder::new(b, Mode::Server);
+                builder.set_buffer(BytesMut::from(&frames[.. split]));
+                let (sender, mut receiver) = builder.finish();
+                let pongs = block_on(async {
+                    a.write_all(&frames[split ..]).await.unwrap();
+                    assert_eq!(Message::Text("hello".into()), receiver.receive().await.unwrap());
+                    assert_eq!(Message::Binary(binary[..].into()), receiver.receive().await.unwrap());
+                    assert_eq!(Message::Text("end".into()), receiver.receive().await.unwrap());
+                    drop((sender, receiver));
+                    let mut pongs = Vec::new();
+                    a.read_to_end(&mut pongs).await.unwrap();
+                    pongs
+                });
+                let expected = [&[0x8A, 2][..], b"p1", &[0x8A, 2], b"p2", &[0x8A, 2], b"p3"].concat();
+                assert_eq!(expected, pongs, "chunk = {}, split = {}", chunk, split)
+            }
+        }
+    }
+
+    #[test]
+    fn fragments_appended_to_existing_data() {
+        let frames = [
+            raw_frame(0x01, b"fra"),
+            raw_frame(0x89, b"ping"),
+            raw_frame(0x00, b"gmen"),
+            raw_frame(0x80, b"ted")
+        ].concat();
+        let (mut a, b) = duplex(1);
+        let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            a.write_all(&frames).await.unwrap();
+            a.write_all(&raw_frame(0x81, b"!")).await.unwrap();
+            let mut message = b"already there: ".to_vec();
+            assert!(receiver.receive_data(&mut message).await.unwrap().is_text());
+            assert_eq!(&b"already there: fragmented"[..], &message[..]);
+            assert_eq!(Data::Text(1), receiver.receive_data(&mut message).await.unwrap());
+            assert_eq!(&b"already there: fragmented!"[..], &message[..])
+        })
+    }
+
+    #[test]
+    fn close_on_error() {
+        // Invalid UTF-8 => 1007
+        let (result, answer) = receive_raw(&[raw_frame(0x81, &[b'a', 0xFF])]);
+        assert!(matches!(result, Err(Error::Utf8(_))));
+        assert_eq!(&[0x88, 0x02, 0x03, 0xEF][..], &answer[..]);
+
+        // Invalid UTF-8 across fragments => 1007
+        let (result, answer) = receive_raw(&[raw_frame(0x01, &[0xCE]), raw_frame(0x80, &[0xBA, 0xFF])]);
+        assert!(matches!(result, Err(Error::Utf8(_))));
+        assert_eq!(&[0x88, 0x02, 0x03, 0xEF][..], &answer[..]);
+
+        // A code point split across fragments is fine.
+        let (result, _) = receive_raw(&[raw_frame(0x01, &[0xCE]), raw_frame(0x80, &[0xBA])]);
+        assert_eq!(Message::Text("κ".into()), result.unwrap());
+
+        // Message too large => 1009
+        let (mut a, b) = duplex(64);
+        let mut builder = Builder::new(b, Mode::Server);
+        builder.set_max_message_size(4);
+        let (_sender, mut receiver) = builder.finish();
+        let answer = block_on(async {
+            a.write_all(&raw_frame(0x82, b"too large")).await.unwrap();
+            assert!(matches!(receiver.receive().await, Err(Error::MessageTooLarge { .. })));
+            assert!(matches!(receiver.receive().await, Err(Error::Closed)));
+            let mut answer = Vec::new();
+            a.read_to_end(&mut answer).await.unwrap();
+            answer
+        });
+        assert_eq!(&[0x88, 0x02, 0x03, 0xF1][..], &answer[..]);
+
+        // Clients mask their close frame.
+        let (mut a, b) = duplex(64);
+        let mut builder = Builder::new(b, Mode::Client);
+        builder.set_mask_source(|| [1, 2, 3, 4]);
+        let (_sender, mut receiver) = builder.finish();
+        let answer = block_on(async {
+            a.write_all(&[0x80, 0x00]).await.unwrap(); // unexpected continuation frame
+            assert!(matches!(receiver.receive().await, Err(Error::UnexpectedOpCode(OpCode::Continue))));
+            let mut answer = Vec::new();
+            a.read_to_end(&mut answer).await.unwrap();
+            answer
+        });
+        assert_eq!(&[0x88, 0x82, 1, 2, 3, 4, 0x03 ^ 1, 0xEA ^ 2][..], &answer[..]);
+
+        // If disabled, nothing is sent.
+        let (mut a, b) = duplex(64);
+        let mut builder = Builder::new(b, Mode::Server);
+        builder.set_close_on_error(false);
+        let (sender, mut receiver) = builder.finish();
+        let answer = block_on(async {
+            a.write_all(&raw_frame(0x81, &[0xFF])).await.unwrap();
+            assert!(matches!(receiver.receive().await, Err(Error::Utf8(_))));
+            drop((sender, receiver));
+            let mut answer = Vec::new();
+            a.read_to_end(&mut answer).await.unwrap();
+            answer
+        });
+        assert!(answer.is_empty())
+    }
+
+    #[test]
+    fn close_state_transitions() {
+        // Open: sending and receiving work.
+        let (mut a, b) = duplex(64);
+        let (mut sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            a.write_all(&raw_frame(0x81, b"hi")).await.unwrap();
+            assert_eq!(Message::Text("hi".into()), receiver.receive().await.unwrap());
+            sender.send_text("hi").await.unwrap();
+            sender.flush().await.unwrap();
+            let mut answer = [0; 4];
+            a.read_exact(&mut answer).await.unwrap();
+            assert_eq!(&[0x81, 0x02, b'h', b'i'], &answer)
+        });
+
+        // Open -> CloseSent: only our CLOSE is written, received data and
+        // control frames are discarded until the remote answers our CLOSE.
+        let (mut a, b) = duplex(64);
+        let (mut sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        let answer = block_on(async {
+            sender.close().await.unwrap();
+            assert!(matches!(sender.send_text("x").await, Err(Error::Closed)));
+            assert!(matches!(sender.send_ping(ByteSlice125::try_from(&b""[..]).unwrap()).await, Err(Error::Closed)));
+            assert!(matches!(sender.close().await, Err(Error::Closed)));
+            a.write_all(&raw_frame(0x81, b"late")).await.unwrap();
+            a.write_all(&raw_frame(0x89, b"ping")).await.unwrap();
+            a.write_all(&raw_frame(0x88, &[0x03, 0xE8])).await.unwrap();
+            // CloseSent -> Closed
+            match receiver.receive().await {
+                Ok(Message::Closed(reason)) => assert_eq!(Some(1000), reason.code()),
+                other => panic!("unexpected: {:?}", other)
+            }
+            assert!(matches!(receiver.receive().await, Err(Error::Closed)));
+            assert!(matches!(sender.send_binary(b"x").await, Err(Error::Closed)));
+            drop((sender, receiver));
+            let mut answer = Vec::new();
+            a.read_to_end(&mut answer).await.unwrap();
+            answer
+        });
+        assert_eq!(&[0x88, 0x02, 0x03, 0xE8][..], &answer[..]);
+
+        // Open -> CloseReceived -> Closed: the remote CLOSE is answered and
+        // afterwards nothing can be sent or received.
+        let (mut a, b) = duplex(64);
+        let (mut sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        let answer = block_on(async {
+            a.write_all(&raw_frame(0x88, &[0x03, 0xE8])).await.unwrap();
+            a.write_all(&raw_frame(0x81, b"late")).await.unwrap();
+            assert!(receiver.receive().await.unwrap().is_closed());
+            assert!(matches!(receiver.receive().await, Err(Error::Closed)));
+            assert!(matches!(sender.send_text("x").await, Err(Error::Closed)));
+            assert!(matches!(sender.send_pong(ByteSlice125::try_from(&b""[..]).unwrap()).await, Err(Error::Closed)));
+            assert!(matches!(sender.close().await, Err(Error::Closed)));
+            drop((sender, receiver));
+            let mut answer = Vec::new();
+            a.read_to_end(&mut answer).await.unwrap();
+            answer
+        });
+        assert_eq!(&[0x88, 0x02, 0x03, 0xE8][..], &answer[..]);
+
+        // Open -> Closed on failure: afterwards nothing can be sent.
+        let (mut a, b) = duplex(64);
+        let (mut sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            a.write_all(&raw_frame(0x81, &[0xFF])).await.unwrap();
+            assert!(matches!(receiver.receive().await, Err(Error::Utf8(_))));
+            assert!(matches!(receiver.receive().await, Err(Error::Closed)));
+            assert!(matches!(sender.send_text("x").await, Err(Error::Closed)));
+            assert!(matches!(sender.close().await, Err(Error::Closed)))
+        })
+    }
+
+    #[test]
+    fn unexpected_eof() {
+        let frame = raw_frame(0x82, b"hello");
+        // Stream ends before any frame, mid-header, mid-payload and after a complete frame.
+        for end in &[0, 1, 3, 8, frame.len()] {
+            let (mut a, b) = duplex(64);
+            let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+            block_on(async {
+                a.write_all(&frame[.. *end]).await.unwrap();
+                drop(a);
+                let mut message = Vec::new();
+                if *end == frame.len() {
+                    receiver.receive_data(&mut message).await.unwrap();
+                }
+                let e = receiver.receive_data(&mut message).await.unwrap_err();
+                assert!(matches!(e, Error::UnexpectedEof), "end = {}: {:?}", end, e);
+                assert!(e.is_abnormal_closure())
+            })
+        }
+
+        // After a CLOSE frame the end of the stream is no error.
+        let (mut a, b) = duplex(64);
+        let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            a.write_all(&raw_frame(0x88, &[0x03, 0xE8])).await.unwrap();
+            drop(a);
+            assert!(receiver.receive().await.unwrap().is_closed());
+            let e = receiver.receive().await.unwrap_err();
+            assert!(matches!(e, Error::Closed));
+            assert!(!e.is_abnormal_closure())
+        })
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_events() {
+        use std::{collections::HashMap, fmt, sync::{Arc, Mutex}};
+        use tracing::{Event, Metadata, field::{Field, Visit}, span};
+
+        type Fields = HashMap<&'static str, String>;
+
+        /// A subscriber recording the fields of all events.
+        struct Collector(Arc<Mutex<Vec<Fields>>>);
+
+        struct Visitor<'a>(&'a mut Fields);
+
+        impl Visit for Visitor<'_> {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0.insert(field.name(), format!("{:?}", value));
+            }
+        }
+
+        impl tracing::Subscriber for Collector {
+            fn enabled(&self, _: &Metadata<'_>) -> bool { true }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id { span::Id::from_u64(1) }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields::new();
+                event.record(&mut Visitor(&mut fields));
+                self.0.lock().unwrap().push(fields)
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        tracing::subscriber::with_default(Collector(events.clone()), || {
+            let (a, b) = duplex(64);
+            let mut client = Builder::new(a, Mode::Client);
+            client.set_trace_id("client-1");
+            client.set_mask_source(|| [1, 2, 3, 4]);
+            let mut server = Builder::new(b, Mode::Server);
+            server.set_trace_id("server-1");
+            let (mut sender, _) = client.finish();
+            let (_, mut receiver) = server.finish();
+            block_on(async {
+                sender.send_text("hi").await.unwrap();
+                sender.flush().await.unwrap();
+                assert_eq!(Message::Text("hi".into()), receiver.receive().await.unwrap())
+            })
+        });
+
+        let expected = |id: &str, direction: &str| -> Fields {
+            vec![
+                ("message", direction.to_string()),
+                ("id", id.to_string()),
+                ("opcode", "Text".to_string()),
+                ("payload_len", "2".to_string()),
+                ("fin", "true".to_string()),
+                ("masked", "true".to_string()),
+                ("mask", "16909060".to_string())
+            ].into_iter().collect()
+        };
+        let events = events.lock().unwrap();
+        assert!(events.contains(&expected("client-1", "send")), "{:?}", events);
+        assert!(events.contains(&expected("server-1", "recv")), "{:?}", events)
+    }
+
+    type TcpStream = tokio_util::compat::Compat<tokio::net::TcpStream>;
+
+    static_assertions::assert_impl_all!(Builder<TcpStream>: Send, Sync);
+    static_assertions::assert_impl_all!(Sender<TcpStream>: Send, Sync);
+    static_assertions::assert_impl_all!(Receiver<TcpStream>: Send, Sync);
+    static_assertions::assert_impl_all!(SharedSender<TcpStream>: Send, Sync, Clone);
+    static_assertions::assert_impl_all!(SenderSink<TcpStream>: Send);
+    static_assertions::assert_impl_all!(Error: Send, Sync);
+
+    /// A socket type which does not implement `Debug`.
+    struct Opaque;
+
+    static_assertions::assert_impl_all!(Builder<Opaque>: std::fmt::Debug);
+    static_assertions::assert_impl_all!(Sender<Opaque>: std::fmt::Debug);
+    static_assertions::assert_impl_all!(Receiver<Opaque>: std::fmt::Debug);
+    static_assertions::assert_impl_all!(SharedSender<Opaque>: std::fmt::Debug);
+    static_assertions::assert_impl_all!(SenderSink<Opaque>: std::fmt::Debug);
+    static_assertions::assert_impl_all!(ReuniteError<Opaque>: std::fmt::Debug);
+
+    #[test]
+    fn debug_output() {
+        let (_, b) = duplex(64);
+        let mut builder = Builder::new(b, Mode::Server);
+        builder.set_trace_id("conn-1");
+        builder.add_extensions(vec![enabled(MockExtension::new("x-mock"))]);
+        let debug = format!("{:?}", builder);
+        assert!(debug.starts_with("Builder { id: conn-1, mode: Server, extensions: [\"x-mock\"]"), "{}", debug);
+        let (sender, receiver) = builder.finish();
+        assert!(format!("{:?}", sender).starts_with("Sender { id: conn-1"));
+        assert!(format!("{:?}", receiver).starts_with("Receiver { id: conn-1"))
+    }
+
+    #[test]
+    fn futures_are_send() {
+        fn is_send<T: Send>(_: T) {}
+        let (a, _b) = duplex(64);
+        let (mut sender, mut receiver) = Builder::new(a, Mode::Client).finish();
+        is_send(sender.send_text("hello"));
+        is_send(sender.send_binary(b"hello"));
+        is_send(sender.flush());
+        is_send(sender.close());
+        is_send(receiver.receive());
+        is_send(receiver.receive_data(&mut Vec::new()));
+        is_send(receiver.into_stream());
+        is_send(sender.into_shared().send_text("hello"))
+    }
+
+    #[test]
+    fn message_meta() {
+        let (mut a, b) = duplex(64);
+        let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            assert_eq!(0, receiver.message_meta().fragments);
+
+            a.write_all(&raw_frame(0x81, b"hello")).await.unwrap();
+            receiver.receive().await.unwrap();
+            let meta = receiver.message_meta();
+            assert_eq!((1, 5), (meta.fragments, meta.total_frames_len));
+            assert!(!meta.rsv1 && !meta.rsv2 && !meta.rsv3);
+
+            a.write_all(&raw_frame(0x02, b"ab")).await.unwrap();
+            a.write_all(&raw_frame(0x89, b"ping")).await.unwrap();
+            a.write_all(&raw_frame(0x00, b"c")).await.unwrap();
+            a.write_all(&raw_frame(0x80, b"def")).await.unwrap();
+            receiver.receive().await.unwrap();
+            let meta = receiver.message_meta();
+            assert_eq!((3, 6), (meta.fragments, meta.total_frames_len))
+        })
+    }
+
+    #[test]
+    fn pong_between_fragments() {
+        let (mut a, b) = duplex(64);
+        let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            a.write_all(&raw_frame(0x01, b"hel")).await.unwrap();
+            a.write_all(&raw_frame(0x8A, b"pong")).await.unwrap();
+            a.write_all(&raw_frame(0x80, b"lo")).await.unwrap();
+            assert_eq!(Message::Pong(b"pong".to_vec()), receiver.receive().await.unwrap());
+            assert_eq!(Message::Text("hello".into()), receiver.receive().await.unwrap());
+            assert_eq!((2, 5), (receiver.message_meta().fragments, receiver.message_meta().total_frames_len))
+        })
+    }
+
+    #[test]
+    fn pongs_between_fragments_keep_message_data() {
+        let (mut a, b) = duplex(64);
+        let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            a.write_all(&raw_frame(0x02, b"ab")).await.unwrap();
+            a.write_all(&raw_frame(0x8A, b"1")).await.unwrap();
+            a.write_all(&raw_frame(0x00, b"cd")).await.unwrap();
+            a.write_all(&raw_frame(0x8A, b"2")).await.unwrap();
+            a.write_all(&raw_frame(0x80, b"ef")).await.unwrap();
+            a.write_all(&raw_frame(0x82, b"next")).await.unwrap();
+            assert_eq!(Message::Pong(b"1".to_vec()), receiver.receive().await.unwrap());
+            assert_eq!(Message::Pong(b"2".to_vec()), receiver.receive().await.unwrap());
+            assert_eq!(Message::Binary("abcdef".into()), receiver.receive().await.unwrap());
+            assert_eq!(Message::Binary("next".into()), receiver.receive().await.unwrap());
+            assert_eq!((1, 4), (receiver.message_meta().fragments, receiver.message_meta().total_frames_len))
+        })
+    }
+
+    #[test]
+    fn too_many_control_frames() {
+        let pongs = |n| {
+            let mut bytes = raw_frame(0x01, b"hel");
+            for _ in 0 .. n {
+                bytes.extend_from_slice(&raw_frame(0x8A, b""))
+            }
+            bytes.extend_from_slice(&raw_frame(0x80, b"lo"));
+            bytes
+        };
+        let mut input = pongs(1000);
+        input.extend_from_slice(&pongs(1001));
+
+        let (mut a, b) = duplex(64);
+        let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            a.write_all(&input).await.unwrap();
+            let mut message = Vec::new();
+            assert!(receiver.receive_data(&mut message).await.unwrap().is_text());
+            assert_eq!(b"hello", &message[..]);
+            let result = receiver.receive_data(&mut message).await;
+            assert!(matches!(result, Err(Error::TooManyControlFrames { maximum: 1000 })));
+            let mut answer = [0; 4];
+            a.read_exact(&mut answer).await.unwrap();
+            assert_eq!([0x88, 0x02, 0x03, 0xF0], answer) // 1008
+        });
+
+        let mut state = ConnectionState::new(Mode::Server);
+        let events = state.handle_input(&input);
+        assert_eq!(2002, events.len());
+        assert!(matches!(events[1000], Event::Message(Message::Text(_))));
+        assert!(matches!(events[2001], Event::Error(Error::TooManyControlFrames { maximum: 1000 })));
+
+        // Without closing on errors, PINGs between messages are answered afterwards.
+        let mut input = raw_frame(0x01, b"hel");
+        for _ in 0 .. 3 {
+            input.extend_from_slice(&raw_frame(0x8A, b""))
+        }
+        input.extend_from_slice(&raw_frame(0x89, b"k"));
+        input.extend_from_slice(&raw_frame(0x81, b"x"));
+
+        let (mut a, b) = duplex(64);
+        let mut builder = Builder::new(b, Mode::Server);
+        builder.set_max_control_frames_per_message(2);
+        builder.set_close_on_error(false);
+        let (_sender, mut receiver) = builder.finish();
+        block_on(async {
+            a.write_all(&input).await.unwrap();
+            let mut message = Vec::new();
+            let result = receiver.receive_data(&mut message).await;
+            assert!(matches!(result, Err(Error::TooManyControlFrames { maximum: 2 })));
+            assert!(message.is_empty());
+            assert_eq!(Message::Text("x".into()), receiver.receive().await.unwrap());
+            let mut answer = [0; 3];
+            a.read_exact(&mut answer).await.unwrap();
+            assert_eq!([0x8A, 0x01, b'k'], answer)
+        })
+    }
+
+    #[test]
+    fn idle_keepalive_pings_are_not_limited() {
+        let mut input = Vec::new();
+        for _ in 0 .. 2000 {
+            input.extend_from_slice(&raw_frame(0x89, b""))
+        }
+        input.extend_from_slice(&raw_frame(0x81, b"hello"));
+
+        let (mut a, b) = duplex(64);
+        let mut builder = Builder::new(b, Mode::Server);
+        builder.set_max_control_frames_per_message(10);
+        let (_sender, mut receiver) = builder.finish();
+        block_on(future::join(
+            async {
+                assert_eq!(Message::Text("hello".into()), receiver.receive().await.unwrap())
+            },
+            async {
+                a.write_all(&input).await.unwrap();
+                let mut pongs = vec![0; 2 * 2000];
+                a.read_exact(&mut pongs).await.unwrap();
+                assert!(pongs.chunks(2).all(|p| p == [0x8A, 0x00]))
+            }
+        ));
+
+        let mut state = ConnectionState::new(Mode::Server);
+        state.set_max_control_frames_per_message(10);
+        let events = state.handle_input(&input);
+        assert_eq!(2001, events.len());
+        assert!(matches!(events[2000], Event::Message(Message::Text(_))))
+    }
+
+    #[test]
+    fn receive_methods_continue_interrupted_messages() {
+        let frames = [
+            raw_frame(0x01, b"hel"),
+            raw_frame(0x8A, b"p"),
+            raw_frame(0x80, b"lo"),
+            raw_frame(0x82, b"next"),
+            raw_frame(0x01, b"ab"),
+            raw_frame(0x8A, b"q"),
+            raw_frame(0x80, b"cd"),
+            raw_frame(0x02, b"x"),
+            raw_frame(0x8A, b"r"),
+            raw_frame(0x80, b"yz")
+        ];
+        let (mut a, b) = duplex(1024);
+        let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            a.write_all(&frames.concat()).await.unwrap();
+
+            assert_eq!(Message::Pong(b"p".to_vec()), receiver.receive().await.unwrap());
+            let mut buffer = b">".to_vec();
+            assert_eq!(Data::Text(5), receiver.receive_data(&mut buffer).await.unwrap());
+            assert_eq!(b">hello", &buffer[..]);
+            assert_eq!(Message::Binary("next".into()), receiver.receive().await.unwrap());
+
+            assert_eq!(Message::Pong(b"q".to_vec()), receiver.receive().await.unwrap());
+            let mut bytes = BytesMut::new();
+            assert_eq!(Data::Text(4), receiver.receive_into(&mut bytes).await.unwrap());
+            assert_eq!(b"abcd", &bytes[..]);
+
+            assert_eq!(Message::Pong(b"r".to_vec()), receiver.receive().await.unwrap());
+            assert_eq!(Message::Binary("xyz".into()), receiver.receive().await.unwrap());
+            assert_eq!((2, 3), (receiver.message_meta().fragments, receiver.message_meta().total_frames_len))
+        })
+    }
+
+    #[test]
+    fn message_timeout() {
+        use futures::channel::oneshot;
+        use std::sync::{Arc, Mutex};
+
+        let timers = Arc::new(Mutex::new(Vec::new()));
+        let (mut a, b) = duplex(64);
+        let mut builder = Builder::new(b, Mode::Server);
+        let t = timers.clone();
+        builder.set_message_timeout(move || {
+            let (tx, rx) = oneshot::channel::<()>();
+            t.lock().unwrap().push(tx);
+            rx.map(|_| ())
+        });
+        let (_sender, mut receiver) = builder.finish();
+        block_on(async {
+            // The timer of a complete message is dropped.
+            a.write_all(&raw_frame(0x81, b"hello")).await.unwrap();
+            assert_eq!(Message::Text("hello".into()), receiver.receive().await.unwrap());
+            assert!(timers.lock().unwrap()[0].is_canceled());
+
+            // A message which is never completed fails the connection with 1008.
+            a.write_all(&raw_frame(0x01, b"hel")).await.unwrap();
+            a.write_all(&raw_frame(0x89, b"ping")).await.unwrap();
+            let fire = async {
+                timers.lock().unwrap().remove(1).send(()).unwrap()
+            };
+            let (result, ()) = future::join(receiver.receive(), fire).await;
+            assert!(matches!(result, Err(Error::MessageTimeout)));
+            let mut answer = [0; 10];
+            a.read_exact(&mut answer).await.unwrap();
+            assert_eq!([0x8A, 0x04], answer[.. 2]); // PONG
+            assert_eq!([0x88, 0x02, 0x03, 0xF0], answer[6 ..]); // 1008
+            assert!(matches!(receiver.receive().await, Err(Error::Closed)))
+        })
+    }
+
+    #[test]
+    fn abort() {
+        let (mut a, b) = duplex(64);
+        let (mut sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            a.write_all(&raw_frame(0x81, b"hello")).await.unwrap();
+            sender.abort().await;
+            assert!(matches!(sender.send_text("hello").await, Err(Error::Closed)));
+            assert!(matches!(sender.close().await, Err(Error::Closed)));
+            assert!(matches!(receiver.receive().await, Err(Error::Closed)));
+
+            // Nothing has been written to the socket.
+            drop(sender.reunite(receiver).unwrap().into_inner());
+            let mut buf = Vec::new();
+            a.read_to_end(&mut buf).await.unwrap();
+            assert!(buf.is_empty())
+        })
+    }
+
+    /// A socket which records the address of every written buffer.
+    struct Recorder {
+        writes: std::sync::Arc<std::sync::Mutex<Vec<(usize, usize)>>>
+    }
+
+    impl AsyncRead for Recorder {
+        fn poll_read(self: Pin<&mut Self>, _: &mut Context, _: &mut [u8]) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for Recorder {
+        fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            self.writes.lock().unwrap().push((buf.as_ptr() as usize, buf.len()));
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn shared_sends_do_not_copy_in_server_mode() {
+        let payload = bytes::Bytes::from(vec![7; 1000]);
+        let text: std::sync::Arc<str> = "broadcast".into();
+        let addr = payload.as_ptr() as usize;
+        let text_addr = text.as_ptr() as usize;
+
+        let writes = |mode, extension: bool| {
+            let writes = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let mut builder = Builder::new(Recorder { writes: writes.clone() }, mode);
+            if extension {
+                builder.add_extensions(vec![enabled(tag("x", false, &CallLog::default()))])
+            }
+            let (mut sender, _receiver) = builder.finish();
+            block_on(async {
+                for _ in 0 .. 3 {
+                    sender.send_binary_shared(payload.clone()).await.unwrap();
+                    sender.send_text_shared(text.clone()).await.unwrap()
+                }
+            });
+            let w = writes.lock().unwrap().clone();
+            w
+        };
+
+        // Servers write the shared buffers themselves.
+        let w = writes(Mode::Server, false);
+        assert_eq!(3, w.iter().filter(|&&(a, n)| a == addr && n == 1000).count());
+        assert_eq!(3, w.iter().filter(|&&(a, n)| a == text_addr && n == 9).count());
+
+        // Clients mask a copy and extensions modify a copy.
+        for &(mode, extension) in &[(Mode::Client, false), (Mode::Server, true)] {
+            let w = writes(mode, extension);
+            assert!(w.iter().all(|&(a, _)| a != addr && a != text_addr))
+        }
+
+        // The shared buffers are unchanged and no references are retained.
+        assert!(payload.iter().all(|&b| b == 7));
+        assert_eq!(1, std::sync::Arc::strong_count(&text));
+        assert_eq!("broadcast", &*text)
+    }
+
+    #[test]
+    fn receive_data_appends_one_message() {
+        let (a, b) = duplex(7);
+        let mut client = Builder::new(a, Mode::Client);
+        client.set_max_frame_size(4);
+        let (mut sender, _) = client.finish();
+        let (_, mut receiver) = Builder::new(b, Mode::Server).finish();
+
+        block_on(async {
+            // Pipelined messages, fragmented and with a PONG in between.
+            sender.send_text("first message").await.unwrap();
+            sender.send_binary(&[1, 2]).await.unwrap();
+            sender.send_pong(ByteBuf125::new()).await.unwrap();
+            sender.send_text("third").await.unwrap();
+            sender.send_binary(&[]).await.unwrap();
+            sender.flush().await.unwrap();
+            drop(sender);
+
+            let mut buffer = b"left over".to_vec();
+            assert_eq!(Data::Text(13), receiver.receive_data(&mut buffer).await.unwrap());
+            assert_eq!(b"left overfirst message", &buffer[..]);
+
+            buffer.clear();
+            assert_eq!(Data::Binary(2), receiver.receive_data(&mut buffer).await.unwrap());
+            assert_eq!(&[1, 2], &buffer[..]);
+
+            let mut buffer = Vec::with_capacity(1);
+            buffer.push(0xFF);
+            assert_eq!(Data::Text(5), receiver.receive_data(&mut buffer).await.unwrap());
+            assert_eq!(b"\xFFthird", &buffer[..]);
+
+            assert_eq!(Data::Binary(0), receiver.receive_data(&mut buffer).await.unwrap());
+            assert_eq!(b"\xFFthird", &buffer[..]);
+
+            // Errors leave the buffer unchanged.
+            assert!(receiver.receive_data(&mut buffer).await.is_err());
+            assert_eq!(b"\xFFthird", &buffer[..])
+        })
+    }
+
+    #[test]
+    fn receive_into_custom_payload_buf() {
+        /// Storage which appends to a shared arena and records its allocations.
+        #[derive(Default)]
+        struct Arena {
+            data: Vec<u8>,
+            reserved: Vec<usize>
+        }
+
+        impl PayloadBuf for Arena {
+            fn len(&self) -> usize {
+                self.data.len()
+            }
+
+            fn reserve(&mut self, additional: usize) {
+                self.reserved.push(additional);
+                self.data.reserve(additional)
+            }
+
+            fn extend_from_slice(&mut self, data: &[u8]) {
+                self.data.extend_from_slice(data)
+            }
+
+            fn truncate(&mut self, len: usize) {
+                self.data.truncate(len)
+            }
+        }
+
+        let (a, b) = duplex(3);
+        let mut client = Builder::new(a, Mode::Client);
+        client.set_max_frame_size(4);
+        let (mut sender, _) = client.finish();
+        let (_, mut receiver) = Builder::new(b, Mode::Server).finish();
+
+        block_on(async {
+            sender.send_text("fragmented message").await.unwrap();
+            sender.send_pong(ByteBuf125::new()).await.unwrap();
+            sender.send_binary(&[1, 2, 3, 4, 5, 6]).await.unwrap();
+            sender.send_text("bytes").await.unwrap();
+            sender.flush().await.unwrap();
+            drop(sender);
+
+            let mut arena = Arena::default();
+            assert_eq!(Data::Text(18), receiver.receive_into(&mut arena).await.unwrap());
+            assert_eq!(5, receiver.message_meta().fragments);
+            assert_eq!(Data::Binary(6), receiver.receive_into(&mut arena).await.unwrap());
+            assert_eq!(b"fragmented message\x01\x02\x03\x04\x05\x06", &arena.data[..]);
+            assert_eq!(vec![4, 4, 4, 4, 2, 4, 2], arena.reserved);
+            assert!(receiver.partial.is_empty());
+            assert_eq!(0, receiver.partial.capacity());
+
+            let mut bytes = BytesMut::from(&b">"[..]);
+            assert_eq!(Data::Text(5), receiver.receive_into(&mut bytes).await.unwrap());
+            assert_eq!(b">bytes", &bytes[..]);
+
+            // Errors leave the storage unchanged.
+            assert!(receiver.receive_into(&mut arena).await.is_err());
+            assert_eq!(24, arena.len());
+            assert_eq!(7, arena.reserved.len())
+        })
+    }
+
+    #[test]
+    fn extensions_decode_only_the_received_message() {
+        let log = CallLog::default();
+        let (a, b) = duplex(64);
+        let mut client = Builder::new(a, Mode::Client);
+        client.add_extensions(vec![enabled(tag("x", true, &log))]);
+        let (mut sender, _) = client.finish();
+        let mut server = Builder::new(b, Mode::Server);
+        server.add_extensions(vec![enabled(tag("x", true, &log))]);
+        let (_, mut receiver) = server.finish();
+
+        block_on(async {
+            sender.send_text("message").await.unwrap();
+            sender.flush().await.unwrap();
+            let mut buffer = b"previous ".to_vec();
+            assert_eq!(Data::Text(7), receiver.receive_data(&mut buffer).await.unwrap());
+            assert_eq!(b"previous message", &buffer[..]);
+
+            sender.send_text("whole").await.unwrap();
+            sender.flush().await.unwrap();
+            let mut bytes = BytesMut::new();
+            assert_eq!(Data::Text(5), receiver.receive_into(&mut bytes).await.unwrap());
+            assert_eq!(b"whole", &bytes[..]);
+            assert_eq!(0, receiver.partial.capacity())
+        });
+
+        let decoded = log.calls().into_iter().filter_map(|c| match c {
+            Call::Decode { data, .. } => Some(data),
+            _ => None
+        });
+        assert_eq!(vec![b"messagex".to_vec(), b"wholex".to_vec()], decoded.collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn configured_builders() {
+        use crate::handshake;
+
+        // A configured builder from a handshake and a standalone one.
+        let configure = |builder: &mut Builder<_>| {
+            builder.set_trace_id("configured");
+            builder.set_max_message_size(4);
+            builder.set_close_on_error(false);
+            builder.set_max_control_frames_per_message(1)
+        };
+        let (a, b) = duplex(64);
+        let (c, d) = duplex(64);
+        let mut from_handshake = handshake::Server::new(a).into_builder();
+        configure(&mut from_handshake);
+        let mut standalone = Builder::new(c, Mode::Server);
+        configure(&mut standalone);
+
+        for (builder, mut remote) in [(from_handshake, b), (standalone, d)] {
+            assert!(format!("{:?}", builder).contains("configured"));
+            let (mut local, mut receiver) = builder.finish();
+            let frames = [
+                raw_frame(0x02, b"a"),
+                raw_frame(0x89, b""),
+                raw_frame(0x89, b""),
+                raw_frame(0x82, b"too large")
+            ];
+            block_on(async {
+                remote.write_all(&frames.concat()).await.unwrap();
+                assert!(matches!(receiver.receive().await, Err(Error::TooManyControlFrames { maximum: 1 })));
+                assert!(matches!(receiver.receive().await, Err(Error::MessageTooLarge { maximum: 4, .. })));
+                // The connection has not been closed.
+                local.send_text("still open").await.unwrap()
+            })
+        }
+    }
+
+    #[test]
+    fn large_payloads_are_not_over_allocated() {
+        const LEN: usize = 64 * 1024 * 1024;
+        const SLACK: usize = 8 * 1024;
+
+        let (a, b) = duplex(SLACK);
+        let (mut sender, _) = Builder::new(a, Mode::Server).finish();
+        let (_, mut receiver) = Builder::new(b, Mode::Client).finish();
+        let data = vec![0xAB; LEN];
+
+        block_on(async {
+            // Some payload data is buffered together with the header.
+            sender.send_binary(&data).await.unwrap();
+            sender.send_ping(ByteSlice125::try_from(&[1; 125][..]).unwrap()).await.unwrap();
+            sender.send_binary(&data[.. 100]).await.unwrap();
+            sender.flush().await.unwrap();
+
+            let mut message = Vec::new();
+            assert_eq!(Data::Binary(LEN), receiver.receive_data(&mut message).await.unwrap());
+            assert_eq!(LEN, message.len());
+            assert!(message.capacity() <= LEN + SLACK, "capacity = {}", message.capacity());
+            drop(message);
+
+            let mut message = Vec::new();
+            assert_eq!(Data::Binary(100), receiver.receive_data(&mut message).await.unwrap());
+            assert!(receiver.buffer.capacity() <= 2 * SLACK, "capacity = {}", receiver.buffer.capacity());
+            assert!(receiver.ctrl_buffer.capacity() <= 2 * SLACK, "capacity = {}", receiver.ctrl_buffer.capacity())
+        })
+    }
+
+    #[test]
+    fn large_sends_are_fragmented_and_yield() {
+        use futures::{executor::LocalPool, task::LocalSpawnExt};
+        use std::{cell::Cell, rc::Rc};
+
+        let (a, b) = duplex(1024);
+        let mut builder = Builder::new(a, Mode::Client);
+        builder.set_max_frame_size(1024);
+        let (mut sender, _receiver) = builder.finish();
+        let (_, mut receiver) = Builder::new(b, Mode::Server).finish();
+        let (c, d) = duplex(1024);
+        let (mut other_sender, _other_receiver) = Builder::new(c, Mode::Client).finish();
+        let (_, mut other_receiver) = Builder::new(d, Mode::Server).finish();
+
+        let data: Vec<u8> = (0 .. 64 * 1024).map(|i| i as u8).collect();
+        let polls = Rc::new(Cell::new(0));
+        let large_done = Rc::new(Cell::new(false));
+        let small_done_first = Rc::new(Cell::new(false));
+
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+        {
+            let (polls, large_done, data) = (polls.clone(), large_done.clone(), data.clone());
+            let mut send = Box::pin(async move {
+                sender.send_binary(&data).await.unwrap();
+                sender.flush().await.unwrap()
+            });
+            spawner.spawn_local(future::poll_fn(move |cx| {
+                polls.set(polls.get() + 1);
+                let p = send.as_mut().poll(cx);
+                if p.is_ready() {
+                    large_done.set(true)
+                }
+                p
+            })).unwrap();
+        }
+        {
+            let (large_done, small_done_first) = (large_done.clone(), small_done_first.clone());
+            spawner.spawn_local(async move {
+                other_sender.send_text("small").await.unwrap();
+                other_sender.flush().await.unwrap();
+                small_done_first.set(!large_done.get())
+            }).unwrap();
+        }
+        pool.run();
+
+        assert!(large_done.get());
+        assert!(polls.get() >= 64, "{} polls", polls.get());
+        assert!(small_done_first.get());
+
+        block_on(async {
+            assert_eq!(Message::Binary(data[..].into()), receiver.receive().await.unwrap());
+            assert_eq!(64, receiver.message_meta().fragments);
+            assert_eq!(Message::Text("small".into()), other_receiver.receive().await.unwrap())
+        })
+    }
+
+    #[test]
+    fn pings_are_answered_between_fragments() {
+        use futures::{executor::LocalPool, task::LocalSpawnExt};
+
+        let (mut a, b) = duplex(64 * 1024);
+        let mut builder = Builder::new(b, Mode::Server);
+        builder.set_max_frame_size(1024);
+        let (mut sender, mut receiver) = builder.finish();
+        let data = vec![1; 8 * 1024];
+        block_on(a.write_all(&raw_frame(0x89, b"ping"))).unwrap();
+
+        let mut pool = LocalPool::new();
+        let spawner = pool.spawner();
+        let large = data.clone();
+        spawner.spawn_local(async move {
+            sender.send_binary(&large).await.unwrap();
+            sender.flush().await.unwrap()
+        }).unwrap();
+        spawner.spawn_local(async move {
+            let _ = receiver.receive().await;
+        }).unwrap();
+        pool.run_until_stalled();
+
+        let mut remote = ConnectionState::new(Mode::Client);
+        let mut events = Vec::new();
+        let mut buffer = vec![0; 4096];
+        while events.len() < 2 {
+            let n = block_on(a.read(&mut buffer)).unwrap();
+            events.extend(remote.handle_input(&buffer[.. n]))
+        }
+        assert!(matches!(&events[0], Event::Message(Message::Pong(p)) if p == b"ping"));
+        assert!(matches!(&events[1], Event::Message(Message::Binary(b)) if b[..] == data[..]));
+        assert_eq!(8, remote.message_meta().fragments)
+    }
+
+    #[test]
+    fn ping_round_trip() {
+        let (a, b) = duplex(64);
+        let mut client = Builder::new(a, Mode::Client);
+        client.set_clock(std::time::Instant::now);
+        let (mut sender, mut receiver) = client.finish();
+        let (mut server_sender, mut server_receiver) = Builder::new(b, Mode::Server).finish();
+
+        block_on(future::join(
+            async {
+                // The server answers PINGs while receiving.
+                assert!(matches!(server_receiver.receive().await, Ok(Message::Closed(_))));
+            },
+            async {
+                server_sender.send_pong(ByteSlice125::try_from(&b"unsolicited"[..]).unwrap()).await.unwrap();
+                server_sender.flush().await.unwrap();
+                let token = sender.ping_with_token().await.unwrap();
+                assert_eq!(0, token.id());
+                sender.send_ping(ByteSlice125::try_from(&b"other"[..]).unwrap()).await.unwrap();
+                sender.flush().await.unwrap();
+
+                assert_eq!(Message::Pong(b"unsolicited".to_vec()), receiver.receive().await.unwrap());
+                assert_eq!(None, receiver.last_rtt());
+                assert!(matches!(receiver.receive().await.unwrap(), Message::Pong(_)));
+                let rtt = token.receipt().await.unwrap();
+                assert!(rtt.is_some());
+                assert_eq!(rtt, receiver.last_rtt());
+                assert_eq!(Message::Pong(b"other".to_vec()), receiver.receive().await.unwrap());
+                assert_eq!(rtt, receiver.last_rtt());
+
+                // Receipts of unanswered PINGs fail when the connection is closed.
+                let token = sender.ping_with_token().await.unwrap();
+                sender.close().await.unwrap();
+                assert!(matches!(receiver.receive().await, Ok(Message::Closed(_))));
+                assert!(matches!(token.receipt().await, Err(Error::Closed)))
+            }
+        ));
+    }
+
+    #[test]
+    fn reunite() {
+        let (mut a, b) = duplex(64);
+        let (c, d) = duplex(64);
+        let (sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        let (other_sender, other_receiver) = Builder::new(d, Mode::Server).finish();
+        block_on(async {
+            let mut frames = raw_frame(0x81, b"one");
+            frames.extend_from_slice(&raw_frame(0x81, b"two"));
+            a.write_all(&frames).await.unwrap();
+            assert_eq!(Message::Text("one".into()), receiver.receive().await.unwrap());
+
+            let ReuniteError(sender, other_receiver) = sender.reunite(other_receiver).unwrap_err();
+            let ReuniteError(other_sender, receiver) = other_sender.reunite(receiver).unwrap_err();
+            drop((other_sender, other_receiver, c));
+
+            // Data read before reuniting is still received afterwards.
+            let (mut sender, mut receiver) = sender.reunite(receiver).unwrap().finish();
+            assert_eq!(Message::Text("two".into()), receiver.receive().await.unwrap());
+            sender.send_text("three").await.unwrap();
+            sender.flush().await.unwrap();
+
+            let mut b = sender.reunite(receiver).unwrap().into_inner();
+            b.write_all(b"four").await.unwrap();
+            let mut buf = [0; 7];
+            a.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[2 ..], b"three");
+            a.read_exact(&mut buf[.. 4]).await.unwrap();
+            assert_eq!(&buf[.. 4], b"four")
+        })
+    }
+
+    #[test]
+    fn frame_observer() {
+        use std::sync::{Arc, Mutex};
+
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let (mut a, b) = duplex(64);
+        let mut builder = Builder::new(b, Mode::Server);
+        let observed = frames.clone();
+        builder.set_frame_observer(move |direction, header, data| {
+            observed.lock().unwrap().push((direction, header.opcode(), header.is_fin(), data.to_vec()))
+        });
+        let (mut sender, mut receiver) = builder.finish();
+        block_on(async {
+            a.write_all(&raw_frame(0x01, b"hel")).await.unwrap(); // first text fragment
+            a.write_all(&raw_frame(0x89, b"p")).await.unwrap();   // ping
+            a.write_all(&raw_frame(0x80, b"lo")).await.unwrap();  // last continuation
+            assert_eq!(Message::Text("hello".into()), receiver.receive().await.unwrap());
+            sender.send_text("ok").await.unwrap();
+            sender.close().await.unwrap()
+        });
+
+        let expected = vec![
+            (Direction::Incoming, OpCode::Text, false, b"hel".to_vec()),
+            (Direction::Incoming, OpCode::Ping, true, b"p".to_vec()),
+            (Direction::Outgoing, OpCode::Pong, true, b"p".to_vec()),
+            (Direction::Incoming, OpCode::Continue, true, b"lo".to_vec()),
+            (Direction::Outgoing, OpCode::Text, true, b"ok".to_vec()),
+            (Direction::Outgoing, OpCode::Close, true, vec![0x03, 0xE8])
+        ];
+        let frames = frames.lock().unwrap();
+        assert_eq!(expected, *frames)
+    }
+
+    #[test]
+    fn stream_ends_after_close() {
+        let (a, b) = duplex(64);
+        let (mut sender, _receiver) = Builder::new(a, Mode::Client).finish();
+        let (_, receiver) = Builder::new(b, Mode::Server).finish();
+        let mut stream = Box::pin(receiver.into_stream());
+
+        block_on(async {
+            sender.send_text("hello").await.unwrap();
+            sender.close().await.unwrap();
+            assert_eq!(Message::Text("hello".into()), stream.next().await.unwrap().unwrap());
+            assert!(stream.next().await.unwrap().unwrap().is_closed());
+            assert!(stream.next().await.is_none());
+            assert!(stream.is_terminated());
+            assert!(stream.next().await.is_none())
+        })
+    }
+
+    #[test]
+    fn sink_send_after_close() {
+        let (a, b) = duplex(64);
+        let (sender, _receiver) = Builder::new(a, Mode::Client).finish();
+        let (_sender, mut receiver) = Builder::new(b, Mode::Server).finish();
+        let mut sink = sender.into_sink();
+
+        block_on(future::join(
+            async {
+                sink.send(OutgoingMessage::Close(CloseReason::with_code(1001))).await.unwrap();
+                assert!(matches!(sink.send("x".into()).await, Err(Error::Closed)));
+                sink.close().await.unwrap()
+            },
+            async {
+                let expected = CloseReason::with_code(1001);
+                assert_eq!(Message::Closed(expected), receiver.receive().await.unwrap())
+            }
+        ));
+    }
+
+    #[test]
+    fn sink_contract_violation() {
+        let (a, _b) = duplex(64);
+        let (sender, _receiver) = Builder::new(a, Mode::Client).finish();
+        let mut sink = sender.into_sink();
+        let mut sink = Pin::new(&mut sink);
+        sink.as_mut().start_send("a".into()).unwrap();
+        // The previous message has not been sent yet.
+        assert!(matches!(sink.as_mut().start_send("b".into()), Err(Error::SinkNotReady)));
+        block_on(sink.as_mut().close()).unwrap();
+        assert!(matches!(sink.as_mut().start_send("c".into()), Err(Error::Closed)))
+    }
+
+    #[test]
+    fn sink_errors() {
+        let (a, _b) = duplex(64);
+        let mut failing = MockExtension::new("x-fail");
+        failing.set_enabled(true);
+        failing.on_encode(|_, _| Err("encoding failed".into()));
+        let mut builder = Builder::new(a, Mode::Client);
+        builder.add_extensions(vec![Box::new(failing) as Box<dyn Extension + Send + Sync>]);
+        let (sender, _receiver) = builder.finish();
+        let mut sink = sender.into_sink();
+
+        block_on(async {
+            assert!(matches!(sink.send(b"data".to_vec().into()).await, Err(Error::Extension(_))));
+            // The sink remains usable after an error.
+            sink.send(OutgoingMessage::Ping(ByteBuf125::new())).await.unwrap()
+        })
+    }
+
+    /// Send some frames as client with a fixed mask source and get back the bytes written.
+    fn send_with_mask_source() -> Vec<u8> {
+        let (a, mut b) = duplex(64);
+        let mut builder = Builder::new(a, Mode::Client);
+        let mut n = 0u8;
+        builder.set_mask_source(move || {
+            n = n.wrapping_add(1);
+            [n, 0x22, 0x33, 0x44]
+        });
+        let (mut sender, _receiver) = builder.finish();
+        block_on(async move {
+            sender.send_text("hello").await.unwrap();
+            sender.send_binary(&[1, 2, 3]).await.unwrap();
+            sender.close().await.unwrap();
+            let mut bytes = Vec::new();
+            b.read_to_end(&mut bytes).await.unwrap();
+            bytes
+        })
+    }
+
+    #[test]
+    fn deterministic_masks() {
+        let bytes = send_with_mask_source();
+        assert_eq!(bytes, send_with_mask_source());
+        assert_eq!(&bytes[.. 11], &[0x81, 0x85, 0x01, 0x22, 0x33, 0x44, b'h' ^ 1, b'e' ^ 0x22, b'l' ^ 0x33, b'l' ^ 0x44, b'o' ^ 1]);
+        assert_eq!(&bytes[11 .. 16], &[0x82, 0x83, 0x02, 0x22, 0x33]);
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn seeded_mask_rng() {
+        use rand::{SeedableRng, rngs::StdRng};
+
+        let send = |rng: Option<StdRng>| {
+            let (a, mut b) = duplex(64);
+            let mut builder = Builder::new(a, Mode::Client);
+            if let Some(rng) = rng {
+                builder.set_mask_rng(rng)
+            }
+            let (mut sender, _receiver) = builder.finish();
+            block_on(async move {
+                for _ in 0 .. 8 {
+                    sender.send_binary(&[0; 4]).await.unwrap()
+                }
+                sender.close().await.unwrap();
+                let mut bytes = Vec::new();
+                b.read_to_end(&mut bytes).await.unwrap();
+                // The masks of the binary frames.
+                bytes.chunks(10).take(8).map(|f| f[2 .. 6].to_vec()).collect::<Vec<_>>()
+            })
+        };
+
+        let masks = send(Some(StdRng::seed_from_u64(7)));
+        assert_eq!(masks, send(Some(StdRng::seed_from_u64(7))));
+        assert_ne!(masks, send(Some(StdRng::seed_from_u64(8))));
+
+        // The default generator produces different masks for every frame and connection.
+        let (x, y) = (send(None), send(None));
+        assert_ne!(x, y);
+        assert!(x.windows(2).all(|w| w[0] != w[1]))
+    }
+}
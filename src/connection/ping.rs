@@ -0,0 +1,185 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Correlation of PINGs and PONGs to measure round-trip times.
+//!
+//! PINGs sent with [`Sender::ping_with_token`](super::Sender::ping_with_token)
+//! carry a tag and a counter as payload data. A PONG with the same payload
+//! completes the PING and all older ones, because the remote may answer only
+//! the most recent PING. PONGs with any other payload are ignored.
+
+use crate::data::ByteBuf125;
+use futures::{channel::oneshot, prelude::*};
+use std::{collections::VecDeque, convert::TryFrom, fmt, pin::Pin, task::{Context, Poll}, time::{Duration, Instant}};
+use super::Error;
+
+/// Prefix of the payload data of PINGs sent with a token.
+const TAG: &[u8; 4] = b"skto";
+
+/// A clock measuring round-trip times, cf. [`Builder::set_clock`](super::Builder::set_clock).
+pub(super) struct Clock(pub(super) Box<dyn Fn() -> Instant + Send + Sync>);
+
+impl fmt::Debug for Clock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+/// A PING which has been sent with [`Sender::ping_with_token`](super::Sender::ping_with_token).
+///
+/// Use [`PingToken::receipt`] to await the matching PONG.
+#[derive(Debug)]
+pub struct PingToken {
+    id: u64,
+    receipt: oneshot::Receiver<Option<Duration>>
+}
+
+impl PingToken {
+    /// The counter value encoded in the PING's payload data.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Turn this token into a future which completes with the matching PONG.
+    pub fn receipt(self) -> PongReceipt {
+        PongReceipt { receipt: self.receipt }
+    }
+}
+
+/// A future which completes when the PONG answering a [`PingToken`] is received.
+///
+/// PONGs are only processed while the [`Receiver`](super::Receiver) of the
+/// connection receives. The output is the round-trip time if a clock has been
+/// set (cf. [`Builder::set_clock`](super::Builder::set_clock)) and `None`
+/// otherwise. If the connection is closed or fails before the PONG arrives,
+/// [`Error::Closed`] is returned.
+#[derive(Debug)]
+pub struct PongReceipt {
+    receipt: oneshot::Receiver<Option<Duration>>
+}
+
+impl Future for PongReceipt {
+    type Output = Result<Option<Duration>, Error>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Pin::new(&mut self.receipt).poll(cx).map_err(|_| Error::Closed)
+    }
+}
+
+/// A PING waiting for its PONG.
+#[derive(Debug)]
+struct Outstanding {
+    id: u64,
+    sent: Option<Instant>,
+    receipt: oneshot::Sender<Option<Duration>>
+}
+
+/// The PINGs waiting for their PONGs, ordered by ID.
+#[derive(Debug, Default)]
+pub(super) struct Pings {
+    next_id: u64,
+    outstanding: VecDeque<Outstanding>
+}
+
+impl Pings {
+    /// Register a new PING and create its payload data.
+    pub(super) fn start(&mut self, clock: Option<&Clock>) -> (ByteBuf125, PingToken) {
+        self.outstanding.retain(|o| !o.receipt.is_canceled());
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        let (tx, rx) = oneshot::channel();
+        let sent = clock.map(|c| (c.0)());
+        self.outstanding.push_back(Outstanding { id, sent, receipt: tx });
+        (payload(id), PingToken { id, receipt: rx })
+    }
+
+    /// Complete the PINGs answered by a PONG with the given payload data.
+    ///
+    /// Returns the round-trip time of the matching PING, if a clock is set.
+    pub(super) fn on_pong(&mut self, data: &[u8], clock: Option<&Clock>) -> Option<Duration> {
+        let id = parse(data)?;
+        let i = self.outstanding.iter().position(|o| o.id == id)?;
+        let now = clock.map(|c| (c.0)());
+        let mut rtt = None;
+        for o in self.outstanding.drain(..= i) {
+            let elapsed = now.and_then(|n| Some(n.saturating_duration_since(o.sent?)));
+            if o.id == id {
+                rtt = elapsed
+            }
+            let _ = o.receipt.send(elapsed);
+        }
+        rtt
+    }
+
+    /// Drop all outstanding PINGs, e.g. because the connection is closed.
+    pub(super) fn clear(&mut self) {
+        self.outstanding.clear()
+    }
+}
+
+/// The payload data of the PING with the given ID.
+fn payload(id: u64) -> ByteBuf125 {
+    let mut data = [0; 12];
+    data[.. 4].copy_from_slice(TAG);
+    data[4 ..].copy_from_slice(&id.to_be_bytes());
+    ByteBuf125::try_from(&data[..]).expect("12 bytes < 125 bytes")
+}
+
+/// Get the PING ID from PONG payload data.
+fn parse(data: &[u8]) -> Option<u64> {
+    if data.len() != 12 || &data[.. 4] != TAG {
+        return None
+    }
+    let mut id = [0; 8];
+    id.copy_from_slice(&data[4 ..]);
+    Some(u64::from_be_bytes(id))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use std::{sync::{Arc, Mutex}, time::{Duration, Instant}};
+    use super::{Clock, Pings};
+
+    #[test]
+    fn unsolicited_and_mismatched_pongs_are_ignored() {
+        let start = Instant::now();
+        let now = Arc::new(Mutex::new(start));
+        let clock = {
+            let now = now.clone();
+            Clock(Box::new(move || *now.lock().unwrap()))
+        };
+        let mut pings = Pings::default();
+        let (p0, t0) = pings.start(Some(&clock));
+        let (p1, t1) = pings.start(Some(&clock));
+        assert_eq!((0, 1), (t0.id(), t1.id()));
+        *now.lock().unwrap() = start + Duration::from_millis(5);
+
+        assert_eq!(None, pings.on_pong(b"pong", Some(&clock)));
+        assert_eq!(None, pings.on_pong(&p0[.. 11], Some(&clock)));
+        assert_eq!(None, pings.on_pong(&super::payload(2), Some(&clock)));
+        assert_eq!(2, pings.outstanding.len());
+
+        // A PONG answering the later PING completes both.
+        assert_eq!(Some(Duration::from_millis(5)), pings.on_pong(&p1, Some(&clock)));
+        assert!(pings.outstanding.is_empty());
+        assert_eq!(Some(Duration::from_millis(5)), block_on(t0.receipt()).unwrap());
+        assert_eq!(Some(Duration::from_millis(5)), block_on(t1.receipt()).unwrap());
+
+        // Repeated PONGs do not match anything.
+        assert_eq!(None, pings.on_pong(&p0, Some(&clock)));
+
+        // Dropped tokens are discarded, pending receipts fail when cleared.
+        let (_, t2) = pings.start(None);
+        drop(pings.start(None));
+        let (_, _t3) = pings.start(None);
+        assert_eq!(2, pings.outstanding.len());
+        pings.clear();
+        assert!(block_on(t2.receipt()).is_err())
+    }
+}
@@ -0,0 +1,1229 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! A websocket connection without I/O.
+//!
+//! [`ConnectionState`] decodes and encodes frames, assembles fragmented
+//! messages, answers PINGs and follows the closing handshake. It is the
+//! protocol core of the [`Sender`](super::Sender)/[`Receiver`](super::Receiver)
+//! pair, which only adds reading from and writing to an async socket, and
+//! can be used directly if reading and writing bytes is left to the user,
+//! e.g. for runtimes which do not provide `AsyncRead` and `AsyncWrite` sockets.
+
+use bytes::{Buf, BytesMut};
+use crate::{BoxedError, Parsing, Storage, base::{self, Header, MAX_HEADER_SIZE, OpCode}, extension::{self, Extension}};
+use crate::data::{ByteBuf125, CloseReason, Data, Message, MessageMeta, OutgoingMessage};
+use crate::observer::Observer;
+use std::{fmt, mem, ops::Range, str, sync::Arc, time::{Duration, Instant}};
+use super::{Builder, Direction, Error, FrameObserver, Id, MaskSource, MessageTimeout, Mode, State};
+use super::{MAX_CONTROL_FRAMES, MAX_FRAME_SIZE, MAX_MESSAGE_SIZE};
+use super::ping::{Clock, PingToken, Pings};
+
+/// What [`ConnectionState::handle_input`] has received.
+#[derive(Debug)]
+pub enum Event {
+    /// A text, binary or PONG message or the remote has closed the connection.
+    ///
+    /// The answer to a CLOSE frame has been queued for output.
+    Message(Message),
+    /// A PING with the given payload data.
+    ///
+    /// The PONG answer has been queued for output.
+    Ping(Vec<u8>),
+    /// Receiving failed and the connection is closed.
+    ///
+    /// If the remote has violated the protocol, a CLOSE frame with the
+    /// corresponding status code has been queued for output (cf.
+    /// [`ConnectionState::set_close_on_error`]).
+    Error(Error)
+}
+
+/// A control frame handled by [`ConnectionState::on_control`].
+#[derive(Debug)]
+pub(super) enum Control {
+    /// A PING, which has been answered if the connection is open.
+    Ping,
+    /// A PONG.
+    Pong,
+    /// The remote has closed the connection.
+    Closed(CloseReason)
+}
+
+/// A fragmented message which has not been received completely.
+#[derive(Debug)]
+struct Assembly {
+    opcode: OpCode,
+    rsv_bits: (bool, bool, bool),
+    fragments: usize,
+    length: usize,
+    /// Number of bytes appended to the message buffer so far.
+    num_bytes: usize,
+    /// Number of payload bytes decoded so far.
+    total: usize,
+    /// UTF-8 validation of text messages decoded fragment by fragment.
+    utf8: Utf8Check
+}
+
+/// Incremental UTF-8 validation of text split into arbitrary pieces.
+#[derive(Debug, Default, Clone, Copy)]
+struct Utf8Check {
+    /// Bytes of an incomplete code point at the end of the last piece.
+    tail: [u8; 3],
+    len: usize
+}
+
+impl Utf8Check {
+    /// Validate the next piece of text.
+    fn feed(&mut self, mut bytes: &[u8]) -> Result<(), str::Utf8Error> {
+        if self.len > 0 {
+            // Complete the code point of the previous piece first.
+            let n = std::cmp::min(3, bytes.len());
+            let mut buf = [0; 6];
+            buf[.. self.len].copy_from_slice(&self.tail[.. self.len]);
+            buf[self.len .. self.len + n].copy_from_slice(&bytes[.. n]);
+            let valid = Utf8Check::valid_up_to(&buf[.. self.len + n])?;
+            if valid < self.len {
+                self.tail[.. self.len + n - valid].copy_from_slice(&buf[valid .. self.len + n]);
+                self.len = self.len + n - valid;
+                return Ok(())
+            }
+            bytes = &bytes[valid - self.len ..]
+        }
+        let valid = Utf8Check::valid_up_to(bytes)?;
+        self.len = bytes.len() - valid;
+        self.tail[.. self.len].copy_from_slice(&bytes[valid ..]);
+        Ok(())
+    }
+
+    /// Check that the text does not end with an incomplete code point.
+    fn finish(&self) -> Result<(), str::Utf8Error> {
+        str::from_utf8(&self.tail[.. self.len]).map(|_| ())
+    }
+
+    /// The length of the valid UTF-8 prefix which may only be followed by
+    /// an incomplete code point.
+    fn valid_up_to(bytes: &[u8]) -> Result<usize, str::Utf8Error> {
+        match str::from_utf8(bytes) {
+            Ok(_) => Ok(bytes.len()),
+            Err(e) if e.error_len().is_none() => Ok(e.valid_up_to()),
+            Err(e) => Err(e)
+        }
+    }
+}
+
+/// The state of a websocket connection without I/O.
+///
+/// Bytes read from the socket are given to [`ConnectionState::handle_input`],
+/// messages to send to [`ConnectionState::queue_send`], and bytes to write
+/// to the socket are taken with [`ConnectionState::take_output`]. Answers to
+/// PING and CLOSE frames are queued automatically, so output should be taken
+/// after handling input as well.
+///
+/// **Note**: Use this type only after a successful [handshake][0].
+///
+/// [0]: https://tools.ietf.org/html/rfc6455#section-4
+#[derive(Debug)]
+pub struct ConnectionState {
+    id: Id,
+    mode: Mode,
+    codec: base::Codec,
+    extensions: Vec<Box<dyn Extension + Send + Sync>>,
+    streaming: bool,
+    state: State,
+    input: BytesMut,
+    output: BytesMut,
+    header: Option<Header>,
+    message: Vec<u8>,
+    chunk_buffer: Vec<u8>,
+    assembly: Option<Assembly>,
+    max_message_size: usize,
+    tolerate_continuation_rsv_bits: bool,
+    close_on_error: bool,
+    mask_source: Option<MaskSource>,
+    observer: Option<FrameObserver>,
+    metrics: Option<Arc<dyn Observer>>,
+    clock: Option<Clock>,
+    pings: Pings,
+    last_rtt: Option<Duration>,
+    meta: MessageMeta,
+    max_control_frames: usize,
+    control_frames: usize
+}
+
+impl ConnectionState {
+    /// Create a new connection state for the given mode.
+    pub fn new(mode: Mode) -> Self {
+        let mut codec = base::Codec::default();
+        codec.set_max_data_size(MAX_FRAME_SIZE);
+        ConnectionState {
+            id: super::new_id(),
+            mode,
+            codec,
+            extensions: Vec::new(),
+            streaming: true,
+            state: State::Open,
+            input: BytesMut::new(),
+            output: BytesMut::new(),
+            header: None,
+            message: Vec::new(),
+            chunk_buffer: Vec::new(),
+            assembly: None,
+            max_message_size: MAX_MESSAGE_SIZE,
+            tolerate_continuation_rsv_bits: false,
+            close_on_error: true,
+            mask_source: None,
+            observer: None,
+            metrics: None,
+            clock: None,
+            pings: Pings::default(),
+            last_rtt: None,
+            meta: MessageMeta::default(),
+            max_control_frames: MAX_CONTROL_FRAMES,
+            control_frames: 0
+        }
+    }
+
+    /// Create a connection state with the settings of the given builder.
+    ///
+    /// This allows using the handshakes of this crate, which return a
+    /// [`Builder`] with the negotiated extensions. The builder's socket is
+    /// returned as well. Data the builder has already read, e.g. frames
+    /// following the handshake response, is handled with the next input,
+    /// which may be empty.
+    ///
+    /// A message timeout (cf. [`Builder::set_message_timeout`]) requires
+    /// timers which a connection state does not have. It is ignored and a
+    /// warning is logged.
+    pub fn from_builder<T>(builder: Builder<T>) -> (T, Self) {
+        let (socket, buffer, timeout, mut state) = ConnectionState::split_builder(builder);
+        if timeout.is_some() {
+            log::warn!("{}: message timeout is not supported by ConnectionState and ignored", state.id)
+        }
+        state.input = buffer;
+        (socket, state)
+    }
+
+    /// Take socket, buffered data and message timeout out of the given
+    /// builder and create a connection state with its other settings.
+    pub(super) fn split_builder<T>(builder: Builder<T>) -> (T, BytesMut, Option<MessageTimeout>, Self) {
+        let mut state = ConnectionState::new(builder.mode);
+        state.id = builder.id;
+        state.codec = builder.codec;
+        state.max_message_size = builder.max_message_size;
+        state.tolerate_continuation_rsv_bits = builder.tolerate_continuation_rsv_bits;
+        state.close_on_error = builder.close_on_error;
+        state.mask_source = builder.mask_source;
+        state.observer = builder.frame_observer;
+        state.metrics = builder.metrics;
+        state.clock = builder.clock;
+        state.max_control_frames = builder.max_control_frames;
+        state.extensions = builder.extensions;
+        for e in &mut state.extensions {
+            e.set_max_message_size(state.max_message_size)
+        }
+        state.streaming = state.extensions.iter_mut().all(|e| e.as_streaming().is_some());
+        (builder.socket, builder.buffer, builder.message_timeout, state)
+    }
+
+    /// Create a builder with the settings of this connection state.
+    ///
+    /// The reverse of [`ConnectionState::split_builder`].
+    pub(super) fn into_builder<T>(self, socket: T, buffer: BytesMut, message_timeout: Option<MessageTimeout>) -> Builder<T> {
+        Builder {
+            id: self.id,
+            mode: self.mode,
+            socket,
+            codec: self.codec,
+            extensions: self.extensions,
+            buffer,
+            max_message_size: self.max_message_size,
+            tolerate_continuation_rsv_bits: self.tolerate_continuation_rsv_bits,
+            close_on_error: self.close_on_error,
+            mask_source: self.mask_source,
+            frame_observer: self.observer,
+            message_timeout,
+            max_control_frames: self.max_control_frames,
+            metrics: self.metrics,
+            clock: self.clock
+        }
+    }
+
+    /// Add extensions to use with this connection.
+    ///
+    /// Cf. [`Builder::add_extensions`](super::Builder::add_extensions).
+    ///
+    /// # Panics
+    ///
+    /// If an extension uses a reserved bit which is already used by another
+    /// extension of this connection.
+    pub fn add_extensions<I>(&mut self, extensions: I)
+    where
+        I: IntoIterator<Item = Box<dyn Extension + Send + Sync>>
+    {
+        for mut e in extensions.into_iter().filter(|e| e.is_enabled()) {
+            extension::assert_no_conflict(&self.extensions, &*e, false);
+            log::debug!("{}: using extension: {}", self.id, e.name());
+            self.codec.add_reserved_bits(e.reserved_bits());
+            e.set_max_message_size(self.max_message_size);
+            self.streaming &= e.as_streaming().is_some();
+            self.extensions.push(e)
+        }
+    }
+
+    /// Set the maximum size of a complete message.
+    ///
+    /// Cf. [`Builder::set_max_message_size`](super::Builder::set_max_message_size).
+    pub fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max;
+        for e in &mut self.extensions {
+            e.set_max_message_size(max)
+        }
+    }
+
+    /// Set the maximum size of a single websocket frame payload.
+    ///
+    /// Cf. [`Builder::set_max_frame_size`](super::Builder::set_max_frame_size).
+    pub fn set_max_frame_size(&mut self, max: usize) {
+        self.codec.set_max_data_size(max);
+    }
+
+    /// Tolerate reserved bits in continuation frames (default: false).
+    ///
+    /// Cf. [`Builder::set_tolerate_continuation_rsv_bits`](super::Builder::set_tolerate_continuation_rsv_bits).
+    pub fn set_tolerate_continuation_rsv_bits(&mut self, tolerate: bool) {
+        self.tolerate_continuation_rsv_bits = tolerate
+    }
+
+    /// Queue a CLOSE frame if the remote violates the protocol (default: true).
+    ///
+    /// Cf. [`Builder::set_close_on_error`](super::Builder::set_close_on_error).
+    pub fn set_close_on_error(&mut self, close: bool) {
+        self.close_on_error = close
+    }
+
+    /// Set the maximum number of control frames received per message (default: 1000).
+    ///
+    /// Cf. [`Builder::set_max_control_frames_per_message`](super::Builder::set_max_control_frames_per_message).
+    pub fn set_max_control_frames_per_message(&mut self, max: usize) {
+        self.max_control_frames = max
+    }
+
+    /// Set an identifier of this connection (default: a random number).
+    pub fn set_trace_id(&mut self, id: impl fmt::Display) {
+        self.id = Id::Custom(id.to_string().into())
+    }
+
+    /// Set a custom source of frame masks.
+    ///
+    /// Cf. [`Builder::set_mask_source`](super::Builder::set_mask_source).
+    pub fn set_mask_source(&mut self, f: impl FnMut() -> [u8; 4] + Send + Sync + 'static) {
+        self.mask_source = Some(MaskSource(Box::new(f)))
+    }
+
+    /// Generate frame masks with the given random number generator (requires feature `rand`).
+    ///
+    /// Cf. [`Builder::set_mask_rng`](super::Builder::set_mask_rng).
+    #[cfg(feature = "rand")]
+    pub fn set_mask_rng(&mut self, rng: impl rand::RngCore + Send + Sync + 'static) {
+        self.mask_source = Some(super::mask_rng(rng))
+    }
+
+    /// Set a callback which observes every frame received or sent.
+    ///
+    /// Cf. [`Builder::set_frame_observer`](super::Builder::set_frame_observer).
+    pub fn set_frame_observer(&mut self, f: impl FnMut(Direction, &Header, &[u8]) + Send + 'static) {
+        self.observer = Some(FrameObserver(Arc::new(std::sync::Mutex::new(Box::new(f)))))
+    }
+
+    /// Set an observer of frames, messages and the closing of the connection.
+    ///
+    /// Cf. [`Builder::set_observer`](super::Builder::set_observer).
+    pub fn set_observer(&mut self, observer: Arc<dyn Observer>) {
+        self.metrics = Some(observer)
+    }
+
+    /// Set a clock to measure round-trip times of PINGs.
+    ///
+    /// Cf. [`Builder::set_clock`](super::Builder::set_clock).
+    pub fn set_clock(&mut self, f: impl Fn() -> Instant + Send + Sync + 'static) {
+        self.clock = Some(Clock(Box::new(f)))
+    }
+
+    /// Has the connection been closed?
+    ///
+    /// This is the case after the closing handshake is complete, the
+    /// connection has failed or been aborted. Output may still be pending.
+    pub fn is_closed(&self) -> bool {
+        self.state == State::Closed
+    }
+
+    /// Information about the frames of the last text or binary message.
+    pub fn message_meta(&self) -> MessageMeta {
+        self.meta
+    }
+
+    /// The round-trip time of the last PING answered by the remote.
+    ///
+    /// Cf. [`Receiver::last_rtt`](super::Receiver::last_rtt).
+    pub fn last_rtt(&self) -> Option<Duration> {
+        self.last_rtt
+    }
+
+    /// Handle bytes read from the socket.
+    ///
+    /// Incomplete frames are buffered until more input is given. Once the
+    /// connection is closed, input is discarded and no events are returned.
+    pub fn handle_input(&mut self, bytes: &[u8]) -> Vec<Event> {
+        let mut events = Vec::new();
+        if self.state == State::Closed {
+            log::debug!("{}: discarding input, connection is closed", self.id);
+            return events
+        }
+        self.input.extend_from_slice(bytes);
+        loop {
+            match self.next_event() {
+                Ok(Some(e)) => events.push(e),
+                Ok(None) => break,
+                Err(e) => {
+                    self.state = State::Closed;
+                    self.input.clear();
+                    events.push(Event::Error(e));
+                    break
+                }
+            }
+        }
+        events
+    }
+
+    /// Queue a message to send.
+    ///
+    /// Text and binary messages with more payload data than the maximum
+    /// frame size are split into fragments (cf. [`ConnectionState::set_max_frame_size`]).
+    /// After [`OutgoingMessage::Close`] nothing else can be sent and received
+    /// data is discarded until the remote answers the CLOSE frame. Sending
+    /// fails with [`Error::Closed`] after that.
+    pub fn queue_send(&mut self, message: OutgoingMessage) -> Result<(), Error> {
+        let (opcode, data) = match message {
+            OutgoingMessage::Text(s) => (OpCode::Text, s.into_bytes()),
+            OutgoingMessage::Binary(b) => (OpCode::Binary, b),
+            OutgoingMessage::Ping(b) => (OpCode::Ping, b.to_vec()),
+            OutgoingMessage::Close(reason) => (OpCode::Close, super::close_payload(&reason))
+        };
+        let mut header = Header::new(opcode);
+        let mut data = Storage::Owned(data);
+        self.prepare_send(&mut header, &mut data)?;
+        for (mut frame, range) in self.fragments(&header, data.as_ref().len()) {
+            self.queue(&mut frame, &data.as_ref()[range])
+        }
+        Ok(())
+    }
+
+    /// Queue a PING with payload data identifying it.
+    ///
+    /// Cf. [`Sender::ping_with_token`](super::Sender::ping_with_token). The
+    /// matching PONG is recognised by [`ConnectionState::handle_input`].
+    pub fn queue_ping_with_token(&mut self) -> Result<PingToken, Error> {
+        let (data, token) = self.start_ping()?;
+        self.queue(&mut Header::new(OpCode::Ping), &data);
+        Ok(token)
+    }
+
+    /// Move all bytes to write to the socket into the given buffer.
+    pub fn take_output(&mut self, buf: &mut BytesMut) {
+        buf.unsplit(self.output.split())
+    }
+
+    /// Abort the connection without a closing handshake.
+    ///
+    /// Cf. [`Sender::abort`](super::Sender::abort). No CLOSE frame is queued,
+    /// but previously queued output is kept.
+    pub fn abort(&mut self) {
+        log::debug!("{}: aborting connection", self.id);
+        self.pings.clear();
+        self.state = State::Closed
+    }
+
+    /// Decode buffered frames until an event occurs or more input is needed.
+    fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        loop {
+            if self.state == State::Closed {
+                return Ok(None)
+            }
+
+            // The header of a frame whose payload data is incomplete has been handled already.
+            let mut header = match self.header.take() {
+                Some(header) => header,
+                None => {
+                    let mut input = mem::take(&mut self.input);
+                    let header = self.next_header(&mut input);
+                    self.input = input;
+                    match header? {
+                        Parsing::Done { value, .. } => value,
+                        Parsing::NeedMore(_) => return Ok(None)
+                    }
+                }
+            };
+
+            if self.input.len() < header.payload_len() {
+                self.header = Some(header);
+                return Ok(None)
+            }
+
+            if header.opcode().is_control() {
+                let mut payload = self.input.split_to(header.payload_len());
+                match self.on_control(&header, &mut payload)? {
+                    None => continue,
+                    Some(Control::Ping) => return Ok(Some(Event::Ping(payload.to_vec()))),
+                    Some(Control::Pong) => return Ok(Some(Event::Message(Message::Pong(payload.to_vec())))),
+                    Some(Control::Closed(reason)) => return Ok(Some(Event::Message(Message::Closed(reason))))
+                }
+            }
+
+            let mut message = mem::take(&mut self.message);
+            let offset = message.len();
+            message.extend_from_slice(&self.input[.. header.payload_len()]);
+            self.input.advance(header.payload_len());
+            match self.on_data(&mut header, &mut message, offset) {
+                Ok(None) => self.message = message,
+                // Collecting a `Vec` into `BytesMut` takes over its allocation without copying.
+                Ok(Some(Data::Text(_))) => return Ok(Some(Event::Message(Message::Text(message.into_iter().collect())))),
+                Ok(Some(Data::Binary(_))) => return Ok(Some(Event::Message(Message::Binary(message.into_iter().collect())))),
+                Err(e) => return Err(e)
+            }
+        }
+    }
+
+    /// Is a CLOSE frame sent and not answered yet?
+    pub(super) fn is_closing(&self) -> bool {
+        self.state == State::CloseSent
+    }
+
+    /// Forget the message being received, e.g. after reading from the socket failed.
+    pub(super) fn discard_message(&mut self) {
+        self.assembly = None;
+        self.message.clear();
+        self.control_frames = 0
+    }
+
+    /// Are messages decoded fragment by fragment?
+    ///
+    /// If so, the payload data of a message received so far may be moved
+    /// out of the message buffer, cf. [`ConnectionState::message_drained`].
+    pub(super) fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    /// The payload data of the message received so far has been moved out
+    /// of the message buffer given to [`ConnectionState::on_data`].
+    pub(super) fn message_drained(&mut self) {
+        debug_assert!(self.streaming);
+        if let Some(a) = &mut self.assembly {
+            a.num_bytes = 0
+        }
+    }
+
+    /// The output which has been queued and is not written yet.
+    pub(super) fn output(&mut self) -> &mut BytesMut {
+        &mut self.output
+    }
+
+    /// Decode and check the next frame header at the start of the given buffer.
+    ///
+    /// The header is removed from the buffer. Invalid headers and messages
+    /// which would exceed the maximum message size fail the connection.
+    pub(super) fn next_header(&mut self, buffer: &mut BytesMut) -> Result<Parsing<Header, usize>, Error> {
+        if self.state == State::Closed {
+            log::debug!("{}: can not receive, connection is closed", self.id);
+            return Err(Error::Closed)
+        }
+
+        let (mut header, offset) = match self.codec.decode_header(buffer) {
+            Ok(Parsing::Done { value, offset }) => (value, offset),
+            Ok(Parsing::NeedMore(n)) => return Ok(Parsing::NeedMore(n)),
+            Err(e @ base::Error::PayloadTooLarge { .. }) => return Err(self.fail(1009, Error::Codec(e))),
+            Err(e) => return Err(self.fail(1002, Error::Codec(e)))
+        };
+        debug_assert!(offset <= MAX_HEADER_SIZE);
+        buffer.advance(offset);
+
+        log::trace!("{}: recv: {}", self.id, header);
+        #[cfg(feature = "tracing")]
+        super::trace_frame("recv", &self.id, &header);
+        if let Some(m) = &self.metrics {
+            m.on_frame_received(header.opcode(), header.payload_len())
+        }
+
+        if header.opcode().is_control() {
+            return Ok(Parsing::Done { value: header, offset })
+        }
+
+        // Reserved bits apply to a message and are only set in its first frame.
+        if header.opcode() == OpCode::Continue {
+            if let Err(e) = self.check_continuation_rsv_bits(&mut header) {
+                return Err(self.fail(1002, e))
+            }
+        }
+
+        // Check if total message does not exceed maximum.
+        let length = self.assembly.as_ref().map_or(0, |a| a.length).saturating_add(header.payload_len());
+        if length > self.max_message_size {
+            log::warn!("{}: accumulated message length exceeds maximum", self.id);
+            let e = Error::MessageTooLarge { current: length, maximum: self.max_message_size };
+            return Err(self.fail(1009, e))
+        }
+
+        Ok(Parsing::Done { value: header, offset })
+    }
+
+    /// Handle a control frame with the given (still masked) payload data.
+    ///
+    /// PINGs are answered and CLOSE frames are answered or complete the
+    /// closing handshake. Returns `None` if the frame has been discarded
+    /// because we are closing the connection.
+    pub(super) fn on_control(&mut self, header: &Header, payload: &mut [u8]) -> Result<Option<Control>, Error> {
+        if self.state == State::Closed {
+            return Err(Error::Closed) // aborted concurrently
+        }
+        base::Codec::apply_mask(header, payload);
+        if let Some(o) = &self.observer {
+            o.observe(Direction::Incoming, header, payload)
+        }
+        // Only control frames between the fragments of a message are limited.
+        if self.assembly.is_some() {
+            self.control_frames += 1;
+            if self.control_frames > self.max_control_frames {
+                log::debug!("{}: too many control frames", self.id);
+                self.discard_message();
+                let e = Error::TooManyControlFrames { maximum: self.max_control_frames };
+                return Err(self.fail(1008, e))
+            }
+        }
+        if self.state == State::CloseSent && header.opcode() != OpCode::Close {
+            log::trace!("{}: discarding control frame while closing", self.id);
+            return Ok(None)
+        }
+        if let OpCode::Ping | OpCode::Close = header.opcode() {
+            self.check_mask_source()? // PINGs and CLOSE frames are answered
+        }
+        match header.opcode() {
+            OpCode::Ping => {
+                self.queue(&mut Header::new(OpCode::Pong), payload);
+                Ok(Some(Control::Ping))
+            }
+            OpCode::Pong => {
+                if let Some(rtt) = self.pings.on_pong(payload, self.clock.as_ref()) {
+                    self.last_rtt = Some(rtt)
+                }
+                Ok(Some(Control::Pong))
+            }
+            OpCode::Close => self.on_close(payload).map(|reason| Some(Control::Closed(reason))),
+            opcode => Err(Error::UnexpectedOpCode(opcode))
+        }
+    }
+
+    /// Handle a data frame whose (still masked) payload data starts at the
+    /// given offset of the message buffer.
+    ///
+    /// Fragments are decoded and appended to the message buffer, which must
+    /// be the same for all fragments of a message. Returns the type and
+    /// length of the message once it is complete and `None` before. If an
+    /// error is returned, the message is discarded.
+    pub(super) fn on_data(&mut self, header: &mut Header, message: &mut Vec<u8>, offset: usize) -> Result<Option<Data>, Error> {
+        let result = self.assemble(header, message, offset);
+        if result.is_err() {
+            self.discard_message()
+        }
+        result
+    }
+
+    /// Check that a frame can be sent and apply extensions to data frames.
+    ///
+    /// Sending a CLOSE frame starts the closing handshake.
+    pub(super) fn prepare_send(&mut self, header: &mut Header, data: &mut Storage<'_>) -> Result<(), Error> {
+        if self.state != State::Open {
+            log::debug!("{}: can not send {:?}, connection is {:?}", self.id, header.opcode(), self.state);
+            return Err(Error::Closed)
+        }
+        self.check_mask_source()?;
+        match header.opcode() {
+            OpCode::Close => self.state = State::CloseSent,
+            OpCode::Ping | OpCode::Pong => {}
+            _ => for e in self.extensions.iter_mut() {
+                log::trace!("{}: encoding with extension: {}", self.id, e.name());
+                e.encode(header, data).map_err(Error::Extension)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Register a PING which measures the round-trip time, cf. [`Pings::start`].
+    pub(super) fn start_ping(&mut self) -> Result<(ByteBuf125, PingToken), Error> {
+        if self.state != State::Open {
+            log::debug!("{}: can not send PING, connection is {:?}", self.id, self.state);
+            return Err(Error::Closed)
+        }
+        self.check_mask_source()?;
+        Ok(self.pings.start(self.clock.as_ref()))
+    }
+
+    /// Split a frame with the given header and length of payload data into
+    /// fragments of at most the maximum frame size.
+    ///
+    /// Control frames are never split.
+    pub(super) fn fragments(&self, header: &Header, len: usize) -> Fragments {
+        let max = std::cmp::max(1, self.codec.max_data_size());
+        if header.opcode().is_control() || len <= max {
+            return Fragments { header: header.clone(), len, max: len, offset: 0, done: false }
+        }
+        log::trace!("{}: sending {} bytes in fragments of {} bytes", self.id, len, max);
+        Fragments { header: header.clone(), len, max, offset: 0, done: false }
+    }
+
+    /// Encode the header of a frame with the given payload data into the output buffer.
+    ///
+    /// The header is masked if necessary, but the payload data is not.
+    pub(super) fn queue_header(&mut self, header: &mut Header, data: &[u8]) {
+        if self.mode.is_client() {
+            header.set_masked(true);
+            header.set_mask(super::next_mask(&mut self.mask_source));
+        }
+        header.set_payload_len(data.len());
+
+        if let Some(m) = &self.metrics {
+            m.on_frame_sent(header.opcode(), header.payload_len());
+            if header.opcode() == OpCode::Close && self.state == State::CloseSent {
+                m.on_close(super::close_reason(data).code(), true)
+            }
+        }
+
+        if let Some(o) = &self.observer {
+            o.observe(Direction::Outgoing, header, data)
+        }
+
+        log::trace!("{}: send: {}", self.id, header);
+        #[cfg(feature = "tracing")]
+        super::trace_frame("send", &self.id, header);
+
+        self.output.extend_from_slice(self.codec.encode_header(header))
+    }
+
+    /// Queue a CLOSE frame with the given status code and close the connection.
+    ///
+    /// Returns the given error which caused the connection to fail.
+    ///
+    /// Nothing is queued and the connection is not closed if closing on
+    /// errors has been disabled.
+    pub(super) fn fail(&mut self, code: u16, e: Error) -> Error {
+        if !self.close_on_error {
+            return e
+        }
+        self.pings.clear();
+        if mem::replace(&mut self.state, State::Closed) != State::Open {
+            return e
+        }
+        if let Some(m) = &self.metrics {
+            m.on_close(Some(code), true)
+        }
+        if self.check_mask_source().is_ok() {
+            self.queue(&mut Header::new(OpCode::Close), &code.to_be_bytes())
+        }
+        e
+    }
+
+    /// Make sure that frames can be masked in client mode.
+    ///
+    /// If no mask source is set, the default source is created. Without
+    /// feature `rand` there is none and [`Error::MaskSourceRequired`] is returned.
+    pub(super) fn check_mask_source(&mut self) -> Result<(), Error> {
+        if self.mode.is_client() && self.mask_source.is_none() {
+            self.mask_source = Some(super::default_mask_source().ok_or(Error::MaskSourceRequired)?)
+        }
+        Ok(())
+    }
+
+    /// Unmask, observe, decode and append a data frame, cf. [`ConnectionState::on_data`].
+    fn assemble(&mut self, header: &mut Header, message: &mut Vec<u8>, offset: usize) -> Result<Option<Data>, Error> {
+        if self.state == State::Closed {
+            return Err(Error::Closed) // aborted concurrently
+        }
+        base::Codec::apply_mask(header, &mut message[offset ..]);
+        if let Some(o) = &self.observer {
+            o.observe(Direction::Incoming, header, &message[offset ..])
+        }
+
+        let (num_bytes, total, length) = self.assembly.as_ref().map_or((0, 0, 0), |a| (a.num_bytes, a.total, a.length));
+        debug_assert!(num_bytes <= offset, "fragments of a message are appended to the same buffer");
+        let start = offset - num_bytes;
+        let length = length + header.payload_len();
+        let mut utf8 = self.assembly.as_ref().map_or(Utf8Check::default(), |a| a.utf8);
+
+        // After we have sent a CLOSE frame, data is discarded until the remote answers it.
+        if self.state == State::CloseSent {
+            log::trace!("{}: discarding data frame while closing", self.id);
+            message.truncate(start);
+            self.discard_message();
+            return Ok(None)
+        }
+
+        let (rsv_bits, fragments) = match (header.is_fin(), header.opcode()) {
+            (false, OpCode::Continue) => { // Intermediate message fragment.
+                if self.assembly.is_none() {
+                    log::debug!("{}: continue frame while not processing message fragments", self.id);
+                    return Err(self.fail(1002, Error::UnexpectedOpCode(OpCode::Continue)))
+                }
+                let is_text = matches!(&self.assembly, Some(a) if a.opcode == OpCode::Text);
+                if self.streaming {
+                    self.decode_fragment(header, message, offset)?;
+                    if is_text {
+                        self.check_utf8(&mut utf8, &message[offset ..])?
+                    }
+                }
+                if let Some(a) = &mut self.assembly {
+                    a.fragments += 1;
+                    a.length = length;
+                    a.num_bytes = message.len() - start;
+                    a.total = total + message.len() - offset;
+                    a.utf8 = utf8
+                }
+                return Ok(None)
+            }
+            (false, oc) => { // Initial message fragment.
+                if self.assembly.is_some() {
+                    log::debug!("{}: initial fragment while processing a fragmented message", self.id);
+                    return Err(self.fail(1002, Error::UnexpectedOpCode(oc)))
+                }
+                let rsv_bits = (header.is_rsv1(), header.is_rsv2(), header.is_rsv3());
+                if self.streaming {
+                    self.decode_fragment(header, message, offset)?;
+                    if oc == OpCode::Text {
+                        self.check_utf8(&mut utf8, &message[offset ..])?
+                    }
+                } else {
+                    self.decode_with_extensions(header, message, start)?
+                }
+                let num_bytes = message.len() - start;
+                let total = message.len() - offset;
+                self.assembly = Some(Assembly { opcode: oc, rsv_bits, fragments: 1, length, num_bytes, total, utf8 });
+                self.control_frames = 0;
+                return Ok(None)
+            }
+            (true, OpCode::Continue) => { // Last message fragment.
+                let a = match self.assembly.take() {
+                    Some(a) => a,
+                    None => {
+                        log::debug!("{}: last continue frame while not processing message fragments", self.id);
+                        return Err(self.fail(1002, Error::UnexpectedOpCode(OpCode::Continue)))
+                    }
+                };
+                let (rsv1, rsv2, rsv3) = a.rsv_bits;
+                header.set_rsv1(rsv1).set_rsv2(rsv2).set_rsv3(rsv3);
+                if self.streaming {
+                    self.decode_fragment(header, message, offset)?
+                } else {
+                    header.set_payload_len(message.len() - start);
+                    log::trace!("{}: last fragment: total length = {} bytes", self.id, header.payload_len());
+                    self.decode_with_extensions(header, message, start)?
+                }
+                header.set_opcode(a.opcode);
+                (a.rsv_bits, a.fragments + 1)
+            }
+            (true, oc) => { // Regular non-fragmented message.
+                if self.assembly.is_some() {
+                    log::debug!("{}: regular message while processing fragmented message", self.id);
+                    return Err(self.fail(1002, Error::UnexpectedOpCode(oc)))
+                }
+                let rsv_bits = (header.is_rsv1(), header.is_rsv2(), header.is_rsv3());
+                if self.streaming {
+                    self.decode_fragment(header, message, offset)?
+                } else {
+                    self.decode_with_extensions(header, message, start)?
+                }
+                (rsv_bits, 1)
+            }
+        };
+
+        let (rsv1, rsv2, rsv3) = rsv_bits;
+        self.meta = MessageMeta { rsv1, rsv2, rsv3, fragments, total_frames_len: length };
+        self.control_frames = 0;
+
+        let num_bytes = if self.streaming {
+            total + message.len() - offset
+        } else {
+            message.len() - start
+        };
+
+        let data = if header.opcode() == OpCode::Text {
+            let valid = if self.streaming {
+                utf8.feed(&message[offset ..]).and_then(|()| utf8.finish())
+            } else {
+                str::from_utf8(&message[start ..]).map(|_| ())
+            };
+            if let Err(e) = valid {
+                return Err(self.fail(1007, Error::Utf8(e)))
+            }
+            Data::Text(num_bytes)
+        } else {
+            Data::Binary(num_bytes)
+        };
+        if let Some(m) = &self.metrics {
+            m.on_message(&data)
+        }
+        Ok(Some(data))
+    }
+
+    /// Validate the next piece of a text message decoded fragment by fragment.
+    fn check_utf8(&mut self, utf8: &mut Utf8Check, bytes: &[u8]) -> Result<(), Error> {
+        utf8.feed(bytes).map_err(|e| self.fail(1007, Error::Utf8(e)))
+    }
+
+    /// Check that a continuation frame does not have any reserved bits set.
+    ///
+    /// If reserved bits are tolerated, they are cleared instead.
+    fn check_continuation_rsv_bits(&self, header: &mut Header) -> Result<(), Error> {
+        let bits = [header.is_rsv1(), header.is_rsv2(), header.is_rsv3()];
+        if let Some(i) = bits.iter().position(|b| *b) {
+            if !self.tolerate_continuation_rsv_bits {
+                log::debug!("{}: continuation frame with reserved bit {}", self.id, i + 1);
+                return Err(Error::Codec(base::Error::InvalidReservedBit(i as u8 + 1)))
+            }
+            header.set_rsv1(false).set_rsv2(false).set_rsv3(false);
+        }
+        Ok(())
+    }
+
+    /// Answer a CLOSE frame with the given payload data.
+    fn on_close(&mut self, data: &[u8]) -> Result<CloseReason, Error> {
+        self.pings.clear();
+        if self.state == State::CloseSent {
+            // The remote has answered our CLOSE frame.
+            self.state = State::Closed;
+            return Ok(super::close_reason(data))
+        }
+        if let Some(m) = &self.metrics {
+            m.on_close(super::close_reason(data).code(), false)
+        }
+        let (code, error) = match super::close_answer(data) {
+            Ok(code) => (code, None),
+            Err((code, e)) => (Some(code), Some(e))
+        };
+        let code = code.map(u16::to_be_bytes);
+        self.queue(&mut Header::new(OpCode::Close), code.as_ref().map_or(&[], |c| &c[..]));
+        self.state = State::Closed;
+        error.map_or_else(|| Ok(super::close_reason(data)), Err)
+    }
+
+    /// Apply all extensions to the given header and the payload data of a message.
+    ///
+    /// The message's payload data starts at the given offset of the message buffer.
+    fn decode_with_extensions(&mut self, header: &mut Header, message: &mut Vec<u8>, offset: usize) -> Result<(), Error> {
+        if self.extensions.is_empty() {
+            return Ok(())
+        }
+        // Data in front of the message belongs to the caller and is not decoded.
+        let data =
+            if offset == 0 {
+                &mut *message
+            } else {
+                self.chunk_buffer.clear();
+                self.chunk_buffer.extend_from_slice(&message[offset ..]);
+                message.truncate(offset);
+                &mut self.chunk_buffer
+            };
+        let mut result = Ok(());
+        for e in self.extensions.iter_mut().rev() {
+            log::trace!("{}: decoding with extension: {}", self.id, e.name());
+            if let Err(e) = e.decode(header, data) {
+                result = Err(e);
+                break
+            }
+        }
+        if offset > 0 {
+            message.extend_from_slice(&self.chunk_buffer)
+        }
+        result.map_err(|e| self.on_extension_error(e))
+    }
+
+    /// Apply all streaming extensions to the payload data of a single frame.
+    ///
+    /// The frame's payload data starts at the given offset of the message buffer.
+    fn decode_fragment(&mut self, header: &mut Header, message: &mut Vec<u8>, offset: usize) -> Result<(), Error> {
+        if self.extensions.is_empty() {
+            return Ok(())
+        }
+        let is_first = header.opcode() != OpCode::Continue;
+        let is_final = header.is_fin();
+        self.chunk_buffer.clear();
+        self.chunk_buffer.extend_from_slice(&message[offset ..]);
+        message.truncate(offset);
+        let mut result = Ok(());
+        for e in self.extensions.iter_mut().rev() {
+            log::trace!("{}: decoding fragment with extension: {}", self.id, e.name());
+            let e = e.as_streaming().expect("streaming is only used if all extensions support it");
+            let chunk = &mut self.chunk_buffer;
+            result = (|| {
+                if is_first {
+                    e.begin_message(header)?
+                }
+                e.process_chunk(chunk, is_final)?;
+                if is_final {
+                    e.end_message(header)?
+                }
+                Ok(())
+            })();
+            if result.is_err() {
+                break
+            }
+        }
+        message.extend_from_slice(&self.chunk_buffer);
+        result.map_err(|e| self.on_extension_error(e))
+    }
+
+    /// Turn an extension error into a connection error.
+    ///
+    /// If a decoded message is too large, the connection is failed.
+    fn on_extension_error(&mut self, e: BoxedError) -> Error {
+        match e.downcast::<Error>() {
+            Ok(e) => match *e {
+                e @ Error::MessageTooLarge {..} => self.fail(1009, e),
+                e => e
+            },
+            Err(e) => Error::Extension(e)
+        }
+    }
+
+    /// Encode header and payload data into the output buffer.
+    ///
+    /// The data will be masked if necessary.
+    fn queue(&mut self, header: &mut Header, data: &[u8]) {
+        // Reserve exactly the capacity needed for the encoded frame.
+        header.set_masked(self.mode.is_client());
+        header.set_payload_len(data.len());
+        self.output.reserve(header.encoded_len() + data.len());
+        self.queue_header(header, data);
+        let n = self.output.len();
+        self.output.extend_from_slice(data);
+        base::Codec::apply_mask(header, &mut self.output[n ..])
+    }
+}
+
+/// The frames of a message to send, cf. [`ConnectionState::fragments`].
+///
+/// Yields the header of every frame and the range of its payload data.
+#[derive(Debug)]
+pub(super) struct Fragments {
+    header: Header,
+    len: usize,
+    max: usize,
+    offset: usize,
+    done: bool
+}
+
+impl Iterator for Fragments {
+    type Item = (Header, Range<usize>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+        let end = std::cmp::min(self.offset.saturating_add(self.max), self.len);
+        let mut header = self.header.clone();
+        if self.offset > 0 {
+            header.set_opcode(OpCode::Continue).set_rsv1(false).set_rsv2(false).set_rsv3(false);
+        }
+        header.set_fin(self.header.is_fin() && end == self.len);
+        let range = self.offset .. end;
+        self.offset = end;
+        self.done = end == self.len;
+        Some((header, range))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use crate::{base::OpCode, data::{ByteBuf125, CloseReason, Message, OutgoingMessage}};
+    use futures::executor::block_on;
+    use std::{convert::TryFrom, sync::{Arc, Mutex}, time::Instant};
+    use super::{Builder, ConnectionState, Error, Event, Mode, Utf8Check};
+
+    /// Move the output of one connection state to the input of another.
+    fn transfer(from: &mut ConnectionState, to: &mut ConnectionState) -> Vec<Event> {
+        let mut bytes = BytesMut::new();
+        from.take_output(&mut bytes);
+        to.handle_input(&bytes)
+    }
+
+    #[test]
+    fn exchange_messages() {
+        let mut client = ConnectionState::new(Mode::Client);
+        let mut server = ConnectionState::new(Mode::Server);
+
+        client.queue_send(OutgoingMessage::Text("hello".into())).unwrap();
+        client.queue_send(OutgoingMessage::Ping(ByteBuf125::try_from("ping").unwrap())).unwrap();
+        client.queue_send(OutgoingMessage::Binary(vec![1, 2, 3])).unwrap();
+
+        // Input may arrive in arbitrary pieces.
+        let mut bytes = BytesMut::new();
+        client.take_output(&mut bytes);
+        let mut events = Vec::new();
+        for b in bytes.chunks(3) {
+            events.extend(server.handle_input(b))
+        }
+        assert!(matches!(&events[..], [
+            Event::Message(Message::Text(t)),
+            Event::Ping(p),
+            Event::Message(Message::Binary(b))
+        ] if t == "hello" && p == b"ping" && b[..] == [1, 2, 3]));
+
+        let events = transfer(&mut server, &mut client);
+        assert!(matches!(&events[..], [Event::Message(Message::Pong(p))] if p == b"ping"));
+        assert_eq!((1, 3), (server.message_meta().fragments, server.message_meta().total_frames_len))
+    }
+
+    #[test]
+    fn closing_handshake() {
+        let mut client = ConnectionState::new(Mode::Client);
+        let mut server = ConnectionState::new(Mode::Server);
+
+        client.queue_send(OutgoingMessage::Close(CloseReason::new(1000, "bye").unwrap())).unwrap();
+        assert!(matches!(client.queue_send(OutgoingMessage::Text("hello".into())), Err(Error::Closed)));
+        assert!(!client.is_closed());
+
+        // Data sent by the server before the CLOSE frame is discarded by the client.
+        server.queue_send(OutgoingMessage::Text("discarded".into())).unwrap();
+        let events = transfer(&mut client, &mut server);
+        assert!(matches!(&events[..], [Event::Message(Message::Closed(r))] if r.code() == Some(1000)));
+        assert!(server.is_closed());
+        assert!(matches!(server.queue_send(OutgoingMessage::Text("hello".into())), Err(Error::Closed)));
+
+        let events = transfer(&mut server, &mut client);
+        assert!(matches!(&events[..], [Event::Message(Message::Closed(r))] if r.code() == Some(1000)));
+        assert!(client.is_closed());
+        assert!(client.handle_input(&[0x81, 0x00]).is_empty())
+    }
+
+    #[test]
+    fn large_messages_are_fragmented() {
+        let mut client = ConnectionState::new(Mode::Client);
+        let mut server = ConnectionState::new(Mode::Server);
+        client.set_max_frame_size(4);
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let observed = frames.clone();
+        client.set_frame_observer(move |_, header, data| {
+            observed.lock().unwrap().push((header.opcode(), header.is_fin(), data.len()))
+        });
+
+        client.queue_send(OutgoingMessage::Text("fragmented".into())).unwrap();
+        client.queue_send(OutgoingMessage::Ping(ByteBuf125::try_from("ping").unwrap())).unwrap();
+        let events = transfer(&mut client, &mut server);
+        assert!(matches!(&events[..], [Event::Message(Message::Text(t)), Event::Ping(_)] if t == "fragmented"));
+        assert_eq!((3, 10), (server.message_meta().fragments, server.message_meta().total_frames_len));
+        let expected = vec![
+            (OpCode::Text, false, 4),
+            (OpCode::Continue, false, 4),
+            (OpCode::Continue, true, 2),
+            (OpCode::Ping, true, 4)
+        ];
+        assert_eq!(expected, *frames.lock().unwrap());
+    }
+
+    #[test]
+    fn ping_round_trip() {
+        let mut client = ConnectionState::new(Mode::Client);
+        let mut server = ConnectionState::new(Mode::Server);
+        client.set_clock(Instant::now);
+
+        let token = client.queue_ping_with_token().unwrap();
+        transfer(&mut client, &mut server);
+        let events = transfer(&mut server, &mut client);
+        assert!(matches!(&events[..], [Event::Message(Message::Pong(_))]));
+        let rtt = block_on(token.receipt()).unwrap();
+        assert!(rtt.is_some());
+        assert_eq!(rtt, client.last_rtt())
+    }
+
+    #[test]
+    fn abort() {
+        let mut server = ConnectionState::new(Mode::Server);
+        server.abort();
+        assert!(server.is_closed());
+        assert!(matches!(server.queue_send(OutgoingMessage::Text("hello".into())), Err(Error::Closed)));
+        let mut output = BytesMut::new();
+        server.take_output(&mut output);
+        assert!(output.is_empty())
+    }
+
+    #[test]
+    fn output_capacity_is_exact() {
+        for &mode in &[Mode::Client, Mode::Server] {
+            let mask_len = if mode.is_client() { 4 } else { 0 };
+            for &(len, header_len) in &[(125, 2), (126, 4), (65535, 4), (65536, 10)] {
+                let mut state = ConnectionState::new(mode);
+                state.queue_send(OutgoingMessage::Binary(vec![0; len])).unwrap();
+                assert_eq!(header_len + mask_len + len, state.output.len());
+                assert_eq!(state.output.len(), state.output.capacity())
+            }
+        }
+    }
+
+    #[test]
+    fn utf8_split_across_fragments() {
+        let text = "aä€😀z".as_bytes();
+        for i in 0 ..= text.len() {
+            for j in i ..= text.len() {
+                let mut check = Utf8Check::default();
+                check.feed(&text[.. i]).unwrap();
+                check.feed(&text[i .. j]).unwrap();
+                check.feed(&text[j ..]).unwrap();
+                check.finish().unwrap()
+            }
+        }
+
+        let mut check = Utf8Check::default();
+        check.feed(&text[.. 2]).unwrap();
+        assert!(check.finish().is_err());
+        assert!(check.feed(b"z").is_err());
+
+        let mut client = ConnectionState::new(Mode::Client);
+        client.set_max_frame_size(3);
+        let mut server = ConnectionState::new(Mode::Server);
+        client.queue_send(OutgoingMessage::Text("aä€😀z".into())).unwrap();
+        match &transfer(&mut client, &mut server)[..] {
+            [Event::Message(Message::Text(t))] => assert_eq!(text, &t[..]),
+            other => panic!("unexpected events: {:?}", other)
+        }
+        assert_eq!(4, server.message_meta().fragments)
+    }
+
+    #[test]
+    #[cfg(not(feature = "rand"))]
+    fn mask_source_required() {
+        let mut client = ConnectionState::new(Mode::Client);
+        let result = client.queue_send(OutgoingMessage::Text("hello".into()));
+        assert!(matches!(result, Err(Error::MaskSourceRequired)));
+        let events = client.handle_input(&[0x89, 0x00]); // PING
+        assert!(matches!(events[..], [Event::Error(Error::MaskSourceRequired)]));
+
+        let mut client = ConnectionState::new(Mode::Client);
+        client.set_mask_source(|| [1, 2, 3, 4]);
+        assert!(client.queue_send(OutgoingMessage::Text("hello".into())).is_ok())
+    }
+
+    #[test]
+    fn builder_with_message_timeout() {
+        let mut builder = Builder::new(futures::io::Cursor::new(Vec::new()), Mode::Server);
+        builder.set_message_timeout(futures::future::pending::<()>);
+        let (_, mut server) = ConnectionState::from_builder(builder);
+        let mut client = ConnectionState::new(Mode::Client);
+        client.queue_send(OutgoingMessage::Text("hello".into())).unwrap();
+        match &transfer(&mut client, &mut server)[..] {
+            [Event::Message(Message::Text(t))] => assert_eq!(b"hello", &t[..]),
+            other => panic!("unexpected events: {:?}", other)
+        }
+    }
+}
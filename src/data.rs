@@ -8,9 +8,265 @@
 
 //! Types describing various forms of payload data.
 
+use bytes::BytesMut;
 use std::{convert::TryFrom, fmt};
 
+/// A message received from the remote end.
+///
+/// Returned by [`Receiver::receive`](crate::connection::Receiver::receive).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Textual data.
+    ///
+    /// The data is valid UTF-8. Use `String::try_from` to get a `String`.
+    Text(BytesMut),
+    /// Binary data.
+    Binary(BytesMut),
+    /// Data sent with a PONG control frame.
+    Pong(Vec<u8>),
+    /// The remote has closed the connection.
+    Closed(CloseReason)
+}
+
+impl Message {
+    /// Is this text or binary data?
+    pub fn is_data(&self) -> bool {
+        self.is_text() || self.is_binary()
+    }
+
+    /// Is this text data?
+    pub fn is_text(&self) -> bool {
+        matches!(self, Message::Text(_))
+    }
+
+    /// Is this binary data?
+    pub fn is_binary(&self) -> bool {
+        matches!(self, Message::Binary(_))
+    }
+
+    /// Is this a PONG?
+    pub fn is_pong(&self) -> bool {
+        matches!(self, Message::Pong(_))
+    }
+
+    /// Has the remote closed the connection?
+    pub fn is_closed(&self) -> bool {
+        matches!(self, Message::Closed(_))
+    }
+
+    /// Access the payload data.
+    ///
+    /// For [`Message::Closed`] this is the close reason text.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Message::Text(d) => d,
+            Message::Binary(d) => d,
+            Message::Pong(d) => d,
+            Message::Closed(r) => r.reason().as_bytes()
+        }
+    }
+}
+
+impl TryFrom<Message> for String {
+    type Error = Message;
+
+    /// Get the text of a [`Message::Text`].
+    ///
+    /// Fails with the original message if it is not text or not valid UTF-8.
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        match message {
+            Message::Text(d) => match std::str::from_utf8(&d) {
+                Ok(s) => Ok(s.to_owned()),
+                Err(_) => Err(Message::Text(d))
+            }
+            other => Err(other)
+        }
+    }
+}
+
+impl From<Message> for Vec<u8> {
+    /// Get the payload data (cf. [`Message::as_bytes`]).
+    fn from(message: Message) -> Self {
+        match message {
+            Message::Pong(d) => d,
+            Message::Closed(r) => r.reason.into_bytes(),
+            other => other.as_bytes().to_vec()
+        }
+    }
+}
+
+/// A message to send to the remote end.
+///
+/// The item type of [`SenderSink`](crate::connection::SenderSink).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OutgoingMessage {
+    /// Textual data.
+    Text(String),
+    /// Binary data.
+    Binary(Vec<u8>),
+    /// A PING with the given payload data.
+    Ping(ByteBuf125),
+    /// Close the connection with the given code and reason.
+    Close(CloseReason)
+}
+
+impl From<String> for OutgoingMessage {
+    fn from(text: String) -> Self {
+        OutgoingMessage::Text(text)
+    }
+}
+
+impl From<&str> for OutgoingMessage {
+    fn from(text: &str) -> Self {
+        OutgoingMessage::Text(text.into())
+    }
+}
+
+impl From<Vec<u8>> for OutgoingMessage {
+    fn from(data: Vec<u8>) -> Self {
+        OutgoingMessage::Binary(data)
+    }
+}
+
+impl TryFrom<Message> for OutgoingMessage {
+    type Error = Message;
+
+    /// Convert a received message so it can be sent, e.g. to relay it.
+    ///
+    /// Fails with the original message for PONGs and invalid UTF-8 text.
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        match message {
+            Message::Text(_) => String::try_from(message).map(OutgoingMessage::Text),
+            Message::Binary(data) => Ok(OutgoingMessage::Binary(data.to_vec())),
+            Message::Closed(reason) => Ok(OutgoingMessage::Close(reason)),
+            pong @ Message::Pong(_) => Err(pong)
+        }
+    }
+}
+
+/// Max. length in bytes of a close reason text.
+///
+/// The payload data of a close frame holds the 2 bytes of the status code
+/// and the reason text and is limited to 125 bytes like all control frames.
+pub const MAX_CLOSE_REASON_LEN: usize = 123;
+
+/// Storage the payload data of received messages can be appended to.
+///
+/// Cf. [`Receiver::receive_into`](crate::connection::Receiver::receive_into).
+/// Implement this trait to place payload data e.g. in arena-backed buffers.
+pub trait PayloadBuf {
+    /// The number of bytes in this buffer.
+    fn len(&self) -> usize;
+
+    /// Is this buffer empty?
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reserve capacity for at least `additional` more bytes.
+    fn reserve(&mut self, additional: usize);
+
+    /// Append the given bytes.
+    fn extend_from_slice(&mut self, data: &[u8]);
+
+    /// Shorten this buffer to the given length, e.g. to remove the payload
+    /// data of a message which could not be received completely.
+    fn truncate(&mut self, len: usize);
+}
+
+impl PayloadBuf for Vec<u8> {
+    fn len(&self) -> usize {
+        Vec::len(self)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        Vec::reserve(self, additional)
+    }
+
+    fn extend_from_slice(&mut self, data: &[u8]) {
+        Vec::extend_from_slice(self, data)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        Vec::truncate(self, len)
+    }
+}
+
+impl PayloadBuf for BytesMut {
+    fn len(&self) -> usize {
+        BytesMut::len(self)
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        BytesMut::reserve(self, additional)
+    }
+
+    fn extend_from_slice(&mut self, data: &[u8]) {
+        BytesMut::extend_from_slice(self, data)
+    }
+
+    fn truncate(&mut self, len: usize) {
+        BytesMut::truncate(self, len)
+    }
+}
+
+/// The close code and reason of a close message.
+///
+/// The default value has neither status code nor reason text.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct CloseReason {
+    code: Option<u16>,
+    reason: String
+}
+
+impl CloseReason {
+    /// Create a close reason with status code and reason text.
+    ///
+    /// Fails if the reason text is longer than [`MAX_CLOSE_REASON_LEN`] bytes.
+    pub fn new(code: u16, reason: &str) -> Result<Self, ReasonTooLong> {
+        if reason.len() > MAX_CLOSE_REASON_LEN {
+            return Err(ReasonTooLong(()))
+        }
+        Ok(CloseReason { code: Some(code), reason: reason.into() })
+    }
+
+    /// Create a close reason with a status code but without reason text.
+    pub fn with_code(code: u16) -> Self {
+        CloseReason { code: Some(code), reason: String::new() }
+    }
+
+    /// Create a close reason with status code and reason text.
+    ///
+    /// If the reason text is longer than [`MAX_CLOSE_REASON_LEN`] bytes, it
+    /// is cut off at the last character boundary which fits.
+    pub fn truncated(code: u16, reason: &str) -> Self {
+        let mut end = std::cmp::min(reason.len(), MAX_CLOSE_REASON_LEN);
+        while !reason.is_char_boundary(end) {
+            end -= 1
+        }
+        CloseReason { code: Some(code), reason: reason[.. end].into() }
+    }
+
+    /// The status code, if any.
+    pub fn code(&self) -> Option<u16> {
+        self.code
+    }
+
+    /// The reason text (empty if none was given).
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
 /// Data received from the remote end.
+///
+/// Use [`Message`] instead, which [`Receiver::receive`] returns. Applications
+/// which want to reuse a buffer for the payload data can use
+/// [`Receiver::receive_data`] together with [`Data`].
+///
+/// [`Receiver::receive`]: crate::connection::Receiver::receive
+/// [`Receiver::receive_data`]: crate::connection::Receiver::receive_data
+#[deprecated(since = "0.5.0", note = "use `Message`, which is returned by `Receiver::receive`")]
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Incoming<'a> {
     /// Text or binary data.
@@ -19,15 +275,16 @@ pub enum Incoming<'a> {
     Pong(&'a [u8])
 }
 
+#[allow(deprecated)]
 impl Incoming<'_> {
     /// Is this text or binary data?
     pub fn is_data(&self) -> bool {
-        if let Incoming::Data(_) = self { true } else { false }
+        matches!(self, Incoming::Data(_))
     }
 
     /// Is this a PONG?
     pub fn is_pong(&self) -> bool {
-        if let Incoming::Pong(_) = self { true } else { false }
+        matches!(self, Incoming::Pong(_))
     }
 
     /// Is this text data?
@@ -55,8 +312,15 @@ impl Incoming<'_> {
             Incoming::Pong(d) => d.len()
         }
     }
+
+    /// Is the length of data zero?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
+/// The type and length of data received with
+/// [`Receiver::receive_data`](crate::connection::Receiver::receive_data).
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Data {
     /// Textual data (number of bytes).
@@ -68,12 +332,12 @@ pub enum Data {
 impl Data {
     /// Is this text data?
     pub fn is_text(&self) -> bool {
-        if let Data::Text(_) = self { true } else { false }
+        matches!(self, Data::Text(_))
     }
 
     /// Is this binary data?
     pub fn is_binary(&self) -> bool {
-        if let Data::Binary(_) = self { true } else { false }
+        matches!(self, Data::Binary(_))
     }
 
     /// The length of data (number of bytes).
@@ -83,6 +347,31 @@ impl Data {
             Data::Binary(n) => *n
         }
     }
+
+    /// Is the length of data zero?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Information about the frames of a received message, cf.
+/// [`Receiver::message_meta`](crate::connection::Receiver::message_meta).
+///
+/// The reserved bits are those of the message's first frame as received,
+/// i.e. before extensions have been applied.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub struct MessageMeta {
+    /// Reserved bit 1 (e.g. set for messages compressed with permessage-deflate).
+    pub rsv1: bool,
+    /// Reserved bit 2.
+    pub rsv2: bool,
+    /// Reserved bit 3.
+    pub rsv3: bool,
+    /// The number of frames of the message (1 if it was not fragmented).
+    pub fragments: usize,
+    /// The sum of the frames' payload lengths (before extensions have been applied).
+    pub total_frames_len: usize
 }
 
 /// Wrapper type which restricts the length of its byte slice to 125 bytes.
@@ -101,6 +390,18 @@ impl fmt::Display for SliceTooLarge {
 
 impl std::error::Error for SliceTooLarge {}
 
+/// Error, if a close reason text is longer than [`MAX_CLOSE_REASON_LEN`] bytes.
+#[derive(Clone, Debug)]
+pub struct ReasonTooLong(());
+
+impl fmt::Display for ReasonTooLong {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Close reason longer than 123 bytes")
+    }
+}
+
+impl std::error::Error for ReasonTooLong {}
+
 impl<'a> TryFrom<&'a [u8]> for ByteSlice125<'a> {
     type Error = SliceTooLarge;
 
@@ -119,3 +420,176 @@ impl AsRef<[u8]> for ByteSlice125<'_> {
     }
 }
 
+
+/// An owned byte buffer of at most 125 bytes, e.g. for control frame payloads.
+#[derive(Clone, Copy)]
+pub struct ByteBuf125 {
+    data: [u8; 125],
+    len: u8
+}
+
+impl ByteBuf125 {
+    /// Create an empty buffer.
+    pub const fn new() -> Self {
+        ByteBuf125 { data: [0; 125], len: 0 }
+    }
+
+    /// Create a buffer from a static byte array.
+    ///
+    /// Arrays larger than 125 bytes are rejected at compile time:
+    ///
+    /// ```compile_fail
+    /// let buf = soketto::data::ByteBuf125::from_static(&[0; 126]);
+    /// ```
+    pub const fn from_static<const N: usize>(bytes: &'static [u8; N]) -> Self {
+        let () = AssertMaxLen::<N>::OK;
+        let mut data = [0; 125];
+        let mut i = 0;
+        while i < N {
+            data[i] = bytes[i];
+            i += 1
+        }
+        ByteBuf125 { data, len: N as u8 }
+    }
+}
+
+/// Compile-time check that `N` does not exceed 125.
+struct AssertMaxLen<const N: usize>;
+
+impl<const N: usize> AssertMaxLen<N> {
+    const OK: () = assert!(N <= 125, "ByteBuf125 can hold at most 125 bytes");
+}
+
+impl Default for ByteBuf125 {
+    fn default() -> Self {
+        ByteBuf125::new()
+    }
+}
+
+impl std::ops::Deref for ByteBuf125 {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[.. usize::from(self.len)]
+    }
+}
+
+impl AsRef<[u8]> for ByteBuf125 {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for ByteBuf125 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("ByteBuf125").field(&&self[..]).finish()
+    }
+}
+
+impl PartialEq for ByteBuf125 {
+    fn eq(&self, other: &Self) -> bool {
+        self[..] == other[..]
+    }
+}
+
+impl Eq for ByteBuf125 {}
+
+impl TryFrom<&[u8]> for ByteBuf125 {
+    type Error = SliceTooLarge;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() > 125 {
+            return Err(SliceTooLarge(()))
+        }
+        let mut buf = ByteBuf125::new();
+        buf.data[.. value.len()].copy_from_slice(value);
+        buf.len = value.len() as u8;
+        Ok(buf)
+    }
+}
+
+impl TryFrom<Vec<u8>> for ByteBuf125 {
+    type Error = SliceTooLarge;
+
+    fn try_from(value: Vec<u8>) -> Result<Self, Self::Error> {
+        ByteBuf125::try_from(&value[..])
+    }
+}
+
+impl TryFrom<&str> for ByteBuf125 {
+    type Error = SliceTooLarge;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        ByteBuf125::try_from(value.as_bytes())
+    }
+}
+
+impl From<ByteSlice125<'_>> for ByteBuf125 {
+    fn from(value: ByteSlice125<'_>) -> Self {
+        let mut buf = ByteBuf125::new();
+        buf.data[.. value.0.len()].copy_from_slice(value.0);
+        buf.len = value.0.len() as u8;
+        buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use quickcheck::QuickCheck;
+    use std::convert::TryFrom;
+    use super::{ByteBuf125, ByteSlice125, CloseReason, MAX_CLOSE_REASON_LEN, Message, OutgoingMessage};
+
+    #[test]
+    fn byte_buf_125_boundary() {
+        let max = vec![7; 125];
+        let buf = ByteBuf125::try_from(max.clone()).unwrap();
+        assert_eq!(&max[..], &buf[..]);
+        assert!(ByteBuf125::try_from(vec![7; 126]).is_err());
+        assert!(ByteBuf125::try_from("x".repeat(125).as_str()).is_ok());
+        assert!(ByteBuf125::try_from("x".repeat(126).as_str()).is_err());
+        assert!(ByteSlice125::try_from(&max[..]).is_ok());
+        assert!(ByteSlice125::try_from(&[7; 126][..]).is_err())
+    }
+
+    #[test]
+    fn byte_buf_125_from_static() {
+        const PING: ByteBuf125 = ByteBuf125::from_static(b"ping");
+        assert_eq!(b"ping", &PING[..]);
+        assert_eq!(125, ByteBuf125::from_static(&[1; 125]).len());
+        assert!(ByteBuf125::from_static(&[]).is_empty())
+    }
+
+    #[test]
+    fn outgoing_text_is_valid_utf8() {
+        let text = Message::Text(BytesMut::from("κόσμε"));
+        assert_eq!(Ok(OutgoingMessage::Text("κόσμε".into())), OutgoingMessage::try_from(text));
+        let invalid = Message::Text(BytesMut::from(&[b'a', 0xFF][..]));
+        assert_eq!(Err(invalid.clone()), OutgoingMessage::try_from(invalid))
+    }
+
+    #[test]
+    fn close_reason_length() {
+        let max = "x".repeat(MAX_CLOSE_REASON_LEN);
+        assert_eq!(&max, CloseReason::new(1000, &max).unwrap().reason());
+        assert!(CloseReason::new(1000, &format!("{}x", max)).is_err());
+        // 'κ' takes 2 bytes, so only 61 of them fit.
+        let greek = "κ".repeat(100);
+        assert_eq!(&greek[.. 122], CloseReason::truncated(1001, &greek).reason());
+        assert_eq!(Some(1001), CloseReason::truncated(1001, &greek).code())
+    }
+
+    #[test]
+    fn close_reason_truncation() {
+        fn property(reason: String) -> bool {
+            let r = CloseReason::truncated(1000, &reason);
+            let fits = r.reason().len() <= MAX_CLOSE_REASON_LEN;
+            let prefix = reason.starts_with(r.reason());
+            let maximal = reason.len() == r.reason().len()
+                || r.reason().len() + reason[r.reason().len() ..].chars().next().map_or(0, char::len_utf8) > MAX_CLOSE_REASON_LEN;
+            let valid = CloseReason::new(1000, &reason).map_or(reason.len() > MAX_CLOSE_REASON_LEN, |x| x == r);
+            fits && prefix && maximal && valid
+        }
+        QuickCheck::new().tests(1000).quickcheck(property as fn(String) -> bool)
+    }
+}
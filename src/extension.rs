@@ -11,10 +11,16 @@
 //!
 //! [rfc6455]: https://tools.ietf.org/html/rfc6455#section-9
 
-#[cfg(feature = "deflate")]
+#[cfg(any(feature = "deflate", feature = "deflate-rust"))]
 pub mod deflate;
 
-use crate::{BoxedError, Storage, base::Header};
+#[cfg(feature = "zstd")]
+pub mod zstd;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+use crate::{BoxedError, Storage, base::{Header, OpCode}};
 use std::{borrow::Cow, fmt};
 
 /// A websocket extension as per RFC 6455, section 9.
@@ -27,25 +33,39 @@ use std::{borrow::Cow, fmt};
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When receiving a handshake request from a client, for each extension
-/// with a matching name, [`Extension::configure`] will be applied to the
-/// request parameters. The extension may internally enable itself.
+///    with a matching name, [`Extension::configure`] will be applied to the
+///    request parameters. The extension may internally enable itself.
 /// 3. When sending back the response, for each extension whose
-/// [`Extension::is_enabled`] returns true, the extension name and its
-/// parameters (as returned by [`Extension::params`]) will be included in the
-/// response.
+///    [`Extension::is_enabled`] returns true, the extension name and its
+///    parameters (as returned by [`Extension::params`]) will be included in the
+///    response.
 ///
 /// # Client
 ///
 /// 1. All extensions should consider themselves as disabled but available.
 /// 2. When creating the handshake request, all extensions and its parameters
-/// (as returned by [`Extension::params`]) will be included in the request.
+///    (as returned by [`Extension::params`]) will be included in the request.
 /// 3. When receiving the response from the server, for every extension with
-/// a matching name in the response, [`Extension::configure`] will be applied
-/// to the response parameters. The extension may internally enable itself.
+///    a matching name in the response, [`Extension::configure`] will be applied
+///    to the response parameters. The extension may internally enable itself.
 ///
 /// After this handshake phase, extensions have been configured and are
 /// potentially enabled. Enabled extensions can then be used for further base
 /// frame processing.
+///
+/// # Order
+///
+/// When sending, extensions are applied in the order in which they have been
+/// added. When receiving, they are applied in reverse order, so that every
+/// extension decodes exactly what it has encoded on the remote side.
+///
+/// # Reserved bits
+///
+/// Extensions must not share reserved bits (cf. [`Extension::reserved_bits`]).
+/// Adding an extension which uses a reserved bit that is already used by
+/// another extension causes a panic. Only extensions with the same name, i.e.
+/// alternative offers of the same extension, may be added more than once
+/// during handshake.
 pub trait Extension: std::fmt::Debug {
     /// Is this extension enabled?
     fn is_enabled(&self) -> bool;
@@ -54,9 +74,21 @@ pub trait Extension: std::fmt::Debug {
     fn name(&self) -> &str;
 
     /// The parameters this extension wants to send for negotiation.
-    fn params(&self) -> &[Param];
+    fn params(&self) -> &[Param<'_>];
 
     /// Configure this extension with the parameters received from negotiation.
+    ///
+    /// The outcome of the negotiation is one of:
+    ///
+    /// - *enabled*: `Ok(())` is returned and [`Extension::is_enabled`]
+    ///   returns true afterwards.
+    /// - *declined*: `Ok(())` is returned but the extension remains disabled.
+    ///   A server should decline offers it does not support. The extension is
+    ///   then omitted from the response and not used by the connection. If
+    ///   the client has offered the extension more than once, the next offer
+    ///   will be tried.
+    /// - *fatal*: an error is returned which aborts the handshake. A client
+    ///   should return an error if it can not honor the server's response.
     fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError>;
 
     /// Encode a frame, given as frame header and payload data.
@@ -72,6 +104,25 @@ pub trait Extension: std::fmt::Debug {
     fn reserved_bits(&self) -> (bool, bool, bool) {
         (false, false, false)
     }
+
+    /// Set the max. size of a decoded message.
+    ///
+    /// A connection sets this to its max. message size. Extensions which
+    /// increase the message size when decoding, e.g. by decompressing the
+    /// payload data, should fail with [`connection::Error::MessageTooLarge`]
+    /// if this size is exceeded.
+    ///
+    /// [`connection::Error::MessageTooLarge`]: crate::connection::Error::MessageTooLarge
+    fn set_max_message_size(&mut self, _max: usize) {}
+
+    /// Access the streaming decoder of this extension, if supported.
+    ///
+    /// If all extensions of a connection support streaming, message
+    /// fragments are decoded as they arrive (cf. [`StreamingExtension`])
+    /// and [`Extension::decode`] is not used.
+    fn as_streaming(&mut self) -> Option<&mut dyn StreamingExtension> {
+        None
+    }
 }
 
 impl<E: Extension + ?Sized> Extension for Box<E> {
@@ -83,7 +134,7 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
         (**self).name()
     }
 
-    fn params(&self) -> &[Param] {
+    fn params(&self) -> &[Param<'_>] {
         (**self).params()
     }
 
@@ -102,6 +153,76 @@ impl<E: Extension + ?Sized> Extension for Box<E> {
     fn reserved_bits(&self) -> (bool, bool, bool) {
         (**self).reserved_bits()
     }
+
+    fn set_max_message_size(&mut self, max: usize) {
+        (**self).set_max_message_size(max)
+    }
+
+    fn as_streaming(&mut self) -> Option<&mut dyn StreamingExtension> {
+        (**self).as_streaming()
+    }
+}
+
+/// An extension which decodes messages fragment by fragment.
+///
+/// For every message, [`StreamingExtension::begin_message`] is called with
+/// the header of the first frame, followed by [`StreamingExtension::process_chunk`]
+/// for the payload data of every frame and finally [`StreamingExtension::end_message`]
+/// with the header of the last frame. This allows decoding large messages
+/// without having to buffer the undecoded payload data of all fragments.
+pub trait StreamingExtension {
+    /// Begin decoding a new message.
+    fn begin_message(&mut self, header: &Header) -> Result<(), BoxedError>;
+
+    /// Decode the payload data of a single frame in place.
+    ///
+    /// `is_final` is true for the last frame of the message.
+    fn process_chunk(&mut self, data: &mut Vec<u8>, is_final: bool) -> Result<(), BoxedError>;
+
+    /// Finish decoding the current message.
+    fn end_message(&mut self, header: &mut Header) -> Result<(), BoxedError>;
+}
+
+/// Decode a message with a streaming extension.
+///
+/// Streaming extensions can use this function to implement
+/// [`Extension::decode`]: The first frame of a fragmented message begins the
+/// message and the complete payload data is decoded with the last frame.
+pub fn decode_message<E>(e: &mut E, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError>
+where
+    E: StreamingExtension + ?Sized
+{
+    match (header.is_fin(), header.opcode()) {
+        (false, OpCode::Continue) => Ok(()),
+        (false, _) => e.begin_message(header),
+        (true, oc) => {
+            if oc != OpCode::Continue {
+                e.begin_message(header)?
+            }
+            e.process_chunk(data, true)?;
+            e.end_message(header)?;
+            header.set_payload_len(data.len());
+            Ok(())
+        }
+    }
+}
+
+// Assert that an extension does not use any reserved bit which is
+// already used by one of the given extensions.
+pub(crate) fn assert_no_conflict<'a, I>(extensions: I, e: &dyn Extension, allow_same_name: bool)
+where
+    I: IntoIterator<Item = &'a Box<dyn Extension + Send + Sync>>
+{
+    let (a1, a2, a3) = e.reserved_bits();
+    for other in extensions {
+        if allow_same_name && other.name().eq_ignore_ascii_case(e.name()) {
+            continue
+        }
+        let (b1, b2, b3) = other.reserved_bits();
+        for &(bit, conflict) in &[(1, a1 && b1), (2, a2 && b2), (3, a3 && b3)] {
+            assert!(!conflict, "extensions {} and {} both use RSV{}", other.name(), e.name(), bit)
+        }
+    }
 }
 
 /// Extension parameter (used for negotiation).
@@ -8,6 +8,11 @@
 
 //! Deflate compression extension mostly conformant with [RFC 7692][rfc7692].
 //!
+//! The extension is available with the `deflate` feature, which uses the C
+//! zlib library, or with the `deflate-rust` feature, which uses a pure Rust
+//! implementation. Both are compatible on the wire. If both features are
+//! enabled, zlib is used.
+//!
 //! [rfc7692]: https://tools.ietf.org/html/rfc7692
 
 use crate::{
@@ -15,11 +20,11 @@ use crate::{
     BoxedError,
     Storage,
     base::{Header, OpCode},
-    connection::Mode,
-    extension::{Extension, Param}
+    connection::{self, Mode},
+    extension::{self, Extension, Param, StreamingExtension}
 };
-use flate2::{Compress, Compression, FlushCompress, Status, write::DeflateDecoder};
-use std::{convert::TryInto, io::{self, Write}, mem};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use std::{convert::TryInto, io, mem, sync::{Arc, Mutex}};
 
 const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
 const SERVER_MAX_WINDOW_BITS: &str = "server_max_window_bits";
@@ -29,8 +34,9 @@ const CLIENT_MAX_WINDOW_BITS: &str = "client_max_window_bits";
 
 /// The deflate extension type.
 ///
-/// The extension does currently not support max. window bits other than the
-/// default, which is 15 and will ask for no context takeover during handshake.
+/// The extension supports max. window bits within 9 ..= 15 (due to zlib
+/// limitations 8 is not supported). By default a client will ask for no
+/// context takeover during handshake.
 #[derive(Debug)]
 pub struct Deflate {
     mode: Mode,
@@ -39,20 +45,85 @@ pub struct Deflate {
     params: Vec<Param<'static>>,
     our_max_window_bits: u8,
     their_max_window_bits: u8,
-    await_last_fragment: bool
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    encoder: Option<Compress>,
+    decoder: Option<Decompress>,
+    compress_threshold: usize,
+    max_decompressed_size: Option<usize>,
+    max_message_size: usize,
+    stats: Arc<Mutex<DeflateStats>>,
+    inflating: Option<Inflating>
+}
+
+/// The progress of decoding a compressed message.
+#[derive(Debug, Default)]
+struct Inflating {
+    bytes_in: u64,
+    bytes_out: usize
+}
+
+/// The parameters negotiated by a deflate extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeflateParams {
+    /// The max. window bits of the server.
+    pub server_max_window_bits: u8,
+    /// The max. window bits of the client.
+    pub client_max_window_bits: u8,
+    /// Does the server reset its compression context after every message?
+    pub server_no_context_takeover: bool,
+    /// Does the client reset its compression context after every message?
+    pub client_no_context_takeover: bool
+}
+
+/// Compression statistics of a deflate extension.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeflateStats {
+    /// Statistics of sent messages.
+    pub encoded: Counters,
+    /// Statistics of received messages.
+    pub decoded: Counters
+}
+
+/// Compression statistics of one direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    /// Number of bytes given to (de-)compression.
+    pub bytes_in: u64,
+    /// Number of bytes produced by (de-)compression.
+    pub bytes_out: u64,
+    /// Number of (de-)compressed messages.
+    pub messages_compressed: u64,
+    /// Number of messages which have not been (de-)compressed.
+    pub messages_passed_through: u64
+}
+
+/// A handle to the statistics of a deflate extension.
+///
+/// Unlike the extension itself, the handle remains usable after the
+/// extension has been given to a connection.
+#[derive(Debug, Clone)]
+pub struct StatsHandle(Arc<Mutex<DeflateStats>>);
+
+impl StatsHandle {
+    /// Get the current statistics.
+    pub fn get(&self) -> DeflateStats {
+        *self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
 }
 
 impl Deflate {
     /// Create a new deflate extension either on client or server side.
     pub fn new(mode: Mode) -> Self {
-        let params = match mode {
-            Mode::Server => Vec::new(),
+        let (params, no_context_takeover) = match mode {
+            Mode::Server => (Vec::new(), false),
             Mode::Client => {
-                let mut params = Vec::new();
-                params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER));
-                params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER));
-                params.push(Param::new(CLIENT_MAX_WINDOW_BITS));
-                params
+                let params = vec![
+                    Param::new(SERVER_NO_CONTEXT_TAKEOVER),
+                    Param::new(CLIENT_NO_CONTEXT_TAKEOVER),
+                    Param::new(CLIENT_MAX_WINDOW_BITS)
+                ];
+                (params, true)
             }
         };
         Deflate {
@@ -62,71 +133,320 @@ impl Deflate {
             params,
             our_max_window_bits: 15,
             their_max_window_bits: 15,
-            await_last_fragment: false
+            server_no_context_takeover: no_context_takeover,
+            client_no_context_takeover: no_context_takeover,
+            encoder: None,
+            decoder: None,
+            compress_threshold: 0,
+            max_decompressed_size: None,
+            max_message_size: usize::MAX,
+            stats: Arc::new(Mutex::new(DeflateStats::default())),
+            inflating: None
+        }
+    }
+
+    /// Set the min. size in bytes of messages to compress (default: 0).
+    ///
+    /// Smaller messages are sent uncompressed, as compressing them is often
+    /// not worth it and may even increase their size.
+    pub fn set_compress_threshold(&mut self, threshold: usize) {
+        self.compress_threshold = threshold
+    }
+
+    /// Set the max. size in bytes of a decompressed message.
+    ///
+    /// If a message exceeds this size while being decompressed, decoding
+    /// fails with [`connection::Error::MessageTooLarge`]. The max. message
+    /// size of a connection is used as limit too (cf.
+    /// [`connection::Builder::set_max_message_size`]).
+    ///
+    /// [`connection::Error::MessageTooLarge`]: crate::connection::Error::MessageTooLarge
+    /// [`connection::Builder::set_max_message_size`]: crate::connection::Builder::set_max_message_size
+    pub fn set_max_decompressed_size(&mut self, max: usize) {
+        self.max_decompressed_size = Some(max)
+    }
+
+    /// Should the server reset its compression context after every message?
+    ///
+    /// In client mode (default: true), this asks the server to do so. In server
+    /// mode (default: false), the server does so even if not asked to and
+    /// announces it to the client.
+    pub fn set_server_no_context_takeover(&mut self, value: bool) {
+        self.server_no_context_takeover = value;
+        if self.mode == Mode::Client {
+            self.set_param(SERVER_NO_CONTEXT_TAKEOVER, value)
+        }
+    }
+
+    /// Should the client reset its compression context after every message?
+    ///
+    /// In client mode (default: true), this informs the server that the
+    /// client does so. In server mode (default: false), this asks the client
+    /// to do so.
+    pub fn set_client_no_context_takeover(&mut self, value: bool) {
+        self.client_no_context_takeover = value;
+        if self.mode == Mode::Client {
+            self.set_param(CLIENT_NO_CONTEXT_TAKEOVER, value)
+        }
+    }
+
+    /// Has no context takeover been negotiated for the server?
+    pub fn server_no_context_takeover(&self) -> bool {
+        self.server_no_context_takeover
+    }
+
+    /// Has no context takeover been negotiated for the client?
+    pub fn client_no_context_takeover(&self) -> bool {
+        self.client_no_context_takeover
+    }
+
+    /// The negotiated parameters, if the extension is enabled.
+    pub fn negotiated_params(&self) -> Option<DeflateParams> {
+        if !self.enabled {
+            return None
+        }
+        Some(DeflateParams {
+            server_max_window_bits: self.server_window_bits(),
+            client_max_window_bits: self.client_window_bits(),
+            server_no_context_takeover: self.server_no_context_takeover,
+            client_no_context_takeover: self.client_no_context_takeover
+        })
+    }
+
+    /// The current compression statistics.
+    pub fn stats(&self) -> DeflateStats {
+        self.stats_handle().get()
+    }
+
+    /// Get a handle to the compression statistics.
+    pub fn stats_handle(&self) -> StatsHandle {
+        StatsHandle(self.stats.clone())
+    }
+
+    // Update the compression statistics.
+    fn update_stats(&self, f: impl FnOnce(&mut DeflateStats)) {
+        f(&mut self.stats.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    // Add or remove a parameter without value.
+    fn set_param(&mut self, name: &'static str, value: bool) {
+        let present = self.params.iter().any(|p| p.name() == name);
+        if value && !present {
+            self.params.push(Param::new(name))
+        } else if !value {
+            self.params.retain(|p| p.name() != name)
+        }
+    }
+
+    // Should we reset our compression context after every message?
+    fn our_no_context_takeover(&self) -> bool {
+        match self.mode {
+            Mode::Client => self.client_no_context_takeover,
+            Mode::Server => self.server_no_context_takeover
+        }
+    }
+
+    // Does the remote reset its compression context after every message?
+    fn their_no_context_takeover(&self) -> bool {
+        match self.mode {
+            Mode::Client => self.server_no_context_takeover,
+            Mode::Server => self.client_no_context_takeover
         }
     }
 
     /// Set the server's max. window bits.
     ///
     /// The value must be within 9 ..= 15.
-    /// The extension must be in client mode.
     ///
-    /// By including this parameter, a client limits the LZ77 sliding window
-    /// size that the server will use to compress messages. A server accepts
-    /// by including the "server_max_window_bits" extension parameter in the
-    /// response with the same or smaller value as the offer.
+    /// In client mode, by including this parameter, a client limits the LZ77
+    /// sliding window size that the server will use to compress messages. A
+    /// server accepts by including the "server_max_window_bits" extension
+    /// parameter in the response with the same or smaller value as the offer.
+    ///
+    /// In server mode, this limits the sliding window size the server uses
+    /// itself. The limit is announced to the client in the response.
     pub fn set_max_server_window_bits(&mut self, max: u8) {
-        assert!(self.mode == Mode::Client, "setting max. server window bits requires client mode");
         assert!(max > 8 && max <= 15, "max. server window bits have to be within 9 ..= 15");
-        self.their_max_window_bits = max; // upper bound of the server's window
-        let mut p = Param::new(SERVER_MAX_WINDOW_BITS);
-        p.set_value(Some(max.to_string()));
-        self.params.push(p)
+        match self.mode {
+            Mode::Client => {
+                self.their_max_window_bits = max; // upper bound of the server's window
+                let mut p = Param::new(SERVER_MAX_WINDOW_BITS);
+                p.set_value(Some(max.to_string()));
+                self.params.push(p)
+            }
+            Mode::Server => self.our_max_window_bits = max
+        }
     }
 
     /// Set the client's max. window bits.
     ///
     /// The value must be within 9 ..= 15.
-    /// The extension must be in client mode.
     ///
-    /// The parameter informs the server that even if it doesn't include the
-    /// "client_max_window_bits" extension parameter in the response with a
-    /// value greater than the one in the negotiation offer or if it doesn't
-    /// include the extension parameter at all, the client is not going to
-    /// use an LZ77 sliding window size greater than one given here.
-    /// The server may also respond with a smaller value which allows the client
-    /// to reduce its sliding window even more.
+    /// In client mode, the parameter informs the server that even if it
+    /// doesn't include the "client_max_window_bits" extension parameter in
+    /// the response with a value greater than the one in the negotiation
+    /// offer or if it doesn't include the extension parameter at all, the
+    /// client is not going to use an LZ77 sliding window size greater than
+    /// one given here. The server may also respond with a smaller value which
+    /// allows the client to reduce its sliding window even more.
+    ///
+    /// In server mode, this limits the sliding window size the client may
+    /// use, provided the client's offer allows for it.
     pub fn set_max_client_window_bits(&mut self, max: u8) {
-        assert!(self.mode == Mode::Client, "setting max. client window bits requires client mode");
         assert!(max > 8 && max <= 15, "max. client window bits have to be within 9 ..= 15");
-        self.our_max_window_bits = max; // upper bound of the client's window
-        if let Some(p) = self.params.iter_mut().find(|p| p.name() == CLIENT_MAX_WINDOW_BITS) {
-            p.set_value(Some(max.to_string()));
-        } else {
-            let mut p = Param::new(CLIENT_MAX_WINDOW_BITS);
-            p.set_value(Some(max.to_string()));
-            self.params.push(p)
+        match self.mode {
+            Mode::Client => {
+                self.our_max_window_bits = max; // upper bound of the client's window
+                if let Some(p) = self.params.iter_mut().find(|p| p.name() == CLIENT_MAX_WINDOW_BITS) {
+                    p.set_value(Some(max.to_string()));
+                } else {
+                    let mut p = Param::new(CLIENT_MAX_WINDOW_BITS);
+                    p.set_value(Some(max.to_string()));
+                    self.params.push(p)
+                }
+            }
+            Mode::Server => self.their_max_window_bits = max
         }
     }
 
-    fn set_their_max_window_bits(&mut self, p: &Param, expected: Option<u8>) -> Result<(), ()> {
-        if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-            if v < 8 || v > 15 {
-                log::debug!("invalid {}: {} (expected range: 8 ..= 15)", p.name(), v);
-                return Err(())
-            }
-            if let Some(x) = expected {
-                if v > x {
-                    log::debug!("invalid {}: {} (expected: {} <= {})", p.name(), v, v, x);
-                    return Err(())
+    /// The negotiated max. window bits of the server.
+    pub fn server_window_bits(&self) -> u8 {
+        match self.mode {
+            Mode::Client => self.their_max_window_bits,
+            Mode::Server => self.our_max_window_bits
+        }
+    }
+
+    /// The negotiated max. window bits of the client.
+    pub fn client_window_bits(&self) -> u8 {
+        match self.mode {
+            Mode::Client => self.our_max_window_bits,
+            Mode::Server => self.their_max_window_bits
+        }
+    }
+
+    // Configure the server with the parameters of a client offer.
+    //
+    // If the offer is not acceptable, the extension remains disabled.
+    fn configure_server(&mut self, params: &[Param]) {
+        if self.enabled {
+            log::debug!("{}: ignoring offer; already accepted one", self.name());
+            return
+        }
+        let mut response = Vec::new();
+        let mut our_max = self.our_max_window_bits;
+        let mut their_max = 15;
+        let mut server_max_offered = false;
+        let mut client_max_offered = false;
+        let mut server_no_context_takeover = self.server_no_context_takeover;
+        let mut client_no_context_takeover = self.client_no_context_takeover;
+        for p in params {
+            log::trace!("configure server with: {}", p);
+            match p.name() {
+                CLIENT_MAX_WINDOW_BITS => {
+                    if p.value().is_some() {
+                        if let Some(v) = window_bits(p) {
+                            their_max = v
+                        } else {
+                            return
+                        }
+                    }
+                    client_max_offered = true
                 }
+                SERVER_MAX_WINDOW_BITS =>
+                    match window_bits(p) {
+                        Some(8) => {
+                            // The RFC allows 8 to 15 bits, but due to zlib
+                            // limitations we only support 9 to 15.
+                            log::debug!("unacceptable server_max_window_bits: 8");
+                            return
+                        }
+                        Some(v) => {
+                            our_max = std::cmp::min(our_max, v);
+                            server_max_offered = true
+                        }
+                        None => return
+                    }
+                CLIENT_NO_CONTEXT_TAKEOVER => client_no_context_takeover = true,
+                SERVER_NO_CONTEXT_TAKEOVER => server_no_context_takeover = true,
+                _ => {
+                    log::debug!("{}: unknown parameter: {}", self.name(), p.name());
+                    return
+                }
+            }
+        }
+        if client_no_context_takeover {
+            response.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER))
+        }
+        if server_no_context_takeover {
+            response.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER))
+        }
+        if server_max_offered || our_max < 15 {
+            let mut p = Param::new(SERVER_MAX_WINDOW_BITS);
+            p.set_value(Some(our_max.to_string()));
+            response.push(p)
+        }
+        if client_max_offered && self.their_max_window_bits < their_max {
+            their_max = self.their_max_window_bits;
+            let mut p = Param::new(CLIENT_MAX_WINDOW_BITS);
+            p.set_value(Some(their_max.to_string()));
+            response.push(p)
+        }
+        self.params = response;
+        self.our_max_window_bits = our_max;
+        // A window of 8 bits can be decoded with 9 bits which zlib supports.
+        self.their_max_window_bits = std::cmp::max(9, their_max);
+        self.server_no_context_takeover = server_no_context_takeover;
+        self.client_no_context_takeover = client_no_context_takeover;
+        self.enabled = true
+    }
+
+    // Configure the client with the parameters of the server response.
+    //
+    // Parameters the client can not honor result in an error.
+    fn configure_client(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+        let mut server_no_context_takeover = false;
+        for p in params {
+            log::trace!("configure client with: {}", p);
+            match p.name() {
+                SERVER_NO_CONTEXT_TAKEOVER => server_no_context_takeover = true,
+                CLIENT_NO_CONTEXT_TAKEOVER => self.client_no_context_takeover = true,
+                SERVER_MAX_WINDOW_BITS =>
+                    match window_bits(p) {
+                        Some(v) if v <= self.their_max_window_bits =>
+                            self.their_max_window_bits = std::cmp::max(9, v),
+                        _ => return Err(format!("invalid {}: {:?}", p.name(), p.value()).into())
+                    }
+                CLIENT_MAX_WINDOW_BITS =>
+                    match window_bits(p) {
+                        // Due to zlib limitations we can not use 8 bits.
+                        Some(v) if v > 8 =>
+                            self.our_max_window_bits = std::cmp::min(self.our_max_window_bits, v),
+                        _ => return Err(format!("unsupported {}: {:?}", p.name(), p.value()).into())
+                    }
+                _ => return Err(format!("unknown parameter: {}", p.name()).into())
             }
-            self.their_max_window_bits = std::cmp::max(9, v);
         }
+        if self.server_no_context_takeover && !server_no_context_takeover {
+            log::debug!("{}: server did not confirm no context takeover", self.name())
+        }
+        self.server_no_context_takeover = server_no_context_takeover;
+        self.enabled = true;
         Ok(())
     }
 }
 
+/// Parse the value of a window bits parameter (valid range: 8 ..= 15).
+fn window_bits(p: &Param) -> Option<u8> {
+    match p.value().map(|s| s.parse::<u8>()) {
+        Some(Ok(v)) if (8 ..= 15).contains(&v) => Some(v),
+        _ => {
+            log::debug!("invalid {}: {:?} (expected range: 8 ..= 15)", p.name(), p.value());
+            None
+        }
+    }
+}
+
 impl Extension for Deflate {
     fn name(&self) -> &str {
         "permessage-deflate"
@@ -136,154 +456,63 @@ impl Extension for Deflate {
         self.enabled
     }
 
-    fn params(&self) -> &[Param] {
+    fn params(&self) -> &[Param<'_>] {
         &self.params
     }
 
     fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
         match self.mode {
             Mode::Server => {
-                self.params.clear();
-                for p in params {
-                    log::trace!("configure server with: {}", p);
-                    match p.name() {
-                        CLIENT_MAX_WINDOW_BITS =>
-                            if self.set_their_max_window_bits(&p, None).is_err() {
-                                // we just accept the client's offer as is => no need to reply
-                                return Ok(())
-                            }
-                        SERVER_MAX_WINDOW_BITS => {
-                            if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-                                // The RFC allows 8 to 15 bits, but due to zlib limitations we
-                                // only support 9 to 15.
-                                if v < 9 || v > 15 {
-                                    log::debug!("unacceptable server_max_window_bits: {}", v);
-                                    return Ok(())
-                                }
-                                let mut x = Param::new(SERVER_MAX_WINDOW_BITS);
-                                x.set_value(Some(v.to_string()));
-                                self.params.push(x);
-                                self.our_max_window_bits = v;
-                            } else {
-                                log::debug!("invalid server_max_window_bits: {:?}", p.value());
-                                return Ok(())
-                            }
-                        }
-                        CLIENT_NO_CONTEXT_TAKEOVER =>
-                            self.params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER)),
-                        SERVER_NO_CONTEXT_TAKEOVER =>
-                            self.params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER)),
-                        _ => {
-                            log::debug!("{}: unknown parameter: {}", self.name(), p.name());
-                            return Ok(())
-                        }
-                    }
-                }
-            }
-            Mode::Client => {
-                let mut server_no_context_takeover = false;
-                for p in params {
-                    log::trace!("configure client with: {}", p);
-                    match p.name() {
-                        SERVER_NO_CONTEXT_TAKEOVER => server_no_context_takeover = true,
-                        CLIENT_NO_CONTEXT_TAKEOVER => {} // must be supported
-                        SERVER_MAX_WINDOW_BITS => {
-                            let expected = Some(self.their_max_window_bits);
-                            if self.set_their_max_window_bits(&p, expected).is_err() {
-                                return Ok(())
-                            }
-                        }
-                        CLIENT_MAX_WINDOW_BITS =>
-                            if let Some(Ok(v)) = p.value().map(|s| s.parse::<u8>()) {
-                                if v < 8 || v > 15 {
-                                    log::debug!("unacceptable client_max_window_bits: {}", v);
-                                    return Ok(())
-                                }
-                                use std::cmp::{min, max};
-                                // Due to zlib limitations we have to use 9 as a lower bound
-                                // here, even if the server allowed us to go down to 8 bits.
-                                self.our_max_window_bits = min(self.our_max_window_bits, max(9, v));
-                            }
-                        _ => {
-                            log::debug!("{}: unknown parameter: {}", self.name(), p.name());
-                            return Ok(())
-                        }
-                    }
-                }
-                if !server_no_context_takeover {
-                    log::debug!("{}: server did not confirm no context takeover", self.name());
-                    return Ok(())
-                }
+                self.configure_server(params);
+                Ok(())
             }
+            Mode::Client => self.configure_client(params)
         }
-        self.enabled = true;
-        Ok(())
     }
 
     fn reserved_bits(&self) -> (bool, bool, bool) {
         (true, false, false)
     }
 
-    fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
-        if data.is_empty() {
-            return Ok(())
-        }
-
-        match header.opcode() {
-            OpCode::Binary | OpCode::Text if header.is_rsv1() => {
-                if !header.is_fin() {
-                    self.await_last_fragment = true;
-                    log::trace!("deflate: not decoding {}; awaiting last fragment", header);
-                    return Ok(())
-                }
-                log::trace!("deflate: decoding {}", header)
-            }
-            OpCode::Continue if header.is_fin() && self.await_last_fragment => {
-                self.await_last_fragment = false;
-                log::trace!("deflate: decoding {}", header)
-            }
-            _ => {
-                log::trace!("deflate: not decoding {}", header);
-                return Ok(())
-            }
-        }
-
-        // Restore LEN and NLEN:
-        data.extend_from_slice(&[0, 0, 0xFF, 0xFF]); // cf. RFC 7692, 7.2.2
-
-        self.buffer.clear();
-        let mut decoder = DeflateDecoder::new(&mut self.buffer);
-        decoder.write_all(&data)?;
-        decoder.finish()?;
-        mem::swap(data, &mut self.buffer);
+    fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max
+    }
 
-        header.set_rsv1(false);
-        header.set_payload_len(data.len());
+    fn as_streaming(&mut self) -> Option<&mut dyn StreamingExtension> {
+        Some(self)
+    }
 
-        Ok(())
+    fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+        extension::decode_message(self, header, data)
     }
 
     fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
-        if data.as_ref().is_empty() {
+        if !matches!(header.opcode(), OpCode::Binary | OpCode::Text) {
+            log::trace!("deflate: not encoding {}", header);
             return Ok(())
         }
 
-        if let OpCode::Binary | OpCode::Text = header.opcode() {
-            log::trace!("deflate: encoding {}", header)
-        } else {
-            log::trace!("deflate: not encoding {}", header);
+        if data.as_ref().is_empty() || data.as_ref().len() < self.compress_threshold {
+            log::trace!("deflate: not encoding {}; below threshold", header);
+            self.update_stats(|s| s.encoded.messages_passed_through += 1);
             return Ok(())
         }
 
+        log::trace!("deflate: encoding {}", header);
+
         self.buffer.clear();
         self.buffer.reserve(data.as_ref().len());
 
-        let mut encoder =
-            Compress::new_with_window_bits(Compression::fast(), false, self.our_max_window_bits);
+        let window_bits = self.our_max_window_bits;
+        let reset = self.our_no_context_takeover();
+        let encoder = self.encoder.get_or_insert_with(|| {
+            Compress::new_with_window_bits(Compression::fast(), false, window_bits)
+        });
+        let start = encoder.total_in();
 
         // Compress all input bytes.
-        while encoder.total_in() < as_u64(data.as_ref().len()) {
-            let i: usize = encoder.total_in().try_into()?;
+        while encoder.total_in() - start < as_u64(data.as_ref().len()) {
+            let i: usize = (encoder.total_in() - start).try_into()?;
             match encoder.compress_vec(&data.as_ref()[i ..], &mut self.buffer, FlushCompress::None)? {
                 Status::BufError => self.buffer.reserve(4096),
                 Status::Ok => continue,
@@ -304,11 +533,22 @@ impl Extension for Deflate {
         // If we still have not seen the empty deflate block appended, something is wrong.
         if !self.buffer.ends_with(&[0, 0, 0xFF, 0xFF]) {
             log::error!("missing 00 00 FF FF");
-            return Err(io::Error::new(io::ErrorKind::Other, "missing 00 00 FF FF").into())
+            return Err(io::Error::other("missing 00 00 FF FF").into())
         }
 
         self.buffer.truncate(self.buffer.len() - 4); // Remove 00 00 FF FF; cf. RFC 7692, 7.2.1
 
+        if reset {
+            encoder.reset()
+        }
+
+        let (n, m) = (as_u64(data.as_ref().len()), as_u64(self.buffer.len()));
+        self.update_stats(|s| {
+            s.encoded.bytes_in += n;
+            s.encoded.bytes_out += m;
+            s.encoded.messages_compressed += 1
+        });
+
         if let Storage::Owned(d) = data {
             mem::swap(d, &mut self.buffer)
         } else {
@@ -320,3 +560,775 @@ impl Extension for Deflate {
     }
 }
 
+impl StreamingExtension for Deflate {
+    fn begin_message(&mut self, header: &Header) -> Result<(), BoxedError> {
+        if header.is_rsv1() && matches!(header.opcode(), OpCode::Binary | OpCode::Text) {
+            log::trace!("deflate: decoding {}", header);
+            self.inflating = Some(Inflating::default())
+        } else {
+            log::trace!("deflate: not decoding {}", header);
+            self.inflating = None
+        }
+        Ok(())
+    }
+
+    fn process_chunk(&mut self, data: &mut Vec<u8>, is_final: bool) -> Result<(), BoxedError> {
+        let maximum = self.max_decompressed_size.map_or(self.max_message_size, |m| m.min(self.max_message_size));
+        let inflating = if let Some(i) = &mut self.inflating { i } else { return Ok(()) };
+
+        inflating.bytes_in += as_u64(data.len());
+
+        if is_final {
+            // Restore LEN and NLEN:
+            data.extend_from_slice(&[0, 0, 0xFF, 0xFF]) // cf. RFC 7692, 7.2.2
+        }
+
+        if data.is_empty() {
+            return Ok(())
+        }
+
+        let remaining = maximum.saturating_sub(inflating.bytes_out);
+
+        self.buffer.clear();
+        self.buffer.reserve(std::cmp::min(2 * data.len(), remaining.saturating_add(1)));
+
+        let window_bits = self.their_max_window_bits;
+        let decoder = self.decoder.get_or_insert_with(|| Decompress::new_with_window_bits(false, window_bits));
+        let start = decoder.total_in();
+
+        // Decompress all input bytes.
+        loop {
+            let i: usize = (decoder.total_in() - start).try_into()?;
+            let n = self.buffer.len();
+            decoder.decompress_vec(&data[i ..], &mut self.buffer, FlushDecompress::Sync)?;
+            if self.buffer.len() > remaining {
+                log::debug!("deflate: decompressed message exceeds {} bytes", maximum);
+                let current = inflating.bytes_out.saturating_add(self.buffer.len());
+                self.decoder = None;
+                self.inflating = None;
+                let e = connection::Error::MessageTooLarge { current, maximum };
+                return Err(e.into())
+            }
+            let buffer_full = self.buffer.len() == self.buffer.capacity();
+            if decoder.total_in() - start == as_u64(data.len()) && !buffer_full {
+                break
+            }
+            if buffer_full {
+                let additional = std::cmp::max(4096, self.buffer.len());
+                self.buffer.reserve_exact(std::cmp::min(additional, (remaining - self.buffer.len()).saturating_add(1)))
+            } else if decoder.total_in() - start == as_u64(i) && self.buffer.len() == n {
+                return Err(io::Error::other("deflate: no progress while decoding").into())
+            }
+        }
+
+        inflating.bytes_out += self.buffer.len();
+        mem::swap(data, &mut self.buffer);
+        Ok(())
+    }
+
+    fn end_message(&mut self, header: &mut Header) -> Result<(), BoxedError> {
+        if let Some(inflating) = self.inflating.take() {
+            if self.their_no_context_takeover() {
+                if let Some(decoder) = &mut self.decoder {
+                    decoder.reset(false)
+                }
+            }
+            self.update_stats(|s| {
+                s.decoded.bytes_in += inflating.bytes_in;
+                s.decoded.bytes_out += as_u64(inflating.bytes_out);
+                s.decoded.messages_compressed += 1
+            });
+            header.set_rsv1(false);
+        } else {
+            self.update_stats(|s| s.decoded.messages_passed_through += 1)
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{base::OpCode, connection::Mode, extension::{Extension, Param}};
+    use super::Deflate;
+
+    fn param(name: &'static str, value: Option<&str>) -> Param<'static> {
+        let mut p = Param::new(name);
+        p.set_value(value.map(String::from));
+        p
+    }
+
+    fn params(d: &Deflate) -> Vec<String> {
+        d.params().iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn server_window_bits_negotiation() {
+        // Client may use any window, server uses the default.
+        let mut d = Deflate::new(Mode::Server);
+        d.configure(&[param("client_max_window_bits", None)]).unwrap();
+        assert!(d.is_enabled());
+        assert!(params(&d).is_empty());
+        assert_eq!((15, 15), (d.server_window_bits(), d.client_window_bits()));
+
+        // Client limits its own window.
+        let mut d = Deflate::new(Mode::Server);
+        d.configure(&[param("client_max_window_bits", Some("10"))]).unwrap();
+        assert!(d.is_enabled());
+        assert!(params(&d).is_empty());
+        assert_eq!((15, 10), (d.server_window_bits(), d.client_window_bits()));
+
+        // Client limits the server's window.
+        let mut d = Deflate::new(Mode::Server);
+        d.configure(&[param("server_max_window_bits", Some("10"))]).unwrap();
+        assert!(d.is_enabled());
+        assert_eq!(vec!["server_max_window_bits = 10"], params(&d));
+        assert_eq!((10, 15), (d.server_window_bits(), d.client_window_bits()));
+
+        // Server limits its own window.
+        let mut d = Deflate::new(Mode::Server);
+        d.set_max_server_window_bits(12);
+        d.configure(&[param("server_max_window_bits", Some("13"))]).unwrap();
+        assert_eq!(vec!["server_max_window_bits = 12"], params(&d));
+        let mut d = Deflate::new(Mode::Server);
+        d.set_max_server_window_bits(12);
+        d.configure(&[]).unwrap();
+        assert_eq!(vec!["server_max_window_bits = 12"], params(&d));
+
+        // Server limits the client's window if the client allows it.
+        let mut d = Deflate::new(Mode::Server);
+        d.set_max_client_window_bits(11);
+        d.configure(&[param("client_max_window_bits", None)]).unwrap();
+        assert_eq!(vec!["client_max_window_bits = 11"], params(&d));
+        assert_eq!(11, d.client_window_bits());
+        let mut d = Deflate::new(Mode::Server);
+        d.set_max_client_window_bits(11);
+        d.configure(&[]).unwrap();
+        assert!(params(&d).is_empty());
+        assert_eq!(15, d.client_window_bits());
+
+        // A client window of 8 bits can be decoded with 9 bits.
+        let mut d = Deflate::new(Mode::Server);
+        d.configure(&[param("client_max_window_bits", Some("8"))]).unwrap();
+        assert!(d.is_enabled());
+        assert_eq!(9, d.client_window_bits());
+
+        // Unsupported or invalid values decline the offer.
+        for v in &[Some("8"), Some("16"), Some("x"), None] {
+            let mut d = Deflate::new(Mode::Server);
+            d.configure(&[param("server_max_window_bits", *v)]).unwrap();
+            assert!(!d.is_enabled())
+        }
+        let mut d = Deflate::new(Mode::Server);
+        d.configure(&[param("client_max_window_bits", Some("7"))]).unwrap();
+        assert!(!d.is_enabled())
+    }
+
+    #[test]
+    fn client_window_bits_negotiation() {
+        let confirm = || param("server_no_context_takeover", None);
+
+        let mut d = Deflate::new(Mode::Client);
+        d.configure(&[confirm()]).unwrap();
+        assert!(d.is_enabled());
+        assert_eq!((15, 15), (d.server_window_bits(), d.client_window_bits()));
+
+        let mut d = Deflate::new(Mode::Client);
+        d.set_max_client_window_bits(12);
+        d.configure(&[confirm(), param("server_max_window_bits", Some("10"))]).unwrap();
+        assert!(d.is_enabled());
+        assert_eq!((10, 12), (d.server_window_bits(), d.client_window_bits()));
+
+        let mut d = Deflate::new(Mode::Client);
+        d.set_max_client_window_bits(12);
+        d.configure(&[confirm(), param("client_max_window_bits", Some("9"))]).unwrap();
+        assert_eq!(9, d.client_window_bits());
+
+        // The server must not exceed our offer.
+        let mut d = Deflate::new(Mode::Client);
+        d.set_max_server_window_bits(10);
+        assert!(d.configure(&[confirm(), param("server_max_window_bits", Some("11"))]).is_err());
+
+        // We can not honor a client window of 8 bits.
+        let mut d = Deflate::new(Mode::Client);
+        assert!(d.configure(&[confirm(), param("client_max_window_bits", Some("8"))]).is_err());
+        let mut d = Deflate::new(Mode::Client);
+        assert!(d.configure(&[confirm(), param("client_max_window_bits", None)]).is_err())
+    }
+
+    #[test]
+    fn asymmetric_window_bits() {
+        use crate::{handshake::{Client, Server, ServerResponse}, mock::duplex};
+        use futures::{executor::block_on, future};
+
+        // Repetitions at a distance larger than the smallest window.
+        let data: Vec<u8> = (0 .. 8192u32).map(|i| ((i % 1000) * 7919 % 251) as u8).collect();
+
+        let (a, b) = duplex(1024);
+
+        let client = async {
+            let mut client = Client::new(a, "example.com", "/");
+            let mut deflate = Deflate::new(Mode::Client);
+            deflate.set_max_server_window_bits(10);
+            deflate.set_max_client_window_bits(12);
+            client.add_extension(Box::new(deflate));
+            client.record_response_headers(true);
+            match client.handshake().await.unwrap() {
+                ServerResponse::Accepted { headers, .. } => {
+                    let (_, value) = headers.iter()
+                        .find(|(n, _)| n.eq_ignore_ascii_case("Sec-WebSocket-Extensions"))
+                        .unwrap();
+                    let value = std::str::from_utf8(value).unwrap();
+                    assert!(value.contains("server_max_window_bits=9"));
+                    assert!(value.contains("client_max_window_bits=11"))
+                }
+                other => panic!("unexpected response: {:?}", other)
+            }
+            let (mut sender, mut receiver) = client.into_builder().finish();
+            sender.send_binary(&data).await.unwrap();
+            sender.flush().await.unwrap();
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await.unwrap();
+            assert_eq!(data, message)
+        };
+
+        let server = async {
+            let mut server = Server::new(b);
+            let mut deflate = Deflate::new(Mode::Server);
+            deflate.set_max_server_window_bits(9);
+            deflate.set_max_client_window_bits(11);
+            server.add_extension(Box::new(deflate));
+            let request = server.receive_request().await.unwrap();
+            server.send_response(&request.accept()).await.unwrap();
+            let (mut sender, mut receiver) = server.into_builder().finish();
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await.unwrap();
+            sender.send_binary(&message).await.unwrap();
+            sender.flush().await.unwrap()
+        };
+
+        block_on(future::join(client, server));
+    }
+
+    #[test]
+    fn no_context_takeover_negotiation() {
+        // By default the client asks for no context takeover on both sides.
+        let mut d = Deflate::new(Mode::Server);
+        d.configure(Deflate::new(Mode::Client).params()).unwrap();
+        assert!(d.server_no_context_takeover() && d.client_no_context_takeover());
+        assert_eq! {
+            vec!["client_no_context_takeover", "server_no_context_takeover"],
+            params(&d)
+        }
+
+        // A server may reset its context even if not asked to.
+        let mut c = Deflate::new(Mode::Client);
+        c.set_server_no_context_takeover(false);
+        c.set_client_no_context_takeover(false);
+        assert_eq!(vec!["client_max_window_bits"], params(&c));
+        let mut d = Deflate::new(Mode::Server);
+        d.set_server_no_context_takeover(true);
+        d.configure(c.params()).unwrap();
+        assert_eq!(vec!["server_no_context_takeover"], params(&d));
+        c.configure(d.params()).unwrap();
+        assert!(c.is_enabled());
+        assert!(c.server_no_context_takeover() && !c.client_no_context_takeover());
+
+        // A server may ask the client to reset its context.
+        let mut c = Deflate::new(Mode::Client);
+        c.set_client_no_context_takeover(false);
+        let mut d = Deflate::new(Mode::Server);
+        d.set_client_no_context_takeover(true);
+        d.configure(c.params()).unwrap();
+        c.configure(d.params()).unwrap();
+        assert!(c.server_no_context_takeover() && c.client_no_context_takeover())
+    }
+
+    #[test]
+    fn context_takeover_round_trip() {
+        use crate::{handshake::{Client, Server}, mock::duplex};
+        use futures::{executor::block_on, future};
+
+        let messages: Vec<String> = (0 .. 5).map(|i| format!("{{\"id\":{},\"result\":\"hello world\"}}", i)).collect();
+
+        for &(client_nct, server_nct) in &[(false, false), (true, false), (false, true), (true, true)] {
+            let (a, b) = duplex(1024);
+
+            let client = async {
+                let mut client = Client::new(a, "example.com", "/");
+                let mut deflate = Deflate::new(Mode::Client);
+                deflate.set_client_no_context_takeover(client_nct);
+                deflate.set_server_no_context_takeover(server_nct);
+                client.add_extension(Box::new(deflate));
+                client.handshake().await.unwrap();
+                let (mut sender, mut receiver) = client.into_builder().finish();
+                for m in &messages {
+                    sender.send_text(m).await.unwrap();
+                    sender.flush().await.unwrap();
+                    let mut message = Vec::new();
+                    receiver.receive_data(&mut message).await.unwrap();
+                    assert_eq!(m.as_bytes(), &message[..])
+                }
+            };
+
+            let server = async {
+                let mut server = Server::new(b);
+                server.add_extension(Box::new(Deflate::new(Mode::Server)));
+                let request = server.receive_request().await.unwrap();
+                server.send_response(&request.accept()).await.unwrap();
+                let (mut sender, mut receiver) = server.into_builder().finish();
+                for _ in 0 .. messages.len() {
+                    let mut message = Vec::new();
+                    receiver.receive_data(&mut message).await.unwrap();
+                    sender.send_text(std::str::from_utf8(&message).unwrap()).await.unwrap();
+                    sender.flush().await.unwrap()
+                }
+            };
+
+            block_on(future::join(client, server));
+        }
+    }
+
+    #[test]
+    fn compress_threshold() {
+        use crate::{handshake::{Client, Server}, mock::duplex};
+        use futures::{executor::block_on, future, io::AsyncReadExt};
+
+        let (a, b) = duplex(1024);
+
+        let client = async {
+            let mut client = Client::new(a, "example.com", "/");
+            let mut deflate = Deflate::new(Mode::Client);
+            deflate.set_compress_threshold(16);
+            client.add_extension(Box::new(deflate));
+            client.handshake().await.unwrap();
+            let mut builder = client.into_builder();
+            builder.set_mask_source(|| [0; 4]);
+            let (mut sender, _) = builder.finish();
+            sender.send_text("ok").await.unwrap();
+            sender.send_binary(&[0; 64][..]).await.unwrap();
+            sender.send_text("ok").await.unwrap();
+            sender.flush().await.unwrap()
+        };
+
+        let server = async {
+            let mut server = Server::new(b);
+            server.add_extension(Box::new(Deflate::new(Mode::Server)));
+            let request = server.receive_request().await.unwrap();
+            server.send_response(&request.accept()).await.unwrap();
+            let mut socket = server.into_inner();
+            let tiny = [0x81, 0x82, 0, 0, 0, 0, b'o', b'k'];
+            let mut frame = [0; 8];
+            socket.read_exact(&mut frame).await.unwrap();
+            assert_eq!(tiny, frame);
+            let mut frame = [0; 2];
+            socket.read_exact(&mut frame).await.unwrap();
+            assert_eq!(0xC2, frame[0]); // compressed binary frame (RSV1 set)
+            let mut payload = vec![0; 4 + usize::from(frame[1] & 0x7F)];
+            socket.read_exact(&mut payload).await.unwrap();
+            let mut frame = [0; 8];
+            socket.read_exact(&mut frame).await.unwrap();
+            assert_eq!(tiny, frame)
+        };
+
+        block_on(future::join(client, server));
+    }
+
+    #[test]
+    fn decompression_bomb() {
+        use crate::{Storage, base::{Codec, Header, OpCode}, connection::{Builder, Error}, mock::duplex};
+        use futures::{executor::block_on, io::{AsyncReadExt, AsyncWriteExt}};
+
+        // 1 MiB of zeros compresses to about 1 KiB.
+        let mut client = Deflate::new(Mode::Client);
+        client.configure(&[param("server_no_context_takeover", None)]).unwrap();
+        let mut header = Header::new(OpCode::Binary);
+        let mut data = Storage::Owned(vec![0; 1024 * 1024]);
+        client.encode(&mut header, &mut data).unwrap();
+        assert!(data.as_ref().len() < 64 * 1024);
+        header.set_masked(true);
+        header.set_mask(0);
+        header.set_payload_len(data.as_ref().len());
+
+        let (mut a, b) = duplex(1024);
+        let mut server = Deflate::new(Mode::Server);
+        server.configure(&[]).unwrap();
+        let mut builder = Builder::new(b, Mode::Server);
+        builder.add_extensions(vec![Box::new(server) as Box<_>]);
+        builder.set_max_message_size(64 * 1024);
+        let (_sender, mut receiver) = builder.finish();
+
+        block_on(async {
+            a.write_all(Codec::default().encode_header(&header)).await.unwrap();
+            a.write_all(data.as_ref()).await.unwrap();
+            let mut message = Vec::new();
+            match receiver.receive_data(&mut message).await {
+                Err(Error::MessageTooLarge { maximum, .. }) => assert_eq!(64 * 1024, maximum),
+                other => panic!("unexpected result: {:?}", other)
+            }
+            let mut close = Vec::new();
+            a.read_to_end(&mut close).await.unwrap();
+            assert_eq!(vec![0x88, 0x02, 0x03, 0xF1], close) // 1009
+        })
+    }
+
+    /// Compress a JSON text and return the text and the compressed payload data.
+    fn compressed_text() -> (String, Vec<u8>) {
+        use crate::{Storage, base::{Header, OpCode}};
+        let text: String = (0 .. 500).map(|i| format!("{{\"id\":{},\"result\":\"hello world\"}}", i)).collect();
+        let mut client = Deflate::new(Mode::Client);
+        client.configure(&[]).unwrap();
+        let mut header = Header::new(OpCode::Text);
+        let mut data = Storage::Owned(text.clone().into_bytes());
+        client.encode(&mut header, &mut data).unwrap();
+        let compressed = data.as_ref().to_vec();
+        (text, compressed)
+    }
+
+    /// Write the compressed payload data as a text message of three frames.
+    async fn write_fragmented(socket: &mut crate::mock::Endpoint, compressed: &[u8], rsv1_on_all: bool) {
+        use crate::base::{Codec, Header, OpCode};
+        use futures::io::AsyncWriteExt;
+        let mut codec = Codec::default();
+        let chunks = compressed.chunks(compressed.len() / 3 + 1).collect::<Vec<_>>();
+        assert_eq!(3, chunks.len());
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut header = Header::new(if i == 0 { OpCode::Text } else { OpCode::Continue });
+            header.set_rsv1(i == 0 || rsv1_on_all);
+            header.set_fin(i == chunks.len() - 1);
+            header.set_masked(true);
+            header.set_mask(0);
+            header.set_payload_len(chunk.len());
+            socket.write_all(codec.encode_header(&header)).await.unwrap();
+            socket.write_all(chunk).await.unwrap()
+        }
+    }
+
+    /// A server-side deflate extension which has been enabled.
+    fn enabled_server() -> Deflate {
+        let mut server = Deflate::new(Mode::Server);
+        server.configure(&[]).unwrap();
+        server
+    }
+
+    #[test]
+    fn fragmented_message() {
+        use crate::{BoxedError, Storage, base::Header, connection::Builder, mock::duplex};
+        use futures::executor::block_on;
+
+        // An extension which only supports decoding complete messages.
+        #[derive(Debug)]
+        struct Buffered(Deflate);
+
+        impl Extension for Buffered {
+            fn is_enabled(&self) -> bool { self.0.is_enabled() }
+            fn name(&self) -> &str { self.0.name() }
+            fn params(&self) -> &[Param<'_>] { self.0.params() }
+            fn configure(&mut self, p: &[Param]) -> Result<(), BoxedError> { self.0.configure(p) }
+            fn encode(&mut self, h: &mut Header, d: &mut Storage) -> Result<(), BoxedError> { self.0.encode(h, d) }
+            fn reserved_bits(&self) -> (bool, bool, bool) { self.0.reserved_bits() }
+            fn decode(&mut self, h: &mut Header, d: &mut Vec<u8>) -> Result<(), BoxedError> {
+                // The reassembled message has the reserved bits of its first frame.
+                assert!(h.is_rsv1());
+                self.0.decode(h, d)
+            }
+        }
+
+        let (text, compressed) = compressed_text();
+        let streaming: Box<dyn Extension + Send + Sync> = Box::new(enabled_server());
+        let buffered: Box<dyn Extension + Send + Sync> = Box::new(Buffered(enabled_server()));
+
+        for extension in [streaming, buffered] {
+            let (mut a, b) = duplex(1024);
+            let mut builder = Builder::new(b, Mode::Server);
+            builder.add_extensions(vec![extension]);
+            let (_sender, mut receiver) = builder.finish();
+
+            block_on(async {
+                write_fragmented(&mut a, &compressed, false).await;
+                let mut message = Vec::new();
+                receiver.receive_data(&mut message).await.unwrap();
+                assert_eq!(text.as_bytes(), &message[..]);
+                let meta = receiver.message_meta();
+                assert!(meta.rsv1);
+                assert_eq!((3, compressed.len()), (meta.fragments, meta.total_frames_len))
+            })
+        }
+    }
+
+    #[test]
+    fn continuation_rsv_bits() {
+        use crate::{base, connection::{Builder, Error}, mock::duplex};
+        use futures::executor::block_on;
+
+        let (text, compressed) = compressed_text();
+
+        for &tolerate in &[false, true] {
+            let (mut a, b) = duplex(1024);
+            let mut builder = Builder::new(b, Mode::Server);
+            builder.add_extensions(vec![Box::new(enabled_server()) as Box<_>]);
+            builder.set_tolerate_continuation_rsv_bits(tolerate);
+            let (_sender, mut receiver) = builder.finish();
+
+            block_on(async {
+                write_fragmented(&mut a, &compressed, true).await;
+                let mut message = Vec::new();
+                match receiver.receive_data(&mut message).await {
+                    Ok(_) if tolerate => assert_eq!(text.as_bytes(), &message[..]),
+                    Err(Error::Codec(base::Error::InvalidReservedBit(1))) if !tolerate => {}
+                    other => panic!("unexpected result: {:?}", other)
+                }
+            })
+        }
+    }
+
+    #[test]
+    fn cross_backend_frames() {
+        use crate::base::{Header, OpCode};
+
+        let messages = ["Hello, hello, hello websocket!", "Hello again, hello websocket!"];
+
+        // Payload data of both messages as sent by a server with context
+        // takeover, captured with the zlib and the zlib-rs backend.
+        let zlib: &[&[u8]] = &[
+            &[242, 72, 205, 201, 201, 215, 81, 200, 64, 162, 20, 202, 83, 147, 138, 243, 147, 179, 83, 75, 20, 1, 0],
+            &[242, 0, 9, 43, 36, 166, 39, 102, 230, 65, 213, 32, 73, 2, 0]
+        ];
+        let zlib_rs: &[&[u8]] = &[
+            &[242, 72, 205, 201, 201, 215, 81, 200, 64, 162, 20, 202, 83, 147, 138, 243, 147, 179, 83, 75, 20, 1, 0],
+            &[2, 203, 42, 36, 166, 39, 102, 230, 65, 213, 32, 73, 2, 0]
+        ];
+
+        for frames in &[zlib, zlib_rs] {
+            let mut client = Deflate::new(Mode::Client);
+            client.configure(&[]).unwrap();
+            for (frame, message) in frames.iter().zip(&messages) {
+                let mut header = Header::new(OpCode::Text);
+                header.set_rsv1(true);
+                let mut data = frame.to_vec();
+                client.decode(&mut header, &mut data).unwrap();
+                assert_eq!(message.as_bytes(), &data[..])
+            }
+        }
+    }
+
+    #[test]
+    fn negotiated_params_and_stats() {
+        use crate::{handshake::{Client, Server}, mock::duplex};
+        use futures::{executor::block_on, future};
+        use super::{Counters, DeflateParams};
+
+        let mut deflate = Deflate::new(Mode::Client);
+        assert_eq!(None, deflate.negotiated_params());
+        deflate.set_compress_threshold(16);
+        let client_stats = deflate.stats_handle();
+        let server_deflate = Deflate::new(Mode::Server);
+        let server_stats = server_deflate.stats_handle();
+
+        let (a, b) = duplex(1024);
+
+        let client = async {
+            let mut client = Client::new(a, "example.com", "/");
+            client.add_extension(Box::new(deflate));
+            client.handshake().await.unwrap();
+            let (mut sender, _) = client.into_builder().finish();
+            sender.send_text("ok").await.unwrap();
+            sender.send_text("x".repeat(1000)).await.unwrap();
+            sender.flush().await.unwrap()
+        };
+
+        let server = async {
+            let mut server = Server::new(b);
+            server.add_extension(Box::new(server_deflate));
+            let request = server.receive_request().await.unwrap();
+            server.send_response(&request.accept()).await.unwrap();
+            let (_, mut receiver) = server.into_builder().finish();
+            for _ in 0 .. 2 {
+                receiver.receive_data(&mut Vec::new()).await.unwrap();
+            }
+        };
+
+        block_on(future::join(client, server));
+
+        let encoded = client_stats.get().encoded;
+        assert_eq!((1000, 1, 1), (encoded.bytes_in, encoded.messages_compressed, encoded.messages_passed_through));
+        assert!(encoded.bytes_out < 100);
+        assert_eq!(Counters::default(), client_stats.get().decoded);
+
+        let decoded = server_stats.get().decoded;
+        assert_eq!(encoded.bytes_out, decoded.bytes_in);
+        assert_eq!((1000, 1, 1), (decoded.bytes_out, decoded.messages_compressed, decoded.messages_passed_through));
+
+        let mut d = Deflate::new(Mode::Client);
+        d.configure(&[param("server_no_context_takeover", None)]).unwrap();
+        let params = DeflateParams {
+            server_max_window_bits: 15,
+            client_max_window_bits: 15,
+            server_no_context_takeover: true,
+            client_no_context_takeover: true
+        };
+        assert_eq!(Some(params), d.negotiated_params())
+    }
+
+    #[test]
+    fn end_to_end() {
+        const OFFER: &str = "permessage-deflate; server_no_context_takeover; client_no_context_takeover; client_max_window_bits; server_max_window_bits=12";
+        const RESPONSE: &str = "permessage-deflate; client_no_context_takeover; server_no_context_takeover; server_max_window_bits=12; client_max_window_bits=10";
+        use crate::{connection::Direction, handshake::{Client, Server, ServerResponse}, mock::duplex};
+        use futures::{executor::block_on, future};
+        use std::sync::{Arc, Mutex};
+
+        fn header<'a>(headers: &'a [(String, Vec<u8>)], name: &str) -> &'a str {
+            let (_, value) = headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).unwrap();
+            std::str::from_utf8(value).unwrap()
+        }
+
+        // (direction, opcode is text, RSV1 set, payload length) of every frame on the wire.
+        type Frames = Arc<Mutex<Vec<(Direction, bool, bool, usize)>>>;
+
+        let small = "hello";
+        let large = "soketto ".repeat(200);
+
+        // The client offers a window limit for the server and the server
+        // picks a smaller client window out of the client's offer.
+        let mut client_deflate = Deflate::new(Mode::Client);
+        client_deflate.set_max_server_window_bits(12);
+        client_deflate.set_compress_threshold(64);
+        let mut server_deflate = Deflate::new(Mode::Server);
+        server_deflate.set_max_client_window_bits(10);
+        server_deflate.set_compress_threshold(64);
+
+        let client_frames = Frames::default();
+        let server_frames = Frames::default();
+        let (a, b) = duplex(1024);
+
+        let client = async {
+            let mut client = Client::new(a, "example.com", "/");
+            client.add_extension(Box::new(client_deflate));
+            client.record_response_headers(true);
+            match client.handshake().await.unwrap() {
+                ServerResponse::Accepted { extensions, headers, .. } => {
+                    assert_eq!(vec!["permessage-deflate".to_string()], extensions);
+                    assert_eq!(RESPONSE, header(&headers, "Sec-WebSocket-Extensions"))
+                }
+                other => panic!("unexpected response: {:?}", other)
+            }
+            let mut builder = client.into_builder();
+            let frames = client_frames.clone();
+            builder.set_frame_observer(move |d, h, _| {
+                frames.lock().unwrap().push((d, h.opcode() == OpCode::Text, h.is_rsv1(), h.payload_len()))
+            });
+            let (mut sender, mut receiver) = builder.finish();
+            for text in &[small, large.as_str()] {
+                sender.send_text(text).await.unwrap();
+                sender.flush().await.unwrap();
+                let mut message = Vec::new();
+                receiver.receive_data(&mut message).await.unwrap();
+                assert_eq!(text.as_bytes(), &message[..])
+            }
+        };
+
+        let server = async {
+            let mut server = Server::new(b);
+            server.add_extension(Box::new(server_deflate));
+            server.record_request_headers(true);
+            let request = server.receive_request().await.unwrap();
+            assert_eq!(OFFER, header(request.headers(), "Sec-WebSocket-Extensions"));
+            server.send_response(&request.accept()).await.unwrap();
+            let mut builder = server.into_builder();
+            let frames = server_frames.clone();
+            builder.set_frame_observer(move |d, h, _| {
+                frames.lock().unwrap().push((d, h.opcode() == OpCode::Text, h.is_rsv1(), h.payload_len()))
+            });
+            let (mut sender, mut receiver) = builder.finish();
+            for _ in 0 .. 2 {
+                let mut message = Vec::new();
+                receiver.receive_data(&mut message).await.unwrap();
+                sender.send_text(std::str::from_utf8(&message).unwrap()).await.unwrap();
+                sender.flush().await.unwrap()
+            }
+        };
+
+        block_on(future::join(client, server));
+
+        // Small messages are sent uncompressed, large ones compressed with RSV1 set.
+        for frames in &[client_frames, server_frames] {
+            let frames = frames.lock().unwrap();
+            assert_eq!(4, frames.len());
+            for (direction, text, rsv1, len) in frames.iter() {
+                assert!(text, "{:?}", direction);
+                if *rsv1 {
+                    assert!(*len < 64)
+                } else {
+                    assert_eq!(small.len(), *len)
+                }
+            }
+            assert_eq!(2, frames.iter().filter(|f| f.2).count())
+        }
+    }
+
+    #[test]
+    fn declined_offer() {
+        use crate::{handshake::{Client, Server, ServerResponse}, mock::duplex};
+        use futures::{executor::block_on, future, io::{AsyncReadExt, AsyncWriteExt}};
+
+        // The server declines an unsupported offer but accepts the handshake.
+        let (a, mut b) = duplex(1024);
+        let mut server = Server::new(a);
+        server.add_extension(Box::new(Deflate::new(Mode::Server)));
+        let response = block_on(async {
+            let request = "GET / HTTP/1.1\r\n\
+                Host: example.com\r\n\
+                Upgrade: websocket\r\n\
+                Connection: upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Sec-WebSocket-Version: 13\r\n\
+                Sec-WebSocket-Extensions: permessage-deflate; server_max_window_bits=8\r\n\r\n";
+            b.write_all(request.as_bytes()).await.unwrap();
+            let request = server.receive_request().await.unwrap();
+            server.send_response(&request.accept()).await.unwrap();
+            assert!(server.extensions().all(|e| !e.is_enabled()));
+            drop(server);
+            let mut response = String::new();
+            b.read_to_string(&mut response).await.unwrap();
+            response
+        });
+        assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+        assert!(!response.contains("Sec-WebSocket-Extensions"));
+
+        // A declined offer leaves the client's extension disabled.
+        let (a, b) = duplex(1024);
+
+        let client = async {
+            let mut client = Client::new(a, "example.com", "/");
+            client.add_extension(Box::new(Deflate::new(Mode::Client)));
+            match client.handshake().await.unwrap() {
+                ServerResponse::Accepted { extensions, .. } => assert!(extensions.is_empty()),
+                other => panic!("unexpected response: {:?}", other)
+            }
+            assert!(client.extensions().all(|e| !e.is_enabled()));
+            let (mut sender, _) = client.into_builder().finish();
+            sender.send_text("hello").await.unwrap();
+            sender.flush().await.unwrap()
+        };
+
+        let server = async {
+            let mut server = Server::new(b);
+            let mut deflate = Deflate::new(Mode::Server);
+            deflate.set_max_client_window_bits(9);
+            server.add_extension(Box::new(deflate));
+            server.set_extension_filter(|_, extensions| extensions.clear());
+            let request = server.receive_request().await.unwrap();
+            server.send_response(&request.accept()).await.unwrap();
+            let (_, mut receiver) = server.into_builder().finish();
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await.unwrap();
+            assert_eq!(b"hello", &message[..])
+        };
+
+        block_on(future::join(client, server));
+
+        // A client can not decline the server's response.
+        let mut d = Deflate::new(Mode::Client);
+        assert!(d.configure(&[param("server_no_context_takeover", None), param("foo", None)]).is_err())
+    }
+}
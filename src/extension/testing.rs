@@ -0,0 +1,395 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Utilities for testing extensions (requires feature `testing`).
+//!
+//! [`MockExtension`] is a configurable extension which records all calls
+//! and can modify headers and payload data on demand. [`exchange`] runs a
+//! handshake and a message exchange between a client and a server with the
+//! given extensions over an in-memory connection and returns the frames
+//! sent on either side.
+//!
+//! # Example
+//!
+//! ```
+//! use soketto::extension::testing::{MockExtension, exchange};
+//!
+//! let client = MockExtension::new("x-mock");
+//! let server = MockExtension::new("x-mock");
+//! let log = server.log();
+//!
+//! let messages: &[&[u8]] = &[b"hello"];
+//! let result = futures::executor::block_on(exchange(vec![Box::new(client)], vec![Box::new(server)], messages))?;
+//!
+//! assert_eq!(vec!["x-mock"], result.extensions);
+//! assert_eq!(b"hello", &result.client_frames[0].payload[..]);
+//! assert!(!log.calls().is_empty());
+//! # Ok::<(), soketto::BoxedError>(())
+//! ```
+
+use crate::{
+    BoxedError,
+    Parsing,
+    Storage,
+    base::{Codec, Header},
+    extension::{Extension, Param},
+    handshake::{Client, Server, ServerResponse},
+    mock::{Endpoint, duplex}
+};
+use futures::{future, io::{AsyncRead, AsyncWrite}};
+use std::{fmt, io, pin::Pin, sync::{Arc, Mutex}, task::{Context, Poll}};
+
+/// A call of an extension method, recorded by a [`MockExtension`].
+#[derive(Debug, Clone)]
+pub enum Call {
+    /// [`Extension::configure`] has been called with the given parameters.
+    Configure {
+        /// The name of the extension.
+        name: String,
+        /// The parameters given.
+        params: Vec<Param<'static>>
+    },
+    /// [`Extension::encode`] has been called with the given header and data.
+    Encode {
+        /// The name of the extension.
+        name: String,
+        /// The frame header given.
+        header: Header,
+        /// The payload data given.
+        data: Vec<u8>
+    },
+    /// [`Extension::decode`] has been called with the given header and data.
+    Decode {
+        /// The name of the extension.
+        name: String,
+        /// The frame header given.
+        header: Header,
+        /// The payload data given.
+        data: Vec<u8>
+    }
+}
+
+/// A shared log of recorded calls.
+///
+/// The log can be shared by several mock extensions to record the order in
+/// which they are invoked.
+#[derive(Debug, Clone, Default)]
+pub struct CallLog(Arc<Mutex<Vec<Call>>>);
+
+impl CallLog {
+    /// Create a new, empty log.
+    pub fn new() -> Self {
+        CallLog::default()
+    }
+
+    /// Get all calls recorded so far.
+    pub fn calls(&self) -> Vec<Call> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Remove all calls recorded so far.
+    pub fn clear(&self) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clear()
+    }
+
+    fn push(&self, call: Call) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).push(call)
+    }
+}
+
+type CodecFn = dyn FnMut(&mut Header, &mut Vec<u8>) -> Result<(), BoxedError> + Send + Sync;
+
+/// A configurable extension for tests.
+///
+/// By default, the extension enables itself when configured, uses no
+/// reserved bits and leaves headers and payload data unchanged.
+pub struct MockExtension {
+    name: String,
+    enabled: bool,
+    accept: bool,
+    params: Vec<Param<'static>>,
+    reserved_bits: (bool, bool, bool),
+    on_encode: Option<Box<CodecFn>>,
+    on_decode: Option<Box<CodecFn>>,
+    log: CallLog
+}
+
+impl fmt::Debug for MockExtension {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MockExtension")
+            .field("name", &self.name)
+            .field("enabled", &self.enabled)
+            .field("accept", &self.accept)
+            .field("params", &self.params)
+            .field("reserved_bits", &self.reserved_bits)
+            .finish()
+    }
+}
+
+impl MockExtension {
+    /// Create a new mock extension with the given name.
+    pub fn new(name: impl Into<String>) -> Self {
+        MockExtension::with_log(name, CallLog::new())
+    }
+
+    /// Create a new mock extension which records calls in the given log.
+    pub fn with_log(name: impl Into<String>, log: CallLog) -> Self {
+        MockExtension {
+            name: name.into(),
+            enabled: false,
+            accept: true,
+            params: Vec::new(),
+            reserved_bits: (false, false, false),
+            on_encode: None,
+            on_decode: None,
+            log
+        }
+    }
+
+    /// Get the log of recorded calls.
+    pub fn log(&self) -> CallLog {
+        self.log.clone()
+    }
+
+    /// Enable or disable the extension.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled
+    }
+
+    /// Should the extension enable itself when configured (default: true)?
+    ///
+    /// If not, it declines negotiation.
+    pub fn set_accept(&mut self, accept: bool) {
+        self.accept = accept
+    }
+
+    /// Set the parameters the extension uses for negotiation.
+    pub fn set_params(&mut self, params: Vec<Param<'static>>) {
+        self.params = params
+    }
+
+    /// Set the reserved bits the extension uses.
+    pub fn set_reserved_bits(&mut self, bits: (bool, bool, bool)) {
+        self.reserved_bits = bits
+    }
+
+    /// Set a function which is applied to header and payload data when encoding.
+    pub fn on_encode<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut Header, &mut Vec<u8>) -> Result<(), BoxedError> + Send + Sync + 'static
+    {
+        self.on_encode = Some(Box::new(f))
+    }
+
+    /// Set a function which is applied to header and payload data when decoding.
+    pub fn on_decode<F>(&mut self, f: F)
+    where
+        F: FnMut(&mut Header, &mut Vec<u8>) -> Result<(), BoxedError> + Send + Sync + 'static
+    {
+        self.on_decode = Some(Box::new(f))
+    }
+}
+
+impl Extension for MockExtension {
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn params(&self) -> &[Param<'_>] {
+        &self.params
+    }
+
+    fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+        let params = params.iter().map(|p| p.clone().acquire()).collect();
+        self.log.push(Call::Configure { name: self.name.clone(), params });
+        self.enabled = self.accept;
+        Ok(())
+    }
+
+    fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+        let call = Call::Encode { name: self.name.clone(), header: header.clone(), data: data.as_ref().to_vec() };
+        self.log.push(call);
+        if let Some(f) = &mut self.on_encode {
+            let mut d = data.as_ref().to_vec();
+            f(header, &mut d)?;
+            header.set_payload_len(d.len());
+            *data = Storage::Owned(d)
+        }
+        Ok(())
+    }
+
+    fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+        let call = Call::Decode { name: self.name.clone(), header: header.clone(), data: data.clone() };
+        self.log.push(call);
+        if let Some(f) = &mut self.on_decode {
+            f(header, data)?;
+            header.set_payload_len(data.len());
+        }
+        Ok(())
+    }
+
+    fn reserved_bits(&self) -> (bool, bool, bool) {
+        self.reserved_bits
+    }
+}
+
+/// A frame as sent over the connection.
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// The frame header.
+    pub header: Header,
+    /// The unmasked payload data.
+    pub payload: Vec<u8>
+}
+
+/// The result of an [`exchange`].
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    /// The names of the extensions enabled by the client.
+    pub extensions: Vec<String>,
+    /// The messages received by the server.
+    pub server_received: Vec<Vec<u8>>,
+    /// The messages received by the client.
+    pub client_received: Vec<Vec<u8>>,
+    /// The frames sent by the client after the handshake.
+    pub client_frames: Vec<Frame>,
+    /// The frames sent by the server after the handshake.
+    pub server_frames: Vec<Frame>
+}
+
+/// Run a handshake and exchange messages between a client and a server.
+///
+/// Both sides use an in-memory connection and the given extensions. After
+/// the handshake, the client sends every message as binary data and the
+/// server echoes back every message it receives.
+pub async fn exchange
+    ( client_extensions: Vec<Box<dyn Extension + Send + Sync>>
+    , server_extensions: Vec<Box<dyn Extension + Send + Sync>>
+    , messages: &[&[u8]]
+    ) -> Result<Exchange, BoxedError>
+{
+    let (a, b) = duplex(4096);
+    let (a, b) = (Recording::new(a), Recording::new(b));
+    let (client_wire, server_wire) = (a.written.clone(), b.written.clone());
+
+    let client = async move {
+        let mut client = Client::new(a, "localhost", "/");
+        for e in client_extensions {
+            client.add_extension(e);
+        }
+        let extensions = match client.handshake().await? {
+            ServerResponse::Accepted { extensions, .. } => extensions,
+            other => return Err(format!("handshake failed: {:?}", other).into())
+        };
+        let (mut sender, mut receiver) = client.into_builder().finish();
+        let mut received = Vec::new();
+        for m in messages {
+            sender.send_binary(m).await?;
+            sender.flush().await?;
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await?;
+            received.push(message)
+        }
+        Ok::<_, BoxedError>((extensions, received))
+    };
+
+    let server = async move {
+        let mut server = Server::new(b);
+        for e in server_extensions {
+            server.add_extension(e);
+        }
+        let request = server.receive_request().await?;
+        server.send_response(&request.accept()).await?;
+        let (mut sender, mut receiver) = server.into_builder().finish();
+        let mut received = Vec::new();
+        for _ in messages {
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await?;
+            sender.send_binary(&message).await?;
+            sender.flush().await?;
+            received.push(message)
+        }
+        Ok::<_, BoxedError>(received)
+    };
+
+    let (client, server) = future::join(client, server).await;
+    let (extensions, client_received) = client?;
+    let server_received = server?;
+
+    let client_frames = parse_frames(&client_wire.lock().unwrap_or_else(|e| e.into_inner()))?;
+    let server_frames = parse_frames(&server_wire.lock().unwrap_or_else(|e| e.into_inner()))?;
+
+    Ok(Exchange { extensions, server_received, client_received, client_frames, server_frames })
+}
+
+/// Parse the frames following the HTTP handshake message.
+fn parse_frames(bytes: &[u8]) -> Result<Vec<Frame>, BoxedError> {
+    let start = bytes.windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or("incomplete handshake")?;
+    let mut bytes = &bytes[start + 4 ..];
+    let mut codec = Codec::new();
+    codec.add_reserved_bits((true, true, true));
+    let mut frames = Vec::new();
+    while !bytes.is_empty() {
+        let (header, offset) = match codec.decode_header(bytes)? {
+            Parsing::Done { value, offset } => (value, offset),
+            Parsing::NeedMore(_) => return Err("incomplete frame header".into())
+        };
+        let end = offset + header.payload_len();
+        if end > bytes.len() {
+            return Err("incomplete frame payload".into())
+        }
+        let mut payload = bytes[offset .. end].to_vec();
+        Codec::apply_mask(&header, &mut payload);
+        frames.push(Frame { header, payload });
+        bytes = &bytes[end ..]
+    }
+    Ok(frames)
+}
+
+/// An I/O resource which records all bytes written.
+#[derive(Debug)]
+struct Recording {
+    inner: Endpoint,
+    written: Arc<Mutex<Vec<u8>>>
+}
+
+impl Recording {
+    fn new(inner: Endpoint) -> Self {
+        Recording { inner, written: Arc::new(Mutex::new(Vec::new())) }
+    }
+}
+
+impl AsyncRead for Recording {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Recording {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.written.lock().unwrap_or_else(|e| e.into_inner()).extend_from_slice(&buf[.. n])
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,603 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Experimental Zstandard compression extension.
+//!
+//! **This extension is experimental.** There is no RFC for compressing
+//! websocket messages with Zstandard. The extension is modelled after
+//! permessage-deflate ([RFC 7692][rfc7692]) and negotiated with the token
+//! `permessage-zstd`. Its negotiation may change in future versions and
+//! interoperability with other implementations is not guaranteed.
+//!
+//! # Negotiation
+//!
+//! The following extension parameters are used:
+//!
+//! - `server_no_context_takeover`, `client_no_context_takeover`: The server
+//!   or the client resets its compression context after every message, i.e.
+//!   every message is a complete Zstandard frame. Otherwise every message
+//!   is a flushed part of one Zstandard frame spanning all messages.
+//! - `server_max_window_log=<n>`, `client_max_window_log=<n>`: The max.
+//!   window log (within 10 ..= 27) the server or the client uses when
+//!   compressing. This bounds the memory the remote needs for decompressing.
+//!
+//! A client offers both window logs and the context takeover parameters it
+//! wants to use. The server responds with both window logs, neither greater
+//! than offered, and all context takeover parameters either side asked for.
+//!
+//! Like permessage-deflate, compressed messages are marked with RSV1, hence
+//! both extensions can not be added to the same handshake or connection.
+//!
+//! [rfc7692]: https://tools.ietf.org/html/rfc7692
+
+use crate::{
+    BoxedError,
+    Storage,
+    base::{Header, OpCode},
+    connection::{self, Mode},
+    extension::{self, Extension, Param, StreamingExtension}
+};
+use ::zstd::stream::raw::{CParameter, DParameter, Decoder, Encoder, InBuffer, Operation, OutBuffer};
+use std::{fmt, io, mem};
+
+const SERVER_NO_CONTEXT_TAKEOVER: &str = "server_no_context_takeover";
+const SERVER_MAX_WINDOW_LOG: &str = "server_max_window_log";
+
+const CLIENT_NO_CONTEXT_TAKEOVER: &str = "client_no_context_takeover";
+const CLIENT_MAX_WINDOW_LOG: &str = "client_max_window_log";
+
+/// Range of supported window logs.
+const MIN_WINDOW_LOG: u8 = 10;
+const MAX_WINDOW_LOG: u8 = 27;
+
+/// Default max. window log (128 KiB).
+const DEFAULT_WINDOW_LOG: u8 = 17;
+
+/// Default compression level.
+const DEFAULT_LEVEL: i32 = 3;
+
+/// The zstd extension type (experimental).
+///
+/// By default, both sides use context takeover and a max. window log of 17.
+pub struct Zstd {
+    mode: Mode,
+    enabled: bool,
+    buffer: Vec<u8>,
+    params: Vec<Param<'static>>,
+    level: i32,
+    our_max_window_log: u8,
+    their_max_window_log: u8,
+    server_no_context_takeover: bool,
+    client_no_context_takeover: bool,
+    encoder: Option<Encoder<'static>>,
+    decoder: Option<Decoder<'static>>,
+    max_message_size: usize,
+    /// The number of bytes decoded so far, if the current message is compressed.
+    decoding: Option<usize>
+}
+
+impl fmt::Debug for Zstd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Zstd")
+            .field("mode", &self.mode)
+            .field("enabled", &self.enabled)
+            .field("params", &self.params)
+            .field("level", &self.level)
+            .field("our_max_window_log", &self.our_max_window_log)
+            .field("their_max_window_log", &self.their_max_window_log)
+            .field("server_no_context_takeover", &self.server_no_context_takeover)
+            .field("client_no_context_takeover", &self.client_no_context_takeover)
+            .finish()
+    }
+}
+
+impl Zstd {
+    /// Create a new zstd extension either on client or server side.
+    pub fn new(mode: Mode) -> Self {
+        let mut zstd = Zstd {
+            mode,
+            enabled: false,
+            buffer: Vec::new(),
+            params: Vec::new(),
+            level: DEFAULT_LEVEL,
+            our_max_window_log: DEFAULT_WINDOW_LOG,
+            their_max_window_log: DEFAULT_WINDOW_LOG,
+            server_no_context_takeover: false,
+            client_no_context_takeover: false,
+            encoder: None,
+            decoder: None,
+            max_message_size: usize::MAX,
+            decoding: None
+        };
+        zstd.update_offer();
+        zstd
+    }
+
+    /// Set the compression level (default: 3).
+    pub fn set_level(&mut self, level: i32) {
+        self.level = level
+    }
+
+    /// Set the server's max. window log.
+    ///
+    /// The value must be within 10 ..= 27.
+    ///
+    /// In client mode, this limits the window the server may use and thus
+    /// the memory needed to decompress its messages. In server mode, this
+    /// limits the window the server uses itself.
+    pub fn set_max_server_window_log(&mut self, max: u8) {
+        assert!(is_valid_window_log(max), "max. server window log has to be within 10 ..= 27");
+        match self.mode {
+            Mode::Client => self.their_max_window_log = max,
+            Mode::Server => self.our_max_window_log = max
+        }
+        self.update_offer()
+    }
+
+    /// Set the client's max. window log.
+    ///
+    /// The value must be within 10 ..= 27.
+    ///
+    /// In client mode, this limits the window the client uses itself. In
+    /// server mode, this limits the window the client may use and thus the
+    /// memory needed to decompress its messages.
+    pub fn set_max_client_window_log(&mut self, max: u8) {
+        assert!(is_valid_window_log(max), "max. client window log has to be within 10 ..= 27");
+        match self.mode {
+            Mode::Client => self.our_max_window_log = max,
+            Mode::Server => self.their_max_window_log = max
+        }
+        self.update_offer()
+    }
+
+    /// Should the server reset its compression context after every message?
+    pub fn set_server_no_context_takeover(&mut self, value: bool) {
+        self.server_no_context_takeover = value;
+        self.update_offer()
+    }
+
+    /// Should the client reset its compression context after every message?
+    pub fn set_client_no_context_takeover(&mut self, value: bool) {
+        self.client_no_context_takeover = value;
+        self.update_offer()
+    }
+
+    /// The (negotiated) max. window log of the server.
+    pub fn server_window_log(&self) -> u8 {
+        match self.mode {
+            Mode::Client => self.their_max_window_log,
+            Mode::Server => self.our_max_window_log
+        }
+    }
+
+    /// The (negotiated) max. window log of the client.
+    pub fn client_window_log(&self) -> u8 {
+        match self.mode {
+            Mode::Client => self.our_max_window_log,
+            Mode::Server => self.their_max_window_log
+        }
+    }
+
+    // Should we reset our compression context after every message?
+    fn our_no_context_takeover(&self) -> bool {
+        match self.mode {
+            Mode::Client => self.client_no_context_takeover,
+            Mode::Server => self.server_no_context_takeover
+        }
+    }
+
+    // Does the remote reset its compression context after every message?
+    fn their_no_context_takeover(&self) -> bool {
+        match self.mode {
+            Mode::Client => self.server_no_context_takeover,
+            Mode::Server => self.client_no_context_takeover
+        }
+    }
+
+    // The parameters describing the current configuration.
+    fn current_params(&self) -> Vec<Param<'static>> {
+        let mut params = Vec::new();
+        let mut server = Param::new(SERVER_MAX_WINDOW_LOG);
+        server.set_value(Some(self.server_window_log().to_string()));
+        params.push(server);
+        let mut client = Param::new(CLIENT_MAX_WINDOW_LOG);
+        client.set_value(Some(self.client_window_log().to_string()));
+        params.push(client);
+        if self.server_no_context_takeover {
+            params.push(Param::new(SERVER_NO_CONTEXT_TAKEOVER))
+        }
+        if self.client_no_context_takeover {
+            params.push(Param::new(CLIENT_NO_CONTEXT_TAKEOVER))
+        }
+        params
+    }
+
+    // Update the parameters a client offers.
+    fn update_offer(&mut self) {
+        if self.mode == Mode::Client {
+            self.params = self.current_params()
+        }
+    }
+
+    // Configure the server with the parameters of a client offer.
+    //
+    // Offers with invalid or unknown parameters are declined.
+    fn configure_server(&mut self, params: &[Param]) {
+        if self.enabled {
+            log::debug!("{}: ignoring additional offer", self.name());
+            return
+        }
+        let mut server_max = MAX_WINDOW_LOG;
+        let mut client_max = MAX_WINDOW_LOG;
+        let mut server_no_context_takeover = self.server_no_context_takeover;
+        let mut client_no_context_takeover = self.client_no_context_takeover;
+        for p in params {
+            log::trace!("configure server with: {}", p);
+            match p.name() {
+                SERVER_NO_CONTEXT_TAKEOVER => server_no_context_takeover = true,
+                CLIENT_NO_CONTEXT_TAKEOVER => client_no_context_takeover = true,
+                SERVER_MAX_WINDOW_LOG =>
+                    if let Some(v) = window_log(p) {
+                        server_max = v
+                    } else {
+                        return
+                    }
+                CLIENT_MAX_WINDOW_LOG =>
+                    if let Some(v) = window_log(p) {
+                        client_max = v
+                    } else {
+                        return
+                    }
+                _ => {
+                    log::debug!("{}: unknown parameter: {}", self.name(), p.name());
+                    return
+                }
+            }
+        }
+        self.our_max_window_log = std::cmp::min(self.our_max_window_log, server_max);
+        self.their_max_window_log = std::cmp::min(self.their_max_window_log, client_max);
+        self.server_no_context_takeover = server_no_context_takeover;
+        self.client_no_context_takeover = client_no_context_takeover;
+        self.params = self.current_params();
+        self.enabled = true
+    }
+
+    // Configure the client with the parameters of the server response.
+    //
+    // Parameters the client can not honor result in an error.
+    fn configure_client(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+        let mut server_no_context_takeover = false;
+        for p in params {
+            log::trace!("configure client with: {}", p);
+            match p.name() {
+                SERVER_NO_CONTEXT_TAKEOVER => server_no_context_takeover = true,
+                CLIENT_NO_CONTEXT_TAKEOVER => self.client_no_context_takeover = true,
+                SERVER_MAX_WINDOW_LOG =>
+                    match window_log(p) {
+                        Some(v) if v <= self.their_max_window_log => self.their_max_window_log = v,
+                        _ => return Err(format!("invalid {}: {:?}", p.name(), p.value()).into())
+                    }
+                CLIENT_MAX_WINDOW_LOG =>
+                    match window_log(p) {
+                        Some(v) => self.our_max_window_log = std::cmp::min(self.our_max_window_log, v),
+                        None => return Err(format!("invalid {}: {:?}", p.name(), p.value()).into())
+                    }
+                _ => return Err(format!("unknown parameter: {}", p.name()).into())
+            }
+        }
+        if self.server_no_context_takeover && !server_no_context_takeover {
+            log::debug!("{}: server did not confirm no context takeover", self.name())
+        }
+        self.server_no_context_takeover = server_no_context_takeover;
+        self.enabled = true;
+        Ok(())
+    }
+}
+
+/// Is the given window log within the supported range?
+fn is_valid_window_log(v: u8) -> bool {
+    (MIN_WINDOW_LOG ..= MAX_WINDOW_LOG).contains(&v)
+}
+
+/// Parse the value of a window log parameter.
+fn window_log(p: &Param) -> Option<u8> {
+    match p.value().map(|s| s.parse::<u8>()) {
+        Some(Ok(v)) if is_valid_window_log(v) => Some(v),
+        _ => {
+            log::debug!("invalid {}: {:?} (expected range: 10 ..= 27)", p.name(), p.value());
+            None
+        }
+    }
+}
+
+impl Extension for Zstd {
+    fn name(&self) -> &str {
+        "permessage-zstd"
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn params(&self) -> &[Param<'_>] {
+        &self.params
+    }
+
+    fn configure(&mut self, params: &[Param]) -> Result<(), BoxedError> {
+        match self.mode {
+            Mode::Server => {
+                self.configure_server(params);
+                Ok(())
+            }
+            Mode::Client => self.configure_client(params)
+        }
+    }
+
+    fn reserved_bits(&self) -> (bool, bool, bool) {
+        (true, false, false)
+    }
+
+    fn set_max_message_size(&mut self, max: usize) {
+        self.max_message_size = max
+    }
+
+    fn as_streaming(&mut self) -> Option<&mut dyn StreamingExtension> {
+        Some(self)
+    }
+
+    fn decode(&mut self, header: &mut Header, data: &mut Vec<u8>) -> Result<(), BoxedError> {
+        extension::decode_message(self, header, data)
+    }
+
+    fn encode(&mut self, header: &mut Header, data: &mut Storage) -> Result<(), BoxedError> {
+        if !matches!(header.opcode(), OpCode::Binary | OpCode::Text) || data.as_ref().is_empty() {
+            log::trace!("zstd: not encoding {}", header);
+            return Ok(())
+        }
+
+        log::trace!("zstd: encoding {}", header);
+
+        let reset = self.our_no_context_takeover();
+        let encoder = if let Some(e) = &mut self.encoder {
+            e
+        } else {
+            let mut e = Encoder::new(self.level)?;
+            e.set_parameter(CParameter::WindowLog(u32::from(self.our_max_window_log)))?;
+            self.encoder.get_or_insert(e)
+        };
+
+        self.buffer.clear();
+        self.buffer.reserve(data.as_ref().len() / 2 + 64);
+
+        // Compress all input bytes.
+        let mut input = InBuffer::around(data.as_ref());
+        while input.pos() < data.as_ref().len() {
+            if self.buffer.len() == self.buffer.capacity() {
+                self.buffer.reserve(4096)
+            }
+            let n = self.buffer.len();
+            encoder.run(&mut input, &mut OutBuffer::around_pos(&mut self.buffer, n))?;
+        }
+
+        // Flush the compressed data or end the frame if the context is not kept.
+        loop {
+            if self.buffer.len() == self.buffer.capacity() {
+                self.buffer.reserve(4096)
+            }
+            let n = self.buffer.len();
+            let mut output = OutBuffer::around_pos(&mut self.buffer, n);
+            let remaining = if reset {
+                encoder.finish(&mut output, true)?
+            } else {
+                encoder.flush(&mut output)?
+            };
+            if remaining == 0 {
+                break
+            }
+        }
+
+        if reset {
+            encoder.reinit()?
+        }
+
+        if let Storage::Owned(d) = data {
+            mem::swap(d, &mut self.buffer)
+        } else {
+            *data = Storage::Owned(mem::take(&mut self.buffer))
+        }
+        header.set_rsv1(true);
+        header.set_payload_len(data.as_ref().len());
+        Ok(())
+    }
+}
+
+impl StreamingExtension for Zstd {
+    fn begin_message(&mut self, header: &Header) -> Result<(), BoxedError> {
+        if header.is_rsv1() && matches!(header.opcode(), OpCode::Binary | OpCode::Text) {
+            log::trace!("zstd: decoding {}", header);
+            self.decoding = Some(0)
+        } else {
+            log::trace!("zstd: not decoding {}", header);
+            self.decoding = None
+        }
+        Ok(())
+    }
+
+    fn process_chunk(&mut self, data: &mut Vec<u8>, _is_final: bool) -> Result<(), BoxedError> {
+        let decoded = if let Some(n) = self.decoding { n } else { return Ok(()) };
+
+        if data.is_empty() {
+            return Ok(())
+        }
+
+        let maximum = self.max_message_size;
+        let remaining = maximum.saturating_sub(decoded);
+
+        let decoder = if let Some(d) = &mut self.decoder {
+            d
+        } else {
+            let mut d = Decoder::new()?;
+            d.set_parameter(DParameter::WindowLogMax(u32::from(self.their_max_window_log)))?;
+            self.decoder.get_or_insert(d)
+        };
+
+        self.buffer.clear();
+        self.buffer.reserve(std::cmp::min(2 * data.len(), remaining.saturating_add(1)));
+
+        // Decompress all input bytes.
+        let mut input = InBuffer::around(&data[..]);
+        loop {
+            let (i, n) = (input.pos(), self.buffer.len());
+            decoder.run(&mut input, &mut OutBuffer::around_pos(&mut self.buffer, n))?;
+            if self.buffer.len() > remaining {
+                log::debug!("zstd: decompressed message exceeds {} bytes", maximum);
+                let current = decoded.saturating_add(self.buffer.len());
+                self.decoder = None;
+                self.decoding = None;
+                let e = connection::Error::MessageTooLarge { current, maximum };
+                return Err(e.into())
+            }
+            let buffer_full = self.buffer.len() == self.buffer.capacity();
+            if input.pos() == data.len() && !buffer_full {
+                break
+            }
+            if buffer_full {
+                let additional = std::cmp::max(4096, self.buffer.len());
+                self.buffer.reserve_exact(std::cmp::min(additional, (remaining - self.buffer.len()).saturating_add(1)))
+            } else if input.pos() == i && self.buffer.len() == n {
+                return Err(io::Error::other("zstd: no progress while decoding").into())
+            }
+        }
+
+        self.decoding = Some(decoded + self.buffer.len());
+        mem::swap(data, &mut self.buffer);
+        Ok(())
+    }
+
+    fn end_message(&mut self, header: &mut Header) -> Result<(), BoxedError> {
+        if self.decoding.take().is_some() {
+            if self.their_no_context_takeover() {
+                if let Some(decoder) = &mut self.decoder {
+                    decoder.reinit()?
+                }
+            }
+            header.set_rsv1(false);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{connection::Mode, extension::{Extension, Param}};
+    use super::Zstd;
+
+    fn param(name: &'static str, value: Option<&str>) -> Param<'static> {
+        let mut p = Param::new(name);
+        p.set_value(value.map(String::from));
+        p
+    }
+
+    fn params(z: &Zstd) -> Vec<String> {
+        z.params().iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut client = Zstd::new(Mode::Client);
+        client.set_max_server_window_log(20);
+        client.set_client_no_context_takeover(true);
+        assert_eq! {
+            vec!["server_max_window_log = 20", "client_max_window_log = 17", "client_no_context_takeover"],
+            params(&client)
+        }
+
+        let mut server = Zstd::new(Mode::Server);
+        server.set_max_server_window_log(15);
+        server.set_server_no_context_takeover(true);
+        server.configure(client.params()).unwrap();
+        assert!(server.is_enabled());
+        assert_eq! {
+            vec![
+                "server_max_window_log = 15",
+                "client_max_window_log = 17",
+                "server_no_context_takeover",
+                "client_no_context_takeover"
+            ],
+            params(&server)
+        }
+
+        client.configure(server.params()).unwrap();
+        assert!(client.is_enabled());
+        assert_eq!((15, 17), (client.server_window_log(), client.client_window_log()));
+        assert!(client.server_no_context_takeover && client.client_no_context_takeover);
+
+        // The server declines invalid offers.
+        let mut server = Zstd::new(Mode::Server);
+        server.configure(&[param("server_max_window_log", Some("9"))]).unwrap();
+        assert!(!server.is_enabled());
+        server.configure(&[param("foo", None)]).unwrap();
+        assert!(!server.is_enabled());
+
+        // The client rejects responses it can not honor.
+        let mut client = Zstd::new(Mode::Client);
+        assert!(client.configure(&[param("server_max_window_log", Some("18"))]).is_err());
+        let mut client = Zstd::new(Mode::Client);
+        assert!(client.configure(&[param("foo", None)]).is_err())
+    }
+
+    #[test]
+    fn round_trip() {
+        use crate::handshake::{Client, Server, ServerResponse};
+        use crate::mock::duplex;
+        use futures::{executor::block_on, future};
+
+        let messages: Vec<String> = (0 .. 5).map(|i| format!("{{\"id\":{},\"result\":\"hello world\"}}", i)).collect();
+
+        for &(client_nct, server_nct) in &[(false, false), (true, false), (false, true), (true, true)] {
+            let (a, b) = duplex(1024);
+
+            let client = async {
+                let mut client = Client::new(a, "example.com", "/");
+                let mut zstd = Zstd::new(Mode::Client);
+                zstd.set_client_no_context_takeover(client_nct);
+                zstd.set_server_no_context_takeover(server_nct);
+                client.add_extension(Box::new(zstd));
+                match client.handshake().await.unwrap() {
+                    ServerResponse::Accepted { extensions, .. } =>
+                        assert_eq!(vec!["permessage-zstd"], extensions),
+                    other => panic!("unexpected response: {:?}", other)
+                }
+                let (mut sender, mut receiver) = client.into_builder().finish();
+                for m in &messages {
+                    sender.send_text(m).await.unwrap();
+                    sender.flush().await.unwrap();
+                    let mut message = Vec::new();
+                    receiver.receive_data(&mut message).await.unwrap();
+                    assert_eq!(m.as_bytes(), &message[..])
+                }
+            };
+
+            let server = async {
+                let mut server = Server::new(b);
+                server.add_extension(Box::new(Zstd::new(Mode::Server)));
+                let request = server.receive_request().await.unwrap();
+                server.send_response(&request.accept()).await.unwrap();
+                let (mut sender, mut receiver) = server.into_builder().finish();
+                for _ in 0 .. messages.len() {
+                    let mut message = Vec::new();
+                    receiver.receive_data(&mut message).await.unwrap();
+                    sender.send_text(std::str::from_utf8(&message).unwrap()).await.unwrap();
+                    sender.flush().await.unwrap()
+                }
+            };
+
+            block_on(future::join(client, server));
+        }
+    }
+}
@@ -13,55 +13,105 @@
 pub mod client;
 pub mod server;
 
+#[cfg(feature = "http")]
+pub mod http;
+
 use bytes::BytesMut;
-use crate::extension::{Param, Extension};
-use std::{fmt, io, str};
+use crate::{connection::Mode, extension::{Param, Extension}};
+use futures::Future;
+use std::{borrow::Cow, fmt, io, str};
 
-pub use client::{Client, ServerResponse};
-pub use server::{Server, ClientRequest};
+pub use client::{Client, Host, ServerResponse};
+pub use server::{Server, ClientRequest, WebSocketKey};
 
 // Defined in RFC 6455 and used to generate the `Sec-WebSocket-Accept` header
 // in the server handshake response.
 const KEY: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
-// How many HTTP headers do we support during parsing?
-const MAX_NUM_HEADERS: usize = 32;
+// How many HTTP headers do we support during parsing by default?
+const MAX_NUM_HEADERS: usize = 128;
+
+// Up to this many headers are parsed without allocating.
+const STACK_NUM_HEADERS: usize = 32;
 
 // Some HTTP headers we need to check during parsing.
 const SEC_WEBSOCKET_EXTENSIONS: &str = "Sec-WebSocket-Extensions";
 const SEC_WEBSOCKET_PROTOCOL: &str = "Sec-WebSocket-Protocol";
+const SEC_WEBSOCKET_VERSION: &str = "Sec-WebSocket-Version";
+
+/// Run a handshake in a tracing span with the given trace ID (feature `tracing`).
+#[cfg(feature = "tracing")]
+fn in_span<F: Future>(mode: Mode, id: Option<&str>, f: F) -> impl Future<Output = F::Output> {
+    use tracing::Instrument;
+    let span = tracing::debug_span!("handshake", mode = ?mode, id = tracing::field::Empty);
+    if let Some(id) = id {
+        span.record("id", id);
+    }
+    f.instrument(span)
+}
+
+#[cfg(not(feature = "tracing"))]
+fn in_span<F: Future>(_: Mode, _: Option<&str>, f: F) -> F {
+    f
+}
 
 /// Check a set of headers contains a specific one.
-fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str) -> Result<(), Error> {
+///
+/// The header value must contain the given token, cf. [`contains_token`].
+fn expect_ascii_header(headers: &[httparse::Header], name: &str, ours: &str, strict: bool) -> Result<(), Error> {
     enum State {
-        Init, // Start state
-        Name, // Header name found
-        Match // Header value matches
+        Init,         // Start state
+        Name(String), // Header name found (with the last value seen)
+        Match         // Header value matches
     }
 
     headers.iter()
         .filter(|h| h.name.eq_ignore_ascii_case(name))
-        .fold(Ok(State::Init), |result, header| {
-            if let Ok(State::Match) = result {
-                return result
+        .try_fold(State::Init, |state, header| {
+            if let State::Match = state {
+                return Ok(state)
             }
-            if str::from_utf8(header.value)?
-                .split(',')
-                .any(|v| v.trim().eq_ignore_ascii_case(ours))
-            {
+            let value = str::from_utf8(header.value)?;
+            if contains_token(value, ours, strict) {
                 return Ok(State::Match)
             }
-            Ok(State::Name)
+            Ok(State::Name(value.into()))
         })
         .and_then(|state| {
             match state {
                 State::Init => Err(Error::HeaderNotFound(name.into())),
-                State::Name => Err(Error::UnexpectedHeader(name.into())),
+                State::Name(value) => Err(Error::UnexpectedHeader { name: name.into(), value }),
                 State::Match => Ok(())
             }
         })
 }
 
+/// Get the length of the body of a request or response from the `Content-Length` header.
+///
+/// If no such header is present, an empty body is assumed.
+fn content_length(headers: &[httparse::Header]) -> Result<usize, Error> {
+    if headers.iter().any(|h| h.name.eq_ignore_ascii_case("Transfer-Encoding")) {
+        return Err(Error::UnsupportedTransferEncoding)
+    }
+    match with_first_header(headers, "Content-Length", |v| Ok(str::from_utf8(v)?.trim())) {
+        Ok(v) => v.parse().map_err(|_| Error::UnexpectedHeader { name: "Content-Length".into(), value: v.into() }),
+        Err(Error::HeaderNotFound(_)) => Ok(0),
+        Err(e) => Err(e)
+    }
+}
+
+/// Check if a comma-separated header value contains the given token.
+///
+/// Tokens are compared case-insensitively and optional whitespace (spaces
+/// and tabs) around them is ignored. Unless `strict`, parameters following
+/// a token, e.g. `;foo=bar`, are ignored as well.
+fn contains_token(value: &str, token: &str, strict: bool) -> bool {
+    value.split(',').any(|v| {
+        let v = if strict { v } else { v.split(';').next().unwrap_or(v) };
+        v.trim_matches(|c| c == ' ' || c == '\t').eq_ignore_ascii_case(token)
+    })
+}
+
 /// Pick the first header with the given name and apply the given closure to it.
 fn with_first_header<'a, F, R>(headers: &[httparse::Header<'a>], name: &str, f: F) -> Result<R, Error>
 where
@@ -74,34 +124,194 @@ where
     }
 }
 
+/// Collect the protocols of all `Sec-WebSocket-Protocol` headers, in order.
+///
+/// Each header value is a comma-separated list. Optional whitespace (spaces
+/// and tabs) around protocols is ignored and empty list elements are skipped.
+/// Protocols are tokens which have to be compared case-sensitively.
+fn protocol_tokens<'a>(headers: &[httparse::Header<'a>]) -> Result<Vec<&'a str>, Error> {
+    let mut protocols = Vec::new();
+    for h in headers.iter().filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_PROTOCOL)) {
+        let tokens = str::from_utf8(h.value)?.split(',')
+            .map(|p| p.trim_matches(|c| c == ' ' || c == '\t'))
+            .filter(|p| !p.is_empty());
+        protocols.extend(tokens)
+    }
+    Ok(protocols)
+}
+
 // Configure all extensions with parsed parameters.
-fn configure_extensions(extensions: &mut [Box<dyn Extension + Send>], line: &str) -> Result<(), Error> {
-    for e in line.split(',') {
-        let mut ext_parts = e.split(';');
-        if let Some(name) = ext_parts.next() {
-            let name = name.trim();
-            if let Some(ext) = extensions.iter_mut().find(|x| x.name().eq_ignore_ascii_case(name)) {
-                let mut params = Vec::new();
-                for p in ext_parts {
-                    let mut key_value = p.split('=');
-                    if let Some(key) = key_value.next().map(str::trim) {
-                        let val = key_value.next().map(|v| v.trim().trim_matches('"'));
-                        let mut p = Param::new(key);
-                        p.set_value(val);
-                        params.push(p)
-                    }
+//
+// An extension may be offered more than once. Offers are tried in order
+// until the extension enables itself. At most one extension of the same
+// name will be enabled.
+fn configure_extensions(extensions: &mut [Box<dyn Extension + Send + Sync>], line: &str) -> Result<(), Error> {
+    let offers = parse_extensions(line)?;
+    for i in 0 .. extensions.len() {
+        let (previous, rest) = extensions.split_at_mut(i);
+        let ext = &mut rest[0];
+        if previous.iter().any(|e| e.is_enabled() && e.name().eq_ignore_ascii_case(ext.name())) {
+            continue
+        }
+        for offer in &offers {
+            if ext.is_enabled() {
+                break
+            }
+            if !offer.name.eq_ignore_ascii_case(ext.name()) {
+                continue
+            }
+            ext.configure(&offer.params).map_err(Error::Extension)?
+        }
+    }
+    Ok(())
+}
+
+// Configure the client's extensions with the server's response.
+//
+// Every extension in the response must correspond to one of our offers and
+// may be accepted at most once. If an extension has been offered more than
+// once, the offers are tried in order and the first one which accepts the
+// response parameters is enabled.
+fn configure_client_extensions(extensions: &mut [Box<dyn Extension + Send + Sync>], line: &str) -> Result<(), Error> {
+    for response in parse_extensions(line)? {
+        let matches = |name: &str| response.name.eq_ignore_ascii_case(name);
+        if extensions.iter().any(|e| matches(e.name()) && e.is_enabled()) {
+            return Err(Error::UnsolicitedExtension)
+        }
+        let mut result = Err(Error::UnsolicitedExtension);
+        for ext in extensions.iter_mut().filter(|e| matches(e.name())) {
+            match ext.configure(&response.params) {
+                Ok(()) if ext.is_enabled() => {
+                    result = Ok(());
+                    break
                 }
-                ext.configure(&params).map_err(Error::Extension)?
+                Ok(()) => continue,
+                Err(e) => result = Err(Error::Extension(e))
             }
         }
+        result?
     }
     Ok(())
 }
 
+/// An extension with parameters, as given in a `Sec-WebSocket-Extensions` header.
+#[derive(Debug)]
+struct ExtensionOffer<'a> {
+    name: &'a str,
+    params: Vec<Param<'a>>
+}
+
+// Parse a `Sec-WebSocket-Extensions` header value (cf. RFC 6455, section 9.1).
+fn parse_extensions(line: &str) -> Result<Vec<ExtensionOffer<'_>>, Error> {
+    let malformed = || Error::Extension(format!("malformed extension header: {}", line).into());
+    let mut offers = Vec::new();
+    let mut input = line;
+    loop {
+        input = input.trim_start();
+        if input.is_empty() {
+            return Ok(offers)
+        }
+        if let Some(rest) = input.strip_prefix(',') { // empty list element
+            input = rest;
+            continue
+        }
+        let (name, rest) = split_token(input).ok_or_else(malformed)?;
+        input = rest.trim_start();
+        let mut params = Vec::new();
+        while let Some(rest) = input.strip_prefix(';') {
+            let (name, rest) = split_token(rest.trim_start()).ok_or_else(malformed)?;
+            let mut param = Param::new(name);
+            input = rest.trim_start();
+            if let Some(rest) = input.strip_prefix('=') {
+                let rest = rest.trim_start();
+                let (value, rest) =
+                    if rest.starts_with('"') {
+                        split_quoted_token(rest).ok_or_else(malformed)?
+                    } else {
+                        split_token(rest).map(|(v, r)| (v.into(), r)).ok_or_else(malformed)?
+                    };
+                param.set_value(Some(value));
+                input = rest.trim_start()
+            }
+            params.push(param)
+        }
+        offers.push(ExtensionOffer { name, params });
+        match input.strip_prefix(',') {
+            Some(rest) => input = rest,
+            None if input.is_empty() => {}
+            None => return Err(malformed())
+        }
+    }
+}
+
+/// Is the given character allowed in a token (cf. RFC 7230, section 3.2.6)?
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c)
+}
+
+/// The length of empty lines preceding a request or status line.
+///
+/// RFC 7230, section 3.5 asks to ignore at least one empty line (CRLF)
+/// before the request line, which some proxies emit.
+fn leading_empty_lines(bytes: &[u8]) -> usize {
+    let mut n = 0;
+    loop {
+        if bytes[n ..].starts_with(b"\r\n") {
+            n += 2
+        } else if bytes[n ..].starts_with(b"\n") {
+            n += 1
+        } else {
+            return n
+        }
+    }
+}
+
+/// Check if a header line of a request or response starts with whitespace.
+///
+/// Such lines continue the previous header value (obsolete line folding, cf.
+/// RFC 7230, section 3.2.4). The first line is the request or status line
+/// and the header section ends with an empty line.
+fn has_obsolete_line_folding(bytes: &[u8]) -> bool {
+    bytes.split(|b| *b == b'\n')
+        .skip(1)
+        .take_while(|line| !line.is_empty() && *line != b"\r")
+        .any(|line| line.starts_with(b" ") || line.starts_with(b"\t"))
+}
+
+// Split a non-empty token off the start of the input.
+fn split_token(input: &str) -> Option<(&str, &str)> {
+    let n = input.find(|c| !is_token_char(c)).unwrap_or(input.len());
+    if n == 0 {
+        return None
+    }
+    Some(input.split_at(n))
+}
+
+// Split a quoted string off the start of the input and unescape it.
+//
+// The unescaped value must be a token (cf. RFC 6455, section 9.1).
+fn split_quoted_token(input: &str) -> Option<(Cow<'_, str>, &str)> {
+    let mut value = String::new();
+    let mut chars = input.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => {
+                if value.is_empty() || !value.chars().all(is_token_char) {
+                    return None
+                }
+                return Some((value.into(), &input[i + 1 ..]))
+            }
+            '\\' => value.push(chars.next()?.1),
+            c => value.push(c)
+        }
+    }
+    None
+}
+
 // Write all extensions to the given buffer.
 fn append_extensions<'a, I>(extensions: I, bytes: &mut BytesMut)
 where
-    I: IntoIterator<Item = &'a Box<dyn Extension + Send>>
+    I: IntoIterator<Item = &'a Box<dyn Extension + Send + Sync>>
 {
     let mut iter = extensions.into_iter().peekable();
 
@@ -132,50 +342,146 @@ pub enum Error {
     /// An I/O error has been encountered.
     Io(io::Error),
     /// An HTTP version =/= 1.1 was encountered.
-    UnsupportedHttpVersion,
+    ///
+    /// If a server received the request, it is included.
+    UnsupportedHttpVersion { request: Option<Box<server::RequestInfo>> },
     /// The handshake request was not a GET request.
-    InvalidRequestMethod,
+    InvalidRequestMethod { request: Box<server::RequestInfo> },
     /// An HTTP header has not been present.
     HeaderNotFound(String),
     /// An HTTP header value was not expected.
-    UnexpectedHeader(String),
+    UnexpectedHeader { name: String, value: String },
+    /// The client requested an unsupported websocket version.
+    ///
+    /// A server should respond with [`server::Response::UpgradeRequired`].
+    UnsupportedWebSocketVersion { version: Vec<u8>, request: Box<server::RequestInfo> },
+    /// The Sec-WebSocket-Key header is malformed or given more than once.
+    InvalidSecWebSocketKey,
     /// The Sec-WebSocket-Accept header value did not match.
     InvalidSecWebSocketAccept,
     /// The server returned an extension we did not ask for.
     UnsolicitedExtension,
-    /// The server returned a protocol we did not ask for.
+    /// The remote selected a protocol which has not been offered.
     UnsolicitedProtocol,
+    /// The request does not offer any of the required protocols.
+    ProtocolRequired,
+    /// The request's host is not allowed.
+    InvalidHost(String),
+    /// The request's origin is not allowed.
+    InvalidOrigin(Option<String>),
+    /// An extra response header is malformed or reserved.
+    InvalidResponseHeader(String),
+    /// The server kept redirecting after the given max. number of redirects.
+    TooManyRedirects(usize),
+    /// The response body is larger than the configured maximum.
+    ResponseBodyTooLarge { length: usize, maximum: usize },
+    /// The response body uses an unsupported transfer encoding.
+    UnsupportedTransferEncoding,
+    /// The handshake request has a body (cf. [`server::Server::skip_request_body`]).
+    ///
+    /// The length is `None` if the body uses a transfer encoding. A server
+    /// should respond with [`server::Response::bad_request`].
+    UnexpectedRequestBody { length: Option<usize> },
+    /// The response is larger than the configured maximum.
+    ResponseTooLarge { length: usize, maximum: usize },
+    /// The request is larger than the configured maximum.
+    RequestTooLarge { length: usize, maximum: usize },
+    /// The request or response has more headers than the configured maximum.
+    TooManyHeaders { maximum: usize },
+    /// The connection was closed before the handshake completed.
+    UnexpectedEof,
+    /// The handshake did not complete before the deadline.
+    Timeout,
     /// An extension produced an error while encoding or decoding.
     Extension(crate::BoxedError),
     /// The HTTP entity could not be parsed successfully.
     Http(crate::BoxedError),
+    /// A header is continued on the next line (obsolete line folding).
+    ///
+    /// RFC 7230, section 3.2.4 requires rejecting such requests. A server
+    /// should respond with [`server::Response::bad_request`].
+    ObsoleteLineFolding,
+    /// No nonce has been set and feature `rand` is disabled (cf. [`client::Client::set_nonce`]).
+    NonceRequired,
     /// UTF-8 decoding failed.
     Utf8(str::Utf8Error)
 }
 
+impl Error {
+    /// The request which caused this error, if the server could parse it.
+    ///
+    /// This is useful for logging the request line of rejected requests.
+    pub fn request_info(&self) -> Option<&server::RequestInfo> {
+        match self {
+            Error::UnsupportedHttpVersion { request } => request.as_deref(),
+            Error::InvalidRequestMethod { request } => Some(request),
+            Error::UnsupportedWebSocketVersion { request, .. } => Some(request),
+            _ => None
+        }
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::Io(e) =>
                 write!(f, "i/o error: {}", e),
-            Error::UnsupportedHttpVersion =>
+            Error::UnsupportedHttpVersion { .. } =>
                 f.write_str("http version was not 1.1"),
-            Error::InvalidRequestMethod =>
+            Error::InvalidRequestMethod { .. } =>
                 f.write_str("handshake was not a GET request"),
             Error::HeaderNotFound(name) =>
                 write!(f, "header {} not found", name),
-            Error::UnexpectedHeader(name) =>
-                write!(f, "header {} had an unexpected value", name),
+            Error::UnexpectedHeader { name, value } =>
+                write!(f, "header {} had an unexpected value: {:?}", name, value),
+            Error::UnsupportedWebSocketVersion { version: v, .. } =>
+                write!(f, "unsupported websocket version: {}", String::from_utf8_lossy(v)),
+            Error::InvalidSecWebSocketKey =>
+                f.write_str("invalid websocket key"),
             Error::InvalidSecWebSocketAccept =>
                 f.write_str("websocket key mismatch"),
             Error::UnsolicitedExtension =>
                 f.write_str("unsolicited extension returned"),
             Error::UnsolicitedProtocol =>
                 f.write_str("unsolicited protocol returned"),
+            Error::ProtocolRequired =>
+                f.write_str("no required protocol offered"),
+            Error::InvalidHost(host) =>
+                write!(f, "invalid host: {}", host),
+            Error::InvalidOrigin(Some(origin)) =>
+                write!(f, "invalid origin: {}", origin),
+            Error::InvalidOrigin(None) =>
+                f.write_str("missing origin"),
+            Error::InvalidResponseHeader(name) =>
+                write!(f, "invalid response header: {}", name),
+            Error::TooManyRedirects(n) =>
+                write!(f, "too many redirects (max. {})", n),
+            Error::ResponseBodyTooLarge { length, maximum } =>
+                write!(f, "response body too large: len = {}, maximum = {}", length, maximum),
+            Error::UnsupportedTransferEncoding =>
+                f.write_str("unsupported transfer encoding"),
+            Error::UnexpectedRequestBody { length: Some(n) } =>
+                write!(f, "unexpected request body of {} bytes", n),
+            Error::UnexpectedRequestBody { length: None } =>
+                f.write_str("unexpected request body with transfer encoding"),
+            Error::ResponseTooLarge { length, maximum } =>
+                write!(f, "response too large: len >= {}, maximum = {}", length, maximum),
+            Error::RequestTooLarge { length, maximum } =>
+                write!(f, "request too large: len >= {}, maximum = {}", length, maximum),
+            Error::TooManyHeaders { maximum } =>
+                write!(f, "too many headers: maximum = {}", maximum),
+            Error::UnexpectedEof =>
+                f.write_str("connection closed during handshake"),
+            Error::Timeout =>
+                f.write_str("handshake timeout"),
             Error::Extension(e) =>
                 write!(f, "extension error: {}", e),
             Error::Http(e) =>
                 write!(f, "http parser error: {}", e),
+            Error::ObsoleteLineFolding =>
+                f.write_str("obsolete line folding in header"),
+            Error::NonceRequired =>
+                f.write_str("no nonce set for the handshake request"),
             Error::Utf8(e) =>
                 write!(f, "utf-8 decoding error: {}", e)
         }
@@ -189,13 +495,30 @@ impl std::error::Error for Error {
             Error::Extension(e) => Some(&**e),
             Error::Http(e) => Some(&**e),
             Error::Utf8(e) => Some(e),
-            Error::UnsupportedHttpVersion
-            | Error::InvalidRequestMethod
+            Error::UnsupportedHttpVersion {..}
+            | Error::InvalidRequestMethod {..}
             | Error::HeaderNotFound(_)
-            | Error::UnexpectedHeader(_)
+            | Error::UnexpectedHeader {..}
+            | Error::UnsupportedWebSocketVersion {..}
+            | Error::InvalidSecWebSocketKey
             | Error::InvalidSecWebSocketAccept
             | Error::UnsolicitedExtension
             | Error::UnsolicitedProtocol
+            | Error::ProtocolRequired
+            | Error::InvalidHost(_)
+            | Error::InvalidOrigin(_)
+            | Error::InvalidResponseHeader(_)
+            | Error::TooManyRedirects(_)
+            | Error::ResponseBodyTooLarge {..}
+            | Error::UnsupportedTransferEncoding
+            | Error::UnexpectedRequestBody {..}
+            | Error::ResponseTooLarge {..}
+            | Error::RequestTooLarge {..}
+            | Error::TooManyHeaders {..}
+            | Error::UnexpectedEof
+            | Error::Timeout
+            | Error::ObsoleteLineFolding
+            | Error::NonceRequired
             => None
         }
     }
@@ -203,7 +526,11 @@ impl std::error::Error for Error {
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Self {
-        Error::Io(e)
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            Error::UnexpectedEof
+        } else {
+            Error::Io(e)
+        }
     }
 }
 
@@ -215,7 +542,153 @@ impl From<str::Utf8Error> for Error {
 
 #[cfg(test)]
 mod tests {
-    use super::expect_ascii_header;
+    use super::{Client, ClientRequest, Error, Server, ServerResponse, expect_ascii_header, has_obsolete_line_folding, leading_empty_lines, parse_extensions};
+    use super::server::Response;
+
+    type TcpStream = tokio_util::compat::Compat<tokio::net::TcpStream>;
+
+    static_assertions::assert_impl_all!(Client<'static, TcpStream>: Send, Sync);
+    static_assertions::assert_impl_all!(Server<'static, TcpStream>: Send, Sync);
+    static_assertions::assert_impl_all!(ClientRequest<'static>: Send, Sync);
+    static_assertions::assert_impl_all!(ServerResponse: Send, Sync);
+    static_assertions::assert_impl_all!(Response<'static>: Send, Sync);
+
+    /// A socket type which does not implement `Debug`.
+    struct Opaque;
+
+    static_assertions::assert_impl_all!(Client<'static, Opaque>: std::fmt::Debug);
+    static_assertions::assert_impl_all!(Server<'static, Opaque>: std::fmt::Debug);
+    static_assertions::assert_impl_all!(Error: Send, Sync);
+
+    static_assertions::assert_impl_all!(Client<'static, crate::BoxedSocket>: Send);
+    static_assertions::assert_impl_all!(Server<'static, crate::BoxedSocket>: Send);
+    static_assertions::assert_impl_all!(crate::Sender<crate::BoxedSocket>: Send);
+    static_assertions::assert_impl_all!(crate::Receiver<crate::BoxedSocket>: Send);
+
+    #[test]
+    fn boxed_sockets() {
+        use crate::{BoxedSocket, Message, mock::duplex};
+        use futures::{executor::block_on, future};
+
+        let (a, b) = duplex(1024);
+        let (a, b): (BoxedSocket, BoxedSocket) = (Box::pin(a), Box::pin(b));
+
+        let client = async move {
+            let mut client = Client::new(a, "localhost", "/");
+            assert!(matches!(client.handshake().await.unwrap(), ServerResponse::Accepted { .. }));
+            let (mut sender, mut receiver) = client.into_builder().finish();
+            sender.send_text("hello").await.unwrap();
+            sender.flush().await.unwrap();
+            assert_eq!(Message::Text("hello".into()), receiver.receive().await.unwrap());
+            sender.close().await.unwrap()
+        };
+
+        let server = async move {
+            let mut server = Server::new(b);
+            let request = server.receive_request().await.unwrap();
+            server.send_response(&request.accept()).await.unwrap();
+            let (mut sender, mut receiver) = server.into_builder().finish();
+            let message = receiver.receive().await.unwrap();
+            sender.send_text(std::str::from_utf8(message.as_bytes()).unwrap()).await.unwrap();
+            sender.flush().await.unwrap();
+            assert!(matches!(receiver.receive().await.unwrap(), Message::Closed(_)))
+        };
+
+        block_on(future::join(client, server));
+    }
+
+    #[test]
+    fn futures_are_send() {
+        fn is_send<T: Send>(_: T) {}
+        let (a, b) = crate::mock::duplex(64);
+        let mut client = Client::new(a, "example.com", "/");
+        let mut server = Server::new(b);
+        is_send(client.handshake());
+        is_send(server.receive_request())
+    }
+
+    #[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+    #[test]
+    fn inspect_deflate_extensions() {
+        use crate::{Mode, extension::deflate::Deflate, mock::duplex};
+        use futures::{executor::block_on, future};
+        use super::{Client, Server, ServerResponse, server::Response};
+
+        let (a, b) = duplex(64);
+
+        let client = async move {
+            let mut client = Client::new(a, "example.com", "/");
+            client.add_extension(Box::new(Deflate::new(Mode::Client)));
+            match client.handshake().await.unwrap() {
+                ServerResponse::Accepted { extensions, .. } =>
+                    assert_eq!(vec!["permessage-deflate".to_string()], extensions),
+                other => panic!("unexpected response: {:?}", other)
+            }
+            assert!(client.extensions().all(|e| e.is_enabled()));
+            assert_eq!(1, client.extensions_mut().count());
+            let (mut sender, _) = client.into_builder().finish();
+            sender.send_text("hello hello hello hello").await.unwrap();
+            sender.flush().await.unwrap()
+        };
+
+        let server = async move {
+            let mut server = Server::new(b);
+            server.add_extension(Box::new(Deflate::new(Mode::Server)));
+            let key = server.receive_request().await.unwrap().into_key();
+            let names: Vec<_> = server.extensions().filter(|e| e.is_enabled()).map(|e| e.name()).collect();
+            assert_eq!(vec!["permessage-deflate"], names);
+            server.send_response(&Response::Accept { key: key.as_ref(), protocol: None, extra_headers: &[] }).await.unwrap();
+            let (_, mut receiver) = server.into_builder().finish();
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await.unwrap();
+            assert_eq!(&b"hello hello hello hello"[..], &message[..])
+        };
+
+        block_on(future::join(client, server));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn tokio_sockets() {
+        use crate::Message;
+        use super::{Client, Server, ServerResponse, server::Response};
+
+        let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut server = Server::from_tokio(socket);
+            let key = server.receive_request().await.unwrap().into_key();
+            let accept = Response::Accept { key: key.as_ref(), protocol: None, extra_headers: &[] };
+            server.send_response(&accept).await.unwrap();
+            let (mut sender, _) = server.into_builder().finish();
+            sender.send_text("hello").await.unwrap();
+            sender.flush().await.unwrap()
+        };
+
+        let client = async move {
+            let socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut client = Client::from_tokio(socket, "localhost", "/");
+            assert!(matches!(client.handshake().await.unwrap(), ServerResponse::Accepted { .. }));
+            let (_, mut receiver) = client.into_builder().finish();
+            assert_eq!(Message::Text("hello".into()), receiver.receive().await.unwrap())
+        };
+
+        futures::future::join(server, client).await;
+    }
+
+    #[test]
+    fn line_quirks() {
+        assert_eq!(0, leading_empty_lines(b"GET / HTTP/1.1\r\n"));
+        assert_eq!(2, leading_empty_lines(b"\r\nGET / HTTP/1.1\r\n"));
+        assert_eq!(3, leading_empty_lines(b"\r\n\nGET / HTTP/1.1\r\n"));
+        assert_eq!(2, leading_empty_lines(b"\r\n"));
+
+        assert!(!has_obsolete_line_folding(b"GET / HTTP/1.1\r\nHost: a\r\n\r\n body"));
+        assert!(has_obsolete_line_folding(b"GET / HTTP/1.1\r\nHost: a\r\n b\r\n\r\n"));
+        assert!(has_obsolete_line_folding(b"HTTP/1.1 101 OK\r\nX: a\r\n\tb\r\n\r\n"));
+    }
 
     #[test]
     fn header_match() {
@@ -228,18 +701,147 @@ mod tests {
             httparse::Header { name: "baz", value: b"123" }
         ];
 
-        assert!(expect_ascii_header(headers, "foo", "a").is_ok());
-        assert!(expect_ascii_header(headers, "foo", "b").is_ok());
-        assert!(expect_ascii_header(headers, "foo", "c").is_ok());
-        assert!(expect_ascii_header(headers, "foo", "d").is_ok());
-        assert!(expect_ascii_header(headers, "foo", "x").is_ok());
-        assert!(expect_ascii_header(headers, "foo", "y").is_ok());
-        assert!(expect_ascii_header(headers, "foo", "z").is_ok());
-        assert!(expect_ascii_header(headers, "foo", "a").is_ok());
-        assert!(expect_ascii_header(headers, "bar", "xxx").is_ok());
-        assert!(expect_ascii_header(headers, "bar", "sdfsdf 423 42 424").is_ok());
-        assert!(expect_ascii_header(headers, "baz", "123").is_ok());
-        assert!(expect_ascii_header(headers, "baz", "???").is_err());
-        assert!(expect_ascii_header(headers, "???", "x").is_err());
+        assert!(expect_ascii_header(headers, "foo", "a", false).is_ok());
+        assert!(expect_ascii_header(headers, "foo", "b", false).is_ok());
+        assert!(expect_ascii_header(headers, "foo", "c", false).is_ok());
+        assert!(expect_ascii_header(headers, "foo", "d", false).is_ok());
+        assert!(expect_ascii_header(headers, "foo", "x", false).is_ok());
+        assert!(expect_ascii_header(headers, "foo", "y", false).is_ok());
+        assert!(expect_ascii_header(headers, "foo", "z", false).is_ok());
+        assert!(expect_ascii_header(headers, "foo", "a", false).is_ok());
+        assert!(expect_ascii_header(headers, "bar", "xxx", false).is_ok());
+        assert!(expect_ascii_header(headers, "bar", "sdfsdf 423 42 424", false).is_ok());
+        assert!(expect_ascii_header(headers, "baz", "123", false).is_ok());
+        assert!(matches!(expect_ascii_header(headers, "baz", "???", false),
+            Err(Error::UnexpectedHeader { name, value }) if name == "baz" && value == "123"));
+        assert!(matches!(expect_ascii_header(headers, "???", "x", false), Err(Error::HeaderNotFound(_))));
+
+        // Header shapes seen in browser and proxy traffic.
+        let real_world: &[(&str, &[u8], &str)] = &[
+            ("Upgrade", b"WebSocket", "websocket"),
+            ("Upgrade", b"websocket, h2c", "websocket"),
+            ("Connection", b"keep-alive, Upgrade", "upgrade"),
+            ("Connection", b"Keep-Alive,Upgrade", "upgrade"),
+            ("Connection", b"\tUpgrade\t", "upgrade"),
+            ("Connection", b"Upgrade;foo=bar", "upgrade"),
+            ("Connection", b"keep-alive, Upgrade ; q=1", "upgrade"),
+            ("Sec-WebSocket-Version", b"13, 8", "13")
+        ];
+        for (name, value, ours) in real_world {
+            let headers = &[httparse::Header { name, value }];
+            assert!(expect_ascii_header(headers, name, ours, false).is_ok(), "{}: {:?}", name, value);
+            let has_params = value.contains(&b';');
+            assert_eq!(!has_params, expect_ascii_header(headers, name, ours, true).is_ok(), "{}: {:?}", name, value)
+        }
+        let headers = &[httparse::Header { name: "Connection", value: b"keep-alive;upgrade" }];
+        assert!(matches!(expect_ascii_header(headers, "Connection", "upgrade", false),
+            Err(Error::UnexpectedHeader { value, .. }) if value == "keep-alive;upgrade"));
+        let headers = &[httparse::Header { name: "Connection", value: b"Upgrade;foo=bar" }];
+        assert!(matches!(expect_ascii_header(headers, "Connection", "upgrade", true),
+            Err(Error::UnexpectedHeader { value, .. }) if value == "Upgrade;foo=bar"));
+    }
+
+    #[test]
+    fn error_display() {
+        let e = Error::UnexpectedHeader { name: "Upgrade".into(), value: "h2c".into() };
+        assert_eq!("header Upgrade had an unexpected value: \"h2c\"", e.to_string());
+        let e = Error::ResponseTooLarge { length: 8192, maximum: 4096 };
+        assert_eq!("response too large: len >= 8192, maximum = 4096", e.to_string());
+        let e = Error::RequestTooLarge { length: 8192, maximum: 4096 };
+        assert_eq!("request too large: len >= 8192, maximum = 4096", e.to_string());
+        let e = Error::from(std::io::Error::from(std::io::ErrorKind::UnexpectedEof));
+        assert_eq!("connection closed during handshake", e.to_string());
+        let e = crate::connection::Error::from(Error::InvalidSecWebSocketAccept);
+        assert_eq!("handshake error: websocket key mismatch", e.to_string());
+        assert!(std::error::Error::source(&e).is_some())
+    }
+
+    #[test]
+    fn unexpected_eof() {
+        use crate::mock::duplex;
+        use futures::executor::block_on;
+        use super::Server;
+
+        let (a, b) = duplex(64);
+        drop(a);
+        let mut server = Server::new(b);
+        assert!(matches!(block_on(server.receive_request()), Err(Error::UnexpectedEof)))
+    }
+
+    #[test]
+    fn owned_key_round_trip() {
+        use crate::mock::duplex;
+        use futures::{executor::block_on, future};
+        use super::{Client, Server, ServerResponse, WebSocketKey, server::Response};
+
+        let (a, b) = duplex(64);
+
+        let client = async move {
+            let mut client = Client::new(a, "example.com", "/");
+            client.set_nonce(b"the sample nonce");
+            assert!(matches!(client.handshake().await, Ok(ServerResponse::Accepted { .. })))
+        };
+
+        let server = async move {
+            let mut server = Server::new(b);
+            let key: WebSocketKey = server.receive_request().await.unwrap().into_key();
+            assert_eq!(&b"dGhlIHNhbXBsZSBub25jZQ=="[..], key.as_ref());
+            let accept = Response::Accept { key: key.as_ref(), protocol: None, extra_headers: &[] };
+            server.send_response(&accept).await.unwrap()
+        };
+
+        block_on(future::join(client, server));
+    }
+
+    #[test]
+    fn extension_header_parsing() {
+        // Render parsed offers in a normalised form.
+        fn parse(line: &str) -> String {
+            let offers = parse_extensions(line).unwrap();
+            let offers: Vec<String> = offers.iter().map(|o| {
+                let mut s = o.name.to_string();
+                for p in &o.params {
+                    s.push_str("; ");
+                    s.push_str(p.name());
+                    if let Some(v) = p.value() {
+                        s.push('=');
+                        s.push_str(v)
+                    }
+                }
+                s
+            })
+            .collect();
+            offers.join(", ")
+        }
+
+        let valid = &[
+            ("permessage-deflate", "permessage-deflate"),
+            ("permessage-deflate; client_max_window_bits", "permessage-deflate; client_max_window_bits"),
+            ( "permessage-deflate; client_max_window_bits, permessage-deflate; client_max_window_bits=\"10\""
+            , "permessage-deflate; client_max_window_bits, permessage-deflate; client_max_window_bits=10"
+            ),
+            ( "permessage-deflate;server_max_window_bits = 10 ;server_no_context_takeover,, foo"
+            , "permessage-deflate; server_max_window_bits=10; server_no_context_takeover, foo"
+            ),
+            ("foo; bar=\"b\\az\"", "foo; bar=baz"),
+            ("", "")
+        ];
+        for (line, expected) in valid {
+            assert_eq!(*expected, parse(line))
+        }
+
+        let invalid = &[
+            "permessage-deflate;",
+            "; client_max_window_bits",
+            "permessage-deflate client_max_window_bits",
+            "permessage-deflate; client_max_window_bits=",
+            "permessage-deflate; client_max_window_bits=\"10",
+            "permessage-deflate; client_max_window_bits=\"1 0\"",
+            "permessage-deflate; client_max_window_bits=\"\"",
+            "permessage-deflate; client_max_window_bits==10"
+        ];
+        for line in invalid {
+            assert!(parse_extensions(line).is_err(), "{}", line)
+        }
     }
 }
@@ -0,0 +1,139 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Helpers to upgrade requests handled by an HTTP library (requires feature `http`).
+//!
+//! HTTP servers such as hyper can handle routing and the HTTP part of the
+//! handshake, leaving only the websocket connection to soketto:
+//!
+//! 1. Check the request with [`is_upgrade_request`].
+//! 2. Send back the response created by [`upgrade_response`].
+//! 3. Once the connection has been upgraded, give the I/O resource to
+//!    [`connection::Builder::new`] with [`Mode::Server`].
+//!
+//! With hyper (and tokio's compat adapters) this looks like:
+//!
+//! ```no_run
+//! # use soketto::{BoxedError, connection::Mode};
+//! # use tokio_util::compat::Tokio02AsyncReadCompatExt;
+//! async fn handle(request: hyper::Request<hyper::Body>) -> Result<hyper::Response<hyper::Body>, BoxedError> {
+//!     if !soketto::handshake::http::is_upgrade_request(&request) {
+//!         return Ok(hyper::Response::new(hyper::Body::from("not a websocket request")))
+//!     }
+//!     let response = soketto::handshake::http::upgrade_response(&request, None)?;
+//!     tokio::spawn(async move {
+//!         let upgraded = request.into_body().on_upgrade().await?;
+//!         let builder = soketto::connection::Builder::new(upgraded.compat(), Mode::Server);
+//!         let (sender, receiver) = builder.finish();
+//!         // ...
+//! #       Ok::<_, BoxedError>(())
+//!     });
+//!     Ok(response.map(|()| hyper::Body::empty()))
+//! }
+//! ```
+//!
+//! See `examples/hyper_server.rs` for a complete example.
+//!
+//! Extensions are not negotiated by these functions.
+//!
+//! [`connection::Builder::new`]: crate::connection::Builder::new
+//! [`Mode::Server`]: crate::connection::Mode::Server
+
+use std::convert::TryFrom;
+use super::{ClientRequest, Error, expect_ascii_header};
+
+/// Is the given request a websocket upgrade request?
+///
+/// This checks the `Upgrade` and `Connection` headers only. Use
+/// [`upgrade_response`] to validate the request completely.
+pub fn is_upgrade_request<B>(request: &::http::Request<B>) -> bool {
+    let headers: Vec<httparse::Header> = request.headers().iter()
+        .map(|(name, value)| httparse::Header { name: name.as_str(), value: value.as_bytes() })
+        .collect();
+    expect_ascii_header(&headers, "Upgrade", "websocket", false).is_ok()
+        && expect_ascii_header(&headers, "Connection", "upgrade", false).is_ok()
+}
+
+/// Create the response which accepts the given upgrade request.
+///
+/// The response has status code 101 and contains the `Sec-WebSocket-Accept`
+/// header and the given protocol, which must have been offered by the client.
+/// Fails if the request is not a valid websocket handshake request.
+pub fn upgrade_response<B>(request: &::http::Request<B>, protocol: Option<&str>) -> Result<::http::Response<()>, Error> {
+    ClientRequest::try_from(request)?
+        .accept_response(protocol)?
+        .body(())
+        .map_err(|e| Error::Http(Box::new(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_upgrade_request, upgrade_response};
+
+    #[test]
+    fn upgrade() {
+        let request = ::http::Request::get("/chat")
+            .header("Host", "example.com")
+            .header("Upgrade", "WebSocket")
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Protocol", "chat")
+            .body(())
+            .unwrap();
+        assert!(is_upgrade_request(&request));
+
+        let response = upgrade_response(&request, Some("chat")).unwrap();
+        assert_eq!(::http::StatusCode::SWITCHING_PROTOCOLS, response.status());
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", response.headers()["Sec-WebSocket-Accept"]);
+        assert_eq!("chat", response.headers()["Sec-WebSocket-Protocol"]);
+        assert!(upgrade_response(&request, Some("other")).is_err());
+
+        let request = ::http::Request::get("/").header("Host", "example.com").body(()).unwrap();
+        assert!(!is_upgrade_request(&request));
+        assert!(upgrade_response(&request, None).is_err())
+    }
+
+    #[tokio::test]
+    async fn hyper_server() {
+        use crate::{Message, connection::{Builder, Mode}, handshake::{Client, ServerResponse}};
+        use hyper::{Body, Request, Response, service::{make_service_fn, service_fn}};
+        use tokio_util::compat::Tokio02AsyncReadCompatExt;
+
+        async fn handle(request: Request<Body>) -> Result<Response<Body>, crate::BoxedError> {
+            if !is_upgrade_request(&request) {
+                return Ok(Response::new(Body::from("not a websocket request")))
+            }
+            let response = upgrade_response(&request, None)?;
+            tokio::spawn(async move {
+                let upgraded = request.into_body().on_upgrade().await.unwrap();
+                let (mut sender, mut receiver) = Builder::new(upgraded.compat(), Mode::Server).finish();
+                let mut message = Vec::new();
+                receiver.receive_data(&mut message).await.unwrap();
+                sender.send_binary(&message).await.unwrap();
+                sender.flush().await.unwrap()
+            });
+            Ok(response.map(|()| Body::empty()))
+        }
+
+        let make_service = make_service_fn(|_| async {
+            Ok::<_, crate::BoxedError>(service_fn(handle))
+        });
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut client = Client::new(socket.compat(), "localhost", "/");
+        assert!(matches!(client.handshake().await.unwrap(), ServerResponse::Accepted { .. }));
+        let (mut sender, mut receiver) = client.into_builder().finish();
+        sender.send_binary(b"hello").await.unwrap();
+        sender.flush().await.unwrap();
+        assert_eq!(Message::Binary(b"hello"[..].into()), receiver.receive().await.unwrap())
+    }
+}
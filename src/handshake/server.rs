@@ -11,45 +11,131 @@
 //! [handshake]: https://tools.ietf.org/html/rfc6455#section-4
 
 use bytes::{Buf, BytesMut};
-use crate::{Parsing, extension::Extension};
+use crate::{Parsing, extension::{self, Extension}};
 use crate::connection::{self, Mode};
+use crate::observer::{HandshakeObserver, HandshakeOutcome};
 use futures::prelude::*;
 use sha1::{Digest, Sha1};
-use std::{mem, str};
+use std::{borrow::Cow, fmt, mem, str, sync::Arc};
 use super::{
     Error,
     KEY,
     MAX_NUM_HEADERS,
+    STACK_NUM_HEADERS,
     SEC_WEBSOCKET_EXTENSIONS,
     SEC_WEBSOCKET_PROTOCOL,
+    SEC_WEBSOCKET_VERSION,
     append_extensions,
     configure_extensions,
+    contains_token,
+    content_length,
     expect_ascii_header,
+    has_obsolete_line_folding,
+    in_span,
+    leading_empty_lines,
+    protocol_tokens,
     with_first_header
 };
 
 const BLOCK_SIZE: usize = 8 * 1024;
-const SOKETTO_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-/// Websocket handshake client.
-#[derive(Debug)]
+/// Default max. size of a handshake request.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+const SERVER_HEADER: &str = concat!("soketto-", env!("CARGO_PKG_VERSION"));
+
+/// Websocket handshake server.
 pub struct Server<'a, T> {
     socket: T,
     /// Protocols the server supports.
-    protocols: Vec<&'a str>,
+    protocols: Vec<Cow<'a, str>>,
+    /// Protocols of which the client must offer at least one.
+    required_protocols: Vec<Cow<'a, str>>,
     /// Extensions the server supports.
-    extensions: Vec<Box<dyn Extension + Send>>,
+    extensions: Vec<Box<dyn Extension + Send + Sync>>,
+    /// Hosts the server accepts requests for (if empty, all hosts are accepted).
+    allowed_hosts: Vec<Cow<'a, str>>,
+    /// Policy deciding which origins are accepted.
+    origin_policy: Option<OriginPolicy>,
+    /// The protocols offered by the client in the last request.
+    offered_protocols: Vec<String>,
+    /// Max. size of a handshake request.
+    max_request_size: usize,
+    /// Max. number of headers of a handshake request.
+    max_headers: usize,
+    /// Max. size of a request body to skip (if `None`, bodies are rejected).
+    max_body_size: Option<usize>,
+    /// Should header values be matched without ignoring parameters?
+    strict_headers: bool,
+    /// ID of the connection in logs and tracing events.
+    trace_id: Option<String>,
+    /// Observer of the handshake outcome.
+    observer: Option<Arc<dyn HandshakeObserver>>,
+    /// Value of the `Server` response header.
+    server_header: Option<&'a str>,
+    /// Should all request headers be included in the [`ClientRequest`]?
+    record_headers: bool,
+    /// Filter applied to the extensions of every request.
+    extension_filter: Option<ExtensionFilter>,
+    /// Factories creating extensions for every connection.
+    extension_factories: Vec<ExtensionFactory>,
+    /// Have extensions been created by the factories for the current socket?
+    extensions_created: bool,
     /// Encoding/decoding buffer.
     buffer: BytesMut
 }
 
+/// The socket is not included.
+impl<T> fmt::Debug for Server<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Server")
+            .field("protocols", &self.protocols)
+            .field("required_protocols", &self.required_protocols)
+            .field("extensions", &self.extensions.iter().map(|e| e.name()).collect::<Vec<_>>())
+            .field("allowed_hosts", &self.allowed_hosts)
+            .field("buffered", &self.buffer.len())
+            .field("max_request_size", &self.max_request_size)
+            .field("trace_id", &self.trace_id)
+            .finish()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<'a, T> Server<'a, tokio_util::compat::Compat<T>>
+where
+    T: tokio_util::compat::Tokio02AsyncReadCompatExt,
+    tokio_util::compat::Compat<T>: AsyncRead + AsyncWrite + Unpin
+{
+    /// Create a new server handshake for a tokio socket (requires feature `tokio`).
+    ///
+    /// The socket is wrapped in a [`Compat`](tokio_util::compat::Compat)
+    /// adapter, cf. [`Server::new`].
+    pub fn from_tokio(socket: T) -> Self {
+        Server::new(socket.compat())
+    }
+}
+
 impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
     /// Create a new server handshake.
     pub fn new(socket: T) -> Self {
         Server {
             socket,
             protocols: Vec::new(),
+            required_protocols: Vec::new(),
             extensions: Vec::new(),
+            allowed_hosts: Vec::new(),
+            origin_policy: None,
+            offered_protocols: Vec::new(),
+            max_request_size: MAX_REQUEST_SIZE,
+            max_headers: MAX_NUM_HEADERS,
+            max_body_size: None,
+            strict_headers: false,
+            trace_id: None,
+            observer: None,
+            server_header: Some(SERVER_HEADER),
+            record_headers: false,
+            extension_filter: None,
+            extension_factories: Vec::new(),
+            extensions_created: false,
             buffer: BytesMut::new()
         }
     }
@@ -65,28 +151,230 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         mem::take(&mut self.buffer)
     }
 
+    /// Set the max. size in bytes of a handshake request (default: 16 KiB).
+    ///
+    /// If the request exceeds this size, [`Server::receive_request`] fails
+    /// with [`Error::RequestTooLarge`]. The request may still be rejected,
+    /// e.g. with status code 431.
+    pub fn set_max_request_size(&mut self, max: usize) -> &mut Self {
+        self.max_request_size = max;
+        self
+    }
+
+    /// Set the max. number of headers of a handshake request (default: 128).
+    ///
+    /// If the request has more headers, [`Server::receive_request`] fails
+    /// with [`Error::TooManyHeaders`]. The request may still be rejected,
+    /// e.g. with status code 431.
+    pub fn set_max_headers(&mut self, max: usize) -> &mut Self {
+        self.max_headers = max;
+        self
+    }
+
+    /// Skip the body of a handshake request, up to the given max. size in bytes.
+    ///
+    /// Upgrade requests should not have a body, but some clients send one
+    /// nevertheless. By default (`None`) or if the body is larger than the
+    /// given size, [`Server::receive_request`] fails with
+    /// [`Error::UnexpectedRequestBody`]. Otherwise the body is read and
+    /// discarded, so that it is not mistaken for websocket frames. The body
+    /// length must be given by a `Content-Length` header and counts towards
+    /// the max. request size (cf. [`Server::set_max_request_size`]).
+    pub fn skip_request_body(&mut self, max_size: Option<usize>) -> &mut Self {
+        self.max_body_size = max_size;
+        self
+    }
+
+    /// Match the values of `Upgrade`, `Connection` and `Sec-WebSocket-Version`
+    /// headers strictly (default: false).
+    ///
+    /// Header values are lists of case-insensitive tokens, e.g.
+    /// `Connection: keep-alive, Upgrade`. By default, parameters after a
+    /// token, as in `Upgrade;foo=bar`, are ignored. If strict, a token must
+    /// match exactly, apart from case and surrounding whitespace.
+    pub fn set_strict_header_matching(&mut self, strict: bool) -> &mut Self {
+        self.strict_headers = strict;
+        self
+    }
+
+    /// Set an identifier of the connection, e.g. the remote address.
+    ///
+    /// With feature `tracing`, [`Server::receive_request`] runs in a span
+    /// with this ID. It is passed on to the [`connection::Builder`], cf.
+    /// [`connection::Builder::set_trace_id`].
+    pub fn set_trace_id(&mut self, id: impl fmt::Display) -> &mut Self {
+        self.trace_id = Some(id.to_string());
+        self
+    }
+
+    /// Set an observer of the handshake outcome, cf. [`crate::observer`].
+    pub fn set_observer(&mut self, observer: Arc<dyn HandshakeObserver>) -> &mut Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Include all HTTP headers of the request in the [`ClientRequest`].
+    ///
+    /// By default headers are not recorded and [`ClientRequest::headers`]
+    /// is empty.
+    pub fn record_request_headers(&mut self, record: bool) -> &mut Self {
+        self.record_headers = record;
+        self
+    }
+
+    /// Set a filter which may change the extensions per request.
+    ///
+    /// The filter is applied after the extensions have been configured with
+    /// the parameters of a request. It may remove extensions, e.g. to not
+    /// use compression with certain clients. Removed extensions are neither
+    /// included in the response nor used by the connection.
+    pub fn set_extension_filter<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnMut(&ClientRequest<'_>, &mut Vec<Box<dyn Extension + Send + Sync>>) + Send + Sync + 'static
+    {
+        self.extension_filter = Some(ExtensionFilter(Box::new(f)));
+        self
+    }
+
+    /// Set the value of the `Server` response header.
+    ///
+    /// By default, responses contain `Server: soketto-<version>`. With
+    /// `None` the header is omitted. A `Server` header given as extra
+    /// response header always takes precedence.
+    pub fn set_server_header(&mut self, value: Option<&'a str>) -> &mut Self {
+        self.server_header = value;
+        self
+    }
+
     /// Add a protocol the server supports.
-    pub fn add_protocol(&mut self, p: &'a str) -> &mut Self {
-        self.protocols.push(p);
+    pub fn add_protocol(&mut self, p: impl Into<Cow<'a, str>>) -> &mut Self {
+        self.protocols.push(p.into());
+        self
+    }
+
+    /// Add a protocol the server supports and require it.
+    ///
+    /// If protocols are required, a request must offer at least one of them,
+    /// otherwise [`Server::receive_request`] fails with
+    /// [`Error::ProtocolRequired`] and the request should be rejected, e.g.
+    /// with [`Response::bad_request`].
+    pub fn require_protocol(&mut self, p: impl Into<Cow<'a, str>>) -> &mut Self {
+        let p = p.into();
+        self.protocols.push(p.clone());
+        self.required_protocols.push(p);
+        self
+    }
+
+    /// Restrict the hosts the server accepts requests for.
+    ///
+    /// If set, the `Host` header of a request must match one of the given
+    /// hosts, otherwise [`Server::receive_request`] fails with
+    /// [`Error::InvalidHost`] and the request should be rejected, e.g. with
+    /// status code 403. Hosts are compared case-insensitively. An allowed
+    /// host without port matches requests for any port, otherwise the ports
+    /// must match too. IPv6 addresses must be given in brackets, e.g. `[::1]`.
+    ///
+    /// By default the `Host` header must be present but its value is not
+    /// checked, so clients on transports without host names, e.g. unix
+    /// domain sockets, may send any value.
+    pub fn set_allowed_hosts<I, H>(&mut self, hosts: I) -> &mut Self
+    where
+        I: IntoIterator<Item = H>,
+        H: Into<Cow<'a, str>>
+    {
+        self.allowed_hosts = hosts.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set a policy which decides whether a request's origin is acceptable.
+    ///
+    /// The policy is given the value of the request's `Origin` header, or
+    /// `None` if the request has no such header, which is typical for
+    /// non-browser clients. If the policy returns `false`,
+    /// [`Server::receive_request`] fails with [`Error::InvalidOrigin`] and
+    /// the request should be rejected, e.g. with status code 403.
+    pub fn set_origin_policy(&mut self, f: impl Fn(Option<&str>) -> bool + Send + Sync + 'static) -> &mut Self {
+        self.origin_policy = Some(OriginPolicy(Box::new(f)));
         self
     }
 
     /// Add an extension the server supports.
-    pub fn add_extension(&mut self, e: Box<dyn Extension + Send>) -> &mut Self {
+    ///
+    /// # Panics
+    ///
+    /// If the extension uses a reserved bit which is already used by another
+    /// extension with a different name.
+    pub fn add_extension(&mut self, e: Box<dyn Extension + Send + Sync>) -> &mut Self {
+        extension::assert_no_conflict(&self.extensions, &*e, true);
         self.extensions.push(e);
         self
     }
 
+    /// Add a factory which creates an extension the server supports.
+    ///
+    /// Unlike extensions added with [`Server::add_extension`], which are
+    /// moved into the first connection, the factory creates a new extension
+    /// for every socket. This allows using one server for several
+    /// connections (cf. [`Server::take_builder`]), each with its own
+    /// extension state.
+    pub fn add_extension_factory<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnMut() -> Box<dyn Extension + Send + Sync> + Send + Sync + 'static
+    {
+        self.extension_factories.push(ExtensionFactory(Box::new(f)));
+        self
+    }
+
+    /// Access all extensions.
+    pub fn extensions(&self) -> impl Iterator<Item = &(dyn Extension + Send + Sync)> {
+        self.extensions.iter().map(|e| &**e as &(dyn Extension + Send + Sync))
+    }
+
+    /// Access all extensions mutably.
+    pub fn extensions_mut(&mut self) -> impl Iterator<Item = &mut (dyn Extension + Send + Sync)> {
+        self.extensions.iter_mut().map(|e| &mut **e as &mut (dyn Extension + Send + Sync))
+    }
+
     /// Get back all extensions.
-    pub fn drain_extensions(&mut self) -> impl Iterator<Item = Box<dyn Extension + Send>> + '_ {
+    pub fn drain_extensions(&mut self) -> impl Iterator<Item = Box<dyn Extension + Send + Sync>> + '_ {
         self.extensions.drain(..)
     }
 
     /// Await an incoming client handshake request.
+    ///
+    /// If the request is invalid, an error is returned. Unless it is an I/O
+    /// error, a response can still be sent with [`Server::send_response`],
+    /// e.g. [`Response::method_not_allowed`] after
+    /// [`Error::InvalidRequestMethod`].
     pub async fn receive_request(&mut self) -> Result<ClientRequest<'a>, Error> {
+        if let Some(o) = &self.observer {
+            o.on_start(Mode::Server)
+        }
+        let id = self.trace_id.clone();
+        let result = in_span(Mode::Server, id.as_deref(), self.read_request()).await;
+        if let (Some(o), Err(_)) = (&self.observer, &result) {
+            o.on_end(Mode::Server, HandshakeOutcome::Failed)
+        }
+        result
+    }
+
+    /// Read and decode the client handshake request.
+    async fn read_request(&mut self) -> Result<ClientRequest<'a>, Error> {
+        if !self.extensions_created {
+            for f in &mut self.extension_factories {
+                let e = (f.0)();
+                extension::assert_no_conflict(&self.extensions, &*e, true);
+                self.extensions.push(e)
+            }
+            self.extensions_created = true
+        }
         self.buffer.clear();
         loop {
-            crate::read(&mut self.socket, &mut self.buffer, BLOCK_SIZE).await?;
+            if self.buffer.len() >= self.max_request_size {
+                return Err(Error::RequestTooLarge { length: self.buffer.len(), maximum: self.max_request_size })
+            }
+            let max = std::cmp::min(BLOCK_SIZE, self.max_request_size - self.buffer.len());
+            crate::read(&mut self.socket, &mut self.buffer, max).await?;
             if let Parsing::Done { value, offset } = self.decode_request()? {
                 self.buffer.advance(offset);
                 return Ok(value)
@@ -94,24 +382,85 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         }
     }
 
+    /// Like [`Server::receive_request`] but abort once the given deadline completes.
+    ///
+    /// The deadline can be any future, e.g. a timer of the async runtime in
+    /// use. If it completes before a request has been received,
+    /// [`Error::Timeout`] is returned. The request may still be rejected,
+    /// e.g. with status code 408.
+    pub async fn receive_request_with_deadline<D: Future>(&mut self, deadline: D) -> Result<ClientRequest<'a>, Error> {
+        let request = self.receive_request();
+        futures::pin_mut!(request, deadline);
+        match future::select(request, deadline).await {
+            future::Either::Left((result, _)) => result,
+            future::Either::Right(_) => {
+                log::debug!("handshake deadline expired");
+                Err(Error::Timeout)
+            }
+        }
+    }
+
     /// Respond to the client.
     pub async fn send_response(&mut self, r: &Response<'_>) -> Result<(), Error> {
+        let result = self.write_response(r).await;
+        if let Some(o) = &self.observer {
+            let outcome = match (&result, r) {
+                (Err(_), _) => HandshakeOutcome::Failed,
+                (Ok(()), Response::Accept { .. }) => HandshakeOutcome::Accepted,
+                (Ok(()), Response::Reject { status_code, .. }) => HandshakeOutcome::Rejected { status_code: *status_code },
+                (Ok(()), Response::UpgradeRequired { .. }) => HandshakeOutcome::Rejected { status_code: 426 }
+            };
+            o.on_end(Mode::Server, outcome)
+        }
+        result
+    }
+
+    /// Encode and send the response.
+    ///
+    /// Data received after the request, e.g. websocket frames sent by the
+    /// client without waiting for the response, is kept in the buffer.
+    async fn write_response(&mut self, r: &Response<'_>) -> Result<(), Error> {
+        let unread = self.buffer.split();
+        let result = self.encode_response(r);
+        if result.is_ok() {
+            self.socket.write_all(&self.buffer).await?;
+            self.socket.flush().await?
+        }
         self.buffer.clear();
-        self.encode_response(r);
-        self.socket.write_all(&self.buffer).await?;
-        self.socket.flush().await?;
-        self.buffer.clear();
-        Ok(())
+        self.buffer.unsplit(unread);
+        result
     }
 
     /// Turn this handshake into a [`connection::Builder`].
     pub fn into_builder(mut self) -> connection::Builder<T> {
         let mut builder = connection::Builder::new(self.socket, Mode::Server);
+        if let Some(id) = &self.trace_id {
+            builder.set_trace_id(id)
+        }
         builder.set_buffer(self.buffer);
         builder.add_extensions(self.extensions.drain(..));
         builder
     }
 
+    /// Turn this server into a connection builder and continue with the next socket.
+    ///
+    /// The builder receives the current socket and the extensions negotiated
+    /// for it. All settings, protocols and extension factories are kept, so
+    /// the server can receive the handshake request of the next socket, e.g.
+    /// of the next connection accepted by a listener.
+    pub fn take_builder(&mut self, next: T) -> connection::Builder<T> {
+        let socket = mem::replace(&mut self.socket, next);
+        let mut builder = connection::Builder::new(socket, Mode::Server);
+        if let Some(id) = self.trace_id.take() {
+            builder.set_trace_id(id)
+        }
+        builder.set_buffer(mem::take(&mut self.buffer));
+        builder.add_extensions(self.extensions.drain(..));
+        self.offered_protocols.clear();
+        self.extensions_created = false;
+        builder
+    }
+
     /// Get out the inner socket of the server.
     pub fn into_inner(self) -> T {
         self.socket
@@ -119,33 +468,95 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
 
     // Decode client handshake request.
     fn decode_request(&mut self) -> Result<Parsing<ClientRequest<'a>>, Error> {
-        let mut header_buf = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
-        let mut request = httparse::Request::new(&mut header_buf);
+        let mut stack_buf;
+        let mut heap_buf;
+        let header_buf: &mut [httparse::Header] =
+            if self.max_headers <= STACK_NUM_HEADERS {
+                stack_buf = [httparse::EMPTY_HEADER; STACK_NUM_HEADERS];
+                &mut stack_buf[.. self.max_headers]
+            } else {
+                heap_buf = vec![httparse::EMPTY_HEADER; self.max_headers];
+                &mut heap_buf
+            };
+        let mut request = httparse::Request::new(header_buf);
 
-        let offset = match request.parse(self.buffer.as_ref()) {
-            Ok(httparse::Status::Complete(off)) => off,
+        let start = leading_empty_lines(&self.buffer);
+        let mut offset = match request.parse(&self.buffer[start ..]) {
+            Ok(httparse::Status::Complete(off)) => start + off,
             Ok(httparse::Status::Partial) => return Ok(Parsing::NeedMore(())),
+            Err(httparse::Error::TooManyHeaders) =>
+                return Err(Error::TooManyHeaders { maximum: self.max_headers }),
+            Err(_) if has_obsolete_line_folding(&self.buffer[start ..]) =>
+                return Err(Error::ObsoleteLineFolding),
             Err(e) => return Err(Error::Http(Box::new(e)))
         };
 
+        let info = RequestInfo::new(&request, &self.buffer[start ..]);
+
         if request.method != Some("GET") {
-            return Err(Error::InvalidRequestMethod)
+            return Err(Error::InvalidRequestMethod { request: Box::new(info) })
         }
         if request.version != Some(1) {
-            return Err(Error::UnsupportedHttpVersion)
+            return Err(Error::UnsupportedHttpVersion { request: Some(Box::new(info)) })
         }
 
-        // TODO: Host Validation
-        with_first_header(&request.headers, "Host", |_h| Ok(()))?;
+        let body_len = match content_length(request.headers) {
+            Ok(n) => n,
+            Err(Error::UnsupportedTransferEncoding) => return Err(Error::UnexpectedRequestBody { length: None }),
+            Err(e) => return Err(e)
+        };
+        if body_len > 0 {
+            match self.max_body_size {
+                Some(max) if body_len <= max => {
+                    if self.buffer.len() < offset + body_len {
+                        return Ok(Parsing::NeedMore(()))
+                    }
+                    log::debug!("skipping request body of {} bytes", body_len);
+                    offset += body_len
+                }
+                _ => return Err(Error::UnexpectedRequestBody { length: Some(body_len) })
+            }
+        }
 
-        expect_ascii_header(request.headers, "Upgrade", "websocket")?;
-        expect_ascii_header(request.headers, "Connection", "upgrade")?;
-        expect_ascii_header(request.headers, "Sec-WebSocket-Version", "13")?;
+        with_first_header(request.headers, "Host", |h| {
+            if self.allowed_hosts.is_empty() {
+                return Ok(())
+            }
+            let host = str::from_utf8(h)?.trim();
+            if self.allowed_hosts.iter().any(|allowed| host_matches(allowed, host)) {
+                Ok(())
+            } else {
+                log::debug!("host {} not allowed", host);
+                Err(Error::InvalidHost(host.into()))
+            }
+        })?;
 
-        let ws_key = with_first_header(&request.headers, "Sec-WebSocket-Key", |k| {
-            Ok(Vec::from(k))
+        expect_ascii_header(request.headers, "Upgrade", "websocket", self.strict_headers)?;
+        expect_ascii_header(request.headers, "Connection", "upgrade", self.strict_headers)?;
+        with_first_header(request.headers, SEC_WEBSOCKET_VERSION, |v| {
+            if str::from_utf8(v).is_ok_and(|v| contains_token(v, "13", self.strict_headers)) {
+                Ok(())
+            } else {
+                log::debug!("unsupported websocket version: {:?}", v);
+                Err(Error::UnsupportedWebSocketVersion { version: Vec::from(v), request: Box::new(info.clone()) })
+            }
         })?;
 
+        let origin = match with_first_header(request.headers, "Origin", |o| Ok(String::from(str::from_utf8(o)?))) {
+            Ok(o) => Some(o),
+            Err(Error::HeaderNotFound(_)) => None,
+            Err(e) => return Err(e)
+        };
+
+        if let Some(policy) = &self.origin_policy {
+            if !(policy.0)(origin.as_deref()) {
+                log::debug!("origin {:?} not allowed", origin);
+                return Err(Error::InvalidOrigin(origin))
+            }
+        }
+
+        let ws_key = websocket_key(request.headers)?;
+
         for h in request.headers.iter()
             .filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_EXTENSIONS))
         {
@@ -153,12 +564,20 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
         }
 
         let mut protocols = Vec::new();
-        for p in request.headers.iter()
-            .filter(|h| h.name.eq_ignore_ascii_case(SEC_WEBSOCKET_PROTOCOL))
-        {
-            if let Some(&p) = self.protocols.iter().find(|x| x.as_bytes() == p.value) {
-                protocols.push(p)
+        let mut offered_protocols = Vec::new();
+        for o in protocol_tokens(request.headers)? {
+            if let Some(p) = self.protocols.iter().find(|x| *x == o) {
+                protocols.push(p.clone())
             }
+            offered_protocols.push(String::from(o))
+        }
+        self.offered_protocols = offered_protocols.clone();
+
+        if !self.required_protocols.is_empty()
+            && !self.required_protocols.iter().any(|r| protocols.contains(r))
+        {
+            log::debug!("none of the required protocols offered: {:?}", offered_protocols);
+            return Err(Error::ProtocolRequired)
         }
 
         let mut path = String::new();
@@ -166,27 +585,37 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
             path.push_str(val)
         }
 
-        Ok(Parsing::Done {
-            value: ClientRequest { ws_key, protocols, path }, offset,
-        })
+        let headers =
+            if self.record_headers {
+                request.headers.iter().map(|h| (String::from(h.name), Vec::from(h.value))).collect()
+            } else {
+                Vec::new()
+            };
+
+        let request = ClientRequest { ws_key, protocols, offered_protocols, path, origin, headers, info };
+
+        if let Some(filter) = &mut self.extension_filter {
+            (filter.0)(&request, &mut self.extensions)
+        }
+
+        Ok(Parsing::Done { value: request, offset })
     }
 
     // Encode server handshake response.
-    fn encode_response(&mut self, response: &Response<'_>) {
+    fn encode_response(&mut self, response: &Response<'_>) -> Result<(), Error> {
         match response {
-            Response::Accept { key, protocol } => {
+            Response::Accept { key, protocol, extra_headers } => {
+                validate_headers(extra_headers)?;
+                if let Some(p) = protocol {
+                    if !self.offered_protocols.iter().any(|o| o == p) {
+                        log::debug!("protocol {} has not been offered by the client", p);
+                        return Err(Error::UnsolicitedProtocol)
+                    }
+                }
                 let mut key_buf = [0; 32];
-                let accept_value = {
-                    let mut digest = Sha1::new();
-                    digest.update(key);
-                    digest.update(KEY);
-                    let d = digest.finalize();
-                    let n = base64::encode_config_slice(&d, base64::STANDARD, &mut key_buf);
-                    &key_buf[.. n]
-                };
+                let accept_value = accept_key(key, &mut key_buf);
                 self.buffer.extend_from_slice(b"HTTP/1.1 101 Switching Protocols");
-                self.buffer.extend_from_slice(b"\r\nServer: soketto-");
-                self.buffer.extend_from_slice(SOKETTO_VERSION.as_bytes());
+                self.encode_server_header(extra_headers);
                 self.buffer.extend_from_slice(b"\r\nUpgrade: websocket\r\nConnection: upgrade");
                 self.buffer.extend_from_slice(b"\r\nSec-WebSocket-Accept: ");
                 self.buffer.extend_from_slice(accept_value);
@@ -195,9 +624,11 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
                     self.buffer.extend_from_slice(p.as_bytes())
                 }
                 append_extensions(self.extensions.iter().filter(|e| e.is_enabled()), &mut self.buffer);
+                self.encode_extra_headers(extra_headers);
                 self.buffer.extend_from_slice(b"\r\n\r\n")
             }
-            Response::Reject { status_code } => {
+            Response::Reject { status_code, extra_headers, body, content_type } => {
+                validate_headers(extra_headers)?;
                 self.buffer.extend_from_slice(b"HTTP/1.1 ");
                 let (_, s, reason) =
                     if let Ok(i) = STATUSCODES.binary_search_by_key(status_code, |(n, _, _)| *n) {
@@ -208,38 +639,449 @@ impl<'a, T: AsyncRead + AsyncWrite + Unpin> Server<'a, T> {
                 self.buffer.extend_from_slice(s.as_bytes());
                 self.buffer.extend_from_slice(b" ");
                 self.buffer.extend_from_slice(reason.as_bytes());
-                self.buffer.extend_from_slice(b"\r\n\r\n")
+                self.encode_server_header(extra_headers);
+                self.encode_extra_headers(extra_headers);
+                if let Some(t) = content_type {
+                    if t.bytes().any(|b| b == b'\r' || b == b'\n') {
+                        return Err(Error::InvalidResponseHeader("Content-Type".into()))
+                    }
+                    self.buffer.extend_from_slice(b"\r\nContent-Type: ");
+                    self.buffer.extend_from_slice(t.as_bytes())
+                }
+                self.buffer.extend_from_slice(b"\r\nContent-Length: ");
+                self.buffer.extend_from_slice(body.len().to_string().as_bytes());
+                self.buffer.extend_from_slice(b"\r\n\r\n");
+                self.buffer.extend_from_slice(body)
+            }
+            Response::UpgradeRequired { extra_headers } => {
+                validate_headers(extra_headers)?;
+                self.buffer.extend_from_slice(b"HTTP/1.1 426 Upgrade Required");
+                self.encode_server_header(extra_headers);
+                self.buffer.extend_from_slice(b"\r\nUpgrade: websocket\r\nConnection: upgrade");
+                self.buffer.extend_from_slice(b"\r\nSec-WebSocket-Version: 13");
+                self.encode_extra_headers(extra_headers);
+                self.buffer.extend_from_slice(b"\r\nContent-Length: 0\r\n\r\n")
             }
         }
+        Ok(())
+    }
+
+    // Encode the `Server` header, unless given as an extra header.
+    fn encode_server_header(&mut self, extra_headers: &[(&str, &[u8])]) {
+        if extra_headers.iter().any(|(n, _)| n.eq_ignore_ascii_case("Server")) {
+            return
+        }
+        if let Some(value) = self.server_header {
+            self.buffer.extend_from_slice(b"\r\nServer: ");
+            self.buffer.extend_from_slice(value.as_bytes())
+        }
     }
+
+    // Encode extra response headers.
+    fn encode_extra_headers(&mut self, extra_headers: &[(&str, &[u8])]) {
+        for (name, value) in extra_headers {
+            self.buffer.extend_from_slice(b"\r\n");
+            self.buffer.extend_from_slice(name.as_bytes());
+            self.buffer.extend_from_slice(b": ");
+            self.buffer.extend_from_slice(value)
+        }
+    }
+}
+
+/// Get the value of the `Sec-WebSocket-Key` header.
+///
+/// The key must be given exactly once and be the base64 encoding of 16 bytes.
+fn websocket_key(headers: &[httparse::Header]) -> Result<WebSocketKey, Error> {
+    let key = with_first_header(headers, "Sec-WebSocket-Key", Ok)?;
+    if headers.iter().filter(|h| h.name.eq_ignore_ascii_case("Sec-WebSocket-Key")).count() > 1 {
+        log::debug!("multiple websocket keys");
+        return Err(Error::InvalidSecWebSocketKey)
+    }
+    let mut decoded = [0; 18];
+    match base64::decode_config_slice(key, base64::STANDARD, &mut decoded) {
+        Ok(16) if key.len() == 24 => {
+            let mut k = [0; 24];
+            k.copy_from_slice(key);
+            Ok(WebSocketKey(k))
+        }
+        _ => {
+            log::debug!("invalid websocket key: {:?}", key);
+            Err(Error::InvalidSecWebSocketKey)
+        }
+    }
+}
+
+/// Compute the `Sec-WebSocket-Accept` value of a websocket key.
+pub(super) fn accept_key<'b>(key: &[u8], buf: &'b mut [u8; 32]) -> &'b [u8] {
+    let mut digest = Sha1::new();
+    digest.update(key);
+    digest.update(KEY);
+    let d = digest.finalize();
+    let n = base64::encode_config_slice(d, base64::STANDARD, buf);
+    &buf[.. n]
+}
+
+/// Headers which can not be set as extra response headers.
+const RESERVED_HEADERS: &[&str] = &[
+    "Connection",
+    "Content-Length",
+    "Upgrade",
+    "Sec-WebSocket-Accept",
+    SEC_WEBSOCKET_EXTENSIONS,
+    SEC_WEBSOCKET_PROTOCOL,
+    SEC_WEBSOCKET_VERSION
+];
+
+/// Check that extra response headers are well-formed and not reserved.
+fn validate_headers(headers: &[(&str, &[u8])]) -> Result<(), Error> {
+    for (name, value) in headers {
+        let is_token = !name.is_empty() && name.bytes().all(|b| b.is_ascii_graphic() && b != b':');
+        if !is_token || value.iter().any(|&b| b == b'\r' || b == b'\n') {
+            return Err(Error::InvalidResponseHeader((*name).into()))
+        }
+        if RESERVED_HEADERS.iter().any(|r| r.eq_ignore_ascii_case(name)) {
+            return Err(Error::InvalidResponseHeader((*name).into()))
+        }
+    }
+    Ok(())
+}
+
+type OriginFn = dyn Fn(Option<&str>) -> bool + Send + Sync;
+
+type ExtensionFn = dyn FnMut(&ClientRequest<'_>, &mut Vec<Box<dyn Extension + Send + Sync>>) + Send + Sync;
+type FactoryFn = dyn FnMut() -> Box<dyn Extension + Send + Sync> + Send + Sync;
+
+/// A policy deciding whether a request's origin is acceptable.
+struct OriginPolicy(Box<OriginFn>);
+
+impl fmt::Debug for OriginPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("OriginPolicy")
+    }
+}
+
+/// A filter which may change the extensions per request.
+struct ExtensionFilter(Box<ExtensionFn>);
+
+impl fmt::Debug for ExtensionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ExtensionFilter")
+    }
+}
+
+/// A factory creating an extension per connection.
+struct ExtensionFactory(Box<FactoryFn>);
+
+impl fmt::Debug for ExtensionFactory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ExtensionFactory")
+    }
+}
+
+/// Split a host into name and optional port.
+fn split_port(host: &str) -> (&str, Option<&str>) {
+    let i =
+        if host.starts_with('[') {
+            host.find(']').map(|i| i + 1) // IPv6 address
+        } else {
+            host.rfind(':')
+        };
+    match i {
+        Some(i) if host[i ..].starts_with(':') => (&host[.. i], Some(&host[i + 1 ..])),
+        _ => (host, None)
+    }
+}
+
+/// Does the host of a request match an allowed host?
+fn host_matches(allowed: &str, host: &str) -> bool {
+    let (allowed_name, allowed_port) = split_port(allowed);
+    let (name, port) = split_port(host);
+    if !allowed_name.eq_ignore_ascii_case(name) {
+        return false
+    }
+    allowed_port.is_none() || allowed_port == port
 }
 
 /// Handshake request received from the client.
 #[derive(Debug)]
 pub struct ClientRequest<'a> {
-    ws_key: Vec<u8>,
-    protocols: Vec<&'a str>,
+    ws_key: WebSocketKey,
+    protocols: Vec<Cow<'a, str>>,
+    offered_protocols: Vec<String>,
     path: String,
+    origin: Option<String>,
+    headers: Vec<(String, Vec<u8>)>,
+    info: RequestInfo
 }
 
 impl<'a> ClientRequest<'a> {
     /// A reference to the nonce.
     pub fn key(&self) -> &[u8] {
-        &self.ws_key
+        self.ws_key.as_ref()
     }
 
-    pub fn into_key(self) -> Vec<u8> {
+    /// Turn this request into its nonce.
+    pub fn into_key(self) -> WebSocketKey {
         self.ws_key
     }
 
-    /// The protocols the client is proposing.
+    /// The protocols the client is proposing which the server supports.
+    ///
+    /// Only protocols added with [`Server::add_protocol`] or
+    /// [`Server::require_protocol`] are considered. The protocols are
+    /// ordered by client preference.
     pub fn protocols(&self) -> impl Iterator<Item = &str> {
-        self.protocols.iter().cloned()
+        self.protocols.iter().map(|p| p.as_ref())
     }
 
-    /// The path the client is requesting.
+    /// All protocols the client is proposing, in order of preference.
+    ///
+    /// A server may select any of these protocols dynamically. Selecting a
+    /// protocol which has not been offered causes [`Server::send_response`]
+    /// to fail with [`Error::UnsolicitedProtocol`].
+    pub fn offered_protocols(&self) -> impl Iterator<Item = &str> {
+        self.offered_protocols.iter().map(String::as_str)
+    }
+
+    /// The path the client is requesting, without the query string.
+    ///
+    /// The path is returned as sent by the client, i.e. it is not
+    /// percent-decoded.
     pub fn path(&self) -> &str {
-        &self.path
+        self.path.split('?').next().unwrap_or("")
+    }
+
+    /// The query string of the request target (without `?`), if any.
+    ///
+    /// The query string is returned as sent by the client, i.e. it is not
+    /// percent-decoded.
+    pub fn query(&self) -> Option<&str> {
+        self.path.find('?').map(|i| &self.path[i + 1 ..])
+    }
+
+    /// The value of the `Origin` header, if any.
+    pub fn origin(&self) -> Option<&str> {
+        self.origin.as_deref()
+    }
+
+    /// The method, HTTP version and request line of the request, e.g. for logging.
+    pub fn info(&self) -> &RequestInfo {
+        &self.info
+    }
+
+    /// The request headers (if recorded, cf. [`Server::record_request_headers`]).
+    pub fn headers(&self) -> &[(String, Vec<u8>)] {
+        &self.headers
+    }
+
+    /// Create a response accepting this request.
+    ///
+    /// The response selects the first protocol of [`ClientRequest::protocols`],
+    /// if any.
+    pub fn accept(&self) -> Response<'_> {
+        Response::Accept {
+            key: self.ws_key.as_ref(),
+            protocol: self.protocols.first().map(|p| p.as_ref()),
+            extra_headers: &[]
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+impl<'r, B> std::convert::TryFrom<&'r http::Request<B>> for ClientRequest<'static> {
+    type Error = Error;
+
+    /// Validate an already parsed handshake request.
+    ///
+    /// This is useful if the HTTP request has been handled elsewhere, e.g.
+    /// by hyper, and only the websocket connection itself should be managed
+    /// by soketto. Host and origin restrictions as well as extensions are
+    /// not considered and [`ClientRequest::protocols`] is always empty.
+    /// Once the response (cf. [`ClientRequest::accept_response`]) has been
+    /// sent, the upgraded I/O resource can be given to
+    /// [`connection::Builder::new`] with [`Mode::Server`].
+    fn try_from(request: &'r http::Request<B>) -> Result<Self, Error> {
+        let info = RequestInfo::from_http(request);
+        if request.method() != http::Method::GET {
+            return Err(Error::InvalidRequestMethod { request: Box::new(info) })
+        }
+        if request.version() != http::Version::HTTP_11 {
+            return Err(Error::UnsupportedHttpVersion { request: Some(Box::new(info)) })
+        }
+
+        let headers: Vec<httparse::Header> = request.headers().iter()
+            .map(|(name, value)| httparse::Header { name: name.as_str(), value: value.as_bytes() })
+            .collect();
+
+        expect_ascii_header(&headers, "Upgrade", "websocket", false)?;
+        expect_ascii_header(&headers, "Connection", "upgrade", false)?;
+        with_first_header(&headers, SEC_WEBSOCKET_VERSION, |v| {
+            if str::from_utf8(v).is_ok_and(|v| contains_token(v, "13", false)) {
+                Ok(())
+            } else {
+                Err(Error::UnsupportedWebSocketVersion { version: Vec::from(v), request: Box::new(info.clone()) })
+            }
+        })?;
+
+        let origin = match with_first_header(&headers, "Origin", |o| Ok(String::from(str::from_utf8(o)?))) {
+            Ok(o) => Some(o),
+            Err(Error::HeaderNotFound(_)) => None,
+            Err(e) => return Err(e)
+        };
+
+        let ws_key = websocket_key(&headers)?;
+
+        let offered_protocols = protocol_tokens(&headers)?.into_iter().map(String::from).collect();
+
+        let path = request.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/").into();
+
+        let headers = headers.iter().map(|h| (String::from(h.name), Vec::from(h.value))).collect();
+
+        Ok(ClientRequest { ws_key, protocols: Vec::new(), offered_protocols, path, origin, headers, info })
+    }
+}
+
+#[cfg(feature = "http")]
+impl ClientRequest<'_> {
+    /// Create the response accepting this request, using the given protocol.
+    ///
+    /// The response builder contains status code 101 and all headers
+    /// required by the websocket handshake. Further headers may be added
+    /// before building the actual response. The protocol, if any, must be
+    /// one of [`ClientRequest::offered_protocols`].
+    pub fn accept_response(&self, protocol: Option<&str>) -> Result<http::response::Builder, Error> {
+        let mut key_buf = [0; 32];
+        let accept_value = accept_key(self.ws_key.as_ref(), &mut key_buf);
+        let mut builder = http::Response::builder()
+            .status(http::StatusCode::SWITCHING_PROTOCOLS)
+            .header(http::header::UPGRADE, "websocket")
+            .header(http::header::CONNECTION, "upgrade")
+            .header(http::header::SEC_WEBSOCKET_ACCEPT, accept_value);
+        if let Some(p) = protocol {
+            if !self.offered_protocols.iter().any(|o| o == p) {
+                return Err(Error::UnsolicitedProtocol)
+            }
+            builder = builder.header(http::header::SEC_WEBSOCKET_PROTOCOL, p)
+        }
+        Ok(builder)
+    }
+}
+
+/// The method, HTTP version and request line of a handshake request.
+///
+/// It is available from [`ClientRequest::info`] and, if the request has
+/// been rejected, from [`Error::request_info`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestInfo {
+    method: String,
+    http_version: String,
+    request_line: String,
+    websocket_version: Option<String>
+}
+
+impl RequestInfo {
+    /// Collect information about a parsed request.
+    ///
+    /// The given bytes start with the request line.
+    fn new(request: &httparse::Request<'_, '_>, bytes: &[u8]) -> Self {
+        let line = bytes.split(|b| *b == b'\n').next().unwrap_or(bytes);
+        RequestInfo {
+            method: request.method.unwrap_or_default().into(),
+            http_version: format!("HTTP/1.{}", request.version.unwrap_or_default()),
+            request_line: String::from_utf8_lossy(line).trim_end_matches('\r').into(),
+            websocket_version: websocket_version(request.headers)
+        }
+    }
+
+    /// Collect information about a request parsed by the `http` crate.
+    ///
+    /// The request line is reconstructed.
+    #[cfg(feature = "http")]
+    fn from_http<B>(request: &http::Request<B>) -> Self {
+        let headers: Vec<httparse::Header> = request.headers().iter()
+            .filter(|(name, _)| *name == http::header::SEC_WEBSOCKET_VERSION)
+            .map(|(name, value)| httparse::Header { name: name.as_str(), value: value.as_bytes() })
+            .collect();
+        let http_version = format!("{:?}", request.version());
+        RequestInfo {
+            method: request.method().as_str().into(),
+            request_line: format!("{} {} {}", request.method(), request.uri(), http_version),
+            http_version,
+            websocket_version: websocket_version(&headers)
+        }
+    }
+
+    /// The request method, e.g. `GET`.
+    pub fn method(&self) -> &str {
+        &self.method
+    }
+
+    /// The HTTP version of the request, e.g. `HTTP/1.1`.
+    pub fn http_version(&self) -> &str {
+        &self.http_version
+    }
+
+    /// The first line of the request, e.g. `GET /chat HTTP/1.1`.
+    pub fn request_line(&self) -> &str {
+        &self.request_line
+    }
+
+    /// The value of the `Sec-WebSocket-Version` header, if any.
+    pub fn websocket_version(&self) -> Option<&str> {
+        self.websocket_version.as_deref()
+    }
+}
+
+/// The value of the first `Sec-WebSocket-Version` header, if any.
+fn websocket_version(headers: &[httparse::Header]) -> Option<String> {
+    with_first_header(headers, SEC_WEBSOCKET_VERSION, |v| Ok(String::from_utf8_lossy(v).into_owned())).ok()
+}
+
+#[cfg(feature = "http")]
+impl std::convert::TryFrom<&ClientRequest<'_>> for http::Request<()> {
+    type Error = Error;
+
+    /// Convert a handshake request into an `http::Request` (GET, HTTP/1.1).
+    ///
+    /// The URI is the request target, i.e. path and query. If headers have
+    /// been recorded (cf. [`Server::record_request_headers`]) all of them are
+    /// included. Otherwise only `Upgrade`, `Connection`, `Sec-WebSocket-Key`,
+    /// `Sec-WebSocket-Version`, `Origin` and `Sec-WebSocket-Protocol` are
+    /// reconstructed. The conversion is lossy: the values of a header keep
+    /// their order, but `http::HeaderMap` does not preserve the order of
+    /// different headers or the case of header names.
+    fn try_from(request: &ClientRequest<'_>) -> Result<Self, Error> {
+        let mut builder = http::Request::get(request.path.as_str()).version(http::Version::HTTP_11);
+        if request.headers.is_empty() {
+            builder = builder
+                .header(http::header::UPGRADE, "websocket")
+                .header(http::header::CONNECTION, "upgrade")
+                .header(http::header::SEC_WEBSOCKET_KEY, request.key())
+                .header(http::header::SEC_WEBSOCKET_VERSION, "13");
+            if let Some(o) = &request.origin {
+                builder = builder.header(http::header::ORIGIN, o.as_str())
+            }
+            if !request.offered_protocols.is_empty() {
+                builder = builder.header(http::header::SEC_WEBSOCKET_PROTOCOL, request.offered_protocols.join(", "))
+            }
+        } else {
+            for (name, value) in &request.headers {
+                builder = builder.header(name.as_str(), value.as_slice())
+            }
+        }
+        builder.body(()).map_err(|e| Error::Http(Box::new(e)))
+    }
+}
+
+/// The `Sec-WebSocket-Key` of a client request.
+///
+/// The key is the base64 encoding of a 16 byte nonce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WebSocketKey([u8; 24]);
+
+impl AsRef<[u8]> for WebSocketKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
     }
 }
 
@@ -249,11 +1091,56 @@ pub enum Response<'a> {
     /// The server accepts the handshake request.
     Accept {
         key: &'a [u8],
-        protocol: Option<&'a str>
+        protocol: Option<&'a str>,
+        /// Additional headers to include in the response.
+        ///
+        /// A `Server` header replaces the default one. Headers used by the
+        /// websocket handshake itself must not be given.
+        extra_headers: &'a [(&'a str, &'a [u8])]
     },
     /// The server rejects the handshake request.
     Reject {
-        status_code: u16
+        status_code: u16,
+        /// Additional headers to include in the response.
+        ///
+        /// A `Server` header replaces the default one.
+        extra_headers: &'a [(&'a str, &'a [u8])],
+        /// The response body (may be empty).
+        body: &'a [u8],
+        /// The content type of the response body.
+        content_type: Option<&'a str>
+    },
+    /// The server does not support the websocket version of the request.
+    ///
+    /// The response advertises the supported version (13) as required by
+    /// RFC 6455, section 4.2.2.
+    UpgradeRequired {
+        /// Additional headers to include in the response.
+        ///
+        /// A `Server` header replaces the default one.
+        extra_headers: &'a [(&'a str, &'a [u8])]
+    }
+}
+
+impl Response<'static> {
+    /// A rejection with status code 400.
+    ///
+    /// This is the appropriate response to [`Error::ProtocolRequired`] and
+    /// [`Error::ObsoleteLineFolding`].
+    pub fn bad_request() -> Self {
+        Response::Reject { status_code: 400, extra_headers: &[], body: &[], content_type: None }
+    }
+
+    /// A rejection with status code 405 and an `Allow: GET` header.
+    ///
+    /// This is the appropriate response to [`Error::InvalidRequestMethod`].
+    pub fn method_not_allowed() -> Self {
+        Response::Reject {
+            status_code: 405,
+            extra_headers: &[("Allow", b"GET")],
+            body: &[],
+            content_type: None
+        }
     }
 }
 
@@ -321,3 +1208,734 @@ const STATUSCODES: &[(u16, &str, &str)] = &[
     (511, "511", "Network Authentication Required")
 ];
 
+#[cfg(test)]
+mod tests {
+    use crate::handshake::Error;
+    use crate::mock::duplex;
+    use futures::{executor::block_on, prelude::*};
+    use super::{Response, Server};
+
+    /// Create a handshake request with the given extra headers.
+    fn request(headers: &str) -> String {
+        request_for("/chat", headers)
+    }
+
+    /// Create a handshake request for a target with the given extra headers.
+    fn request_for(target: &str, headers: &str) -> String {
+        format!("GET {} HTTP/1.1\r\n\
+            Upgrade: websocket\r\n\
+            Connection: Upgrade\r\n\
+            Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            {}\r\n", target, headers)
+    }
+
+    /// Let a server with the given allowed hosts receive a request.
+    fn receive(allowed_hosts: &[&str], request: &str) -> Result<(), Error> {
+        let (a, mut b) = duplex(64);
+        let mut server = Server::new(a);
+        server.set_allowed_hosts(allowed_hosts.iter().cloned());
+        block_on(async {
+            b.write_all(request.as_bytes()).await.unwrap();
+            server.receive_request().await.map(|_| ())
+        })
+    }
+
+    #[test]
+    fn allowed_hosts() {
+        let allowed = &["example.com", "localhost:8080", "[::1]", "[fe80::1]:9001"];
+
+        for host in &["example.com", "EXAMPLE.com:443", "localhost:8080", "[::1]", "[::1]:80", "[fe80::1]:9001"] {
+            let r = receive(allowed, &request(&format!("Host: {}\r\n", host)));
+            assert!(r.is_ok(), "host: {}", host)
+        }
+
+        for host in &["example.org", "localhost", "localhost:8081", "[::2]", "[fe80::1]", "[fe80::1]:9002", "::1"] {
+            match receive(allowed, &request(&format!("Host: {}\r\n", host))) {
+                Err(Error::InvalidHost(h)) => assert_eq!(host, &h),
+                other => panic!("unexpected result for {}: {:?}", host, other)
+            }
+        }
+    }
+
+    #[test]
+    fn any_host_by_default() {
+        assert!(receive(&[], &request("Host: whatever.example.com:1234\r\n")).is_ok());
+        assert!(matches!(receive(&[], &request("")), Err(Error::HeaderNotFound(_))))
+    }
+
+    #[test]
+    fn origin_policy() {
+        fn receive_with_policy(request: &str) -> Result<Option<String>, Error> {
+            let (a, mut b) = duplex(64);
+            let mut server = Server::new(a);
+            server.set_origin_policy(|origin| origin.map(|o| o == "https://example.com").unwrap_or(true));
+            block_on(async {
+                b.write_all(request.as_bytes()).await.unwrap();
+                server.receive_request().await.map(|r| r.origin().map(String::from))
+            })
+        }
+
+        let allowed = receive_with_policy(&request("Host: example.com\r\nOrigin: https://example.com\r\n"));
+        assert_eq!(Some("https://example.com"), allowed.unwrap().as_deref());
+
+        let absent = receive_with_policy(&request("Host: example.com\r\n"));
+        assert_eq!(None, absent.unwrap());
+
+        match receive_with_policy(&request("Host: example.com\r\nOrigin: https://evil.com\r\n")) {
+            Err(Error::InvalidOrigin(Some(o))) => assert_eq!("https://evil.com", o),
+            other => panic!("unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn path_and_query() {
+        let cases = &[
+            ("/", "/", None),
+            ("/ws/chat", "/ws/chat", None),
+            ("/ws/metrics?token=x", "/ws/metrics", Some("token=x")),
+            ("/a%20b?c=%3F&d?e", "/a%20b", Some("c=%3F&d?e")),
+            ("/?", "/", Some(""))
+        ];
+        for (target, path, query) in cases {
+            let (a, mut b) = duplex(64);
+            let mut server = Server::new(a);
+            block_on(async {
+                let request = request_for(target, "Host: example.com\r\n");
+                b.write_all(request.as_bytes()).await.unwrap();
+                let request = server.receive_request().await.unwrap();
+                assert_eq!(*path, request.path());
+                assert_eq!(*query, request.query())
+            })
+        }
+    }
+
+    #[test]
+    fn extra_response_headers() {
+        let (a, _b) = duplex(64);
+        let mut server = Server::new(a);
+
+        let accept = Response::Accept {
+            key: b"dGhlIHNhbXBsZSBub25jZQ==",
+            protocol: None,
+            extra_headers: &[("Set-Cookie", b"affinity=1"), ("Server", b"custom")]
+        };
+        server.encode_response(&accept).unwrap();
+        assert_eq! {
+            &b"HTTP/1.1 101 Switching Protocols\r\n\
+            Upgrade: websocket\r\n\
+            Connection: upgrade\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+            Set-Cookie: affinity=1\r\n\
+            Server: custom\r\n\r\n"[..],
+            &server.buffer[..]
+        }
+
+        server.buffer.clear();
+        let reject = Response::Reject {
+            status_code: 429,
+            extra_headers: &[("Retry-After", b"120")],
+            body: &[],
+            content_type: None
+        };
+        server.encode_response(&reject).unwrap();
+        let expected = format! {
+            "HTTP/1.1 429 Too Many Requests\r\nServer: {}\r\nRetry-After: 120\r\nContent-Length: 0\r\n\r\n",
+            super::SERVER_HEADER
+        };
+        assert_eq!(expected.as_bytes(), &server.buffer[..])
+    }
+
+    #[test]
+    fn invalid_extra_response_headers() {
+        let (a, _b) = duplex(64);
+        let mut server = Server::new(a);
+        let invalid: &[(&str, &[u8])] = &[
+            ("X-Foo", b"bar\r\nX-Injected: 1"),
+            ("X-Foo\r\nX-Injected", b"1"),
+            ("X Foo", b"1"),
+            ("", b"1"),
+            ("sec-websocket-accept", b"xyz"),
+            ("Connection", b"close")
+        ];
+        for h in invalid {
+            let reject = Response::Reject {
+                status_code: 400,
+                extra_headers: std::slice::from_ref(h),
+                body: &[],
+                content_type: None
+            };
+            assert!(matches!(server.encode_response(&reject), Err(Error::InvalidResponseHeader(_))), "{:?}", h)
+        }
+    }
+
+    #[test]
+    fn reject_with_body() {
+        let (a, _b) = duplex(64);
+        let mut server = Server::new(a);
+        for &body in &[&b""[..], &b"{\"error\":\"invalid token\"}"[..]] {
+            server.buffer.clear();
+            let reject = Response::Reject {
+                status_code: 401,
+                extra_headers: &[],
+                body,
+                content_type: Some("application/json")
+            };
+            server.encode_response(&reject).unwrap();
+
+            let mut headers = [httparse::EMPTY_HEADER; 8];
+            let mut response = httparse::Response::new(&mut headers);
+            let offset = match response.parse(&server.buffer).unwrap() {
+                httparse::Status::Complete(offset) => offset,
+                httparse::Status::Partial => panic!("incomplete response")
+            };
+            assert_eq!(Some(401), response.code);
+            let header = |name: &str| response.headers.iter().find(|h| h.name == name).unwrap().value;
+            assert_eq!(b"application/json", header("Content-Type"));
+            assert_eq!(body.len().to_string().as_bytes(), header("Content-Length"));
+            assert_eq!(body, &server.buffer[offset ..])
+        }
+    }
+
+    #[test]
+    fn protocol_lists() {
+        let cases = [
+            "Sec-WebSocket-Protocol: graphql-ws, chat, mqtt\r\n",
+            "Sec-WebSocket-Protocol: graphql-ws,chat\r\nSec-WebSocket-Protocol: mqtt\r\n",
+            "Sec-WebSocket-Protocol:  graphql-ws ,\tchat\t\r\nSec-WebSocket-Protocol: , mqtt,\r\n",
+            "Sec-WebSocket-Protocol: graphql-ws\r\nSec-WebSocket-Protocol: chat\r\nSec-WebSocket-Protocol: mqtt\r\n"
+        ];
+        for headers in &cases {
+            let (a, mut b) = duplex(64);
+            let mut server = Server::new(a);
+            // Protocols are matched case-sensitively and selected in the client's order.
+            server.add_protocol("MQTT").add_protocol("chat").add_protocol("graphql-ws");
+            block_on(async {
+                b.write_all(request(&format!("Host: example.com\r\n{}", headers)).as_bytes()).await.unwrap();
+                let request = server.receive_request().await.unwrap();
+                assert_eq!(vec!["graphql-ws", "chat", "mqtt"], request.offered_protocols().collect::<Vec<_>>());
+                assert_eq!(vec!["graphql-ws", "chat"], request.protocols().collect::<Vec<_>>());
+                server.send_response(&request.accept()).await.unwrap()
+            });
+            drop(server);
+            let mut response = String::new();
+            block_on(b.read_to_string(&mut response)).unwrap();
+            assert!(response.contains("\r\nSec-WebSocket-Protocol: graphql-ws\r\n"))
+        }
+    }
+
+    #[test]
+    fn dynamic_protocol_selection() {
+        let (a, mut b) = duplex(64);
+        let mut server = Server::new(a);
+        block_on(async {
+            let request = request("Host: example.com\r\nSec-WebSocket-Protocol: json-rpc, graphql-ws\r\n");
+            b.write_all(request.as_bytes()).await.unwrap();
+            let request = server.receive_request().await.unwrap();
+            assert_eq!(0, request.protocols().count());
+            let offered: Vec<_> = request.offered_protocols().collect();
+            assert_eq!(vec!["json-rpc", "graphql-ws"], offered);
+            assert!(offered.contains(&"graphql-ws"));
+
+            let key = request.into_key();
+            let invalid = Response::Accept { key: key.as_ref(), protocol: Some("mqtt"), extra_headers: &[] };
+            assert!(matches!(server.send_response(&invalid).await, Err(Error::UnsolicitedProtocol)));
+
+            let accept = Response::Accept { key: key.as_ref(), protocol: Some("graphql-ws"), extra_headers: &[] };
+            server.send_response(&accept).await.unwrap()
+        });
+        drop(server);
+        let mut response = String::new();
+        block_on(b.read_to_string(&mut response)).unwrap();
+        assert!(response.contains("\r\nSec-WebSocket-Protocol: graphql-ws\r\n"))
+    }
+
+    #[test]
+    fn unsupported_websocket_version() {
+        let (a, mut b) = duplex(64);
+        let mut server = Server::new(a);
+        block_on(async {
+            let request = "GET /chat HTTP/1.1\r\n\
+                Host: example.com\r\n\
+                Upgrade: websocket\r\n\
+                Connection: upgrade\r\n\
+                Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                Sec-WebSocket-Version: 8\r\n\r\n";
+            b.write_all(request.as_bytes()).await.unwrap();
+            match server.receive_request().await {
+                Err(Error::UnsupportedWebSocketVersion { version, request }) => {
+                    assert_eq!(b"8", &version[..]);
+                    assert_eq!("GET", request.method());
+                    assert_eq!("HTTP/1.1", request.http_version());
+                    assert_eq!("GET /chat HTTP/1.1", request.request_line());
+                    assert_eq!(Some("8"), request.websocket_version())
+                }
+                other => panic!("unexpected result: {:?}", other)
+            }
+            let response = Response::UpgradeRequired { extra_headers: &[("Server", b"test")] };
+            server.send_response(&response).await.unwrap()
+        });
+        drop(server);
+        let mut response = String::new();
+        block_on(b.read_to_string(&mut response)).unwrap();
+        assert_eq! {
+            "HTTP/1.1 426 Upgrade Required\r\n\
+            Upgrade: websocket\r\n\
+            Connection: upgrade\r\n\
+            Sec-WebSocket-Version: 13\r\n\
+            Server: test\r\n\
+            Content-Length: 0\r\n\r\n",
+            response
+        }
+    }
+
+    #[test]
+    fn max_request_size() {
+        let (a, mut b) = duplex(64);
+        let mut server = Server::new(a);
+        server.set_max_request_size(128);
+        block_on(async {
+            let request = request(&format!("Host: example.com\r\nX-Padding: {}\r\n", "x".repeat(128)));
+            b.write_all(request.as_bytes()).await.unwrap();
+            assert!(matches!(server.receive_request().await, Err(Error::RequestTooLarge { maximum: 128, .. })));
+            let response = Response::Reject { status_code: 431, extra_headers: &[], body: &[], content_type: None };
+            server.send_response(&response).await.unwrap()
+        });
+        drop(server);
+        let mut response = String::new();
+        block_on(b.read_to_string(&mut response)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"))
+    }
+
+    #[test]
+    fn leading_empty_line_and_obsolete_line_folding() {
+        let (a, mut b) = duplex(1024);
+        let mut server = Server::new(a);
+        block_on(async {
+            let request = format!("\r\n{}", request("Host: example.com\r\n"));
+            b.write_all(request.as_bytes()).await.unwrap();
+            let request = server.receive_request().await.unwrap();
+            assert_eq!("/chat", request.path());
+            assert_eq!("GET", request.info().method());
+            assert_eq!("HTTP/1.1", request.info().http_version());
+            assert_eq!("GET /chat HTTP/1.1", request.info().request_line());
+            assert_eq!(Some("13"), request.info().websocket_version());
+
+            let request = request_for("/chat", "Host: example.com\r\nX-Folded: a\r\n b\r\n");
+            b.write_all(request.as_bytes()).await.unwrap();
+            assert!(matches!(server.receive_request().await, Err(Error::ObsoleteLineFolding)));
+            server.send_response(&Response::bad_request()).await.unwrap()
+        });
+        drop(server);
+        let mut response = String::new();
+        block_on(b.read_to_string(&mut response)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", response)
+    }
+
+    #[test]
+    fn request_body() {
+        let request = request_for("/chat", "Host: example.com\r\nContent-Length: 3\r\n");
+        // A masked text frame (with a zero mask) following the body.
+        let frame = [0x81, 0x82, 0, 0, 0, 0, b'h', b'i'];
+        for skip in &[None, Some(2), Some(3)] {
+            let (a, mut b) = duplex(1024);
+            let mut server = Server::new(a);
+            server.skip_request_body(*skip);
+            block_on(async {
+                b.write_all(request.as_bytes()).await.unwrap();
+                b.write_all(b"abc").await.unwrap();
+                b.write_all(&frame).await.unwrap();
+                let result = server.receive_request().await;
+                if *skip == Some(3) {
+                    let key = result.unwrap().into_key();
+                    let accept = Response::Accept { key: key.as_ref(), protocol: None, extra_headers: &[] };
+                    server.send_response(&accept).await.unwrap();
+                    let (_, mut receiver) = server.into_builder().finish();
+                    assert_eq!(crate::Message::Text("hi".into()), receiver.receive().await.unwrap())
+                } else {
+                    assert!(matches!(result, Err(Error::UnexpectedRequestBody { length: Some(3) })), "{:?}", result)
+                }
+            })
+        }
+
+        let (a, mut b) = duplex(1024);
+        let mut server = Server::new(a);
+        server.skip_request_body(Some(1024));
+        block_on(async {
+            let request = request_for("/chat", "Host: example.com\r\nTransfer-Encoding: chunked\r\n");
+            b.write_all(request.as_bytes()).await.unwrap();
+            let result = server.receive_request().await;
+            assert!(matches!(result, Err(Error::UnexpectedRequestBody { length: None })), "{:?}", result)
+        })
+    }
+
+    #[test]
+    fn max_headers() {
+        let headers: String = (0 .. 64).map(|i| format!("X-Header-{}: {}\r\n", i, i)).collect();
+        let request = request(&format!("Host: example.com\r\n{}", headers));
+        for (max, ok) in &[(None, true), (Some(16), false), (Some(32), false), (Some(68), false), (Some(69), true)] {
+            let (a, mut b) = duplex(1024);
+            let mut server = Server::new(a);
+            if let Some(max) = max {
+                server.set_max_headers(*max);
+            }
+            let result = block_on(async {
+                b.write_all(request.as_bytes()).await.unwrap();
+                server.receive_request().await.map(|_| ())
+            });
+            if *ok {
+                assert!(result.is_ok(), "max = {:?}: {:?}", max, result)
+            } else {
+                let maximum = max.unwrap();
+                assert!(matches!(result, Err(Error::TooManyHeaders { maximum: m }) if m == maximum))
+            }
+        }
+    }
+
+    #[test]
+    fn request_deadline() {
+        let (a, mut b) = duplex(1);
+        let (tx, rx) = futures::channel::oneshot::channel::<()>();
+        let mut server = Server::new(a);
+        block_on(async {
+            // Send an incomplete request.
+            let request = request("Host: example.com\r\n");
+            b.write_all(&request.as_bytes()[.. 32]).await.unwrap();
+            tx.send(()).unwrap();
+            assert!(matches!(server.receive_request_with_deadline(rx).await, Err(Error::Timeout)));
+            let response = Response::Reject { status_code: 408, extra_headers: &[], body: &[], content_type: None };
+            server.send_response(&response).await.unwrap()
+        });
+        drop(server);
+        let mut response = String::new();
+        block_on(b.read_to_string(&mut response)).unwrap();
+        assert!(response.starts_with("HTTP/1.1 408 Request Timeout\r\n"))
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn from_http_request() {
+        use std::convert::TryFrom;
+        use super::ClientRequest;
+
+        let request = http::Request::get("/chat?room=1")
+            .header("Host", "example.com")
+            .header("Upgrade", "websocket")
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Protocol", "chat, superchat")
+            .body(())
+            .unwrap();
+
+        let request = ClientRequest::try_from(&request).unwrap();
+        assert_eq!("/chat", request.path());
+        assert_eq!(Some("room=1"), request.query());
+        assert_eq!("GET /chat?room=1 HTTP/1.1", request.info().request_line());
+        assert_eq!(vec!["chat", "superchat"], request.offered_protocols().collect::<Vec<_>>());
+        assert!(matches!(request.accept_response(Some("other")), Err(Error::UnsolicitedProtocol)));
+
+        let response = request.accept_response(Some("chat")).unwrap().body(()).unwrap();
+        assert_eq!(http::StatusCode::SWITCHING_PROTOCOLS, response.status());
+        assert_eq!("s3pPLMBiTxaQ9kYGzzhZRbK+xOo=", response.headers()["Sec-WebSocket-Accept"]);
+        assert_eq!("chat", response.headers()["Sec-WebSocket-Protocol"]);
+
+        let request = http::Request::post("/chat").body(()).unwrap();
+        assert!(matches!(ClientRequest::try_from(&request),
+            Err(Error::InvalidRequestMethod { request }) if request.method() == "POST"));
+
+        let request = http::Request::get("/chat")
+            .header("Upgrade", "websocket")
+            .header("Connection", "upgrade")
+            .header("Sec-WebSocket-Version", "13")
+            .body(())
+            .unwrap();
+        assert!(matches!(ClientRequest::try_from(&request), Err(Error::HeaderNotFound(_))))
+    }
+
+    #[cfg(feature = "http")]
+    #[test]
+    fn into_http_request() {
+        use std::convert::TryFrom;
+        use super::ClientRequest;
+
+        let original = http::Request::get("/chat?room=1")
+            .header("Host", "example.com")
+            .header("Upgrade", "websocket")
+            .header("Connection", "keep-alive, Upgrade")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Protocol", "chat")
+            .header("Cookie", "a=1")
+            .header("Sec-WebSocket-Protocol", "superchat")
+            .header("Cookie", "b=2")
+            .body(())
+            .unwrap();
+
+        let request = ClientRequest::try_from(&original).unwrap();
+        let converted = http::Request::try_from(&request).unwrap();
+        assert_eq!(original.method(), converted.method());
+        assert_eq!(original.uri(), converted.uri());
+        assert_eq!(original.version(), converted.version());
+        assert_eq!(original.headers(), converted.headers());
+        let request = ClientRequest::try_from(&converted).unwrap();
+        assert_eq!(vec!["chat", "superchat"], request.offered_protocols().collect::<Vec<_>>());
+
+        // Without recorded headers only the handshake headers are reconstructed.
+        let (a, mut b) = duplex(64);
+        let mut server = Server::new(a);
+        let converted = block_on(async {
+            let headers = "Host: example.com\r\nOrigin: http://example.com\r\n\
+                Sec-WebSocket-Protocol: chat\r\nSec-WebSocket-Protocol: superchat\r\n";
+            b.write_all(request_for("/chat?room=1", headers).as_bytes()).await.unwrap();
+            let request = server.receive_request().await.unwrap();
+            http::Request::try_from(&request).unwrap()
+        });
+        let headers = converted.headers();
+        assert_eq!("/chat?room=1", converted.uri());
+        assert_eq!("dGhlIHNhbXBsZSBub25jZQ==", headers[http::header::SEC_WEBSOCKET_KEY]);
+        assert_eq!("http://example.com", headers[http::header::ORIGIN]);
+        assert_eq!("chat, superchat", headers[http::header::SEC_WEBSOCKET_PROTOCOL]);
+        assert!(!headers.contains_key(http::header::HOST));
+        assert!(crate::handshake::http::is_upgrade_request(&converted))
+    }
+
+    #[test]
+    fn invalid_request_method() {
+        let cases = &[
+            ("POST /chat HTTP/1.1", "HTTP/1.1 405 Method Not Allowed\r\n"),
+            ("PUT /chat HTTP/1.1", "HTTP/1.1 405 Method Not Allowed\r\n"),
+            ("GET /chat HTTP/1.0", "HTTP/1.1 505 HTTP Version Not Supported\r\n")
+        ];
+        for (request_line, status_line) in cases {
+            let (a, mut b) = duplex(64);
+            let mut server = Server::new(a);
+            block_on(async {
+                let request = format!("{}\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n", request_line);
+                b.write_all(request.as_bytes()).await.unwrap();
+                let result = server.receive_request().await;
+                let info = result.as_ref().err().and_then(Error::request_info).expect("request info");
+                assert_eq!(*request_line, info.request_line());
+                assert_eq!(request_line.split(' ').next(), Some(info.method()));
+                assert_eq!(None, info.websocket_version());
+                let response = match result {
+                    Err(Error::InvalidRequestMethod { .. }) => Response::method_not_allowed(),
+                    Err(Error::UnsupportedHttpVersion { request: Some(r) }) => {
+                        assert_eq!("HTTP/1.0", r.http_version());
+                        Response::Reject { status_code: 505, extra_headers: &[], body: &[], content_type: None }
+                    }
+                    other => panic!("unexpected result: {:?}", other)
+                };
+                server.send_response(&response).await.unwrap()
+            });
+            drop(server);
+            let mut response = String::new();
+            block_on(b.read_to_string(&mut response)).unwrap();
+            assert!(response.starts_with(status_line), "{}", response);
+            assert_eq!(status_line.contains("405"), response.contains("\r\nAllow: GET\r\n"))
+        }
+    }
+
+    #[test]
+    fn request_does_not_borrow_server() {
+        let (a, mut b) = duplex(64);
+        let mut server = Server::new(a);
+        server.add_protocol("chat");
+        block_on(async {
+            b.write_all(request("Host: example.com\r\nSec-WebSocket-Protocol: chat\r\n").as_bytes()).await.unwrap();
+            let request = server.receive_request().await.unwrap();
+            let protocol = request.protocols().next();
+            let accept = Response::Accept { key: request.key(), protocol, extra_headers: &[] };
+            server.send_response(&accept).await.unwrap();
+            assert_eq!(b"dGhlIHNhbXBsZSBub25jZQ==", request.key())
+        })
+    }
+
+    #[test]
+    fn required_protocol() {
+        let cases = &[
+            ("graphql-transport-ws", Some("graphql-transport-ws")),
+            ("graphql-ws", None),
+            ("graphql-ws, graphql-transport-ws", Some("graphql-transport-ws"))
+        ];
+        for (offered, selected) in cases {
+            let (a, mut b) = duplex(64);
+            let mut server = Server::new(a);
+            server.require_protocol("graphql-transport-ws");
+            block_on(async {
+                let request = request(&format!("Host: example.com\r\nSec-WebSocket-Protocol: {}\r\n", offered));
+                b.write_all(request.as_bytes()).await.unwrap();
+                match server.receive_request().await {
+                    Ok(request) => {
+                        assert!(selected.is_some());
+                        server.send_response(&request.accept()).await.unwrap()
+                    }
+                    Err(Error::ProtocolRequired) => {
+                        assert!(selected.is_none());
+                        server.send_response(&Response::bad_request()).await.unwrap()
+                    }
+                    Err(e) => panic!("unexpected error: {}", e)
+                }
+            });
+            drop(server);
+            let mut response = String::new();
+            block_on(b.read_to_string(&mut response)).unwrap();
+            if let Some(p) = selected {
+                assert!(response.starts_with("HTTP/1.1 101 Switching Protocols\r\n"));
+                assert!(response.contains(&format!("\r\nSec-WebSocket-Protocol: {}\r\n", p)))
+            } else {
+                assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+                assert!(!response.contains("Sec-WebSocket-Protocol"))
+            }
+        }
+    }
+
+    #[test]
+    fn server_header() {
+        let (a, _b) = duplex(64);
+        let mut server = Server::new(a);
+        let reject = Response::bad_request();
+
+        server.encode_response(&reject).unwrap();
+        let expected = format!("HTTP/1.1 400 Bad Request\r\nServer: soketto-{}\r\n", env!("CARGO_PKG_VERSION"));
+        assert!(server.buffer.starts_with(expected.as_bytes()));
+
+        server.buffer.clear();
+        server.set_server_header(Some("example"));
+        server.encode_response(&reject).unwrap();
+        assert!(server.buffer.starts_with(b"HTTP/1.1 400 Bad Request\r\nServer: example\r\n"));
+
+        server.buffer.clear();
+        server.set_server_header(None);
+        server.encode_response(&reject).unwrap();
+        assert_eq!(&b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n"[..], &server.buffer[..])
+    }
+
+    #[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+    #[test]
+    fn extension_filter() {
+        use crate::{Mode, extension::deflate::Deflate};
+
+        for user_agent in &["old-browser", "new-browser"] {
+            let (a, mut b) = duplex(64);
+            let mut server = Server::new(a);
+            server.add_extension(Box::new(Deflate::new(Mode::Server)));
+            server.record_request_headers(true);
+            server.set_extension_filter(|request, extensions| {
+                let old = request.headers().iter()
+                    .any(|(n, v)| n.eq_ignore_ascii_case("User-Agent") && v.starts_with(b"old"));
+                if old {
+                    extensions.clear()
+                }
+            });
+            block_on(async {
+                let headers = format! {
+                    "Host: example.com\r\nUser-Agent: {}\r\nSec-WebSocket-Extensions: permessage-deflate\r\n",
+                    user_agent
+                };
+                b.write_all(request(&headers).as_bytes()).await.unwrap();
+                let request = server.receive_request().await.unwrap();
+                server.send_response(&request.accept()).await.unwrap()
+            });
+            let compressed = server.extensions().any(|e| e.is_enabled());
+            drop(server);
+            let mut response = String::new();
+            block_on(b.read_to_string(&mut response)).unwrap();
+            let negotiated = response.contains("\r\nSec-WebSocket-Extensions: permessage-deflate");
+            assert_eq!(*user_agent == "new-browser", compressed);
+            assert_eq!(compressed, negotiated)
+        }
+    }
+
+    #[cfg(any(feature = "deflate", feature = "deflate-rust"))]
+    #[test]
+    fn extension_factory() {
+        use crate::{Mode, extension::deflate::Deflate, handshake::{Client, ServerResponse}};
+
+        let (a, b) = duplex(1024);
+        let (c, d) = duplex(1024);
+
+        let mut server = Server::new(b);
+        server.add_extension_factory(|| Box::new(Deflate::new(Mode::Server)));
+
+        let server = async move {
+            let request = server.receive_request().await.unwrap();
+            server.send_response(&request.accept()).await.unwrap();
+            let first = server.take_builder(d);
+            let request = server.receive_request().await.unwrap();
+            server.send_response(&request.accept()).await.unwrap();
+            let second = server.into_builder();
+            for builder in [first, second] {
+                let (mut sender, mut receiver) = builder.finish();
+                let mut message = Vec::new();
+                receiver.receive_data(&mut message).await.unwrap();
+                sender.send_binary(&message).await.unwrap();
+                sender.flush().await.unwrap()
+            }
+        };
+
+        let client = |socket| async move {
+            let mut client = Client::new(socket, "example.com", "/");
+            client.add_extension(Box::new(Deflate::new(Mode::Client)));
+            match client.handshake().await.unwrap() {
+                ServerResponse::Accepted { extensions, .. } =>
+                    assert_eq!(vec!["permessage-deflate"], extensions),
+                other => panic!("unexpected response: {:?}", other)
+            }
+            let (mut sender, mut receiver) = client.into_builder().finish();
+            sender.send_binary(b"hello hello hello").await.unwrap();
+            sender.flush().await.unwrap();
+            let mut message = Vec::new();
+            receiver.receive_data(&mut message).await.unwrap();
+            assert_eq!(b"hello hello hello", &message[..])
+        };
+
+        block_on(future::join3(server, client(a), client(c)));
+    }
+
+    #[test]
+    fn invalid_websocket_key() {
+        let (a, _b) = duplex(64);
+        let mut server = Server::new(a);
+        let keys = &[
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZQ==\r\n",
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ=!\r\n",
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZSE=\r\n",
+            "Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZSE\r\n",
+        ];
+        for key in keys {
+            server.buffer.clear();
+            server.buffer.extend_from_slice(format! {
+                "GET / HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\nConnection: upgrade\r\nSec-WebSocket-Version: 13\r\n{}\r\n",
+                key
+            }.as_bytes());
+            assert!(matches!(server.decode_request(), Err(Error::InvalidSecWebSocketKey)), "{}", key)
+        }
+        server.buffer.clear();
+        server.buffer.extend_from_slice(request("Host: example.com\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n").as_bytes());
+        assert!(matches!(server.decode_request(), Err(Error::InvalidSecWebSocketKey)))
+    }
+
+    #[tokio::test]
+    async fn owned_server_in_task() {
+        let (a, mut b) = duplex(64);
+        let config = vec!["graphql-ws".to_string(), "graphql-transport-ws".to_string()];
+
+        let server = tokio::spawn(async move {
+            let mut server: Server<'static, _> = Server::new(a);
+            for p in config {
+                server.add_protocol(p);
+            }
+            let request = server.receive_request().await?;
+            assert_eq!(vec!["graphql-transport-ws"], request.protocols().collect::<Vec<_>>());
+            server.send_response(&request.accept()).await
+        });
+
+        let request = request("Host: example.com\r\nSec-WebSocket-Protocol: graphql-transport-ws\r\n");
+        b.write_all(request.as_bytes()).await.unwrap();
+        server.await.unwrap().unwrap();
+        let mut response = String::new();
+        b.read_to_string(&mut response).await.unwrap();
+        assert!(response.contains("\r\nSec-WebSocket-Protocol: graphql-transport-ws\r\n"))
+    }
+}
@@ -24,12 +24,18 @@
 //! **Note**: None of the `async` methods are safe to cancel so their `Future`s
 //! must not be dropped unless they return `Poll::Ready`.
 //!
+//! **Note**: If the socket is `Send`, the [Sender]/[Receiver] pair is `Send`
+//! and `Sync` and the `Future`s of their `async` methods are `Send`, so they
+//! can be used with multi-threaded executors. The handshake types are `Send`
+//! and `Sync` if the socket is. Extensions, which are shared by these types,
+//! must therefore be `Send + Sync`.
+//!
 //! # Client example
 //!
 //! ```no_run
 //! # use tokio_util::compat::Tokio02AsyncReadCompatExt;
 //! # async fn doc() -> Result<(), soketto::BoxedError> {
-//! use soketto::handshake::{Client, ServerResponse};
+//! use soketto::{Message, handshake::{Client, ServerResponse}};
 //!
 //! // First, we need to establish a TCP connection.
 //! let socket = tokio::net::TcpStream::connect("...").await?;
@@ -40,8 +46,9 @@
 //! // And finally we perform the handshake and handle the result.
 //! let (mut sender, mut receiver) = match client.handshake().await? {
 //!     ServerResponse::Accepted { .. } => client.into_builder().finish(),
-//!     ServerResponse::Redirect { status_code, location } => unimplemented!("follow location URL"),
-//!     ServerResponse::Rejected { status_code } => unimplemented!("handle failure")
+//!     ServerResponse::Redirect { status_code, location, .. } => unimplemented!("follow location URL"),
+//!     ServerResponse::UpgradeRequired { supported_versions, .. } => unimplemented!("unsupported version"),
+//!     ServerResponse::Rejected { status_code, .. } => unimplemented!("handle failure")
 //! };
 //!
 //! // Over the established websocket connection we can send
@@ -49,9 +56,13 @@
 //! sender.send_text("some more text").await?;
 //! sender.flush().await?;
 //!
-//! // ... and receive data.
-//! let mut data = Vec::new();
-//! receiver.receive_data(&mut data).await?;
+//! // ... and receive messages.
+//! match receiver.receive().await? {
+//!     Message::Text(text) => println!("text: {:?}", text),
+//!     Message::Binary(data) => println!("{} bytes of data", data.len()),
+//!     Message::Pong(_) => println!("received a pong"),
+//!     Message::Closed(reason) => println!("connection closed: {:?}", reason.code())
+//! }
 //!
 //! # Ok(())
 //! # }
@@ -64,7 +75,7 @@
 //! # use tokio_util::compat::Tokio02AsyncReadCompatExt;
 //! # use tokio::stream::StreamExt;
 //! # async fn doc() -> Result<(), soketto::BoxedError> {
-//! use soketto::{handshake::{Server, ClientRequest, server::Response}};
+//! use soketto::{Message, handshake::{Server, ClientRequest, server::Response}};
 //!
 //! // First, we listen for incoming connections.
 //! let mut listener = tokio::net::TcpListener::bind("...").await?;
@@ -80,12 +91,18 @@
 //!     };
 //!
 //!     // Here we accept the client unconditionally.
-//!     let accept = Response::Accept { key: &websocket_key, protocol: None };
+//!     let accept = Response::Accept { key: websocket_key.as_ref(), protocol: None, extra_headers: &[] };
 //!     server.send_response(&accept).await?;
 //!
 //!     // And we can finally transition to a websocket connection.
 //!     let (mut sender, mut receiver) = server.into_builder().finish();
 //!
+//!     // Messages can be received with their payload data ...
+//!     if let Message::Text(text) = receiver.receive().await? {
+//!         sender.send_text(std::str::from_utf8(&text)?).await?
+//!     }
+//!
+//!     // ... or into a buffer which can be reused.
 //!     let mut data = Vec::new();
 //!     let data_type = receiver.receive_data(&mut data).await?;
 //!
@@ -116,16 +133,46 @@ pub mod data;
 pub mod extension;
 pub mod handshake;
 pub mod connection;
+pub mod observer;
+
+#[cfg(feature = "blocking")]
+pub mod blocking;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
 
 use bytes::BytesMut;
-use futures::io::{AsyncRead, AsyncReadExt};
-use std::io;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use std::{io, pin::Pin};
 
 pub use connection::{Mode, Receiver, Sender};
-pub use data::{Data, Incoming};
+pub use data::{CloseReason, Data, Message, MessageMeta, OutgoingMessage, PayloadBuf};
+
+#[allow(deprecated)]
+pub use data::Incoming;
 
 pub type BoxedError = Box<dyn std::error::Error + Send + Sync>;
 
+/// An async I/O resource which can be used as a trait object.
+///
+/// Implemented for all `AsyncRead + AsyncWrite` types.
+pub trait AsyncReadWrite: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite + ?Sized> AsyncReadWrite for T {}
+
+/// A type-erased socket, e.g. to use plain and TLS connections with the
+/// same handshake and connection types.
+///
+/// ```
+/// # fn doc(plain: futures::io::Cursor<Vec<u8>>) {
+/// use soketto::{BoxedSocket, handshake::Client};
+///
+/// let socket: BoxedSocket = Box::pin(plain);
+/// let client = Client::new(socket, "localhost", "/");
+/// # }
+/// ```
+pub type BoxedSocket = Pin<Box<dyn AsyncReadWrite + Send>>;
+
 /// A parsing result.
 #[derive(Debug, Clone)]
 pub enum Parsing<T, N = ()> {
@@ -169,6 +216,9 @@ const fn as_u64(a: usize) -> u64 {
 }
 
 /// Fill the buffer from the given `AsyncRead` impl with up to `max` bytes.
+///
+/// `AsyncRead::poll_read` requires an initialised buffer, so the spare
+/// bytes are zeroed before reading (uninitialised memory would need `unsafe`).
 async fn read<R>(reader: &mut R, dest: &mut BytesMut, max: usize) -> io::Result<()>
 where
     R: AsyncRead + Unpin
@@ -0,0 +1,268 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! In-memory I/O resources for tests and benchmarks (requires feature `testing`).
+//!
+//! [`duplex`] creates a connected pair of [`Endpoint`]s. Reads return at
+//! most a configurable number of bytes, so frames can be split at arbitrary
+//! offsets. A [`Control`] of an endpoint holds back reads, e.g. to test
+//! timeouts, and makes reads or writes fail after a number of bytes.
+//! [`connected_pair`] skips the handshake and returns the connection
+//! builders of a client and a server.
+
+use crate::connection::{Builder, Mode};
+use futures::io::{AsyncRead, AsyncWrite};
+use std::{collections::VecDeque, io, pin::Pin, sync::{Arc, Mutex}};
+use std::task::{Context, Poll, Waker};
+
+/// One direction of a duplex connection.
+#[derive(Debug, Default)]
+struct Pipe {
+    buffer: VecDeque<u8>,
+    closed: bool,
+    waker: Option<Waker>,
+    /// Are reads held back?
+    held: bool,
+    /// The number of bytes which can be read before reads fail.
+    read_limit: Option<usize>,
+    /// The number of bytes which can be written before writes fail.
+    write_limit: Option<usize>
+}
+
+/// One end of an in-memory duplex connection.
+#[derive(Debug)]
+pub struct Endpoint {
+    reader: Arc<Mutex<Pipe>>,
+    writer: Arc<Mutex<Pipe>>,
+    max_chunk: usize
+}
+
+/// Create a connected pair of in-memory I/O resources.
+///
+/// Reads return at most `max_chunk` bytes at once.
+pub fn duplex(max_chunk: usize) -> (Endpoint, Endpoint) {
+    assert!(max_chunk > 0);
+    let a = Arc::new(Mutex::new(Pipe::default()));
+    let b = Arc::new(Mutex::new(Pipe::default()));
+    let x = Endpoint { reader: a.clone(), writer: b.clone(), max_chunk };
+    let y = Endpoint { reader: b, writer: a, max_chunk };
+    (x, y)
+}
+
+/// Create the connection builders of a client and a server connected in-memory.
+///
+/// No handshake is performed. Reads return at most `max_chunk` bytes at once.
+pub fn connected_pair(max_chunk: usize) -> (Builder<Endpoint>, Builder<Endpoint>) {
+    let (a, b) = duplex(max_chunk);
+    (Builder::new(a, Mode::Client), Builder::new(b, Mode::Server))
+}
+
+impl Endpoint {
+    /// Set the max. number of bytes a read returns at once.
+    pub fn set_max_chunk(&mut self, max_chunk: usize) {
+        assert!(max_chunk > 0);
+        self.max_chunk = max_chunk
+    }
+
+    /// Get a handle to control this endpoint after it has been moved, e.g.
+    /// into a connection.
+    pub fn control(&self) -> Control {
+        Control { reader: self.reader.clone(), writer: self.writer.clone() }
+    }
+}
+
+/// Controls the reads and writes of an [`Endpoint`], cf. [`Endpoint::control`].
+#[derive(Debug, Clone)]
+pub struct Control {
+    reader: Arc<Mutex<Pipe>>,
+    writer: Arc<Mutex<Pipe>>
+}
+
+impl Control {
+    /// Hold back reads of the endpoint until released.
+    ///
+    /// While held, reads are pending even if data is available. Releasing
+    /// wakes up a pending read.
+    pub fn hold(&self, hold: bool) {
+        let mut pipe = self.reader.lock().unwrap();
+        pipe.held = hold;
+        if !hold {
+            if let Some(w) = pipe.waker.take() {
+                w.wake()
+            }
+        }
+    }
+
+    /// Fail reads of the endpoint with `ConnectionReset` after `n` more bytes.
+    pub fn fail_reads_after(&self, n: usize) {
+        self.reader.lock().unwrap().read_limit = Some(n)
+    }
+
+    /// Fail writes of the endpoint with `BrokenPipe` after `n` more bytes.
+    pub fn fail_writes_after(&self, n: usize) {
+        self.writer.lock().unwrap().write_limit = Some(n)
+    }
+
+    /// The number of bytes written to the endpoint which have not been read yet.
+    pub fn buffered(&self) -> usize {
+        self.reader.lock().unwrap().buffer.len()
+    }
+}
+
+impl AsyncRead for Endpoint {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.reader.lock().unwrap();
+        if pipe.read_limit == Some(0) {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+        }
+        if pipe.held || (pipe.buffer.is_empty() && !pipe.closed) {
+            pipe.waker = Some(cx.waker().clone());
+            return Poll::Pending
+        }
+        if pipe.buffer.is_empty() {
+            return Poll::Ready(Ok(0))
+        }
+        let limit = pipe.read_limit.unwrap_or(usize::MAX);
+        let n = self.max_chunk.min(buf.len()).min(pipe.buffer.len()).min(limit);
+        for (b, x) in buf.iter_mut().zip(pipe.buffer.drain(.. n)) {
+            *b = x
+        }
+        if let Some(l) = &mut pipe.read_limit {
+            *l -= n
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Endpoint {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let mut pipe = self.writer.lock().unwrap();
+        if pipe.closed || pipe.write_limit == Some(0) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+        }
+        let n = buf.len().min(pipe.write_limit.unwrap_or(usize::MAX));
+        pipe.buffer.extend(&buf[.. n]);
+        if let Some(l) = &mut pipe.write_limit {
+            *l -= n
+        }
+        if let Some(w) = pipe.waker.take() {
+            w.wake()
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        close(&self.writer);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        close(&self.writer)
+    }
+}
+
+fn close(pipe: &Mutex<Pipe>) {
+    let mut pipe = pipe.lock().unwrap();
+    pipe.closed = true;
+    if let Some(w) = pipe.waker.take() {
+        w.wake()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Message, connection::{Builder, Error, Mode}};
+    use futures::{executor::block_on, future, prelude::*};
+    use std::{io, task::Poll};
+    use super::{connected_pair, duplex};
+
+    #[test]
+    fn chunked_reads() {
+        let (mut a, mut b) = duplex(3);
+        block_on(async {
+            a.write_all(b"hello world").await.unwrap();
+            let mut buf = [0; 8];
+            assert_eq!(3, b.read(&mut buf).await.unwrap());
+            b.set_max_chunk(16);
+            assert_eq!(8, b.read(&mut buf).await.unwrap());
+            assert_eq!(b"lo world", &buf);
+            drop(a);
+            assert_eq!(0, b.read(&mut buf).await.unwrap())
+        })
+    }
+
+    #[test]
+    fn held_reads() {
+        let (mut a, mut b) = duplex(64);
+        let control = b.control();
+        control.hold(true);
+        block_on(async {
+            a.write_all(b"data").await.unwrap();
+            assert_eq!(4, control.buffered());
+            let mut buf = [0; 4];
+            let mut read = b.read(&mut buf);
+            assert!(matches!(future::poll_fn(|cx| Poll::Ready(read.poll_unpin(cx))).await, Poll::Pending));
+            control.hold(false);
+            assert_eq!(4, read.await.unwrap());
+            assert_eq!(0, control.buffered())
+        })
+    }
+
+    #[test]
+    fn scripted_failures() {
+        let (mut a, mut b) = duplex(64);
+        a.control().fail_writes_after(5);
+        b.control().fail_reads_after(2);
+        block_on(async {
+            assert_eq!(5, a.write(b"hello world").await.unwrap());
+            assert_eq!(io::ErrorKind::BrokenPipe, a.write(b"!").await.unwrap_err().kind());
+            let mut buf = [0; 8];
+            assert_eq!(2, b.read(&mut buf).await.unwrap());
+            assert_eq!(io::ErrorKind::ConnectionReset, b.read(&mut buf).await.unwrap_err().kind())
+        })
+    }
+
+    #[test]
+    fn fragment_boundaries_at_every_offset() {
+        for max_chunk in 1 ..= 24 {
+            let (client, mut server) = connected_pair(max_chunk);
+            server.set_max_frame_size(5);
+            let (mut server_sender, _server_receiver) = server.finish();
+            let (_client_sender, mut client_receiver) = client.finish();
+            block_on(async {
+                server_sender.send_text("fragmented message").await.unwrap();
+                server_sender.flush().await.unwrap();
+                assert_eq!(Message::Text("fragmented message".into()), client_receiver.receive().await.unwrap());
+                assert_eq!(4, client_receiver.message_meta().fragments)
+            })
+        }
+    }
+
+    #[test]
+    fn connection_sees_io_errors() {
+        let (a, b) = duplex(64);
+        let control = b.control();
+        let (mut client_sender, _client_receiver) = Builder::new(a, Mode::Client).finish();
+        let (_server_sender, mut server_receiver) = Builder::new(b, Mode::Server).finish();
+        block_on(async {
+            client_sender.send_text("hello").await.unwrap();
+            client_sender.flush().await.unwrap();
+            assert_eq!(Message::Text("hello".into()), server_receiver.receive().await.unwrap());
+            control.fail_reads_after(3);
+            client_sender.send_text("world").await.unwrap();
+            client_sender.flush().await.unwrap();
+            assert!(matches!(server_receiver.receive().await, Err(Error::Io(_))))
+        })
+    }
+}
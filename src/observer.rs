@@ -0,0 +1,152 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Hooks for metrics of connections and handshakes.
+//!
+//! An [`Observer`] is notified of frames, messages and the closing of a
+//! connection (cf. [`connection::Builder::set_observer`]), a
+//! [`HandshakeObserver`] of the start and outcome of opening handshakes
+//! (cf. [`handshake::Client::set_observer`] and
+//! [`handshake::Server::set_observer`]). All methods do nothing by default,
+//! so implementations only need to provide the ones of interest, e.g. to
+//! update counters or histograms of some metrics library.
+//!
+//! [`connection::Builder::set_observer`]: crate::connection::Builder::set_observer
+//! [`handshake::Client::set_observer`]: crate::handshake::Client::set_observer
+//! [`handshake::Server::set_observer`]: crate::handshake::Server::set_observer
+
+use crate::{base::OpCode, connection::Mode, data::Data};
+use std::fmt;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+/// Observer of connection events.
+///
+/// One observer is shared by the [`Sender`](crate::Sender) and
+/// [`Receiver`](crate::Receiver) of a connection, hence it must be `Sync`.
+pub trait Observer: Send + Sync {
+    /// A frame with the given opcode and payload length has been received.
+    fn on_frame_received(&self, _opcode: OpCode, _len: usize) {}
+
+    /// A frame with the given opcode and payload length is sent.
+    ///
+    /// This includes PONG and CLOSE frames sent in response to the remote.
+    fn on_frame_sent(&self, _opcode: OpCode, _len: usize) {}
+
+    /// A complete text or binary message has been received.
+    fn on_message(&self, _data: &Data) {}
+
+    /// The closing handshake has been started with the given status code.
+    ///
+    /// If `initiated_by_us` is true, we have sent the first CLOSE frame,
+    /// e.g. with [`Sender::close`](crate::Sender::close) or because the
+    /// remote violated the protocol, otherwise the remote has sent it.
+    fn on_close(&self, _code: Option<u16>, _initiated_by_us: bool) {}
+}
+
+impl fmt::Debug for dyn Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
+
+/// The outcome of an opening handshake, cf. [`HandshakeObserver`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HandshakeOutcome {
+    /// The handshake request has been accepted.
+    Accepted,
+    /// The handshake request has been rejected with the given status code.
+    Rejected { status_code: u16 },
+    /// The client is redirected with the given status code.
+    Redirect { status_code: u16 },
+    /// The handshake failed with an error.
+    Failed
+}
+
+/// Observer of opening handshakes.
+///
+/// Handshake durations can be measured between
+/// [`HandshakeObserver::on_start`] and [`HandshakeObserver::on_end`].
+pub trait HandshakeObserver: Send + Sync {
+    /// A handshake has been started, i.e. the client is sending its request
+    /// or the server is receiving a request.
+    fn on_start(&self, _mode: Mode) {}
+
+    /// A handshake has ended with the given outcome, i.e. the client has
+    /// received the response or the server has sent it.
+    fn on_end(&self, _mode: Mode, _outcome: HandshakeOutcome) {}
+}
+
+impl fmt::Debug for dyn HandshakeObserver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("HandshakeObserver")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{base::OpCode, connection::Mode, data::{Data, Message}, mock::duplex};
+    use crate::handshake::{Client, Server, ServerResponse};
+    use futures::{executor::block_on, future};
+    use std::sync::Arc;
+    use super::{HandshakeOutcome, testing::{Record, Recorder}};
+
+    #[test]
+    fn handshake_and_connection_events() {
+        let (a, b) = duplex(1024);
+        let client_records = Arc::new(Recorder::new());
+        let server_records = Arc::new(Recorder::new());
+
+        let client = async {
+            let mut client = Client::new(a, "localhost", "/");
+            client.set_observer(client_records.clone());
+            assert!(matches!(client.handshake().await.unwrap(), ServerResponse::Accepted { .. }));
+            let mut builder = client.into_builder();
+            builder.set_observer(client_records.clone());
+            let (mut sender, mut receiver) = builder.finish();
+            sender.send_text("hello").await.unwrap();
+            sender.flush().await.unwrap();
+            assert!(matches!(receiver.receive().await.unwrap(), Message::Closed(_)))
+        };
+
+        let server = async {
+            let mut server = Server::new(b);
+            server.set_observer(server_records.clone());
+            let request = server.receive_request().await.unwrap();
+            server.send_response(&request.accept()).await.unwrap();
+            let mut builder = server.into_builder();
+            builder.set_observer(server_records.clone());
+            let (mut sender, mut receiver) = builder.finish();
+            assert_eq!(Message::Text("hello".into()), receiver.receive().await.unwrap());
+            sender.close().await.unwrap();
+            assert!(matches!(receiver.receive().await.unwrap(), Message::Closed(_)))
+        };
+
+        block_on(future::join(client, server));
+
+        assert_eq!(client_records.records(), vec![
+            Record::HandshakeStart(Mode::Client),
+            Record::HandshakeEnd(Mode::Client, HandshakeOutcome::Accepted),
+            Record::FrameSent(OpCode::Text, 5),
+            Record::FrameReceived(OpCode::Close, 2),
+            Record::Close { code: Some(1000), initiated_by_us: false },
+            Record::FrameSent(OpCode::Close, 2)
+        ]);
+        assert_eq!(server_records.records(), vec![
+            Record::HandshakeStart(Mode::Server),
+            Record::HandshakeEnd(Mode::Server, HandshakeOutcome::Accepted),
+            Record::FrameReceived(OpCode::Text, 5),
+            Record::Message(Data::Text(5)),
+            Record::FrameSent(OpCode::Close, 2),
+            Record::Close { code: Some(1000), initiated_by_us: true },
+            Record::FrameReceived(OpCode::Close, 2)
+        ])
+    }
+}
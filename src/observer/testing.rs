@@ -0,0 +1,83 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! An observer for tests (requires feature `testing`).
+//!
+//! [`Recorder`] implements [`Observer`] and [`HandshakeObserver`] and records
+//! every event for later assertions.
+
+use crate::{base::OpCode, connection::Mode, data::Data};
+use std::sync::Mutex;
+use super::{HandshakeObserver, HandshakeOutcome, Observer};
+
+/// An event recorded by [`Recorder`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Record {
+    /// Cf. [`Observer::on_frame_received`].
+    FrameReceived(OpCode, usize),
+    /// Cf. [`Observer::on_frame_sent`].
+    FrameSent(OpCode, usize),
+    /// Cf. [`Observer::on_message`].
+    Message(Data),
+    /// Cf. [`Observer::on_close`].
+    Close { code: Option<u16>, initiated_by_us: bool },
+    /// Cf. [`HandshakeObserver::on_start`].
+    HandshakeStart(Mode),
+    /// Cf. [`HandshakeObserver::on_end`].
+    HandshakeEnd(Mode, HandshakeOutcome)
+}
+
+/// An observer which records all events.
+#[derive(Debug, Default)]
+pub struct Recorder {
+    records: Mutex<Vec<Record>>
+}
+
+impl Recorder {
+    /// Create a new recorder without any records.
+    pub fn new() -> Self {
+        Recorder::default()
+    }
+
+    /// Get all events recorded so far.
+    pub fn records(&self) -> Vec<Record> {
+        self.records.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn record(&self, r: Record) {
+        self.records.lock().unwrap_or_else(|e| e.into_inner()).push(r)
+    }
+}
+
+impl Observer for Recorder {
+    fn on_frame_received(&self, opcode: OpCode, len: usize) {
+        self.record(Record::FrameReceived(opcode, len))
+    }
+
+    fn on_frame_sent(&self, opcode: OpCode, len: usize) {
+        self.record(Record::FrameSent(opcode, len))
+    }
+
+    fn on_message(&self, data: &Data) {
+        self.record(Record::Message(data.clone()))
+    }
+
+    fn on_close(&self, code: Option<u16>, initiated_by_us: bool) {
+        self.record(Record::Close { code, initiated_by_us })
+    }
+}
+
+impl HandshakeObserver for Recorder {
+    fn on_start(&self, mode: Mode) {
+        self.record(Record::HandshakeStart(mode))
+    }
+
+    fn on_end(&self, mode: Mode, outcome: HandshakeOutcome) {
+        self.record(Record::HandshakeEnd(mode, outcome))
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright (c) 2019 Parity Technologies (UK) Ltd.
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or http://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+//! Checks that soketto builds for `wasm32-unknown-unknown` without its
+//! default features, i.e. without an OS random number generator.
+//!
+//! Build with `cargo build --target wasm32-unknown-unknown` and run the
+//! tests natively with `cargo test`. Randomness is provided explicitly
+//! with `Client::set_nonce` and `Builder::set_mask_source`.
+
+use futures::{io::{AsyncRead, AsyncWrite, Cursor}, task::{Context, Poll}};
+use soketto::{base, handshake};
+use std::{io, pin::Pin, sync::{Arc, Mutex}};
+
+/// The nonce from RFC 6455, section 1.3.
+pub const NONCE: &[u8; 16] = b"the sample nonce";
+
+/// A fixed mask for client frames.
+pub const MASK: [u8; 4] = [1, 2, 3, 4];
+
+/// An in-memory socket reading from a fixed input and recording the output.
+#[derive(Debug, Default)]
+pub struct Socket {
+    input: Cursor<Vec<u8>>,
+    output: Arc<Mutex<Vec<u8>>>
+}
+
+impl Socket {
+    /// Create a socket which reads the given input.
+    pub fn new(input: Vec<u8>) -> Self {
+        Socket { input: Cursor::new(input), output: Arc::default() }
+    }
+
+    /// A handle to the bytes written to this socket.
+    pub fn output(&self) -> Arc<Mutex<Vec<u8>>> {
+        self.output.clone()
+    }
+}
+
+impl AsyncRead for Socket {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.input).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Socket {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.output.lock().unwrap().extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Encode a masked binary frame and decode it again, returning the payload.
+pub fn frame_roundtrip(payload: &[u8]) -> Result<Vec<u8>, base::Error> {
+    let mut codec = base::Codec::new();
+    let mut header = base::Header::new(base::OpCode::Binary);
+    header.set_masked(true);
+    header.set_mask(u32::from_be_bytes(MASK));
+    header.set_payload_len(payload.len());
+
+    let mut frame = codec.encode_header(&header).to_vec();
+    let mut data = payload.to_vec();
+    base::Codec::apply_mask(&header, &mut data);
+    frame.extend_from_slice(&data);
+
+    match codec.decode_header(&frame)? {
+        soketto::Parsing::Done { value, offset } => {
+            let mut data = frame[offset .. offset + value.payload_len()].to_vec();
+            base::Codec::apply_mask(&value, &mut data);
+            Ok(data)
+        }
+        soketto::Parsing::NeedMore(_) => unreachable!("complete frame")
+    }
+}
+
+/// Perform a client handshake against a canned server response and send a
+/// text message, returning everything written to the socket.
+pub async fn client_session(text: &str) -> Result<Vec<u8>, soketto::BoxedError> {
+    let response = b"HTTP/1.1 101 Switching Protocols\r\n\
+        Upgrade: websocket\r\n\
+        Connection: Upgrade\r\n\
+        Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+    let socket = Socket::new(response.to_vec());
+    let output = socket.output();
+    let mut client = handshake::Client::new(socket, "example.com", "/chat");
+    client.set_nonce(NONCE);
+    match client.handshake().await? {
+        handshake::ServerResponse::Accepted { .. } => {}
+        other => return Err(format!("unexpected response: {:?}", other).into())
+    }
+    let mut builder = client.into_builder();
+    builder.set_mask_source(|| MASK);
+    let (mut sender, _receiver) = builder.finish();
+    sender.send_text(text).await?;
+    sender.flush().await?;
+    let bytes = output.lock().unwrap().clone();
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MASK, client_session, frame_roundtrip};
+
+    #[test]
+    fn frames() {
+        assert_eq!(b"hello".to_vec(), frame_roundtrip(b"hello").unwrap());
+        assert_eq!(vec![0; 1000], frame_roundtrip(&[0; 1000]).unwrap())
+    }
+
+    #[test]
+    fn handshake_and_send() {
+        let output = futures::executor::block_on(client_session("hi")).unwrap();
+        let request_len = output.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let request = std::str::from_utf8(&output[.. request_len]).unwrap();
+        assert!(request.starts_with("GET /chat HTTP/1.1\r\n"));
+        assert!(request.contains("Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"));
+
+        let frame = &output[request_len ..];
+        assert_eq!(&[0x81, 0x82][..], &frame[.. 2]);
+        assert_eq!(&MASK[..], &frame[2 .. 6]);
+        assert_eq!(&[b'h' ^ 1, b'i' ^ 2][..], &frame[6 ..])
+    }
+}